@@ -0,0 +1,154 @@
+//! Compare writing of downloaded pieces directly to files with writing through write cache.
+//! Baseline is the former writer, which did seek, write and flush of every piece.
+//! Run with `cargo bench --bench write_cache`.
+
+use std::path::Path;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use lava_torrent::torrent::v1::Torrent;
+use tokio::fs::{File, OpenOptions};
+use tokio::io::{AsyncSeekExt, AsyncWriteExt};
+use torrent_client::file_layout::FileLayout;
+use torrent_client::storage::{CachedStorage, FileStorage, Storage, DEFAULT_CACHE_SIZE};
+use torrent_client::test_utils::files_torrent;
+
+const PIECE_LENGTH: usize = 256 * 1024;
+const BLOCK_SIZE: usize = 16 * 1024;
+const PIECE_COUNT: usize = 512;
+const ROUNDS: usize = 3;
+
+fn main() {
+    let runtime = tokio::runtime::Builder::new_multi_thread()
+        .enable_all()
+        .build()
+        .unwrap();
+    let torrent = bench_torrent();
+    let layout = Arc::new(FileLayout::from_torrent(&torrent).unwrap());
+    let order = download_order();
+    let total = (PIECE_LENGTH * PIECE_COUNT) as f64 / (1024.0 * 1024.0);
+
+    let mut best = Duration::MAX;
+    for _ in 0..ROUNDS {
+        let dir = tempfile::tempdir().unwrap();
+        let elapsed = runtime.block_on(write_pieces_seeking(dir.path(), &layout, &order));
+        best = best.min(elapsed);
+    }
+    report("baseline", best, total);
+
+    let scenarios = [
+        ("direct pieces", false, PIECE_LENGTH),
+        ("cached pieces", true, PIECE_LENGTH),
+        ("direct blocks", false, BLOCK_SIZE),
+        ("cached blocks", true, BLOCK_SIZE),
+    ];
+    for (name, cached, block_size) in scenarios {
+        let mut best = Duration::MAX;
+        for _ in 0..ROUNDS {
+            let dir = tempfile::tempdir().unwrap();
+            let files: Arc<dyn Storage> =
+                Arc::new(FileStorage::new(dir.path().to_path_buf(), layout.clone()));
+            let storage: Arc<dyn Storage> = if cached {
+                Arc::new(CachedStorage::new(
+                    files,
+                    layout.clone(),
+                    DEFAULT_CACHE_SIZE,
+                ))
+            } else {
+                files
+            };
+            let elapsed = runtime.block_on(write_pieces(storage, &order, block_size));
+            best = best.min(elapsed);
+        }
+        report(name, best, total);
+    }
+}
+
+/// Print the best time of scenario and its throughput for `total` MiB.
+fn report(name: &str, best: Duration, total: f64) {
+    println!(
+        "{name:>14}: {:>8.1} ms, {:>8.1} MiB/s",
+        best.as_secs_f64() * 1000.0,
+        total / best.as_secs_f64()
+    );
+}
+
+/// Torrent with many small files, so pieces cross boundaries of files.
+fn bench_torrent() -> Torrent {
+    let file_length = PIECE_LENGTH * PIECE_COUNT / 64;
+    let files: Vec<(String, i64)> = (0..64)
+        .map(|idx| (format!("file{idx}"), file_length as i64))
+        .collect();
+    let files: Vec<(&str, i64)> = files
+        .iter()
+        .map(|(path, length)| (path.as_str(), *length))
+        .collect();
+    files_torrent(&files, PIECE_LENGTH as i64)
+}
+
+/// Pieces complete in order similar to real download, neighbouring pieces come from different peers.
+fn download_order() -> Vec<usize> {
+    let mut order: Vec<usize> = (0..PIECE_COUNT).collect();
+    for chunk in order.chunks_mut(16) {
+        chunk.reverse();
+        chunk.swap(0, chunk.len() / 2);
+    }
+    order
+}
+
+/// Write pieces as verified pieces come from peers and sync them, returns elapsed time.
+/// Every piece is written in blocks of given size.
+async fn write_pieces(storage: Arc<dyn Storage>, order: &[usize], block_size: usize) -> Duration {
+    let block = vec![0x5au8; block_size];
+    let start = Instant::now();
+    for &piece_index in order {
+        for begin in (0..PIECE_LENGTH).step_by(block_size) {
+            storage
+                .write_block(piece_index, begin, &block)
+                .await
+                .unwrap();
+        }
+    }
+    storage.flush().await.unwrap();
+    start.elapsed()
+}
+
+/// Write pieces as the former writer did, every piece is written by seek, write and flush of its files.
+/// Files are synced at the end, so the result is comparable with storage flush.
+async fn write_pieces_seeking(folder: &Path, layout: &FileLayout, order: &[usize]) -> Duration {
+    let piece = vec![0x5au8; PIECE_LENGTH];
+    let start = Instant::now();
+    let mut files: Vec<Option<File>> = layout.files().iter().map(|_| None).collect();
+    for &piece_index in order {
+        for slice in layout.map_range(layout.piece_offset(piece_index), PIECE_LENGTH) {
+            let file = match &mut files[slice.file_index] {
+                Some(file) => file,
+                empty => {
+                    let path = folder.join(&layout.files()[slice.file_index].path);
+                    tokio::fs::create_dir_all(path.parent().unwrap())
+                        .await
+                        .unwrap();
+                    let file = OpenOptions::new()
+                        .create(true)
+                        .truncate(false)
+                        .write(true)
+                        .open(path)
+                        .await
+                        .unwrap();
+                    empty.insert(file)
+                }
+            };
+            file.seek(std::io::SeekFrom::Start(slice.file_offset))
+                .await
+                .unwrap();
+            file.write_all(&piece[slice.range_offset..slice.range_offset + slice.length])
+                .await
+                .unwrap();
+            file.flush().await.unwrap();
+        }
+    }
+    for file in files.iter_mut().flatten() {
+        file.sync_all().await.unwrap();
+    }
+    start.elapsed()
+}
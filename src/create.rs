@@ -0,0 +1,368 @@
+use std::collections::HashMap;
+use std::fs::File as FsFile;
+use std::io::{Read, Seek, SeekFrom};
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use anyhow::{Context, Result};
+use lava_torrent::bencode::BencodeElem;
+use lava_torrent::torrent::v1::{File, Torrent};
+use sha1::{Digest, Sha1};
+
+/// Smallest piece size chosen automatically.
+const MIN_PIECE_LENGTH: u64 = 16 * 1024;
+/// Largest piece size chosen automatically.
+const MAX_PIECE_LENGTH: u64 = 16 * 1024 * 1024;
+/// Largest piece size, that can be set in options. Every hashing thread keeps one piece in memory.
+const MAX_CUSTOM_PIECE_LENGTH: u64 = 64 * 1024 * 1024;
+/// Number of pieces, that automatically chosen piece size aims for.
+const TARGET_PIECE_COUNT: u64 = 1500;
+
+/// Settings of created torrent.
+#[derive(Debug, Clone, Default)]
+pub struct CreateOptions {
+    /// Tracker URLs, first one is used as `announce`, all of them form `announce-list`.
+    pub trackers: Vec<String>,
+    /// Piece size in bytes, it has to be power of two. `None` means that it is chosen automatically.
+    pub piece_length: Option<u64>,
+    pub comment: Option<String>,
+    /// Value of `created by`, name and version of this client is used if not set.
+    pub created_by: Option<String>,
+    /// Private torrents are shared only through trackers.
+    pub private: bool,
+    /// HTTP URLs with the same content (BEP 19).
+    pub web_seeds: Vec<String>,
+    /// Insert padding files, so every file starts on piece boundary (BEP 47).
+    pub pad_files: bool,
+    /// Number of threads used for hashing, `0` means number of available CPUs.
+    pub threads: usize,
+}
+
+/// Part of content of created torrent, real file or padding of zeros.
+#[derive(Debug, Clone)]
+enum Segment {
+    File { path: PathBuf, length: u64 },
+    Padding { length: u64 },
+}
+
+impl Segment {
+    fn length(&self) -> u64 {
+        match self {
+            Segment::File { length, .. } | Segment::Padding { length } => *length,
+        }
+    }
+}
+
+/// Create torrent from file or directory on given path.
+/// Pieces are hashed in parallel. Created torrent can be written by `Torrent::write_into_file`.
+pub fn create_torrent(path: impl AsRef<Path>, options: &CreateOptions) -> Result<Torrent> {
+    // Paths like `.` or `dir/..` get name of directory, they point to
+    let path = &std::fs::canonicalize(path.as_ref())
+        .with_context(|| format!("Cannot read {}", path.as_ref().display()))?;
+    let name = path
+        .file_name()
+        .context("Path has no file name")?
+        .to_string_lossy()
+        .into_owned();
+
+    let files = collect_files(path)?;
+    let total_length: u64 = files.iter().map(|(_, length)| length).sum();
+    anyhow::ensure!(total_length > 0, "Torrent content is empty");
+
+    let piece_length = match options.piece_length {
+        Some(piece_length) => {
+            anyhow::ensure!(
+                piece_length.is_power_of_two()
+                    && (MIN_PIECE_LENGTH..=MAX_CUSTOM_PIECE_LENGTH).contains(&piece_length),
+                "Piece length has to be power of two between {MIN_PIECE_LENGTH} and {MAX_CUSTOM_PIECE_LENGTH}"
+            );
+            piece_length
+        }
+        None => auto_piece_length(total_length),
+    };
+
+    // Build list of content segments and file entries of torrent
+    let single_file = path.is_file();
+    let mut segments = Vec::new();
+    let mut torrent_files = Vec::new();
+    let mut offset = 0u64;
+    for (file_idx, (file_path, length)) in files.iter().enumerate() {
+        segments.push(Segment::File {
+            path: file_path.clone(),
+            length: *length,
+        });
+        torrent_files.push(File {
+            length: *length as i64,
+            path: file_path.strip_prefix(path)?.to_path_buf(),
+            extra_fields: file_attributes(file_path),
+        });
+        offset += length;
+
+        let is_last = file_idx == files.len() - 1;
+        let padding = (piece_length - offset % piece_length) % piece_length;
+        if options.pad_files && !single_file && !is_last && padding > 0 {
+            segments.push(Segment::Padding { length: padding });
+            torrent_files.push(File {
+                length: padding as i64,
+                path: PathBuf::from(".pad").join(padding.to_string()),
+                extra_fields: Some(HashMap::from([(
+                    "attr".to_string(),
+                    BencodeElem::String("p".to_string()),
+                )])),
+            });
+            offset += padding;
+        }
+    }
+
+    let pieces = hash_pieces(&segments, offset, piece_length, options.threads)?;
+
+    let mut extra_fields = HashMap::new();
+    if let Some(comment) = &options.comment {
+        extra_fields.insert("comment".to_string(), BencodeElem::String(comment.clone()));
+    }
+    let created_by = options
+        .created_by
+        .clone()
+        .unwrap_or_else(|| format!("PVR torrent client {}", env!("CARGO_PKG_VERSION")));
+    extra_fields.insert("created by".to_string(), BencodeElem::String(created_by));
+    extra_fields.insert(
+        "creation date".to_string(),
+        BencodeElem::Integer(SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs() as i64),
+    );
+    if !options.web_seeds.is_empty() {
+        extra_fields.insert(
+            "url-list".to_string(),
+            BencodeElem::List(
+                options
+                    .web_seeds
+                    .iter()
+                    .map(|url| BencodeElem::String(url.clone()))
+                    .collect(),
+            ),
+        );
+    }
+
+    let extra_info_fields = options
+        .private
+        .then(|| HashMap::from([("private".to_string(), BencodeElem::Integer(1))]));
+
+    Ok(Torrent {
+        announce: options.trackers.first().cloned(),
+        announce_list: (options.trackers.len() > 1).then(|| {
+            options
+                .trackers
+                .iter()
+                .map(|tracker| vec![tracker.clone()])
+                .collect()
+        }),
+        length: offset as i64,
+        files: (!single_file).then_some(torrent_files),
+        name,
+        piece_length: piece_length as i64,
+        pieces,
+        extra_fields: Some(extra_fields),
+        extra_info_fields,
+    })
+}
+
+/// Returns piece size, so the torrent has around `TARGET_PIECE_COUNT` pieces.
+pub fn auto_piece_length(total_length: u64) -> u64 {
+    (total_length / TARGET_PIECE_COUNT)
+        .next_power_of_two()
+        .clamp(MIN_PIECE_LENGTH, MAX_PIECE_LENGTH)
+}
+
+/// Returns all files on given path with their size, directories are walked recursively and sorted by path.
+fn collect_files(path: &Path) -> Result<Vec<(PathBuf, u64)>> {
+    let metadata =
+        std::fs::metadata(path).with_context(|| format!("Cannot read {}", path.display()))?;
+    if metadata.is_file() {
+        return Ok(vec![(path.to_path_buf(), metadata.len())]);
+    }
+
+    let mut files = Vec::new();
+    let mut directories = vec![path.to_path_buf()];
+    while let Some(directory) = directories.pop() {
+        for entry in std::fs::read_dir(&directory)? {
+            let entry = entry?;
+            let file_type = entry.file_type()?;
+            if file_type.is_dir() {
+                directories.push(entry.path());
+            } else if file_type.is_file() {
+                files.push((entry.path(), entry.metadata()?.len()));
+            }
+        }
+    }
+    files.sort();
+    Ok(files)
+}
+
+/// Returns `attr` field of file, `x` is set for executable files.
+fn file_attributes(path: &Path) -> Option<HashMap<String, BencodeElem>> {
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        let mode = std::fs::metadata(path).ok()?.permissions().mode();
+        if mode & 0o111 != 0 {
+            return Some(HashMap::from([(
+                "attr".to_string(),
+                BencodeElem::String("x".to_string()),
+            )]));
+        }
+    }
+    #[cfg(not(unix))]
+    let _ = path;
+    None
+}
+
+/// Compute SHA-1 hashes of all pieces, pieces are split between threads.
+fn hash_pieces(
+    segments: &[Segment],
+    total_length: u64,
+    piece_length: u64,
+    threads: usize,
+) -> Result<Vec<Vec<u8>>> {
+    let piece_count = total_length.div_ceil(piece_length) as usize;
+    let threads = match threads {
+        0 => std::thread::available_parallelism().map_or(1, |n| n.get()),
+        threads => threads,
+    }
+    .min(piece_count)
+    .max(1);
+    let pieces_per_thread = piece_count.div_ceil(threads);
+
+    let results: Vec<Result<Vec<Vec<u8>>>> = std::thread::scope(|scope| {
+        let handles: Vec<_> = (0..threads)
+            .map(|thread_idx| {
+                let first = thread_idx * pieces_per_thread;
+                let last = ((thread_idx + 1) * pieces_per_thread).min(piece_count);
+                scope.spawn(move || {
+                    let mut reader = SegmentReader::new(segments);
+                    let mut buffer = vec![0u8; piece_length as usize];
+                    (first..last)
+                        .map(|piece_idx| {
+                            let start = piece_idx as u64 * piece_length;
+                            let length = piece_length.min(total_length - start) as usize;
+                            reader.read_at(start, &mut buffer[..length])?;
+                            Ok(Sha1::digest(&buffer[..length]).to_vec())
+                        })
+                        .collect()
+                })
+            })
+            .collect();
+        handles
+            .into_iter()
+            .map(|handle| handle.join().expect("Hashing thread panicked"))
+            .collect()
+    });
+
+    let mut pieces = Vec::with_capacity(piece_count);
+    for result in results {
+        pieces.extend(result?);
+    }
+    Ok(pieces)
+}
+
+/// Reader of continuous content made of segments, keeps last opened file.
+struct SegmentReader<'a> {
+    segments: &'a [Segment],
+    open_file: Option<(usize, FsFile)>,
+}
+
+impl<'a> SegmentReader<'a> {
+    fn new(segments: &'a [Segment]) -> Self {
+        SegmentReader {
+            segments,
+            open_file: None,
+        }
+    }
+
+    /// Fill whole buffer with content starting at `offset`.
+    fn read_at(&mut self, offset: u64, buffer: &mut [u8]) -> Result<()> {
+        let mut segment_start = 0u64;
+        let mut written = 0usize;
+        for (segment_idx, segment) in self.segments.iter().enumerate() {
+            let segment_end = segment_start + segment.length();
+            let position = offset + written as u64;
+            if written == buffer.len() {
+                break;
+            }
+            if position >= segment_end {
+                segment_start = segment_end;
+                continue;
+            }
+
+            let in_segment = position - segment_start;
+            let length = ((segment_end - position) as usize).min(buffer.len() - written);
+            let target = &mut buffer[written..written + length];
+            match segment {
+                Segment::Padding { .. } => target.fill(0),
+                Segment::File { path, .. } => {
+                    if self.open_file.as_ref().map(|(idx, _)| *idx) != Some(segment_idx) {
+                        self.open_file = Some((segment_idx, FsFile::open(path)?));
+                    }
+                    if let Some((_, file)) = self.open_file.as_mut() {
+                        file.seek(SeekFrom::Start(in_segment))?;
+                        file.read_exact(target)?;
+                    }
+                }
+            }
+            written += length;
+            segment_start = segment_end;
+        }
+        anyhow::ensure!(written == buffer.len(), "Content is shorter than expected");
+        Ok(())
+    }
+}
+
+#[test]
+fn create_torrent_round_trip() {
+    let dir = tempfile::tempdir().unwrap();
+    let content = dir.path().join("content");
+    std::fs::create_dir_all(content.join("sub")).unwrap();
+    std::fs::write(content.join("a.bin"), vec![1u8; 20_000]).unwrap();
+    std::fs::write(content.join("sub/b.bin"), vec![2u8; 50_000]).unwrap();
+
+    let options = CreateOptions {
+        trackers: vec![
+            "http://tracker.example/announce".to_string(),
+            "udp://tracker.example:6969".to_string(),
+        ],
+        comment: Some("build artifacts".to_string()),
+        private: true,
+        web_seeds: vec!["http://seed.example/content".to_string()],
+        pad_files: true,
+        threads: 2,
+        ..Default::default()
+    };
+    let torrent = create_torrent(&content, &options).unwrap();
+    let torrent_path = dir.path().join("content.torrent");
+    torrent.clone().write_into_file(&torrent_path).unwrap();
+
+    let read = Torrent::read_from_file(&torrent_path).unwrap();
+    assert_eq!(read.info_hash(), torrent.info_hash());
+    assert_eq!(read.name, "content");
+    assert_eq!(read.piece_length, 16 * 1024);
+    assert!(read.is_private());
+    assert_eq!(read.announce_list.as_ref().map(Vec::len), Some(2));
+
+    // a.bin is padded to piece boundary, so b.bin starts in new piece
+    let files = read.files.unwrap();
+    assert_eq!(files.len(), 3);
+    assert_eq!(files[1].path, PathBuf::from(".pad").join("12768"));
+    assert_eq!(read.length, 32_768 + 50_000);
+    assert_eq!(read.pieces.len(), 6);
+    assert_eq!(read.pieces[2], Sha1::digest(vec![2u8; 16 * 1024]).to_vec());
+
+    // Name is taken from directory, to which path points
+    let parent_path = content.join("sub").join("..");
+    assert_eq!(
+        create_torrent(parent_path, &options).unwrap().name,
+        "content"
+    );
+    let huge = CreateOptions {
+        piece_length: Some(1 << 40),
+        ..Default::default()
+    };
+    assert!(create_torrent(&content, &huge).is_err());
+}
@@ -0,0 +1,311 @@
+"use strict";
+
+// Token is kept for the browser session, it can be passed in URL as `#token=<token>`
+const TOKEN_KEY = "torrent-client-token";
+const PRIORITIES = ["skip", "low", "normal", "high"];
+
+const state = {
+  token: sessionStorage.getItem(TOKEN_KEY),
+  torrents: [],
+  selected: null,
+  tab: "files",
+  stream: null,
+};
+
+const $ = (selector) => document.querySelector(selector);
+
+// Create element with attributes and children, text is never parsed as HTML.
+function element(tag, attributes = {}, ...children) {
+  const node = document.createElement(tag);
+  for (const [name, value] of Object.entries(attributes)) {
+    if (name.startsWith("on")) {
+      node.addEventListener(name.slice(2), value);
+    } else {
+      node[name] = value;
+    }
+  }
+  for (const child of children) {
+    node.append(child instanceof Node ? child : String(child));
+  }
+  return node;
+}
+
+function formatSize(bytes) {
+  const units = ["B", "KiB", "MiB", "GiB", "TiB"];
+  let unit = 0;
+  while (bytes >= 1024 && unit < units.length - 1) {
+    bytes /= 1024;
+    unit += 1;
+  }
+  return `${bytes.toFixed(unit === 0 ? 0 : 1)} ${units[unit]}`;
+}
+
+const formatRate = (rate) => `${formatSize(rate)}/s`;
+
+function progress(torrent) {
+  if (torrent.piece_count === 0) {
+    return 1;
+  }
+  return (torrent.piece_count - torrent.remaining_pieces) / torrent.piece_count;
+}
+
+function showError(err) {
+  $("#error").textContent = err ? String(err.message || err) : "";
+}
+
+// Call method of JSON-RPC API of daemon.
+async function rpc(method, params = {}) {
+  const response = await fetch("/rpc", {
+    method: "POST",
+    headers: {
+      "Authorization": `Bearer ${state.token}`,
+      "Content-Type": "application/json",
+    },
+    body: JSON.stringify({ jsonrpc: "2.0", id: Date.now(), method, params }),
+  });
+  if (response.status === 401) {
+    logout();
+    throw new Error("Invalid token");
+  }
+  const body = await response.json();
+  if (body.error) {
+    throw new Error(body.error.message);
+  }
+  return body.result;
+}
+
+// Read Server-Sent Events from daemon. EventSource can't send Authorization header, so the stream is read by fetch.
+async function listen() {
+  while (state.token) {
+    const controller = new AbortController();
+    state.stream = controller;
+    try {
+      const response = await fetch("/events", {
+        headers: { "Authorization": `Bearer ${state.token}` },
+        signal: controller.signal,
+      });
+      if (response.status === 401) {
+        logout();
+        return;
+      }
+      const reader = response.body.pipeThrough(new TextDecoderStream()).getReader();
+      let buffer = "";
+      for (;;) {
+        const { value, done } = await reader.read();
+        if (done) {
+          break;
+        }
+        buffer += value;
+        let end;
+        while ((end = buffer.indexOf("\n\n")) >= 0) {
+          const message = buffer.slice(0, end);
+          buffer = buffer.slice(end + 2);
+          const data = message
+            .split("\n")
+            .filter((line) => line.startsWith("data:"))
+            .map((line) => line.slice(5).trim())
+            .join("\n");
+          if (data) {
+            onTorrents(JSON.parse(data));
+          }
+        }
+      }
+    } catch (err) {
+      if (controller.signal.aborted) {
+        return;
+      }
+      showError(err);
+    }
+    // Daemon was restarted or connection was lost
+    await new Promise((resolve) => setTimeout(resolve, 2000));
+  }
+}
+
+function onTorrents(torrents) {
+  state.torrents = torrents;
+  if (!torrents.some((torrent) => torrent.info_hash === state.selected)) {
+    state.selected = null;
+  }
+  renderTorrents();
+  renderDetails();
+}
+
+function renderTorrents() {
+  const rows = state.torrents.map((torrent) => {
+    const paused = torrent.state === "paused";
+    const action = element("button", {
+      type: "button",
+      onclick: (event) => {
+        event.stopPropagation();
+        const method = paused ? "resume_torrent" : "pause_torrent";
+        rpc(method, { info_hash: torrent.info_hash }).catch(showError);
+      },
+    }, paused ? "Resume" : "Pause");
+    const remove = element("button", {
+      type: "button",
+      onclick: (event) => {
+        event.stopPropagation();
+        if (confirm(`Remove ${torrent.name}?`)) {
+          rpc("remove_torrent", { info_hash: torrent.info_hash }).catch(showError);
+        }
+      },
+    }, "Remove");
+    const stateText = torrent.reason ? `${torrent.state} (${torrent.reason})` : torrent.state;
+    return element("tr", {
+      className: torrent.info_hash === state.selected ? "selected" : "",
+      onclick: () => {
+        state.selected = torrent.info_hash;
+        renderTorrents();
+        renderDetails();
+      },
+    },
+      element("td", { className: "name", title: torrent.name }, torrent.name),
+      element("td", {},
+        element("progress", { max: 1, value: progress(torrent) }),
+        ` ${(progress(torrent) * 100).toFixed(1)} %`),
+      element("td", { className: torrent.state === "failed" ? "failed" : "" }, stateText),
+      element("td", {}, formatRate(torrent.download_rate)),
+      element("td", {}, formatRate(torrent.upload_rate)),
+      element("td", {}, torrent.connected_peers),
+      element("td", {}, torrent.ratio.toFixed(2)),
+      element("td", {}, action, " ", remove));
+  });
+  $("#torrents tbody").replaceChildren(...rows);
+
+  const download = state.torrents.reduce((sum, torrent) => sum + torrent.download_rate, 0);
+  const upload = state.torrents.reduce((sum, torrent) => sum + torrent.upload_rate, 0);
+  $("#rates").textContent = `down ${formatRate(download)}, up ${formatRate(upload)}`;
+}
+
+// Show files, peers or trackers of selected torrent.
+async function renderDetails() {
+  const details = $("#details");
+  if (!state.selected) {
+    details.hidden = true;
+    return;
+  }
+  let stats;
+  try {
+    stats = await rpc("torrent_stats", { info_hash: state.selected });
+  } catch (err) {
+    showError(err);
+    return;
+  }
+  details.hidden = false;
+  $("#details-name").textContent = stats.name;
+  const tracker = stats.tracker_error ? `, tracker error: ${stats.tracker_error}` : "";
+  $("#details-info").textContent =
+    `Info hash ${stats.info_hash}, folder ${stats.folder}, downloaded ${formatSize(stats.downloaded)}, ` +
+    `uploaded ${formatSize(stats.uploaded)}${tracker}`;
+  for (const button of document.querySelectorAll("#tabs button")) {
+    button.classList.toggle("active", button.dataset.tab === state.tab);
+  }
+
+  let columns;
+  let rows;
+  if (state.tab === "files") {
+    columns = ["File", "Size", "Priority"];
+    rows = stats.files.map((file) => {
+      const priority = element("select", {
+        onchange: (event) => {
+          const params = { info_hash: stats.info_hash, file_index: file.index, priority: event.target.value };
+          rpc("set_file_priority", params).catch(showError);
+        },
+      }, ...PRIORITIES.map((name) => element("option", { value: name, selected: name === file.priority }, name)));
+      return [file.path, formatSize(file.length), priority];
+    });
+  } else if (state.tab === "peers") {
+    columns = ["Address", "Client", "Down", "Up", "Downloaded", "Uploaded", "Requests"];
+    rows = stats.peers.map((peer) => [
+      `${peer.addr}${peer.incoming ? " (incoming)" : ""}`,
+      peer.client,
+      formatRate(peer.download_rate),
+      formatRate(peer.upload_rate),
+      formatSize(peer.downloaded),
+      formatSize(peer.uploaded),
+      `${peer.outstanding_requests}${peer.snubbed ? " (snubbed)" : ""}`,
+    ]);
+  } else {
+    columns = ["Tracker", "Status"];
+    rows = stats.trackers.map((tracker) => {
+      const status = !tracker.announced ? "not announced" : tracker.error || "working";
+      return [tracker.url, status];
+    });
+  }
+  $("#details-table thead").replaceChildren(
+    element("tr", {}, ...columns.map((column) => element("th", {}, column))));
+  $("#details-table tbody").replaceChildren(
+    ...rows.map((row) => element("tr", {}, ...row.map((cell) => element("td", {}, cell)))));
+}
+
+// Add torrent from selected file, or from magnet link.
+async function addTorrent(event) {
+  event.preventDefault();
+  const file = $("#torrent-file").files[0];
+  const magnet = $("#magnet").value.trim();
+  try {
+    let params;
+    if (file) {
+      const bytes = new Uint8Array(await file.arrayBuffer());
+      let binary = "";
+      for (const byte of bytes) {
+        binary += String.fromCharCode(byte);
+      }
+      params = { metainfo: btoa(binary) };
+    } else if (magnet) {
+      params = { magnet };
+    } else {
+      throw new Error("Choose torrent file or paste magnet link");
+    }
+    const added = await rpc("add_torrent", params);
+    state.selected = added.info_hash;
+    $("#add-form").reset();
+    showError(null);
+  } catch (err) {
+    showError(err);
+  }
+}
+
+function login(token) {
+  state.token = token;
+  sessionStorage.setItem(TOKEN_KEY, token);
+  $("#login").hidden = true;
+  $("#app").hidden = false;
+  $("#logout").hidden = false;
+  listen();
+}
+
+function logout() {
+  state.token = null;
+  sessionStorage.removeItem(TOKEN_KEY);
+  if (state.stream) {
+    state.stream.abort();
+  }
+  $("#login").hidden = false;
+  $("#app").hidden = true;
+  $("#logout").hidden = true;
+}
+
+$("#login-form").addEventListener("submit", (event) => {
+  event.preventDefault();
+  login($("#token-input").value);
+});
+$("#logout").addEventListener("click", logout);
+$("#add-form").addEventListener("submit", addTorrent);
+for (const button of document.querySelectorAll("#tabs button")) {
+  button.addEventListener("click", () => {
+    state.tab = button.dataset.tab;
+    renderDetails();
+  });
+}
+
+const hashToken = new URLSearchParams(location.hash.slice(1)).get("token");
+if (hashToken) {
+  // Token is removed from address bar, so it's not kept in history
+  history.replaceState(null, "", location.pathname);
+  login(hashToken);
+} else if (state.token) {
+  login(state.token);
+} else {
+  logout();
+}
@@ -0,0 +1,245 @@
+use std::sync::Arc;
+use std::time::Duration;
+
+use anyhow::Result;
+use tokio::io::{AsyncBufReadExt, AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, BufReader};
+use tokio::time::timeout;
+
+use super::rpc::{handle_rpc, RpcContext};
+use super::transmission::{handle_transmission, SESSION_ID_HEADER, TRANSMISSION_RPC_PATH};
+use super::web::{asset, stream_events, EVENTS_PATH};
+
+/// Maximal size of request line and headers.
+const MAX_HEADER_SIZE: usize = 8 * 1024;
+/// Maximal size of request body, torrent files are sent in it as base64.
+const MAX_BODY_SIZE: usize = 16 * 1024 * 1024;
+/// Time for receiving next request on kept-alive connection.
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(60);
+/// Path of JSON-RPC endpoint.
+pub const RPC_PATH: &str = "/rpc";
+
+/// Parsed HTTP request with its body.
+struct Request {
+    method: String,
+    path: String,
+    authorization: Option<String>,
+    /// Value of `X-Transmission-Session-Id` header.
+    session_id: Option<String>,
+    keep_alive: bool,
+    body: Vec<u8>,
+}
+
+/// Serve requests of one connection over TCP or Unix socket, until client closes it or asks for closing.
+pub(super) async fn serve_connection<S>(stream: S, context: Arc<RpcContext>) -> Result<()>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let mut stream = BufReader::new(stream);
+    loop {
+        let request = match timeout(REQUEST_TIMEOUT, read_request(&mut stream)).await {
+            Ok(Ok(Some(request))) => request,
+            Ok(Ok(None)) | Err(_) => return Ok(()),
+            Ok(Err(err)) => {
+                write_response(stream.get_mut(), "400 Bad Request", &[], b"", false).await?;
+                return Err(err);
+            }
+        };
+        let keep_alive = request.keep_alive;
+        let response = route(&context, request).await;
+        if response.event_stream {
+            return stream_events(stream.get_mut(), &context).await;
+        }
+        let headers: Vec<(&str, &str)> = response
+            .headers
+            .iter()
+            .map(|(name, value)| (*name, value.as_str()))
+            .collect();
+        write_response(
+            stream.get_mut(),
+            response.status,
+            &headers,
+            &response.body,
+            keep_alive,
+        )
+        .await?;
+        if !keep_alive {
+            return Ok(());
+        }
+    }
+}
+
+/// Response of API, before it's written to connection.
+struct Response {
+    status: &'static str,
+    headers: Vec<(&'static str, String)>,
+    body: Vec<u8>,
+    /// Connection is used for stream of events, instead of this response.
+    event_stream: bool,
+}
+
+impl Response {
+    fn new(status: &'static str) -> Self {
+        Response {
+            status,
+            headers: Vec::new(),
+            body: Vec::new(),
+            event_stream: false,
+        }
+    }
+
+    fn content(content_type: &str, body: Vec<u8>) -> Self {
+        Response::new("200 OK")
+            .header("Content-Type", content_type)
+            .with_body(body)
+    }
+
+    fn json(body: Vec<u8>) -> Self {
+        Response::content("application/json", body)
+    }
+
+    fn with_body(mut self, body: Vec<u8>) -> Self {
+        self.body = body;
+        self
+    }
+
+    fn header(mut self, name: &'static str, value: impl Into<String>) -> Self {
+        self.headers.push((name, value.into()));
+        self
+    }
+}
+
+/// Handle request by endpoint given by its path.
+/// Static files of web UI don't need token, the UI sends it with its requests.
+/// Transmission clients authenticate by `Basic` credentials, and they have to send current session id against CSRF.
+async fn route(context: &RpcContext, request: Request) -> Response {
+    let path = request.path.as_str();
+    let method = if path == EVENTS_PATH || asset(path).is_some() {
+        "GET"
+    } else if path == RPC_PATH || path == TRANSMISSION_RPC_PATH {
+        "POST"
+    } else {
+        return Response::new("404 Not Found");
+    };
+    if request.method != method {
+        return Response::new("405 Method Not Allowed").header("Allow", method);
+    }
+    if let Some((content_type, content)) = asset(path) {
+        return Response::content(content_type, content.as_bytes().to_vec());
+    }
+    if !context.authorize(request.authorization.as_deref()) {
+        let challenge = if path == TRANSMISSION_RPC_PATH {
+            "Basic realm=\"Transmission\""
+        } else {
+            "Bearer"
+        };
+        return Response::new("401 Unauthorized").header("WWW-Authenticate", challenge);
+    }
+    if path == RPC_PATH {
+        return Response::json(handle_rpc(context, &request.body).await);
+    }
+    if path == EVENTS_PATH {
+        return Response {
+            event_stream: true,
+            ..Response::new("200 OK")
+        };
+    }
+
+    let session_id = context.transmission.session_id();
+    if request.session_id.as_deref() != Some(session_id) {
+        return Response::new("409 Conflict").header(SESSION_ID_HEADER, session_id);
+    }
+    Response::json(handle_transmission(context, &request.body).await)
+        .header(SESSION_ID_HEADER, session_id)
+}
+
+/// Read request line, headers and body, returns `None` if client closed connection.
+async fn read_request<S>(stream: &mut BufReader<S>) -> Result<Option<Request>>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let mut lines = Vec::new();
+    let mut size = 0;
+    loop {
+        let mut line = String::new();
+        let read = (&mut *stream)
+            .take((MAX_HEADER_SIZE - size) as u64)
+            .read_line(&mut line)
+            .await?;
+        if read == 0 {
+            anyhow::ensure!(lines.is_empty(), "Request is not complete");
+            return Ok(None);
+        }
+        size += read;
+        anyhow::ensure!(line.ends_with('\n'), "Request header is too long");
+        let line = line.trim_end().to_string();
+        if line.is_empty() {
+            break;
+        }
+        lines.push(line);
+    }
+
+    let mut request_line = lines
+        .first()
+        .map(|line| line.split(' '))
+        .into_iter()
+        .flatten();
+    let (Some(method), Some(path), Some(version)) = (
+        request_line.next(),
+        request_line.next(),
+        request_line.next(),
+    ) else {
+        anyhow::bail!("Invalid request line");
+    };
+    let mut request = Request {
+        method: method.to_string(),
+        path: path.split('?').next().unwrap_or_default().to_string(),
+        authorization: None,
+        session_id: None,
+        keep_alive: version == "HTTP/1.1",
+        body: Vec::new(),
+    };
+    let mut content_length = 0;
+    for line in &lines[1..] {
+        let Some((name, value)) = line.split_once(':') else {
+            anyhow::bail!("Invalid header {line}");
+        };
+        let value = value.trim();
+        match name.trim().to_ascii_lowercase().as_str() {
+            "authorization" => request.authorization = Some(value.to_string()),
+            "x-transmission-session-id" => request.session_id = Some(value.to_string()),
+            "connection" => request.keep_alive = !value.eq_ignore_ascii_case("close"),
+            "content-length" => content_length = value.parse()?,
+            _ => {}
+        }
+    }
+    anyhow::ensure!(content_length <= MAX_BODY_SIZE, "Request body is too long");
+    request.body = vec![0u8; content_length];
+    stream.read_exact(&mut request.body).await?;
+    Ok(Some(request))
+}
+
+/// Write complete response with given headers and body.
+async fn write_response<S>(
+    stream: &mut S,
+    status: &str,
+    headers: &[(&str, &str)],
+    body: &[u8],
+    keep_alive: bool,
+) -> Result<()>
+where
+    S: AsyncWrite + Unpin,
+{
+    let mut head = format!("HTTP/1.1 {status}\r\n");
+    for (name, value) in headers {
+        head.push_str(&format!("{name}: {value}\r\n"));
+    }
+    let connection = if keep_alive { "keep-alive" } else { "close" };
+    head.push_str(&format!(
+        "Content-Length: {}\r\nConnection: {connection}\r\n\r\n",
+        body.len()
+    ));
+    stream.write_all(head.as_bytes()).await?;
+    stream.write_all(body).await?;
+    stream.flush().await?;
+    Ok(())
+}
@@ -0,0 +1,208 @@
+mod http;
+mod rpc;
+mod transmission;
+mod web;
+
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use anyhow::Result;
+use tokio::net::TcpListener;
+use tokio::task::JoinHandle;
+use tokio::time::sleep;
+use tokio_util::sync::CancellationToken;
+
+use crate::session::{Session, SessionOptions};
+use crate::storage::StorageOptions;
+use http::serve_connection;
+pub use http::RPC_PATH;
+use rpc::RpcContext;
+use transmission::TransmissionState;
+pub use transmission::TRANSMISSION_RPC_PATH;
+pub use web::EVENTS_PATH;
+
+/// Default port of JSON-RPC API.
+pub const DEFAULT_RPC_PORT: u16 = 6880;
+/// Waiting time after listener fails to accept connection.
+const ACCEPT_RETRY_INTERVAL: Duration = Duration::from_millis(100);
+
+/// Settings of daemon and of its session.
+#[derive(Debug, Clone)]
+pub struct DaemonOptions {
+    /// Address of HTTP API, it has to be local.
+    pub rpc_addr: SocketAddr,
+    /// Unix socket with the same HTTP API, it's not created if not set.
+    pub rpc_socket: Option<PathBuf>,
+    /// Token, which clients send in `Authorization: Bearer <token>` header.
+    pub token: String,
+    /// Folder of torrents added without their own folder.
+    pub download_folder: PathBuf,
+    pub storage: StorageOptions,
+    pub session: SessionOptions,
+}
+
+impl DaemonOptions {
+    /// Creates options with given token and download folder, API listens on default local port.
+    pub fn new(token: String, download_folder: PathBuf) -> Self {
+        DaemonOptions {
+            rpc_addr: SocketAddr::from(([127, 0, 0, 1], DEFAULT_RPC_PORT)),
+            rpc_socket: None,
+            token,
+            download_folder,
+            storage: StorageOptions::default(),
+            session: SessionOptions::default(),
+        }
+    }
+}
+
+/// Headless client, which hosts session and is controlled remotely by JSON-RPC 2.0 API.
+/// API is served over HTTP on localhost and optionally over Unix socket, requests are sent by `POST` to `/rpc`.
+///
+/// Methods:
+/// - `add_torrent` `{file | metainfo (base64) | magnet, folder?}` returns `{info_hash, name}`
+/// - `list_torrents` returns status of all torrents
+/// - `torrent_stats` `{info_hash}` returns status of torrent with its files and limits
+/// - `pause_torrent`, `resume_torrent` `{info_hash}`
+/// - `remove_torrent` `{info_hash, delete_data?}`
+/// - `set_file_priority` `{info_hash, file_index, priority}`
+/// - `set_limits` `{info_hash?, download_limit, upload_limit}` in bytes per second, session limits without `info_hash`
+///
+/// Subset of Transmission RPC is served on `/transmission/rpc`, so Transmission clients can control the daemon,
+/// token of daemon is their password.
+///
+/// Web UI is served on `/`, it shows torrents with their files, peers and trackers,
+/// and it's updated by Server-Sent Events from `/events`.
+pub struct Daemon {
+    session: Arc<Session>,
+    rpc_addr: SocketAddr,
+    rpc_socket: Option<PathBuf>,
+    tasks: Vec<JoinHandle<()>>,
+    shutdown: CancellationToken,
+}
+
+impl Daemon {
+    /// Create session and start serving API.
+    pub async fn start(options: DaemonOptions) -> Result<Self> {
+        anyhow::ensure!(
+            options.rpc_addr.ip().is_loopback(),
+            "API can be bound only to localhost, not to {}",
+            options.rpc_addr
+        );
+        anyhow::ensure!(!options.token.is_empty(), "Token of API can't be empty");
+        let transmission = TransmissionState::new(
+            options.session.download_limit,
+            options.session.upload_limit,
+            options.session.seeding.ratio,
+        );
+        let session = Arc::new(Session::new(options.session).await?);
+        let shutdown = CancellationToken::new();
+        let context = Arc::new(RpcContext {
+            session: session.clone(),
+            token: options.token,
+            download_folder: Mutex::new(options.download_folder),
+            storage: options.storage,
+            transmission,
+            shutdown: shutdown.clone(),
+        });
+
+        let listener = TcpListener::bind(options.rpc_addr).await?;
+        let rpc_addr = listener.local_addr()?;
+        let mut tasks = vec![tokio::spawn(accept_tcp(listener, context.clone()))];
+        if let Some(path) = &options.rpc_socket {
+            tasks.push(bind_unix(path, context)?);
+        }
+
+        Ok(Daemon {
+            session,
+            rpc_addr,
+            rpc_socket: options.rpc_socket,
+            tasks,
+            shutdown,
+        })
+    }
+
+    /// Returns address of HTTP API.
+    pub fn rpc_addr(&self) -> SocketAddr {
+        self.rpc_addr
+    }
+
+    /// Returns session of daemon.
+    pub fn session(&self) -> Arc<Session> {
+        self.session.clone()
+    }
+
+    /// Stop serving API and shut down session gracefully, Unix socket is removed.
+    pub async fn shutdown(&self) {
+        self.shutdown.cancel();
+        for task in &self.tasks {
+            task.abort();
+        }
+        if let Some(path) = &self.rpc_socket {
+            let _ = std::fs::remove_file(path);
+        }
+        self.session.shutdown().await;
+    }
+}
+
+impl Drop for Daemon {
+    fn drop(&mut self) {
+        self.shutdown.cancel();
+        for task in &self.tasks {
+            task.abort();
+        }
+    }
+}
+
+/// Accept connections to HTTP API, every connection is served in its own task.
+async fn accept_tcp(listener: TcpListener, context: Arc<RpcContext>) {
+    loop {
+        match listener.accept().await {
+            Ok((stream, _)) => {
+                let context = context.clone();
+                tokio::spawn(async move {
+                    let _ = serve_connection(stream, context).await;
+                });
+            }
+            Err(_) => sleep(ACCEPT_RETRY_INTERVAL).await,
+        }
+    }
+}
+
+/// Bind Unix socket only accessible by its owner, and accept its connections in new task.
+/// Socket left by previous run is replaced.
+#[cfg(unix)]
+fn bind_unix(path: &std::path::Path, context: Arc<RpcContext>) -> Result<JoinHandle<()>> {
+    use std::os::unix::fs::{FileTypeExt, PermissionsExt};
+    use tokio::net::UnixListener;
+
+    if let Ok(metadata) = std::fs::symlink_metadata(path) {
+        anyhow::ensure!(
+            metadata.file_type().is_socket(),
+            "{} exists and it's not a socket",
+            path.display()
+        );
+        std::fs::remove_file(path)?;
+    }
+    let listener = UnixListener::bind(path)?;
+    std::fs::set_permissions(path, std::fs::Permissions::from_mode(0o600))?;
+    Ok(tokio::spawn(async move {
+        loop {
+            match listener.accept().await {
+                Ok((stream, _)) => {
+                    let context = context.clone();
+                    tokio::spawn(async move {
+                        let _ = serve_connection(stream, context).await;
+                    });
+                }
+                Err(_) => sleep(ACCEPT_RETRY_INTERVAL).await,
+            }
+        }
+    }))
+}
+
+#[cfg(not(unix))]
+fn bind_unix(_path: &std::path::Path, _context: Arc<RpcContext>) -> Result<JoinHandle<()>> {
+    anyhow::bail!("Unix sockets are not supported on this system")
+}
@@ -0,0 +1,405 @@
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex, MutexGuard};
+
+use anyhow::Context;
+use base64::Engine;
+use serde::de::DeserializeOwned;
+use serde::Deserialize;
+use serde_json::{json, Value};
+use tokio_util::sync::CancellationToken;
+
+use crate::file_layout::FilePriority;
+use crate::hash::InfoHash;
+use crate::magnet::MagnetLink;
+use crate::metainfo::Metainfo;
+use crate::peer_comunication::peer_stats::ConnectionDirection;
+use crate::session::{DuplicateTorrent, Session, TorrentHandle, TorrentState, TorrentStatus};
+use crate::storage::StorageOptions;
+
+use super::transmission::TransmissionState;
+
+/// Invalid JSON was received.
+const PARSE_ERROR: i64 = -32700;
+/// JSON is not valid JSON-RPC 2.0 request.
+const INVALID_REQUEST: i64 = -32600;
+const METHOD_NOT_FOUND: i64 = -32601;
+const INVALID_PARAMS: i64 = -32602;
+/// Method failed, e.g. torrent is not in session.
+const SERVER_ERROR: i64 = -32000;
+
+/// State shared by all connections of API.
+pub(super) struct RpcContext {
+    pub(super) session: Arc<Session>,
+    pub(super) token: String,
+    /// Folder of torrents added without their own folder, it can be changed by Transmission clients.
+    pub(super) download_folder: Mutex<PathBuf>,
+    pub(super) storage: StorageOptions,
+    pub(super) transmission: TransmissionState,
+    /// Cancelled when daemon shuts down, so streams of events end.
+    pub(super) shutdown: CancellationToken,
+}
+
+impl RpcContext {
+    /// Returns true, if value of `Authorization` header is `Bearer <token of daemon>`,
+    /// or `Basic` credentials with token of daemon as password (used by Transmission clients).
+    pub(super) fn authorize(&self, authorization: Option<&str>) -> bool {
+        let token = match authorization.and_then(|value| value.split_once(' ')) {
+            Some(("Bearer", token)) => token.to_string(),
+            Some(("Basic", credentials)) => {
+                let Ok(credentials) = base64::engine::general_purpose::STANDARD.decode(credentials)
+                else {
+                    return false;
+                };
+                let credentials = String::from_utf8_lossy(&credentials);
+                match credentials.split_once(':') {
+                    Some((_, password)) => password.to_string(),
+                    None => return false,
+                }
+            }
+            _ => return false,
+        };
+        // Tokens are compared in constant time, so the token can't be guessed by timing
+        token.len() == self.token.len()
+            && token
+                .bytes()
+                .zip(self.token.bytes())
+                .fold(0, |diff, (a, b)| diff | (a ^ b))
+                == 0
+    }
+
+    /// Returns folder of torrents added without their own folder.
+    pub(super) fn download_folder(&self) -> MutexGuard<'_, PathBuf> {
+        self.download_folder
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+
+    fn torrent(&self, info_hash: &InfoHash) -> Result<TorrentHandle, RpcError> {
+        self.session.torrent(info_hash).ok_or_else(|| RpcError {
+            code: SERVER_ERROR,
+            message: format!("Torrent {info_hash} is not in session"),
+        })
+    }
+}
+
+/// Torrent file or magnet link of torrent added by API.
+pub(super) enum TorrentSource {
+    /// Path of torrent file on machine of daemon.
+    File(PathBuf),
+    /// Content of torrent file.
+    Metainfo(Vec<u8>),
+    Magnet(MagnetLink),
+}
+
+impl TorrentSource {
+    /// Returns metainfo of torrent, metadata of magnet link are fetched from peers.
+    /// Metadata are not fetched for torrent, which is already in session.
+    pub(super) async fn metainfo(self, session: &Session) -> anyhow::Result<Metainfo> {
+        match self {
+            TorrentSource::File(path) => Metainfo::read_from_file(path),
+            TorrentSource::Metainfo(bytes) => Metainfo::read_from_bytes(&bytes),
+            TorrentSource::Magnet(link) => {
+                if session.torrent(&link.info_hash).is_some() {
+                    return Err(DuplicateTorrent(link.info_hash).into());
+                }
+                link.fetch_metadata(&session.peer_id(), session.local_addr().port())
+                    .await
+            }
+        }
+    }
+}
+
+/// JSON-RPC 2.0 request, `id` is missing in notifications.
+#[derive(Deserialize)]
+struct RpcRequest {
+    jsonrpc: String,
+    #[serde(default)]
+    id: Value,
+    method: String,
+    #[serde(default)]
+    params: Value,
+}
+
+/// Error returned in JSON-RPC response.
+struct RpcError {
+    code: i64,
+    message: String,
+}
+
+impl From<anyhow::Error> for RpcError {
+    fn from(err: anyhow::Error) -> Self {
+        RpcError {
+            code: SERVER_ERROR,
+            message: format!("{err:#}"),
+        }
+    }
+}
+
+/// Parameters of `add_torrent`, exactly one of `file`, `metainfo` and `magnet` has to be set.
+#[derive(Deserialize)]
+struct AddTorrentParams {
+    /// Path of torrent file on machine of daemon.
+    file: Option<PathBuf>,
+    /// Content of torrent file in base64.
+    metainfo: Option<String>,
+    /// Magnet link, metadata of torrent are fetched from peers before it's added.
+    magnet: Option<String>,
+    /// Download folder of torrent, default folder of daemon is used if not set.
+    folder: Option<PathBuf>,
+}
+
+#[derive(Deserialize)]
+struct TorrentParams {
+    info_hash: InfoHash,
+}
+
+#[derive(Deserialize)]
+struct RemoveParams {
+    info_hash: InfoHash,
+    #[serde(default)]
+    delete_data: bool,
+}
+
+#[derive(Deserialize)]
+struct PriorityParams {
+    info_hash: InfoHash,
+    file_index: usize,
+    /// `skip`, `low`, `normal` or `high`.
+    priority: String,
+}
+
+/// Limits in bytes per second, `0` means unlimited. Limits of session are set, if `info_hash` is not set.
+#[derive(Deserialize)]
+struct LimitsParams {
+    info_hash: Option<InfoHash>,
+    download_limit: u64,
+    upload_limit: u64,
+}
+
+/// Handle JSON-RPC 2.0 request, returns serialized response.
+pub(super) async fn handle_rpc(context: &RpcContext, body: &[u8]) -> Vec<u8> {
+    let (id, result) = match serde_json::from_slice::<Value>(body) {
+        Err(err) => (
+            Value::Null,
+            Err(RpcError {
+                code: PARSE_ERROR,
+                message: err.to_string(),
+            }),
+        ),
+        Ok(request) => match serde_json::from_value::<RpcRequest>(request) {
+            Ok(request) if request.jsonrpc == "2.0" => {
+                let result = call(context, &request.method, request.params).await;
+                (request.id, result)
+            }
+            _ => (
+                Value::Null,
+                Err(RpcError {
+                    code: INVALID_REQUEST,
+                    message: "Invalid JSON-RPC 2.0 request".to_string(),
+                }),
+            ),
+        },
+    };
+    let response = match result {
+        Ok(result) => json!({ "jsonrpc": "2.0", "id": id, "result": result }),
+        Err(err) => json!({
+            "jsonrpc": "2.0",
+            "id": id,
+            "error": { "code": err.code, "message": err.message },
+        }),
+    };
+    serde_json::to_vec(&response).unwrap_or_default()
+}
+
+/// Call method of API with its parameters.
+async fn call(context: &RpcContext, method: &str, params: Value) -> Result<Value, RpcError> {
+    match method {
+        "add_torrent" => add_torrent(context, parse(params)?).await,
+        "list_torrents" => Ok(list_torrents(context).await),
+        "torrent_stats" => {
+            let params: TorrentParams = parse(params)?;
+            torrent_stats(context, &context.torrent(&params.info_hash)?).await
+        }
+        "pause_torrent" => {
+            let params: TorrentParams = parse(params)?;
+            context.torrent(&params.info_hash)?.pause();
+            Ok(Value::Null)
+        }
+        "resume_torrent" => {
+            let params: TorrentParams = parse(params)?;
+            context.torrent(&params.info_hash)?.resume();
+            Ok(Value::Null)
+        }
+        "remove_torrent" => {
+            let params: RemoveParams = parse(params)?;
+            context
+                .session
+                .remove_torrent(&params.info_hash, params.delete_data)
+                .await?;
+            Ok(Value::Null)
+        }
+        "set_file_priority" => {
+            let params: PriorityParams = parse(params)?;
+            let priority: FilePriority = params.priority.parse().map_err(invalid_params)?;
+            context
+                .torrent(&params.info_hash)?
+                .downloader()
+                .set_file_priority(params.file_index, priority)
+                .await?;
+            Ok(Value::Null)
+        }
+        "set_limits" => {
+            let params: LimitsParams = parse(params)?;
+            let bandwidth = match &params.info_hash {
+                Some(info_hash) => context.torrent(info_hash)?.downloader().bandwidth(),
+                None => context.session.bandwidth(),
+            };
+            bandwidth.set(params.download_limit, params.upload_limit);
+            Ok(Value::Null)
+        }
+        _ => Err(RpcError {
+            code: METHOD_NOT_FOUND,
+            message: format!("Unknown method {method}"),
+        }),
+    }
+}
+
+fn parse<T: DeserializeOwned>(params: Value) -> Result<T, RpcError> {
+    serde_json::from_value(params).map_err(|err| invalid_params(err.into()))
+}
+
+fn invalid_params(err: anyhow::Error) -> RpcError {
+    RpcError {
+        code: INVALID_PARAMS,
+        message: format!("{err:#}"),
+    }
+}
+
+/// Add torrent from file, base64 content or magnet link. Returns its info hash and name.
+async fn add_torrent(context: &RpcContext, params: AddTorrentParams) -> Result<Value, RpcError> {
+    let source = match (params.file, params.metainfo, params.magnet) {
+        (Some(file), None, None) => TorrentSource::File(file),
+        (None, Some(metainfo), None) => TorrentSource::Metainfo(
+            base64::engine::general_purpose::STANDARD
+                .decode(metainfo)
+                .context("Invalid base64 of torrent file")
+                .map_err(invalid_params)?,
+        ),
+        (None, None, Some(magnet)) => {
+            TorrentSource::Magnet(magnet.parse().map_err(invalid_params)?)
+        }
+        _ => {
+            return Err(invalid_params(anyhow::anyhow!(
+                "Exactly one of file, metainfo and magnet has to be set"
+            )))
+        }
+    };
+    let metainfo = source.metainfo(&context.session).await?;
+    let folder = params
+        .folder
+        .unwrap_or_else(|| context.download_folder().clone());
+    let handle = context
+        .session
+        .add_torrent(metainfo, folder, &context.storage)
+        .await?;
+    Ok(json!({ "info_hash": handle.info_hash().to_string(), "name": handle.name() }))
+}
+
+/// Returns status of all torrents in session.
+pub(super) async fn list_torrents(context: &RpcContext) -> Value {
+    let mut torrents = Vec::new();
+    for handle in context.session.torrents() {
+        torrents.push(torrent_json(context, &handle).await);
+    }
+    Value::Array(torrents)
+}
+
+/// Returns state and progress of torrent, with its files, peers, trackers and limits.
+async fn torrent_stats(context: &RpcContext, handle: &TorrentHandle) -> Result<Value, RpcError> {
+    let downloader = handle.downloader();
+    let layout = downloader.layout();
+    let priorities = downloader.file_priorities().await;
+    let files: Vec<Value> = layout
+        .files()
+        .iter()
+        .zip(priorities)
+        .enumerate()
+        .map(|(file_index, (entry, priority))| {
+            json!({
+                "index": file_index,
+                "path": entry.path,
+                "length": entry.length,
+                "priority": priority.to_string(),
+            })
+        })
+        .collect();
+    // Connected peers with the fastest download are first
+    let mut peers = downloader.peer_stats().snapshot();
+    peers.sort_by(|a, b| b.download_rate.total_cmp(&a.download_rate));
+    let peers: Vec<Value> = peers
+        .iter()
+        .map(|peer| {
+            json!({
+                "addr": peer.addr.to_string(),
+                "client": peer.client,
+                "incoming": peer.direction == ConnectionDirection::Incoming,
+                "download_rate": peer.download_rate,
+                "upload_rate": peer.upload_rate,
+                "downloaded": peer.payload_downloaded,
+                "uploaded": peer.payload_uploaded,
+                "outstanding_requests": peer.outstanding_requests,
+                "snubbed": peer.snubbed,
+            })
+        })
+        .collect();
+    let bandwidth = downloader.bandwidth();
+
+    let mut stats = torrent_json(context, handle).await;
+    // Only the first tracker is announced, so the error belongs to it
+    let trackers: Vec<Value> = handle
+        .trackers()
+        .iter()
+        .enumerate()
+        .map(|(tracker_index, url)| {
+            let announced = tracker_index == 0;
+            json!({ "url": url, "announced": announced, "error": if announced { stats["tracker_error"].clone() } else { Value::Null } })
+        })
+        .collect();
+    stats["files"] = Value::Array(files);
+    stats["peers"] = Value::Array(peers);
+    stats["trackers"] = Value::Array(trackers);
+    stats["download_limit"] = bandwidth.download.rate().into();
+    stats["upload_limit"] = bandwidth.upload.rate().into();
+    Ok(stats)
+}
+
+/// Returns status of torrent as JSON object.
+async fn torrent_json(context: &RpcContext, handle: &TorrentHandle) -> Value {
+    let status: TorrentStatus = handle.status().await;
+    let (state, reason) = match &status.state {
+        TorrentState::Downloading => ("downloading", None),
+        TorrentState::Queued => ("queued", None),
+        TorrentState::Seeding => ("seeding", None),
+        TorrentState::Paused(reason) => ("paused", Some(reason)),
+        TorrentState::Finished => ("finished", None),
+        TorrentState::Failed(err) => ("failed", Some(err)),
+    };
+    json!({
+        "info_hash": status.info_hash.to_string(),
+        "name": status.name,
+        "folder": status.folder,
+        "state": state,
+        "reason": reason,
+        "queue_position": context.session.queue_position(&status.info_hash),
+        "piece_count": status.piece_count,
+        "remaining_pieces": status.remaining_pieces,
+        "connected_peers": status.connected_peers,
+        "download_rate": status.download_rate,
+        "upload_rate": status.upload_rate,
+        "downloaded": status.downloaded,
+        "uploaded": status.uploaded,
+        "ratio": status.ratio,
+        "seeding_time": status.seeding_time.map(|time| time.as_secs()),
+        "tracker_error": status.tracker_error,
+    })
+}
@@ -0,0 +1,681 @@
+use std::collections::HashMap;
+use std::path::PathBuf;
+use std::sync::{Mutex, MutexGuard};
+use std::time::Instant;
+
+use anyhow::{Context, Result};
+use base64::Engine;
+use serde::Deserialize;
+use serde_json::{json, Map, Value};
+
+use crate::file_layout::FilePriority;
+use crate::hash::InfoHash;
+use crate::peer_comunication::peer_stats::ConnectionDirection;
+use crate::session::{DuplicateTorrent, TorrentHandle, TorrentState};
+
+use super::rpc::{RpcContext, TorrentSource};
+
+/// Path of endpoint compatible with Transmission RPC.
+pub const TRANSMISSION_RPC_PATH: &str = "/transmission/rpc";
+/// Header with CSRF token, requests without current token are refused with `409 Conflict`.
+pub(super) const SESSION_ID_HEADER: &str = "X-Transmission-Session-Id";
+/// Version of Transmission RPC protocol, whose subset is implemented.
+const RPC_VERSION: u32 = 15;
+/// Transmission speeds are in kB/s.
+const SPEED_UNIT: u64 = 1000;
+
+/// Status codes of torrents in Transmission RPC.
+const STATUS_STOPPED: u8 = 0;
+const STATUS_DOWNLOAD_WAIT: u8 = 3;
+const STATUS_DOWNLOAD: u8 = 4;
+const STATUS_SEED_WAIT: u8 = 5;
+const STATUS_SEED: u8 = 6;
+/// Error codes of torrents in Transmission RPC.
+const ERROR_NONE: u8 = 0;
+const ERROR_TRACKER: u8 = 2;
+const ERROR_LOCAL: u8 = 3;
+
+/// State of Transmission clients, which is not stored in session.
+pub(super) struct TransmissionState {
+    session_id: String,
+    started: Instant,
+    ids: Mutex<TorrentIds>,
+    settings: Mutex<Settings>,
+}
+
+/// Transmission identifies torrents by numbers, numbers are not reused after torrent is removed.
+#[derive(Default)]
+struct TorrentIds {
+    next: i64,
+    ids: HashMap<InfoHash, i64>,
+}
+
+/// Settings of session, which are remembered also when they are disabled.
+struct Settings {
+    /// Speed limits in kB/s.
+    speed_limit_down: u64,
+    speed_limit_down_enabled: bool,
+    speed_limit_up: u64,
+    speed_limit_up_enabled: bool,
+    seed_ratio_limit: f64,
+}
+
+impl TransmissionState {
+    /// Creates state with new session id, settings are taken from limits of session in bytes per second.
+    pub(super) fn new(download_limit: u64, upload_limit: u64, seed_ratio: Option<f64>) -> Self {
+        TransmissionState {
+            session_id: hex::encode(rand::random::<[u8; 16]>()),
+            started: Instant::now(),
+            ids: Mutex::new(TorrentIds::default()),
+            settings: Mutex::new(Settings {
+                speed_limit_down: download_limit / SPEED_UNIT,
+                speed_limit_down_enabled: download_limit > 0,
+                speed_limit_up: upload_limit / SPEED_UNIT,
+                speed_limit_up_enabled: upload_limit > 0,
+                seed_ratio_limit: seed_ratio.unwrap_or(2.0),
+            }),
+        }
+    }
+
+    /// Returns session id, which clients have to send in `X-Transmission-Session-Id` header.
+    pub(super) fn session_id(&self) -> &str {
+        &self.session_id
+    }
+
+    fn settings(&self) -> MutexGuard<'_, Settings> {
+        self.settings
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+
+    /// Returns torrents of session with their ids, new torrents get next ids.
+    fn torrents(&self, context: &RpcContext) -> Vec<(i64, TorrentHandle)> {
+        let mut ids = self
+            .ids
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner());
+        let torrents = context.session.torrents();
+        ids.ids.retain(|info_hash, _| {
+            torrents
+                .iter()
+                .any(|handle| handle.info_hash() == *info_hash)
+        });
+        torrents
+            .into_iter()
+            .map(|handle| {
+                let TorrentIds { next, ids } = &mut *ids;
+                let id = *ids.entry(handle.info_hash()).or_insert_with(|| {
+                    *next += 1;
+                    *next
+                });
+                (id, handle)
+            })
+            .collect()
+    }
+
+    /// Returns torrents selected by `ids` argument, which can be id, info hash, their array or `recently-active`.
+    /// All torrents are selected, if `ids` are missing.
+    fn select(&self, context: &RpcContext, ids: &Option<Value>) -> Vec<(i64, TorrentHandle)> {
+        let torrents = self.torrents(context);
+        let selectors = match ids {
+            None => return torrents,
+            Some(Value::String(ids)) if ids == "recently-active" => return torrents,
+            Some(Value::Array(selectors)) => selectors.clone(),
+            Some(selector) => vec![selector.clone()],
+        };
+        torrents
+            .into_iter()
+            .filter(|(id, handle)| {
+                selectors.iter().any(|selector| match selector {
+                    Value::Number(number) => number.as_i64() == Some(*id),
+                    Value::String(hash) => hash.eq_ignore_ascii_case(&handle.info_hash().to_hex()),
+                    _ => false,
+                })
+            })
+            .collect()
+    }
+}
+
+/// Request of Transmission RPC, `tag` is returned in response.
+#[derive(Deserialize)]
+struct TransmissionRequest {
+    method: String,
+    #[serde(default)]
+    arguments: Value,
+    tag: Option<Value>,
+}
+
+#[derive(Deserialize)]
+struct TorrentAddArgs {
+    /// Path of torrent file, URL of torrent file or magnet link.
+    filename: Option<String>,
+    /// Content of torrent file in base64.
+    metainfo: Option<String>,
+    #[serde(rename = "download-dir")]
+    download_dir: Option<PathBuf>,
+    #[serde(default)]
+    paused: bool,
+}
+
+#[derive(Deserialize)]
+struct TorrentGetArgs {
+    ids: Option<Value>,
+    #[serde(default)]
+    fields: Vec<String>,
+}
+
+#[derive(Deserialize)]
+struct TorrentActionArgs {
+    ids: Option<Value>,
+}
+
+#[derive(Deserialize)]
+struct TorrentRemoveArgs {
+    ids: Option<Value>,
+    #[serde(rename = "delete-local-data", default)]
+    delete_local_data: bool,
+}
+
+#[derive(Deserialize)]
+struct SessionSetArgs {
+    #[serde(rename = "download-dir")]
+    download_dir: Option<PathBuf>,
+    #[serde(rename = "speed-limit-down")]
+    speed_limit_down: Option<u64>,
+    #[serde(rename = "speed-limit-down-enabled")]
+    speed_limit_down_enabled: Option<bool>,
+    #[serde(rename = "speed-limit-up")]
+    speed_limit_up: Option<u64>,
+    #[serde(rename = "speed-limit-up-enabled")]
+    speed_limit_up_enabled: Option<bool>,
+    #[serde(rename = "seedRatioLimit")]
+    seed_ratio_limit: Option<f64>,
+    #[serde(rename = "seedRatioLimited")]
+    seed_ratio_limited: Option<bool>,
+}
+
+/// Handle request of Transmission RPC, returns serialized response.
+/// Failed methods have error message in `result`, instead of `success`.
+pub(super) async fn handle_transmission(context: &RpcContext, body: &[u8]) -> Vec<u8> {
+    let (tag, result) = match serde_json::from_slice::<TransmissionRequest>(body) {
+        Ok(request) => {
+            let result = call(context, &request.method, request.arguments).await;
+            (request.tag, result)
+        }
+        Err(err) => (
+            None,
+            Err(anyhow::Error::from(err).context("Invalid request")),
+        ),
+    };
+    let mut response = match result {
+        Ok(arguments) => json!({ "result": "success", "arguments": arguments }),
+        Err(err) => json!({ "result": format!("{err:#}"), "arguments": {} }),
+    };
+    if let Some(tag) = tag {
+        response["tag"] = tag;
+    }
+    serde_json::to_vec(&response).unwrap_or_default()
+}
+
+/// Call method of Transmission RPC with its arguments.
+async fn call(context: &RpcContext, method: &str, arguments: Value) -> Result<Value> {
+    let state = &context.transmission;
+    let arguments = match arguments {
+        Value::Null => json!({}),
+        arguments => arguments,
+    };
+    match method {
+        "torrent-add" => torrent_add(context, serde_json::from_value(arguments)?).await,
+        "torrent-get" => {
+            let arguments: TorrentGetArgs = serde_json::from_value(arguments)?;
+            let mut torrents = Vec::new();
+            for (id, handle) in state.select(context, &arguments.ids) {
+                torrents.push(torrent_fields(context, id, &handle, &arguments.fields).await);
+            }
+            Ok(json!({ "torrents": torrents }))
+        }
+        "torrent-start" | "torrent-start-now" => {
+            let arguments: TorrentActionArgs = serde_json::from_value(arguments)?;
+            for (_, handle) in state.select(context, &arguments.ids) {
+                handle.resume();
+            }
+            Ok(json!({}))
+        }
+        "torrent-stop" => {
+            let arguments: TorrentActionArgs = serde_json::from_value(arguments)?;
+            for (_, handle) in state.select(context, &arguments.ids) {
+                handle.pause();
+            }
+            Ok(json!({}))
+        }
+        "torrent-remove" => {
+            let arguments: TorrentRemoveArgs = serde_json::from_value(arguments)?;
+            for (_, handle) in state.select(context, &arguments.ids) {
+                context
+                    .session
+                    .remove_torrent(&handle.info_hash(), arguments.delete_local_data)
+                    .await?;
+            }
+            Ok(json!({}))
+        }
+        "session-get" => Ok(session_get(context)),
+        "session-set" => {
+            session_set(context, serde_json::from_value(arguments)?);
+            Ok(json!({}))
+        }
+        "session-stats" => session_stats(context).await,
+        _ => anyhow::bail!("method name not recognized"),
+    }
+}
+
+/// Add torrent from `filename` (path, URL or magnet link) or base64 `metainfo`.
+/// Torrent already in session is returned as `torrent-duplicate`.
+async fn torrent_add(context: &RpcContext, arguments: TorrentAddArgs) -> Result<Value> {
+    let source = match (arguments.filename, arguments.metainfo) {
+        (_, Some(metainfo)) => {
+            // Some clients split base64 to lines
+            let metainfo: String = metainfo.split_whitespace().collect();
+            TorrentSource::Metainfo(
+                base64::engine::general_purpose::STANDARD
+                    .decode(metainfo)
+                    .context("Invalid base64 of torrent file")?,
+            )
+        }
+        (Some(filename), None) if filename.starts_with("magnet:") => {
+            TorrentSource::Magnet(filename.parse()?)
+        }
+        (Some(filename), None)
+            if filename.starts_with("http://") || filename.starts_with("https://") =>
+        {
+            let response = reqwest::get(&filename).await?.error_for_status()?;
+            TorrentSource::Metainfo(response.bytes().await?.to_vec())
+        }
+        (Some(filename), None) => TorrentSource::File(PathBuf::from(filename)),
+        (None, None) => anyhow::bail!("no filename or metainfo specified"),
+    };
+    let folder = arguments
+        .download_dir
+        .unwrap_or_else(|| context.download_folder().clone());
+
+    let added = match source.metainfo(&context.session).await {
+        Ok(metainfo) => {
+            context
+                .session
+                .add_torrent(metainfo, folder, &context.storage)
+                .await
+        }
+        Err(err) => Err(err),
+    };
+    let (key, info_hash) = match added {
+        Ok(handle) => {
+            if arguments.paused {
+                handle.pause();
+            }
+            ("torrent-added", handle.info_hash())
+        }
+        Err(err) => match err.downcast_ref::<DuplicateTorrent>() {
+            Some(DuplicateTorrent(info_hash)) => ("torrent-duplicate", *info_hash),
+            None => return Err(err),
+        },
+    };
+    let (id, handle) = context
+        .transmission
+        .select(context, &Some(Value::String(info_hash.to_hex())))
+        .into_iter()
+        .next()
+        .context("Added torrent is not in session")?;
+    Ok(json!({
+        key: { "id": id, "name": handle.name(), "hashString": info_hash.to_hex() }
+    }))
+}
+
+/// Returns requested fields of torrent, unknown fields are left out.
+async fn torrent_fields(
+    context: &RpcContext,
+    id: i64,
+    handle: &TorrentHandle,
+    fields: &[String],
+) -> Value {
+    let status = handle.status().await;
+    let downloader = handle.downloader();
+    let layout = downloader.layout();
+    let priorities = downloader.file_priorities().await;
+    let wanted = |file_index: usize| priorities.get(file_index) != Some(&FilePriority::Skip);
+
+    let size_when_done: u64 = layout
+        .files()
+        .iter()
+        .enumerate()
+        .filter(|(file_index, _)| wanted(*file_index))
+        .map(|(_, file)| file.length)
+        .sum();
+    let left_until_done =
+        (status.remaining_pieces as u64 * layout.piece_length()).min(size_when_done);
+    let percent_done = if size_when_done == 0 {
+        1.0
+    } else {
+        (size_when_done - left_until_done) as f64 / size_when_done as f64
+    };
+    let (status_code, error, error_string) = match &status.state {
+        TorrentState::Downloading => (STATUS_DOWNLOAD, ERROR_NONE, String::new()),
+        TorrentState::Queued if status.remaining_pieces == 0 => {
+            (STATUS_SEED_WAIT, ERROR_NONE, String::new())
+        }
+        TorrentState::Queued => (STATUS_DOWNLOAD_WAIT, ERROR_NONE, String::new()),
+        TorrentState::Seeding => (STATUS_SEED, ERROR_NONE, String::new()),
+        TorrentState::Paused(reason) => (STATUS_STOPPED, ERROR_NONE, reason.clone()),
+        TorrentState::Finished => (STATUS_STOPPED, ERROR_NONE, String::new()),
+        TorrentState::Failed(err) => (STATUS_STOPPED, ERROR_LOCAL, err.clone()),
+    };
+    let (error, error_string) = match &status.tracker_error {
+        Some(tracker_error) if error == ERROR_NONE => (ERROR_TRACKER, tracker_error.clone()),
+        _ => (error, error_string),
+    };
+    let eta = if left_until_done == 0 {
+        0
+    } else if status.download_rate >= 1.0 {
+        (left_until_done as f64 / status.download_rate) as i64
+    } else {
+        -1
+    };
+
+    let mut torrent = Map::new();
+    torrent.insert("id".into(), id.into());
+    torrent.insert("hashString".into(), status.info_hash.to_hex().into());
+    torrent.insert("name".into(), status.name.clone().into());
+    torrent.insert("status".into(), status_code.into());
+    torrent.insert("error".into(), error.into());
+    torrent.insert("errorString".into(), error_string.into());
+    torrent.insert("downloadDir".into(), json!(status.folder));
+    torrent.insert("totalSize".into(), layout.total_length().into());
+    torrent.insert("sizeWhenDone".into(), size_when_done.into());
+    torrent.insert("leftUntilDone".into(), left_until_done.into());
+    torrent.insert(
+        "haveValid".into(),
+        (size_when_done - left_until_done).into(),
+    );
+    torrent.insert("percentDone".into(), percent_done.into());
+    torrent.insert("metadataPercentComplete".into(), 1.into());
+    torrent.insert("recheckProgress".into(), 0.into());
+    torrent.insert(
+        "isFinished".into(),
+        (status.state == TorrentState::Finished).into(),
+    );
+    torrent.insert("rateDownload".into(), (status.download_rate as u64).into());
+    torrent.insert("rateUpload".into(), (status.upload_rate as u64).into());
+    torrent.insert("downloadedEver".into(), status.downloaded.into());
+    torrent.insert("uploadedEver".into(), status.uploaded.into());
+    torrent.insert("uploadRatio".into(), status.ratio.into());
+    torrent.insert("eta".into(), eta.into());
+    torrent.insert("peersConnected".into(), status.connected_peers.into());
+    torrent.insert(
+        "queuePosition".into(),
+        json!(context.session.queue_position(&status.info_hash)),
+    );
+    torrent.insert("pieceCount".into(), status.piece_count.into());
+    torrent.insert("pieceSize".into(), layout.piece_length().into());
+    torrent.insert(
+        "secondsSeeding".into(),
+        status
+            .seeding_time
+            .map(|time| time.as_secs())
+            .unwrap_or(0)
+            .into(),
+    );
+
+    let requested = |field: &str| fields.iter().any(|requested| requested == field);
+    if requested("files") || requested("fileStats") {
+        // Completed bytes of files are computed from written pieces
+        let mut completed = vec![0u64; layout.files().len()];
+        for piece_index in downloader.resume_data().await.pieces {
+            let offset = layout.piece_offset(piece_index);
+            for slice in layout.map_range(offset, layout.piece_size(piece_index)) {
+                completed[slice.file_index] += slice.length as u64;
+            }
+        }
+        let files: Vec<Value> = layout
+            .files()
+            .iter()
+            .zip(&completed)
+            .map(|(file, completed)| {
+                json!({ "name": file.path, "length": file.length, "bytesCompleted": completed })
+            })
+            .collect();
+        let file_stats: Vec<Value> = completed
+            .iter()
+            .enumerate()
+            .map(|(file_index, completed)| {
+                let priority = match priorities.get(file_index) {
+                    Some(FilePriority::Low) => -1,
+                    Some(FilePriority::High) => 1,
+                    _ => 0,
+                };
+                json!({ "bytesCompleted": completed, "wanted": wanted(file_index), "priority": priority })
+            })
+            .collect();
+        torrent.insert("files".into(), files.into());
+        torrent.insert("fileStats".into(), file_stats.into());
+    }
+    if requested("peers") {
+        let peers: Vec<Value> = downloader
+            .peer_stats()
+            .snapshot()
+            .into_iter()
+            .map(|peer| {
+                json!({
+                    "address": peer.addr.ip().to_string(),
+                    "port": peer.addr.port(),
+                    "clientName": peer.client,
+                    "isIncoming": peer.direction == ConnectionDirection::Incoming,
+                    "rateToClient": peer.download_rate as u64,
+                    "rateToPeer": peer.upload_rate as u64,
+                })
+            })
+            .collect();
+        torrent.insert("peers".into(), peers.into());
+    }
+
+    // All known fields are returned, if client doesn't ask for any
+    if !fields.is_empty() {
+        torrent.retain(|field, _| requested(field));
+    }
+    Value::Object(torrent)
+}
+
+/// Returns settings of session.
+fn session_get(context: &RpcContext) -> Value {
+    let state = &context.transmission;
+    let settings = state.settings();
+    let seeding_limits = context.session.seeding_limits();
+    json!({
+        "version": format!("{} (torrent_client)", env!("CARGO_PKG_VERSION")),
+        "rpc-version": RPC_VERSION,
+        "rpc-version-minimum": 1,
+        "session-id": state.session_id(),
+        "download-dir": *context.download_folder(),
+        "peer-port": context.session.local_addr().port(),
+        "speed-limit-down": settings.speed_limit_down,
+        "speed-limit-down-enabled": settings.speed_limit_down_enabled,
+        "speed-limit-up": settings.speed_limit_up,
+        "speed-limit-up-enabled": settings.speed_limit_up_enabled,
+        "alt-speed-enabled": false,
+        "seedRatioLimit": seeding_limits.ratio.unwrap_or(settings.seed_ratio_limit),
+        "seedRatioLimited": seeding_limits.ratio.is_some(),
+        "units": {
+            "speed-units": ["kB/s", "MB/s", "GB/s", "TB/s"],
+            "speed-bytes": SPEED_UNIT,
+            "size-units": ["kB", "MB", "GB", "TB"],
+            "size-bytes": 1000,
+            "memory-units": ["KiB", "MiB", "GiB", "TiB"],
+            "memory-bytes": 1024,
+        },
+    })
+}
+
+/// Change settings of session, speed limits and seed ratio are applied to session immediately.
+fn session_set(context: &RpcContext, arguments: SessionSetArgs) {
+    if let Some(download_dir) = arguments.download_dir {
+        *context.download_folder() = download_dir;
+    }
+
+    let mut settings = context.transmission.settings();
+    settings.speed_limit_down = arguments
+        .speed_limit_down
+        .unwrap_or(settings.speed_limit_down);
+    settings.speed_limit_down_enabled = arguments
+        .speed_limit_down_enabled
+        .unwrap_or(settings.speed_limit_down_enabled);
+    settings.speed_limit_up = arguments.speed_limit_up.unwrap_or(settings.speed_limit_up);
+    settings.speed_limit_up_enabled = arguments
+        .speed_limit_up_enabled
+        .unwrap_or(settings.speed_limit_up_enabled);
+    let limit = |enabled: bool, limit: u64| if enabled { limit * SPEED_UNIT } else { 0 };
+    context.session.bandwidth().set(
+        limit(settings.speed_limit_down_enabled, settings.speed_limit_down),
+        limit(settings.speed_limit_up_enabled, settings.speed_limit_up),
+    );
+
+    let mut seeding_limits = context.session.seeding_limits();
+    if let Some(ratio) = arguments.seed_ratio_limit {
+        settings.seed_ratio_limit = ratio;
+        seeding_limits.ratio = seeding_limits.ratio.map(|_| ratio);
+    }
+    if let Some(limited) = arguments.seed_ratio_limited {
+        seeding_limits.ratio = limited.then_some(settings.seed_ratio_limit);
+    }
+    context.session.set_seeding_limits(seeding_limits);
+}
+
+/// Returns number of torrents, current rates and transferred bytes of session.
+async fn session_stats(context: &RpcContext) -> Result<Value> {
+    let mut active = 0;
+    let mut paused = 0;
+    let (mut download_speed, mut upload_speed) = (0.0, 0.0);
+    let (mut downloaded, mut uploaded) = (0, 0);
+    let torrents = context.session.torrents();
+    for handle in &torrents {
+        let status = handle.status().await;
+        match status.state {
+            TorrentState::Downloading | TorrentState::Seeding => active += 1,
+            TorrentState::Paused(_) => paused += 1,
+            _ => {}
+        }
+        download_speed += status.download_rate;
+        upload_speed += status.upload_rate;
+        downloaded += status.downloaded;
+        uploaded += status.uploaded;
+    }
+    let stats = json!({
+        "uploadedBytes": uploaded,
+        "downloadedBytes": downloaded,
+        "filesAdded": torrents.len(),
+        "sessionCount": 1,
+        "secondsActive": context.transmission.started.elapsed().as_secs(),
+    });
+    Ok(json!({
+        "activeTorrentCount": active,
+        "pausedTorrentCount": paused,
+        "torrentCount": torrents.len(),
+        "downloadSpeed": download_speed as u64,
+        "uploadSpeed": upload_speed as u64,
+        "current-stats": stats,
+        "cumulative-stats": stats,
+    }))
+}
+
+#[tokio::test]
+async fn transmission_client_controls_session() {
+    use super::{Daemon, DaemonOptions};
+    use crate::session::SessionOptions;
+    use crate::test_utils::test_torrent;
+    use sha1::{Digest, Sha1};
+    use std::net::SocketAddr;
+
+    let mut torrent = test_torrent(32, 16);
+    torrent.pieces = vec![Sha1::digest([0u8; 16]).to_vec(); 2];
+    let metainfo = base64::engine::general_purpose::STANDARD.encode(torrent.encode().unwrap());
+    let folder = tempfile::tempdir().unwrap();
+    let mut options = DaemonOptions::new("secret".to_string(), folder.path().to_path_buf());
+    options.rpc_addr = SocketAddr::from(([127, 0, 0, 1], 0));
+    options.session = SessionOptions {
+        listen_addr: SocketAddr::from(([127, 0, 0, 1], 0)),
+        ..SessionOptions::default()
+    };
+    let daemon = Daemon::start(options).await.unwrap();
+    let url = format!("http://{}{TRANSMISSION_RPC_PATH}", daemon.rpc_addr());
+    let client = reqwest::Client::builder().no_proxy().build().unwrap();
+
+    // Client gets session id from the first refused request
+    let response = client
+        .post(&url)
+        .basic_auth("user", Some("secret"))
+        .body(r#"{"method":"session-get"}"#)
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status(), reqwest::StatusCode::CONFLICT);
+    let session_id = response.headers()[SESSION_ID_HEADER]
+        .to_str()
+        .unwrap()
+        .to_string();
+    let call = |request: Value| {
+        let request = client
+            .post(&url)
+            .basic_auth("user", Some("secret"))
+            .header(SESSION_ID_HEADER, &session_id)
+            .json(&request);
+        async move {
+            let response: Value = request.send().await.unwrap().json().await.unwrap();
+            assert_eq!(response["result"], "success", "{response}");
+            response["arguments"].clone()
+        }
+    };
+
+    let added = call(
+        json!({ "method": "torrent-add", "arguments": { "metainfo": metainfo, "paused": true } }),
+    )
+    .await;
+    let id = added["torrent-added"]["id"].clone();
+    assert_eq!(added["torrent-added"]["name"], "data");
+    let duplicate =
+        call(json!({ "method": "torrent-add", "arguments": { "metainfo": metainfo } })).await;
+    assert_eq!(duplicate["torrent-duplicate"]["id"], id);
+
+    let fields = json!([
+        "id",
+        "name",
+        "status",
+        "totalSize",
+        "percentDone",
+        "files",
+        "fileStats"
+    ]);
+    let got = call(json!({ "method": "torrent-get", "arguments": { "ids": [id], "fields": fields }, "tag": 7 })).await;
+    let torrent = &got["torrents"][0];
+    assert_eq!(torrent["status"], STATUS_STOPPED);
+    assert_eq!(torrent["totalSize"], 32);
+    assert_eq!(torrent["files"][0]["name"], "data");
+    assert_eq!(torrent["fileStats"][0]["wanted"], true);
+    assert!(torrent.get("rateDownload").is_none());
+
+    call(json!({ "method": "torrent-start", "arguments": { "ids": [id] } })).await;
+    let got = call(json!({ "method": "torrent-get", "arguments": { "fields": ["status"] } })).await;
+    assert_ne!(got["torrents"][0]["status"], STATUS_STOPPED);
+    call(json!({ "method": "torrent-stop", "arguments": { "ids": [id] } })).await;
+
+    let settings = json!({ "speed-limit-down": 100, "speed-limit-down-enabled": true, "seedRatioLimit": 1.5, "seedRatioLimited": true });
+    call(json!({ "method": "session-set", "arguments": settings })).await;
+    let session = call(json!({ "method": "session-get" })).await;
+    assert_eq!(session["speed-limit-down"], 100);
+    assert_eq!(session["seedRatioLimit"], 1.5);
+    assert_eq!(
+        daemon.session().bandwidth().download.rate(),
+        100 * SPEED_UNIT
+    );
+    assert_eq!(daemon.session().seeding_limits().ratio, Some(1.5));
+
+    call(json!({ "method": "torrent-remove", "arguments": { "ids": [id], "delete-local-data": true } })).await;
+    let got = call(json!({ "method": "torrent-get", "arguments": { "fields": ["id"] } })).await;
+    assert_eq!(got["torrents"], json!([]));
+    daemon.shutdown().await;
+}
@@ -0,0 +1,108 @@
+use std::time::Duration;
+
+use anyhow::Result;
+use tokio::io::{AsyncWrite, AsyncWriteExt};
+
+use super::rpc::{list_torrents, RpcContext};
+
+/// Path of Server-Sent Events stream with status of all torrents.
+pub const EVENTS_PATH: &str = "/events";
+/// Time between two events with status of torrents.
+const EVENTS_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Static files of web UI with their content types, they are bundled into binary.
+const ASSETS: [(&str, &str, &str); 4] = [
+    (
+        "/",
+        "text/html; charset=utf-8",
+        include_str!("assets/index.html"),
+    ),
+    (
+        "/index.html",
+        "text/html; charset=utf-8",
+        include_str!("assets/index.html"),
+    ),
+    (
+        "/app.js",
+        "text/javascript; charset=utf-8",
+        include_str!("assets/app.js"),
+    ),
+    (
+        "/style.css",
+        "text/css; charset=utf-8",
+        include_str!("assets/style.css"),
+    ),
+];
+
+/// Returns content type and content of static file of web UI.
+pub(super) fn asset(path: &str) -> Option<(&'static str, &'static str)> {
+    ASSETS
+        .iter()
+        .find(|(asset_path, _, _)| *asset_path == path)
+        .map(|(_, content_type, content)| (*content_type, *content))
+}
+
+/// Send status of all torrents as `torrents` event every second, until client closes connection or daemon shuts down.
+pub(super) async fn stream_events<S>(stream: &mut S, context: &RpcContext) -> Result<()>
+where
+    S: AsyncWrite + Unpin,
+{
+    let head = "HTTP/1.1 200 OK\r\nContent-Type: text/event-stream\r\nCache-Control: no-cache\r\nConnection: close\r\n\r\n";
+    stream.write_all(head.as_bytes()).await?;
+    let mut interval = tokio::time::interval(EVENTS_INTERVAL);
+    loop {
+        tokio::select! {
+            _ = context.shutdown.cancelled() => return Ok(()),
+            _ = interval.tick() => {}
+        }
+        let torrents = serde_json::to_string(&list_torrents(context).await)?;
+        let event = format!("event: torrents\ndata: {torrents}\n\n");
+        stream.write_all(event.as_bytes()).await?;
+        stream.flush().await?;
+    }
+}
+
+#[tokio::test]
+async fn web_ui_and_events_are_served() {
+    use super::{Daemon, DaemonOptions};
+    use crate::session::SessionOptions;
+    use std::net::SocketAddr;
+
+    let folder = tempfile::tempdir().unwrap();
+    let mut options = DaemonOptions::new("secret".to_string(), folder.path().to_path_buf());
+    options.rpc_addr = SocketAddr::from(([127, 0, 0, 1], 0));
+    options.session = SessionOptions {
+        listen_addr: SocketAddr::from(([127, 0, 0, 1], 0)),
+        ..SessionOptions::default()
+    };
+    let daemon = Daemon::start(options).await.unwrap();
+    let base = format!("http://{}", daemon.rpc_addr());
+    let client = reqwest::Client::builder().no_proxy().build().unwrap();
+
+    // Static files are bundled and served without token
+    let page = client.get(format!("{base}/")).send().await.unwrap();
+    assert_eq!(page.headers()["Content-Type"], "text/html; charset=utf-8");
+    assert!(page.text().await.unwrap().contains("/app.js"));
+    let script = client.get(format!("{base}/app.js")).send().await.unwrap();
+    assert!(script.status().is_success());
+
+    let events = client
+        .get(format!("{base}{EVENTS_PATH}"))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(events.status(), reqwest::StatusCode::UNAUTHORIZED);
+    let mut events = client
+        .get(format!("{base}{EVENTS_PATH}"))
+        .bearer_auth("secret")
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(events.headers()["Content-Type"], "text/event-stream");
+    let event = events.chunk().await.unwrap().unwrap();
+    assert_eq!(&event[..], b"event: torrents\ndata: []\n\n");
+
+    // Stream ends, when daemon shuts down
+    daemon.shutdown().await;
+    assert!(events.chunk().await.unwrap().is_none());
+}
@@ -1,49 +1,143 @@
 use std::collections::HashMap;
-use std::path::PathBuf;
-use std::sync::atomic::AtomicUsize;
-use std::sync::Arc;
+use std::future::Future;
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, OnceLock};
+use std::time::{Duration, Instant};
 
 use anyhow::Result;
 use lava_torrent::torrent::v1::Torrent;
 use lava_torrent::tracker::Peer;
 use tokio::net::TcpStream;
 use tokio::sync::mpsc::{Receiver, Sender};
-use tokio::sync::{mpsc, Mutex};
-use tokio::task::{self, JoinHandle};
-use tokio::time::timeout;
+use tokio::sync::{mpsc, watch, Mutex, OwnedSemaphorePermit, Semaphore};
+use tokio::task::{self, JoinHandle, JoinSet};
+use tokio::time::{sleep, timeout};
+use tokio_util::sync::CancellationToken;
 
-use crate::hash::Hash;
-use crate::peer_comunication::peer_connection::{downloading_pieces_from_pear, TIMEOUT};
+use crate::file_layout::{FileLayout, FilePriority};
+use crate::hash::InfoHash;
+use crate::ip_filter::IpFilter;
+use crate::metainfo::{MetaVersion, Metainfo, V2Metadata};
+use crate::peer_comunication::connection_manager::{ConnectionManager, PeerSource};
+use crate::peer_comunication::handshake::Handshake;
+use crate::peer_comunication::peer_connection::{
+    downloading_pieces_from_pear, seeding_to_peer, PeerConnection, TorrentContext, TIMEOUT,
+};
+use crate::peer_comunication::peer_score::{PeerScores, ProtocolViolation};
+use crate::peer_comunication::peer_stats::PeerStatsRegistry;
 use crate::peer_id::PeerId;
-use crate::piece::{pieces_from_torrent, Piece, PieceData};
-use crate::writer::PieceFileWriter;
+use crate::piece::{pieces_from_torrent, PieceData};
+use crate::piece_pool::{PickMode, PieceAvailability, PiecePool};
+use crate::rate_limit::{BandwidthLimits, TransferLimiters};
+use crate::resume::ResumeData;
+use crate::storage::{Storage, StorageOptions};
+use crate::tracker_connection::get_peers::AnnounceStats;
+use crate::writer::PieceWriter;
+
+/// Maximal time between two checks of peer table for new connection candidates.
+const RECONNECT_INTERVAL: Duration = Duration::from_secs(5);
+/// Minimal time between two checks of peer table, so connection loop is not spinning.
+const MIN_RECONNECT_INTERVAL: Duration = Duration::from_millis(100);
+/// Number of verified pieces waiting for writer, peers wait when writer falls behind.
+const PIECE_CHANNEL_CAPACITY: usize = 16;
+/// Number of incoming connections waiting until download loop accepts them.
+const INCOMING_CHANNEL_CAPACITY: usize = 8;
+
+/// Peer, which connected to this client and sent handshake with info hash of this torrent.
+struct IncomingPeer {
+    stream: TcpStream,
+    handshake: Handshake,
+}
 
 /// Structure that represents downloading torrent file from peers, and its saving to file
 pub struct TorrentDownloader {
-    info_hash: [u8; 20],
+    info_hash: InfoHash,
+    version: MetaVersion,
+    v2: Option<Arc<V2Metadata>>,
+    /// Info hashes of other swarms of hybrid torrent, for peers discovered in them.
+    swarm_peers: std::sync::Mutex<HashMap<SocketAddr, InfoHash>>,
+    /// Info hashes of all swarms of torrent, two for hybrid torrents.
+    swarm_hashes: Vec<InfoHash>,
     total_pieces: usize,
     torrent: Torrent,
-    piece_pool: Arc<Mutex<HashMap<usize, Piece>>>,
-    download_count: Arc<AtomicUsize>,
+    layout: Arc<FileLayout>,
+    piece_pool: Arc<Mutex<PiecePool>>,
+    availability: Arc<PieceAvailability>,
+    /// Storage of downloaded data, it's created when download starts, if it wasn't set.
+    storage: OnceLock<Arc<dyn Storage>>,
+    connection_manager: Arc<Mutex<ConnectionManager>>,
+    peer_scores: Arc<Mutex<PeerScores>>,
+    ip_filter: Arc<IpFilter>,
+    bandwidth: BandwidthLimits,
+    global_bandwidth: BandwidthLimits,
+    peer_stats: Arc<PeerStatsRegistry>,
+    /// Reason, why torrent is paused, `None` while it's running.
+    pause: Arc<watch::Sender<Option<String>>>,
+    incoming: Sender<IncomingPeer>,
+    incoming_receiver: Mutex<Receiver<IncomingPeer>>,
+    /// Connection slots shared by all torrents of session, number of connections is not limited if not set.
+    connection_slots: Option<Arc<Semaphore>>,
+    /// Cancelled when client shuts down, download and seeding end after written data are flushed.
+    shutdown: CancellationToken,
 }
 
 impl TorrentDownloader {
     /// Create a new torrent downloader based on given torrent file
     pub fn new(torrent: Torrent) -> Result<Self> {
-        let piece_pool = pieces_from_torrent(&torrent)?
-            .into_iter()
-            .enumerate()
-            .collect();
+        TorrentDownloader::from_metainfo(Metainfo::from_torrent(torrent)?)
+    }
+
+    /// Create a new torrent downloader for torrent of any version.
+    pub fn from_metainfo(metainfo: Metainfo) -> Result<Self> {
+        let info_hash = metainfo.info_hash();
+        let swarm_hashes = metainfo.swarm_hashes();
+        let Metainfo {
+            torrent,
+            version,
+            v2,
+            ..
+        } = metainfo;
+        let layout = Arc::new(FileLayout::from_torrent(&torrent)?);
+        let piece_pool = PiecePool::new(pieces_from_torrent(&torrent)?, layout.clone());
+        let (incoming, incoming_receiver) = mpsc::channel(INCOMING_CHANNEL_CAPACITY);
         Ok(TorrentDownloader {
-            info_hash: Hash::new(torrent.info_hash_bytes())?.to_arr(),
+            info_hash,
+            version,
+            v2,
+            swarm_peers: std::sync::Mutex::new(HashMap::new()),
+            swarm_hashes,
             total_pieces: torrent.pieces.len(),
+            layout,
             torrent,
+            availability: piece_pool.availability(),
             piece_pool: Arc::new(Mutex::new(piece_pool)),
-            download_count: Arc::new(AtomicUsize::new(0)),
+            storage: OnceLock::new(),
+            connection_manager: Arc::new(Mutex::new(ConnectionManager::default())),
+            peer_scores: Arc::new(Mutex::new(PeerScores::new())),
+            ip_filter: Arc::new(IpFilter::new()),
+            bandwidth: BandwidthLimits::unlimited(),
+            global_bandwidth: BandwidthLimits::unlimited(),
+            peer_stats: Arc::new(PeerStatsRegistry::new()),
+            pause: Arc::new(watch::channel(None).0),
+            incoming,
+            incoming_receiver: Mutex::new(incoming_receiver),
+            connection_slots: None,
+            shutdown: CancellationToken::new(),
         })
     }
 
-    /// Download a file from peers, and save it to given folder.
+    /// Returns info hash identifying downloaded torrent.
+    pub fn info_hash(&self) -> InfoHash {
+        self.info_hash
+    }
+
+    /// Returns info hashes of all swarms of torrent, hybrid torrents are in v1 and v2 swarm.
+    pub fn swarm_hashes(&self) -> &[InfoHash] {
+        &self.swarm_hashes
+    }
+
+    /// Download a file from peers, and save it to storage set by `set_storage`, or to files in given folder.
     /// Given PeerId is used to comunicate with other peers.
     /// Download sender is used to accept indexes of already downloaded pieces.
     pub async fn download_torrent(
@@ -53,90 +147,700 @@ impl TorrentDownloader {
         folder_path: String,
         downloaded_sender: Sender<usize>,
     ) -> Result<()> {
-        let (connection_tasks, receiver) = self.make_peers_connections(peers, peer_id).await?;
-
-        let writer_handle = self
-            .init_writer(folder_path, receiver, downloaded_sender)
+        let (sender, receiver) = mpsc::channel(PIECE_CHANNEL_CAPACITY);
+        let storage = self
+            .storage
+            .get_or_init(|| {
+                StorageOptions::default().create(PathBuf::from(folder_path), self.layout.clone())
+            })
+            .clone();
+        let context = self.torrent_context(peer_id, &sender, storage.clone());
+        let mut writer_handle = self
+            .init_writer(storage, receiver, downloaded_sender)
             .await?;
 
-        // Wait for writer to finish
-        writer_handle.await??;
+        self.connection_manager
+            .lock()
+            .await
+            .add_peers(peers.iter().map(|peer| peer.addr), PeerSource::Tracker);
+
+        // Keep number of connections under the limit, and replace dead connections with new candidates
+        let mut incoming = self.incoming_receiver.lock().await;
+        let mut pause = self.subscribe_pause();
+        let mut connection_tasks = JoinSet::new();
+        loop {
+            // Paused torrent doesn't connect new peers
+            let retry_in = if self.pause.borrow().is_none() {
+                self.make_peers_connections(&mut connection_tasks, &context)
+                    .await
+            } else {
+                RECONNECT_INTERVAL
+            };
 
-        // Wait for all pieces to download
-        for task in connection_tasks {
-            task.abort();
-            let _ = task.await;
+            tokio::select! {
+                writer_result = &mut writer_handle => {
+                    writer_result??;
+                    break;
+                }
+                Some(_) = connection_tasks.join_next() => {}
+                Some(peer) = incoming.recv() => {
+                    self.accept_peer(&mut connection_tasks, peer, &context, false).await;
+                }
+                _ = self.shutdown.cancelled() => {
+                    // Connections end on shutdown, writer flushes written data and ends
+                    while connection_tasks.join_next().await.is_some() {}
+                    writer_handle.await??;
+                    return Ok(());
+                }
+                Ok(()) = pause.changed() => {
+                    // Connections end on pause, their pieces are returned to pool and slots are released
+                    if pause.borrow_and_update().is_some() {
+                        while connection_tasks.join_next().await.is_some() {}
+                    }
+                }
+                _ = sleep(retry_in) => {}
+            }
         }
 
+        // All pieces are downloaded, close remaining connections
+        connection_tasks.shutdown().await;
+
         Ok(())
     }
 
-    /// Do TCP connection to given peers, and start bittorent protocol with them.
-    async fn make_peers_connections(
+    /// Upload complete pieces to peers, which connect to this client, until `until` completes or client shuts down.
+    /// Paused torrent closes its connections and doesn't accept new peers.
+    pub async fn seed_torrent(
         &self,
-        peers: Vec<Peer>,
         peer_id: &PeerId,
-    ) -> Result<(Vec<JoinHandle<Result<()>>>, Receiver<PieceData>)> {
-        // Establish connections to peers concurrently
-        let (sender, receiver) = mpsc::channel(1024);
-        let connection_tasks: Vec<_> = peers
-            .into_iter()
-            .map(|peer| {
-                let info_hash = self.info_hash;
-                let peer_id_arr = peer_id.to_arr();
-                let sender_clone = sender.clone();
-                let piece_count = self.torrent.pieces.len();
-                let piece_pool = self.piece_pool.clone();
-                let downloaded_count = self.download_count.clone();
-
-                task::spawn(async move {
-                    match timeout(TIMEOUT, TcpStream::connect(&peer.addr)).await {
-                        Ok(Ok(stream)) => {
-                            downloading_pieces_from_pear(
-                                stream,
-                                info_hash,
-                                peer_id_arr,
-                                piece_count,
-                                sender_clone,
-                                piece_pool,
-                                downloaded_count,
-                            )
-                            .await
-                        }
-                        _ => anyhow::bail!("Unable to open tcp connection"),
+        until: impl Future<Output = ()>,
+    ) -> Result<()> {
+        let Some(storage) = self.storage() else {
+            anyhow::bail!("Storage is created when download starts");
+        };
+        // Seeding connections never send pieces to writer
+        let (sender, _) = mpsc::channel(1);
+        let context = self.torrent_context(peer_id, &sender, storage);
+
+        let mut incoming = self.incoming_receiver.lock().await;
+        let mut pause = self.subscribe_pause();
+        let mut connection_tasks = JoinSet::new();
+        tokio::pin!(until);
+        loop {
+            tokio::select! {
+                _ = &mut until => break,
+                _ = self.shutdown.cancelled() => {
+                    while connection_tasks.join_next().await.is_some() {}
+                    break;
+                }
+                Some(_) = connection_tasks.join_next() => {}
+                Some(peer) = incoming.recv() => {
+                    self.accept_peer(&mut connection_tasks, peer, &context, true).await;
+                }
+                Ok(()) = pause.changed() => {
+                    if pause.borrow_and_update().is_some() {
+                        while connection_tasks.join_next().await.is_some() {}
                     }
-                })
+                }
+            }
+        }
+
+        connection_tasks.shutdown().await;
+        Ok(())
+    }
+
+    /// Add peers discovered in swarm with given info hash, hybrid torrents are in v1 and v2 swarm.
+    /// Peers given to `download_torrent` are connected with default info hash.
+    pub async fn add_swarm_peers(&self, peers: &[Peer], info_hash: InfoHash) {
+        if info_hash != self.info_hash {
+            let mut swarm_peers = self
+                .swarm_peers
+                .lock()
+                .unwrap_or_else(|err| err.into_inner());
+            swarm_peers.extend(peers.iter().map(|peer| (peer.addr, info_hash)));
+        }
+        self.connection_manager
+            .lock()
+            .await
+            .add_peers(peers.iter().map(|peer| peer.addr), PeerSource::Tracker);
+    }
+
+    /// Returns priorities of all files of torrent, including padding files.
+    pub async fn file_priorities(&self) -> Vec<FilePriority> {
+        self.piece_pool.lock().await.file_priorities().to_vec()
+    }
+
+    /// Change priority of file with given index, can be done while the download runs.
+    /// Skipped files are not created, pieces they share with other files are still downloaded.
+    pub async fn set_file_priority(&self, file_index: usize, priority: FilePriority) -> Result<()> {
+        self.piece_pool
+            .lock()
+            .await
+            .set_file_priority(file_index, priority)
+    }
+
+    /// Returns number of pieces of torrent.
+    pub fn piece_count(&self) -> usize {
+        self.total_pieces
+    }
+
+    /// Returns number of wanted pieces, which are not written yet.
+    pub async fn remaining_pieces(&self) -> usize {
+        self.piece_pool.lock().await.remaining()
+    }
+
+    /// Returns transferred payload and remaining bytes of wanted pieces, which are reported to trackers.
+    pub async fn announce_stats(&self) -> AnnounceStats {
+        let (downloaded, uploaded) = self.peer_stats.totals();
+        let remaining = self.remaining_pieces().await as u64;
+        AnnounceStats {
+            uploaded,
+            downloaded,
+            left: (remaining * self.layout.piece_length()).min(self.layout.total_length()),
+        }
+    }
+
+    /// Returns mapping of pieces to files.
+    pub fn layout(&self) -> Arc<FileLayout> {
+        self.layout.clone()
+    }
+
+    /// Wait until piece with given index is verified and written to disk.
+    pub async fn wait_for_piece(&self, piece_index: usize) -> Result<()> {
+        loop {
+            let mut written = {
+                let pool = self.piece_pool.lock().await;
+                if pool.is_written(piece_index) {
+                    return Ok(());
+                }
+                pool.subscribe_written()
+            };
+            written.changed().await?;
+        }
+    }
+
+    /// Change order in which pieces are downloaded, can be done while the download runs.
+    pub async fn set_pick_mode(&self, mode: PickMode) {
+        self.piece_pool.lock().await.set_mode(mode);
+    }
+
+    /// Move read cursor of sequential and streaming mode to given byte of torrent data.
+    /// Consumer of data should move it whenever it reads or seeks.
+    pub async fn set_read_cursor(&self, offset: u64) {
+        let piece_index = offset / self.layout.piece_length();
+        self.piece_pool
+            .lock()
+            .await
+            .set_cursor(piece_index as usize);
+    }
+
+    /// Move read cursor to given byte of file with given index.
+    pub async fn set_file_read_cursor(&self, file_index: usize, offset: u64) -> Result<()> {
+        let Some(file) = self.layout.files().get(file_index) else {
+            anyhow::bail!("File index {file_index} out of range");
+        };
+        self.set_read_cursor(file.offset + offset.min(file.length))
+            .await;
+        Ok(())
+    }
+
+    /// Returns reason, why torrent is paused, `None` if it's running.
+    pub fn pause_reason(&self) -> Option<String> {
+        self.pause.borrow().clone()
+    }
+
+    /// Returns receiver notified about changes of pause reason.
+    pub fn subscribe_pause(&self) -> watch::Receiver<Option<String>> {
+        self.pause.subscribe()
+    }
+
+    /// Pause torrent with given reason, downloaded pieces are not written and no new peers are connected.
+    pub fn pause(&self, reason: &str) {
+        self.pause.send_replace(Some(reason.to_string()));
+    }
+
+    /// Resume paused torrent, writing stopped by full disk is tried again immediately.
+    pub fn resume(&self) {
+        self.pause.send_replace(None);
+    }
+
+    /// Pause torrent with given reason, only if it's running.
+    /// Returns false, if torrent is already paused for other reason.
+    pub fn pause_if_running(&self, reason: &str) -> bool {
+        self.pause.send_if_modified(|current| {
+            if current.is_some() {
+                return false;
+            }
+            *current = Some(reason.to_string());
+            true
+        })
+    }
+
+    /// Resume torrent, only if it's paused with given reason.
+    /// Returns false, if torrent runs or it's paused for other reason.
+    pub fn resume_if_paused_by(&self, reason: &str) -> bool {
+        self.pause
+            .send_if_modified(|current| match current.as_deref() {
+                Some(current_reason) if current_reason == reason => current.take().is_some(),
+                _ => false,
+            })
+    }
+
+    /// Change reason of pause, only if torrent is paused with one of given reasons.
+    /// Returns false, if torrent runs or it's paused for other reason.
+    pub fn replace_pause_reason(&self, reasons: &[&str], reason: &str) -> bool {
+        self.pause
+            .send_if_modified(|current| match current.as_deref() {
+                Some(current_reason) if reasons.contains(&current_reason) => {
+                    *current = Some(reason.to_string());
+                    true
+                }
+                _ => false,
             })
-            .collect();
+    }
+
+    /// Returns resume data with pieces, which are written whole to storage, and transferred bytes.
+    /// Seeding time is not known to downloader, it's zero.
+    pub async fn resume_data(&self) -> ResumeData {
+        let (downloaded, uploaded) = self.peer_stats.totals();
+        ResumeData {
+            info_hash: self.info_hash,
+            pieces: self
+                .piece_pool
+                .lock()
+                .await
+                .complete_pieces()
+                .pieces()
+                .collect(),
+            downloaded,
+            uploaded,
+            seeding_time: 0,
+        }
+    }
+
+    /// Mark pieces from resume data as written, so they are not downloaded again, transferred bytes are restored.
+    /// Has to be done before the download starts, data of pieces in storage are trusted.
+    pub async fn load_resume_data(&self, resume_data: &ResumeData) -> Result<()> {
+        if resume_data.info_hash != self.info_hash {
+            anyhow::bail!("Resume data belong to torrent {}", resume_data.info_hash);
+        }
+        let mut piece_pool = self.piece_pool.lock().await;
+        for &piece_index in &resume_data.pieces {
+            if piece_index >= self.total_pieces {
+                anyhow::bail!("Invalid piece index {piece_index} in resume data");
+            }
+            piece_pool.mark_written(piece_index, false);
+        }
+        self.peer_stats
+            .add_closed_totals(resume_data.downloaded, resume_data.uploaded);
+        Ok(())
+    }
+
+    /// Shut down download and seeding, connections with peers are closed and written data are flushed.
+    /// Torrent can't be started again, it continues from its resume data after restart.
+    pub fn shutdown(&self) {
+        self.shutdown.cancel();
+    }
+
+    /// Returns true, if torrent was shut down.
+    pub fn is_shut_down(&self) -> bool {
+        self.shutdown.is_cancelled()
+    }
 
-        Ok((connection_tasks, receiver))
+    /// Returns token cancelled, when torrent is shut down.
+    pub fn shutdown_token(&self) -> CancellationToken {
+        self.shutdown.clone()
+    }
+
+    /// Set token, which shuts down torrent when it's cancelled, so torrents of session are shut down together.
+    pub fn set_shutdown_token(&mut self, shutdown: CancellationToken) {
+        self.shutdown = shutdown;
+    }
+
+    /// Returns storage of downloaded data, if it was set.
+    pub fn storage(&self) -> Option<Arc<dyn Storage>> {
+        self.storage.get().cloned()
+    }
+
+    /// Set storage of downloaded data, files in download folder are used if not set.
+    pub fn set_storage(&mut self, storage: Arc<dyn Storage>) {
+        self.storage = OnceLock::from(storage);
+    }
+
+    /// Move downloaded data to other folder, while torrent is running.
+    /// Files are copied if the folder is on other file system, downloading continues in the new folder.
+    pub async fn move_storage(&self, folder: &Path) -> Result<()> {
+        let Some(storage) = self.storage() else {
+            anyhow::bail!("Storage is created when download starts");
+        };
+        storage.move_to(folder).await
+    }
+
+    /// Returns peer table of this torrent.
+    pub fn connection_manager(&self) -> Arc<Mutex<ConnectionManager>> {
+        self.connection_manager.clone()
+    }
+
+    /// Returns trust scores and bans of peers.
+    pub fn peer_scores(&self) -> Arc<Mutex<PeerScores>> {
+        self.peer_scores.clone()
+    }
+
+    /// Replace trust scores of peers, so bans can be shared between torrents or persisted.
+    pub fn set_peer_scores(&mut self, peer_scores: Arc<Mutex<PeerScores>>) {
+        self.peer_scores = peer_scores;
+    }
+
+    /// Returns IP filter applied to peers before connecting.
+    pub fn ip_filter(&self) -> Arc<IpFilter> {
+        self.ip_filter.clone()
+    }
+
+    /// Set IP filter applied to peers before connecting.
+    pub fn set_ip_filter(&mut self, ip_filter: Arc<IpFilter>) {
+        self.ip_filter = ip_filter;
+    }
+
+    /// Returns bandwidth limits of this torrent, they can be changed while downloading.
+    pub fn bandwidth(&self) -> BandwidthLimits {
+        self.bandwidth.clone()
+    }
+
+    /// Set bandwidth limits shared by all torrents of the client.
+    /// Has to be set before the download starts.
+    pub fn set_global_bandwidth(&mut self, global_bandwidth: BandwidthLimits) {
+        self.global_bandwidth = global_bandwidth;
+    }
+
+    /// Set connection slots shared with other torrents, every connection of this torrent takes one slot.
+    pub fn set_connection_slots(&mut self, connection_slots: Arc<Semaphore>) {
+        self.connection_slots = Some(connection_slots);
+    }
+
+    /// Hand over peer, which connected to this client and sent handshake with one of `swarm_hashes`.
+    /// Connection is accepted by running download, it's closed if download doesn't run.
+    pub fn add_incoming_peer(&self, stream: TcpStream, handshake: Handshake) -> Result<()> {
+        self.incoming
+            .try_send(IncomingPeer { stream, handshake })
+            .map_err(|_| anyhow::anyhow!("Torrent doesn't accept incoming connections"))
+    }
+
+    /// Returns transfer statistics of connected peers.
+    pub fn peer_stats(&self) -> Arc<PeerStatsRegistry> {
+        self.peer_stats.clone()
+    }
+
+    /// Do TCP connection to peers chosen by connection manager, and start bittorent protocol with them.
+    /// Returns time after which new connections should be tried, even if no running connection ends.
+    async fn make_peers_connections(
+        &self,
+        connection_tasks: &mut JoinSet<Result<()>>,
+        context: &TorrentContext,
+    ) -> Duration {
+        let mut manager = self.connection_manager.lock().await;
+        let now = Instant::now();
+
+        let candidates = manager.next_candidates(now);
+        let scores = self.peer_scores.lock().await;
+        for addr in candidates {
+            if scores.is_banned(&addr.ip()) || !self.ip_filter.allows(addr.ip()) {
+                manager.on_banned(addr);
+                continue;
+            }
+            let Some(permit) = self.take_connection_slot() else {
+                manager.on_postponed(addr);
+                continue;
+            };
+
+            let info_hash = self
+                .swarm_peers
+                .lock()
+                .unwrap_or_else(|err| err.into_inner())
+                .get(&addr)
+                .copied()
+                .unwrap_or(self.info_hash);
+            let mut context = context.clone();
+            context.info_hash = info_hash;
+            self.spawn_connection(connection_tasks, addr, permit, false, async move {
+                let stream = match timeout(TIMEOUT, TcpStream::connect(addr)).await {
+                    Ok(Ok(stream)) => stream,
+                    _ => anyhow::bail!("Unable to open tcp connection"),
+                };
+                PeerConnection::new(stream, context).await
+            });
+        }
+
+        manager
+            .next_retry_in(now)
+            .unwrap_or(RECONNECT_INTERVAL)
+            .clamp(MIN_RECONNECT_INTERVAL, RECONNECT_INTERVAL)
+    }
+
+    /// Start bittorrent protocol with peer, which connected to this client.
+    /// Peer is refused, if it's banned or the torrent has no free connection slot.
+    async fn accept_peer(
+        &self,
+        connection_tasks: &mut JoinSet<Result<()>>,
+        peer: IncomingPeer,
+        context: &TorrentContext,
+        seeding: bool,
+    ) {
+        let Ok(addr) = peer.stream.peer_addr() else {
+            return;
+        };
+        let paused = self.pause.borrow().is_some();
+        if paused
+            || self.peer_scores.lock().await.is_banned(&addr.ip())
+            || !self.ip_filter.allows(addr.ip())
+        {
+            return;
+        }
+        {
+            let manager = self.connection_manager.lock().await;
+            if manager.active_count() >= manager.max_active() {
+                return;
+            }
+        }
+        let Some(permit) = self.take_connection_slot() else {
+            return;
+        };
+        let Some(info_hash) = self
+            .swarm_hashes
+            .iter()
+            .find(|swarm_hash| swarm_hash.to_arr() == peer.handshake.info_hash)
+            .copied()
+        else {
+            return;
+        };
+
+        let mut context = context.clone();
+        context.info_hash = info_hash;
+        self.spawn_connection(
+            connection_tasks,
+            addr,
+            permit,
+            seeding,
+            PeerConnection::accept(peer.stream, peer.handshake, context),
+        );
+    }
+
+    /// Returns connection slot shared with other torrents, `None` if all slots are taken.
+    /// Torrent without shared slots gets empty permit.
+    fn take_connection_slot(&self) -> Option<Option<OwnedSemaphorePermit>> {
+        match &self.connection_slots {
+            Some(slots) => slots.clone().try_acquire_owned().ok().map(Some),
+            None => Some(None),
+        }
+    }
+
+    /// Returns informations about torrent for connections with peers, peers are in swarm of default info hash.
+    fn torrent_context(
+        &self,
+        peer_id: &PeerId,
+        sender: &Sender<PieceData>,
+        storage: Arc<dyn Storage>,
+    ) -> TorrentContext {
+        TorrentContext {
+            info_hash: self.info_hash,
+            version: self.version,
+            v2: self.v2.clone(),
+            layout: self.layout.clone(),
+            peer_id: peer_id.to_arr(),
+            piece_count: self.torrent.pieces.len(),
+            piece_channel: sender.clone(),
+            piece_pool: self.piece_pool.clone(),
+            availability: self.availability.clone(),
+            peer_scores: self.peer_scores.clone(),
+            limiters: TransferLimiters::from_limits(&[&self.global_bandwidth, &self.bandwidth]),
+            peer_stats: self.peer_stats.clone(),
+            storage,
+        }
+    }
+
+    /// Run connection with peer in new task, until download from it or upload to it ends, or torrent is paused or shut down.
+    /// Result of connection is recorded in peer table, and peer is banned for protocol violations.
+    /// Connection slot is released, when the task ends.
+    fn spawn_connection(
+        &self,
+        connection_tasks: &mut JoinSet<Result<()>>,
+        addr: SocketAddr,
+        permit: Option<OwnedSemaphorePermit>,
+        seeding: bool,
+        connect: impl Future<Output = Result<PeerConnection>> + Send + 'static,
+    ) {
+        let connection_manager = self.connection_manager.clone();
+        let peer_scores = self.peer_scores.clone();
+        let shutdown = self.shutdown.clone();
+        let mut pause = self.subscribe_pause();
+
+        connection_tasks.spawn(async move {
+            let _permit = permit;
+            let connection = async {
+                let peer_connection = connect.await?;
+
+                if !connection_manager
+                    .lock()
+                    .await
+                    .on_connected(addr, peer_connection.peer_id().to_arr())
+                {
+                    anyhow::bail!("Peer is already connected from other address");
+                }
+
+                if seeding {
+                    seeding_to_peer(peer_connection).await
+                } else {
+                    downloading_pieces_from_pear(peer_connection).await
+                }
+            };
+            // Socket is closed, when the connection is dropped on shutdown or pause
+            let result = tokio::select! {
+                result = connection => result,
+                _ = shutdown.cancelled() => Ok(()),
+                _ = pause.wait_for(|reason| reason.is_some()) => Ok(()),
+            };
+
+            let banned = {
+                let mut scores = peer_scores.lock().await;
+                if let Err(err) = &result {
+                    if err.downcast_ref::<ProtocolViolation>().is_some() {
+                        scores.protocol_violation(addr.ip());
+                    }
+                }
+                scores.is_banned(&addr.ip())
+            };
+
+            let mut manager = connection_manager.lock().await;
+            match result {
+                _ if banned => manager.on_banned(addr),
+                Ok(()) => manager.on_disconnected(addr),
+                Err(_) => manager.on_failed(addr),
+            }
+            result
+        });
     }
 
     /// Init writer in new tokio task.
-    /// This writer will save already downloaded pieces to final file.
+    /// This writer will save already downloaded pieces to storage.
     async fn init_writer(
         &self,
-        folder_path: String,
+        storage: Arc<dyn Storage>,
         piece_channel: Receiver<PieceData>,
         downloaded_sender: Sender<usize>,
     ) -> Result<JoinHandle<Result<()>>> {
-        let file_path = PathBuf::from(folder_path).join(&self.torrent.name);
-        let total_pieces = self.total_pieces;
-        let piece_length = self.torrent.piece_length as usize;
-        let file_size = self.torrent.length as u64;
-        let handle = task::spawn(async move {
-            let piece_writer = PieceFileWriter::new(
-                file_path,
-                total_pieces,
-                piece_length,
-                file_size,
-                piece_channel,
-                downloaded_sender,
-            )
-            .await;
-            piece_writer?.write_file().await
-        });
+        let priorities = self.piece_pool.lock().await.file_priorities().to_vec();
+        storage.prepare(&priorities).await?;
+        let mut piece_writer = PieceWriter::new(
+            storage,
+            self.layout.clone(),
+            self.piece_pool.clone(),
+            self.total_pieces,
+            piece_channel,
+            downloaded_sender,
+            self.pause.clone(),
+        );
+        piece_writer.set_shutdown_token(self.shutdown.clone());
+        let handle = task::spawn(async move { piece_writer.write_file().await });
 
         Ok(handle)
     }
 }
+
+#[tokio::test]
+async fn download_ends_when_remaining_files_are_skipped() {
+    use crate::test_utils::{data_torrent, files_torrent, spawn_partial_seeder, tracker_peer};
+
+    let data: Vec<u8> = (0..32).collect();
+    let mut torrent = files_torrent(&[("a", 16), ("b", 16)], 16);
+    torrent.pieces = data_torrent(&data, 16).pieces;
+    let downloader = Arc::new(TorrentDownloader::new(torrent).unwrap());
+
+    // Other peer has only the piece of the first file
+    let seed_addr = spawn_partial_seeder(downloader.info_hash(), data.clone(), 16, &[0]).await;
+    let folder = tempfile::tempdir().unwrap();
+    let folder_path = folder.path().to_string_lossy().to_string();
+    let (downloaded_sender, mut downloaded_receiver) = mpsc::channel(16);
+    tokio::spawn(async move { while downloaded_receiver.recv().await.is_some() {} });
+    let download = tokio::spawn({
+        let downloader = downloader.clone();
+        async move {
+            downloader
+                .download_torrent(
+                    vec![tracker_peer(seed_addr)],
+                    &PeerId::generate(),
+                    folder_path,
+                    downloaded_sender,
+                )
+                .await
+        }
+    });
+    while downloader.remaining_pieces().await > 1 {
+        tokio::time::sleep(Duration::from_millis(10)).await;
+    }
+
+    // Nobody has the second file, skipping it completes the download
+    downloader
+        .set_file_priority(1, FilePriority::Skip)
+        .await
+        .unwrap();
+    tokio::time::timeout(Duration::from_secs(10), download)
+        .await
+        .expect("download should end")
+        .unwrap()
+        .unwrap();
+    assert_eq!(
+        std::fs::read(folder.path().join("bundle/a")).unwrap(),
+        data[..16]
+    );
+}
+
+#[tokio::test]
+async fn queued_torrent_closes_connections() {
+    use crate::session::QUEUED_REASON;
+    use crate::test_utils::{data_torrent, spawn_partial_seeder, tracker_peer};
+
+    let data: Vec<u8> = (0..32).collect();
+    let mut downloader = TorrentDownloader::new(data_torrent(&data, 16)).unwrap();
+    let slots = Arc::new(Semaphore::new(1));
+    downloader.set_connection_slots(slots.clone());
+    let downloader = Arc::new(downloader);
+
+    // Other peer has only the first piece, so the download keeps running
+    let seed_addr = spawn_partial_seeder(downloader.info_hash(), data.clone(), 16, &[0]).await;
+    let folder = tempfile::tempdir().unwrap();
+    let folder_path = folder.path().to_string_lossy().to_string();
+    let (downloaded_sender, mut downloaded_receiver) = mpsc::channel(16);
+    tokio::spawn(async move { while downloaded_receiver.recv().await.is_some() {} });
+    tokio::spawn({
+        let downloader = downloader.clone();
+        async move {
+            downloader
+                .download_torrent(
+                    vec![tracker_peer(seed_addr)],
+                    &PeerId::generate(),
+                    folder_path,
+                    downloaded_sender,
+                )
+                .await
+        }
+    });
+    while downloader.remaining_pieces().await > 1 {
+        tokio::time::sleep(Duration::from_millis(10)).await;
+    }
+    assert_eq!(
+        downloader.connection_manager().lock().await.active_count(),
+        1
+    );
+    assert_eq!(slots.available_permits(), 0);
+
+    // Queued torrent closes the connection and releases its slot for other torrents
+    downloader.pause(QUEUED_REASON);
+    tokio::time::timeout(Duration::from_secs(5), async {
+        while downloader.connection_manager().lock().await.active_count() > 0
+            || slots.available_permits() == 0
+        {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+    })
+    .await
+    .expect("connections should end");
+}
@@ -0,0 +1,266 @@
+use std::fmt::Display;
+use std::path::{Component, Path, PathBuf};
+use std::str::FromStr;
+
+use anyhow::Result;
+use lava_torrent::bencode::BencodeElem;
+use lava_torrent::torrent::v1::Torrent;
+
+/// Download priority of file, skipped files are not created on disk.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Default)]
+pub enum FilePriority {
+    Skip,
+    Low,
+    #[default]
+    Normal,
+    High,
+}
+
+impl Display for FilePriority {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            FilePriority::Skip => write!(f, "skip"),
+            FilePriority::Low => write!(f, "low"),
+            FilePriority::Normal => write!(f, "normal"),
+            FilePriority::High => write!(f, "high"),
+        }
+    }
+}
+
+impl FromStr for FilePriority {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "skip" => Ok(FilePriority::Skip),
+            "low" => Ok(FilePriority::Low),
+            "normal" => Ok(FilePriority::Normal),
+            "high" => Ok(FilePriority::High),
+            _ => anyhow::bail!("Unknown file priority {s}"),
+        }
+    }
+}
+
+/// Attributes of file in torrent (BEP 47).
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct FileAttributes {
+    /// Padding file (`p`), its content are zeros and it is never created on disk.
+    pub padding: bool,
+    /// Executable file (`x`).
+    pub executable: bool,
+    /// Hidden file (`h`).
+    pub hidden: bool,
+    /// Symbolic link (`l`) pointing to given path, relative to root of torrent.
+    pub symlink: Option<PathBuf>,
+}
+
+/// One file of torrent, placed in continuous data of all files.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FileEntry {
+    /// Path relative to download folder, it starts with name of torrent for multi-file torrents.
+    pub path: PathBuf,
+    /// Offset of first byte of file in data of torrent.
+    pub offset: u64,
+    pub length: u64,
+    pub attributes: FileAttributes,
+}
+
+/// Part of file, that is covered by some range of torrent data.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FileSlice {
+    pub file_index: usize,
+    /// Offset inside of file.
+    pub file_offset: u64,
+    /// Offset inside of mapped range.
+    pub range_offset: usize,
+    pub length: usize,
+}
+
+/// Mapping between pieces of torrent and files on disk.
+#[derive(Debug, Clone)]
+pub struct FileLayout {
+    files: Vec<FileEntry>,
+    total_length: u64,
+    piece_length: u64,
+}
+
+impl FileLayout {
+    /// Create layout of files from torrent.
+    /// Paths are checked, so no file can be placed outside of download folder.
+    pub fn from_torrent(torrent: &Torrent) -> Result<Self> {
+        let root = PathBuf::from(&torrent.name);
+        check_relative_path(&root)?;
+
+        let mut files = Vec::new();
+        let mut offset = 0u64;
+        match &torrent.files {
+            None => files.push(FileEntry {
+                path: root,
+                offset: 0,
+                length: torrent.length as u64,
+                attributes: FileAttributes::default(),
+            }),
+            Some(torrent_files) => {
+                for file in torrent_files {
+                    check_relative_path(&file.path)?;
+                    let attributes = file_attributes(file.extra_fields.as_ref());
+                    if let Some(target) = &attributes.symlink {
+                        check_relative_path(target)?;
+                    }
+                    files.push(FileEntry {
+                        path: root.join(&file.path),
+                        offset,
+                        length: file.length as u64,
+                        attributes,
+                    });
+                    offset += file.length as u64;
+                }
+            }
+        }
+
+        Ok(FileLayout {
+            files,
+            total_length: torrent.length as u64,
+            piece_length: torrent.piece_length as u64,
+        })
+    }
+
+    /// Returns all files, including padding files.
+    pub fn files(&self) -> &[FileEntry] {
+        &self.files
+    }
+
+    /// Returns total length of data of torrent, including padding.
+    pub fn total_length(&self) -> u64 {
+        self.total_length
+    }
+
+    /// Returns length of piece, last piece can be shorter.
+    pub fn piece_length(&self) -> u64 {
+        self.piece_length
+    }
+
+    /// Returns offset of first byte of piece in data of torrent.
+    pub fn piece_offset(&self, piece_index: usize) -> u64 {
+        piece_index as u64 * self.piece_length
+    }
+
+    /// Returns length of piece with given index.
+    pub fn piece_size(&self, piece_index: usize) -> usize {
+        let offset = self.piece_offset(piece_index);
+        self.piece_length
+            .min(self.total_length.saturating_sub(offset)) as usize
+    }
+
+    /// Returns parts of files, which are covered by given range of torrent data.
+    pub fn map_range(&self, offset: u64, length: usize) -> Vec<FileSlice> {
+        let end = offset + length as u64;
+        let first = self
+            .files
+            .partition_point(|file| file.offset + file.length <= offset);
+        self.files[first..]
+            .iter()
+            .enumerate()
+            .take_while(|(_, file)| file.offset < end)
+            .filter(|(_, file)| file.length > 0)
+            .map(|(idx, file)| {
+                let start = offset.max(file.offset);
+                let stop = end.min(file.offset + file.length);
+                FileSlice {
+                    file_index: first + idx,
+                    file_offset: start - file.offset,
+                    range_offset: (start - offset) as usize,
+                    length: (stop - start) as usize,
+                }
+            })
+            .collect()
+    }
+
+    /// Returns true, if whole range of torrent data is in padding files, so it doesn't have to be downloaded.
+    pub fn is_padding(&self, offset: u64, length: usize) -> bool {
+        let slices = self.map_range(offset, length);
+        !slices.is_empty()
+            && slices
+                .iter()
+                .all(|slice| self.files[slice.file_index].attributes.padding)
+    }
+}
+
+/// Parse `attr` and `symlink path` fields of file.
+fn file_attributes(
+    extra_fields: Option<&std::collections::HashMap<String, BencodeElem>>,
+) -> FileAttributes {
+    let Some(extra_fields) = extra_fields else {
+        return FileAttributes::default();
+    };
+    let attr = match extra_fields.get("attr") {
+        Some(BencodeElem::String(attr)) => attr.as_str(),
+        _ => "",
+    };
+    let symlink = match extra_fields.get("symlink path") {
+        Some(BencodeElem::List(parts)) if attr.contains('l') => Some(
+            parts
+                .iter()
+                .filter_map(|part| match part {
+                    BencodeElem::String(part) => Some(part.as_str()),
+                    _ => None,
+                })
+                .collect(),
+        ),
+        _ => None,
+    };
+    FileAttributes {
+        padding: attr.contains('p'),
+        executable: attr.contains('x'),
+        hidden: attr.contains('h'),
+        symlink,
+    }
+}
+
+/// Returns error, if path is absolute or goes to parent directory.
+fn check_relative_path(path: &Path) -> Result<()> {
+    anyhow::ensure!(
+        path.components()
+            .all(|component| matches!(component, Component::Normal(_))),
+        "Invalid path {} in torrent",
+        path.display()
+    );
+    Ok(())
+}
+
+#[test]
+fn file_layout_padding_and_mapping() {
+    use crate::test_utils::files_torrent;
+    use std::collections::HashMap;
+
+    let attr = |attr: &str| {
+        Some(HashMap::from([(
+            "attr".to_string(),
+            BencodeElem::String(attr.to_string()),
+        )]))
+    };
+    let mut torrent = files_torrent(&[("bin/run", 100), (".pad/28", 28), ("data", 50)], 64);
+    let files = torrent.files.as_mut().unwrap();
+    files[0].extra_fields = attr("x");
+    files[1].extra_fields = attr("p");
+    let layout = FileLayout::from_torrent(&torrent).unwrap();
+    assert!(layout.files()[0].attributes.executable);
+    assert_eq!(layout.files()[2].path, PathBuf::from("bundle/data"));
+
+    // Second piece ends with padding, third piece is in the last file
+    let slices = layout.map_range(layout.piece_offset(1), 64);
+    assert_eq!(slices.len(), 2);
+    assert_eq!(slices[0].file_offset, 64);
+    assert_eq!(slices[0].length, 36);
+    assert_eq!(slices[1].range_offset, 36);
+    assert!(layout.is_padding(100, 28));
+    assert!(!layout.is_padding(96, 16));
+    assert_eq!(
+        layout.map_range(layout.piece_offset(2), 50)[0].file_index,
+        2
+    );
+
+    let mut evil = torrent.clone();
+    evil.files.as_mut().unwrap()[2].path = PathBuf::from("../escape");
+    assert!(FileLayout::from_torrent(&evil).is_err());
+}
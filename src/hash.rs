@@ -1,27 +1,140 @@
 use std::fmt::Display;
+use std::str::FromStr;
 
-/// Structure respresenting 20 bytes long hash, that is ofthen used in Bittorent protocol
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+
+/// Alphabet of base32 encoding (RFC 4648), used for info hashes in magnet links.
+const BASE32_ALPHABET: &[u8; 32] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZ234567";
+
+/// Structure respresenting 20 bytes long hash, that is ofthen used in Bittorent protocol.
+/// Displayed as lowercase hex, can be parsed from hex (40 characters) or base32 (32 characters).
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Default)]
 pub struct Hash {
-    hash: Vec<u8>,
+    hash: [u8; 20],
 }
 
+/// SHA-1 hash of info dictionary, identifying torrent.
+pub type InfoHash = Hash;
+
+/// SHA-1 hash of data of one piece.
+pub type PieceHash = Hash;
+
 impl Hash {
     /// Create Hash structure out of 20 bytes long vector
     pub fn new(hash: Vec<u8>) -> anyhow::Result<Self> {
-        if hash.len() != 20 {
-            return Err(anyhow::Error::msg(format!(
-                "Expected len of hash 20, but get {}",
-                hash.len()
-            )));
+        Hash::try_from(hash.as_slice())
+    }
+
+    /// Create Hash structure from array of 20 bytes.
+    pub const fn from_bytes(hash: [u8; 20]) -> Self {
+        Hash { hash }
+    }
+
+    /// Returns hash as lowercase hex string of 40 characters.
+    pub fn to_hex(&self) -> String {
+        hex::encode(self.hash)
+    }
+
+    /// Returns hash as base32 string of 32 characters, without padding.
+    pub fn to_base32(&self) -> String {
+        let mut encoded = String::with_capacity(32);
+        for chunk in self.hash.chunks(5) {
+            let bits = chunk
+                .iter()
+                .fold(0u64, |bits, &byte| (bits << 8) | byte as u64);
+            for i in (0..8).rev() {
+                encoded.push(BASE32_ALPHABET[((bits >> (i * 5)) & 0x1f) as usize] as char);
+            }
         }
+        encoded
+    }
+
+    /// Parse hash from hex string of 40 characters.
+    pub fn from_hex(hex: &str) -> anyhow::Result<Self> {
+        let bytes = hex::decode(hex)?;
+        Hash::try_from(bytes.as_slice())
+    }
 
+    /// Parse hash from base32 string of 32 characters, case insensitive.
+    pub fn from_base32(base32: &str) -> anyhow::Result<Self> {
+        anyhow::ensure!(
+            base32.len() == 32,
+            "Expected base32 hash of 32 characters, but get {}",
+            base32.len()
+        );
+
+        let mut hash = [0u8; 20];
+        for (chunk_idx, chunk) in base32.as_bytes().chunks(8).enumerate() {
+            let mut bits = 0u64;
+            for &char in chunk {
+                let value = BASE32_ALPHABET
+                    .iter()
+                    .position(|&known| known == char.to_ascii_uppercase())
+                    .ok_or_else(|| {
+                        anyhow::Error::msg(format!("Invalid base32 character {}", char as char))
+                    })?;
+                bits = (bits << 5) | value as u64;
+            }
+            hash[chunk_idx * 5..chunk_idx * 5 + 5].copy_from_slice(&bits.to_be_bytes()[3..]);
+        }
         Ok(Hash { hash })
     }
+
+    /// Returns reference to bytes stored inside.
+    pub fn as_bytes(&self) -> &[u8; 20] {
+        &self.hash
+    }
 }
 
 impl Display for Hash {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        write!(f, "{}", String::from_utf8(self.hash.clone()).unwrap())
+        write!(f, "{}", self.to_hex())
+    }
+}
+
+impl FromStr for Hash {
+    type Err = anyhow::Error;
+
+    /// Parse hash from hex (40 characters) or base32 (32 characters) string.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.len() {
+            40 => Hash::from_hex(s),
+            32 => Hash::from_base32(s),
+            len => {
+                anyhow::bail!("Expected hash of 40 (hex) or 32 (base32) characters, but get {len}")
+            }
+        }
+    }
+}
+
+impl From<[u8; 20]> for Hash {
+    fn from(hash: [u8; 20]) -> Self {
+        Hash { hash }
+    }
+}
+
+impl From<Hash> for [u8; 20] {
+    fn from(hash: Hash) -> Self {
+        hash.hash
+    }
+}
+
+impl TryFrom<&[u8]> for Hash {
+    type Error = anyhow::Error;
+
+    fn try_from(hash: &[u8]) -> Result<Self, Self::Error> {
+        let hash: [u8; 20] = hash.try_into().map_err(|_| {
+            anyhow::Error::msg(format!("Expected len of hash 20, but get {}", hash.len()))
+        })?;
+        Ok(Hash { hash })
+    }
+}
+
+impl TryFrom<Vec<u8>> for Hash {
+    type Error = anyhow::Error;
+
+    fn try_from(hash: Vec<u8>) -> Result<Self, Self::Error> {
+        Hash::try_from(hash.as_slice())
     }
 }
 
@@ -31,21 +144,95 @@ impl AsRef<[u8]> for Hash {
     }
 }
 
+/// Hash is serialized as hex string.
+impl Serialize for Hash {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(&self.to_hex())
+    }
+}
+
+/// Hash is deserialized from hex or base32 string.
+impl<'de> Deserialize<'de> for Hash {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let hash = String::deserialize(deserializer)?;
+        hash.parse().map_err(serde::de::Error::custom)
+    }
+}
+
 impl Hash {
     /// Get a clone of bytes stored inside, represented as vector
-    #[allow(dead_code)]
     pub fn to_vec(&self) -> Vec<u8> {
-        self.hash.clone()
+        self.hash.to_vec()
     }
 
     /// Get a bytes stored inside, as array of length 20
     pub fn to_arr(&self) -> [u8; 20] {
-        let array: [u8; 20] = match self.hash.clone().try_into() {
-            Ok(arr) => arr,
-            Err(_) => {
-                panic!("The Vec<u8> does not have exactly 20 elements");
-            }
-        };
-        array
+        self.hash
+    }
+}
+
+/// Structure representing 32 bytes long SHA-256 hash, used by BitTorrent v2 (BEP 52).
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Default)]
+pub struct Hash256 {
+    hash: [u8; 32],
+}
+
+/// SHA-256 hash of info dictionary of v2 torrent.
+pub type InfoHashV2 = Hash256;
+
+impl Hash256 {
+    /// Create Hash256 structure from array of 32 bytes.
+    pub const fn from_bytes(hash: [u8; 32]) -> Self {
+        Hash256 { hash }
     }
+
+    /// Returns hash as lowercase hex string of 64 characters.
+    pub fn to_hex(&self) -> String {
+        hex::encode(self.hash)
+    }
+
+    /// Returns first 20 bytes of hash, v2 info hash is truncated this way in handshake and tracker requests.
+    pub fn truncated(&self) -> Hash {
+        let mut hash = [0u8; 20];
+        hash.copy_from_slice(&self.hash[..20]);
+        Hash { hash }
+    }
+
+    /// Returns reference to bytes stored inside.
+    pub fn as_bytes(&self) -> &[u8; 32] {
+        &self.hash
+    }
+}
+
+impl Display for Hash256 {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.to_hex())
+    }
+}
+
+impl TryFrom<&[u8]> for Hash256 {
+    type Error = anyhow::Error;
+
+    fn try_from(hash: &[u8]) -> Result<Self, Self::Error> {
+        let hash: [u8; 32] = hash.try_into().map_err(|_| {
+            anyhow::Error::msg(format!("Expected len of hash 32, but get {}", hash.len()))
+        })?;
+        Ok(Hash256 { hash })
+    }
+}
+
+#[test]
+fn hash_hex_and_base32() {
+    let hash: Hash = "c8295ce630f2064f08440db1534e37effd7e1ee6".parse().unwrap();
+    assert_eq!(hash.to_string(), "c8295ce630f2064f08440db1534e37effd7e1ee6");
+    assert_eq!(hash.to_base32(), "ZAUVZZRQ6IDE6CCEBWYVGTRX576X4HXG");
+    assert_eq!(Hash::from_str(&hash.to_base32()).unwrap(), hash);
+    assert_eq!(
+        Hash::from_str("zauvzzrq6ide6ccebwyvgtrx576x4hxg").unwrap(),
+        hash
+    );
+
+    assert!(Hash::from_str("c8295ce630f2064f").is_err());
+    assert!(Hash::try_from(vec![0u8; 19]).is_err());
+    assert!(Hash::from_base32("1AUVZZRQ6IDE6CCEBWYVGTRX575X4HXG").is_err());
 }
@@ -0,0 +1,254 @@
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+use std::path::Path;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use anyhow::{Context, Result};
+
+/// Highest access level in DAT blocklist, that still means the range is blocked.
+const DAT_MAX_BLOCKED_LEVEL: u32 = 127;
+
+/// Structure representing IP filter (blocklist).
+/// All addresses are stored as IPv6, IPv4 addresses are mapped into `::ffff:0:0/96`.
+/// Ranges are kept sorted and merged, so lookup is binary search.
+#[derive(Debug, Default)]
+pub struct IpFilter {
+    ranges: Vec<(u128, u128)>,
+    blocked: AtomicUsize,
+    /// Number of malformed lines of blocklist, that were skipped.
+    invalid_lines: usize,
+}
+
+impl IpFilter {
+    /// Creates empty filter, that allows all addresses.
+    pub fn new() -> Self {
+        IpFilter::default()
+    }
+
+    /// Load filter from blocklist file.
+    /// Supported formats are P2P plaintext (`name:start-end`), DAT (`start - end , level , name`)
+    /// and CIDR list (`address/prefix`), format is detected for each line separately.
+    pub fn from_file(path: impl AsRef<Path>) -> Result<Self> {
+        let path = path.as_ref();
+        let content = std::fs::read_to_string(path)
+            .with_context(|| format!("Failed to read blocklist {}", path.display()))?;
+        let mut filter = IpFilter::new();
+        filter.add_rules(&content);
+        Ok(filter)
+    }
+
+    /// Add rules from blocklist in text form. Empty lines and lines starting with `#` are skipped.
+    /// Malformed lines are skipped too, so one bad entry doesn't reject the whole list, see `invalid_lines`.
+    /// Returns number of added blocked ranges.
+    pub fn add_rules(&mut self, rules: &str) -> usize {
+        let mut added = 0;
+        for line in rules.lines() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') || line.starts_with("//") {
+                continue;
+            }
+            match parse_rule(line) {
+                Ok(Some((start, end))) => {
+                    self.push_range(start, end);
+                    added += 1;
+                }
+                Ok(None) => {}
+                Err(_) => self.invalid_lines += 1,
+            }
+        }
+        self.merge_ranges();
+        added
+    }
+
+    /// Block all addresses between `start` and `end` (both inclusive).
+    pub fn add_range(&mut self, start: IpAddr, end: IpAddr) {
+        self.push_range(start, end);
+        self.merge_ranges();
+    }
+
+    /// Returns number of disjunct blocked ranges.
+    pub fn len(&self) -> usize {
+        self.ranges.len()
+    }
+
+    /// Returns if the filter doesn't block anything.
+    pub fn is_empty(&self) -> bool {
+        self.ranges.is_empty()
+    }
+
+    /// Returns if given address is blocked by filter.
+    pub fn is_blocked(&self, ip: IpAddr) -> bool {
+        let ip = ip_to_u128(ip);
+        let position = self.ranges.partition_point(|&(start, _)| start <= ip);
+        position > 0 && self.ranges[position - 1].1 >= ip
+    }
+
+    /// Check if connection with given address is allowed.
+    /// Every refused connection is counted, see `blocked_count`.
+    pub fn allows(&self, ip: IpAddr) -> bool {
+        if self.is_blocked(ip) {
+            self.blocked.fetch_add(1, Ordering::Relaxed);
+            return false;
+        }
+        true
+    }
+
+    /// Returns number of malformed blocklist lines, that were skipped.
+    pub fn invalid_lines(&self) -> usize {
+        self.invalid_lines
+    }
+
+    /// Returns number of connections, that were refused by this filter.
+    pub fn blocked_count(&self) -> usize {
+        self.blocked.load(Ordering::Relaxed)
+    }
+
+    /// Add range without merging, `merge_ranges` has to be called afterwards.
+    fn push_range(&mut self, start: IpAddr, end: IpAddr) {
+        let (start, end) = (ip_to_u128(start), ip_to_u128(end));
+        self.ranges.push((start.min(end), start.max(end)));
+    }
+
+    /// Sort ranges and merge overlapping and adjacent ones.
+    fn merge_ranges(&mut self) {
+        self.ranges.sort_unstable();
+        let mut merged: Vec<(u128, u128)> = Vec::with_capacity(self.ranges.len());
+        for &(start, end) in &self.ranges {
+            match merged.last_mut() {
+                Some(last) if start <= last.1.saturating_add(1) => last.1 = last.1.max(end),
+                _ => merged.push((start, end)),
+            }
+        }
+        self.ranges = merged;
+    }
+}
+
+/// Parse one line of blocklist into blocked range.
+/// Returns `None` for DAT ranges, whose access level means that range is allowed.
+fn parse_rule(line: &str) -> Result<Option<(IpAddr, IpAddr)>> {
+    // DAT: "000.000.000.000 - 000.255.255.255 , 000 , description"
+    // Line is DAT only if it starts with range and level, so names of P2P lines can contain ','
+    let mut parts = line.splitn(3, ',');
+    if let (Some(range), Some(level)) = (parts.next(), parts.next()) {
+        if let (Ok(range), Ok(level)) = (parse_range(range), level.trim().parse::<u32>()) {
+            return Ok((level <= DAT_MAX_BLOCKED_LEVEL).then_some(range));
+        }
+    }
+
+    // P2P: "Some organization:1.2.3.0-1.2.3.255", name can contain ':'
+    if let Some((_, range)) = line.rsplit_once(':') {
+        if range.contains('-') && range.contains('.') {
+            return Ok(Some(parse_range(range)?));
+        }
+    }
+
+    // CIDR: "10.0.0.0/8" or "2001:db8::/32"
+    if let Some((address, prefix)) = line.split_once('/') {
+        let address = parse_ip(address)?;
+        let prefix: u32 = prefix.trim().parse().context("Invalid prefix length")?;
+        return Ok(Some(cidr_range(address, prefix)?));
+    }
+
+    // Plain range or single address
+    if line.contains('-') {
+        return Ok(Some(parse_range(line)?));
+    }
+    let address = parse_ip(line)?;
+    Ok(Some((address, address)))
+}
+
+/// Parse range in format `start-end`.
+fn parse_range(range: &str) -> Result<(IpAddr, IpAddr)> {
+    let (start, end) = range.split_once('-').context("Missing '-' in range")?;
+    Ok((parse_ip(start)?, parse_ip(end)?))
+}
+
+/// Parse IP address, IPv4 octets can contain leading zeros, as it is common in DAT files.
+fn parse_ip(address: &str) -> Result<IpAddr> {
+    let address = address.trim();
+    if address.contains(':') {
+        return Ok(IpAddr::V6(address.parse::<Ipv6Addr>()?));
+    }
+    let octets: Vec<u8> = address
+        .split('.')
+        .map(|octet| octet.parse::<u8>())
+        .collect::<std::result::Result<_, _>>()
+        .with_context(|| format!("Invalid IPv4 address {address}"))?;
+    let octets: [u8; 4] = octets
+        .try_into()
+        .map_err(|_| anyhow::Error::msg(format!("Invalid IPv4 address {address}")))?;
+    Ok(IpAddr::V4(Ipv4Addr::from(octets)))
+}
+
+/// Returns first and last address of CIDR block.
+fn cidr_range(address: IpAddr, prefix: u32) -> Result<(IpAddr, IpAddr)> {
+    let (bits, max_prefix) = match address {
+        IpAddr::V4(ip) => (u128::from(u32::from(ip)), 32),
+        IpAddr::V6(ip) => (u128::from(ip), 128),
+    };
+    anyhow::ensure!(prefix <= max_prefix, "Prefix length {prefix} is too long");
+
+    let host_bits = max_prefix - prefix;
+    let host_mask = if host_bits == 128 {
+        u128::MAX
+    } else {
+        (1u128 << host_bits) - 1
+    };
+    let (start, end) = (bits & !host_mask, bits | host_mask);
+
+    Ok(match address {
+        IpAddr::V4(_) => (
+            IpAddr::V4(Ipv4Addr::from(start as u32)),
+            IpAddr::V4(Ipv4Addr::from(end as u32)),
+        ),
+        IpAddr::V6(_) => (
+            IpAddr::V6(Ipv6Addr::from(start)),
+            IpAddr::V6(Ipv6Addr::from(end)),
+        ),
+    })
+}
+
+/// Convert address to number, IPv4 addresses are mapped to IPv6.
+fn ip_to_u128(ip: IpAddr) -> u128 {
+    match ip {
+        IpAddr::V4(ip) => u128::from(ip.to_ipv6_mapped()),
+        IpAddr::V6(ip) => u128::from(ip),
+    }
+}
+
+#[test]
+fn ip_filter_formats() {
+    let mut filter = IpFilter::new();
+    let added = filter.add_rules(
+        "# comment\n\
+             Some org/with: colon:1.2.3.0-1.2.3.255\n\
+             010.000.000.000 - 010.255.255.255 , 000 , Private\n\
+             011.000.000.000 - 011.255.255.255 , 200 , Allowed\n\
+             Foo, Inc:5.6.7.0-5.6.7.255\n\
+             192.168.0.0/16\n\
+             not an address\n\
+             2001:db8::/32\n",
+    );
+    assert_eq!(added, 5);
+    assert_eq!(filter.invalid_lines(), 1);
+
+    assert!(filter.is_blocked("1.2.3.4".parse().unwrap()));
+    assert!(!filter.is_blocked("1.2.4.0".parse().unwrap()));
+    assert!(filter.is_blocked("5.6.7.8".parse().unwrap()));
+    assert!(filter.is_blocked("10.20.30.40".parse().unwrap()));
+    assert!(!filter.is_blocked("11.0.0.1".parse().unwrap()));
+    assert!(filter.is_blocked("192.168.255.255".parse().unwrap()));
+    assert!(!filter.is_blocked("192.169.0.0".parse().unwrap()));
+    assert!(filter.is_blocked("2001:db8::1".parse().unwrap()));
+    assert!(!filter.is_blocked("2001:db9::1".parse().unwrap()));
+}
+
+#[test]
+fn ip_filter_merge_and_count() {
+    let mut filter = IpFilter::new();
+    filter.add_rules("1.0.0.0-1.0.0.10\n1.0.0.11-1.0.0.20\n1.0.0.5-1.0.0.6\n");
+    assert_eq!(filter.len(), 1);
+
+    assert!(!filter.allows("1.0.0.15".parse().unwrap()));
+    assert!(filter.allows("1.0.0.21".parse().unwrap()));
+    assert_eq!(filter.blocked_count(), 1);
+}
@@ -1,8 +1,24 @@
 pub mod peer_id;
 
+pub mod create;
+pub mod daemon;
 pub mod download;
-mod hash;
+pub mod file_layout;
+pub mod hash;
+pub mod ip_filter;
+pub mod magnet;
+mod merkle;
+pub mod metainfo;
 mod piece;
+pub mod piece_pool;
+pub mod rate_limit;
+pub mod resume;
+pub mod session;
+pub mod storage;
+pub mod stream_server;
+#[cfg(any(test, feature = "test-utils"))]
+#[doc(hidden)]
+pub mod test_utils;
 
 pub mod peer_comunication;
 pub mod tracker_connection;
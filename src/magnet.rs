@@ -0,0 +1,369 @@
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::str::FromStr;
+use std::time::Duration;
+
+use anyhow::{Context, Result};
+use lava_torrent::bencode::BencodeElem;
+use lava_torrent::torrent::v1::Torrent;
+use sha1::{Digest, Sha1};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+use tokio::time::timeout;
+
+use crate::hash::InfoHash;
+use crate::metainfo::Metainfo;
+use crate::peer_comunication::handshake::Handshake;
+use crate::peer_comunication::peer_connection::{read_handshake, TIMEOUT};
+use crate::peer_id::PeerId;
+use crate::tracker_connection::get_peers::{announce, AnnounceStats};
+
+/// Id of extension protocol messages (BEP 10).
+const EXTENDED_MESSAGE_ID: u8 = 20;
+/// Extended message id of extended handshake.
+const EXTENDED_HANDSHAKE_ID: u8 = 0;
+/// Extended message id, with which peers send `ut_metadata` messages to this client.
+const LOCAL_METADATA_ID: u8 = 1;
+/// Size of metadata pieces (BEP 9), only the last piece can be smaller.
+const METADATA_PIECE_SIZE: usize = 16 * 1024;
+/// Maximal accepted size of metadata, so peer can't exhaust memory.
+const MAX_METADATA_SIZE: usize = 16 * 1024 * 1024;
+/// Maximal size of message received during metadata exchange, bitfield of large torrent can be long.
+const MAX_MESSAGE_SIZE: usize = 1024 * 1024;
+/// Maximal time of fetching metadata from one peer.
+const PEER_METADATA_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Parsed magnet link, torrent is identified only by info hash and its metadata are fetched from peers.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MagnetLink {
+    pub info_hash: InfoHash,
+    /// Display name of torrent, it's only informative.
+    pub name: Option<String>,
+    /// Urls of trackers of torrent.
+    pub trackers: Vec<String>,
+    /// Addresses of peers given directly in the link.
+    pub peers: Vec<SocketAddr>,
+}
+
+impl FromStr for MagnetLink {
+    type Err = anyhow::Error;
+
+    /// Parse `magnet:?xt=urn:btih:<info hash>&dn=<name>&tr=<tracker>&x.pe=<peer>`,
+    /// info hash can be hex or base32.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let Some(query) = s.strip_prefix("magnet:?") else {
+            anyhow::bail!("Magnet link should start with magnet:?");
+        };
+        let mut info_hash = None;
+        let mut name = None;
+        let mut trackers = Vec::new();
+        let mut peers = Vec::new();
+        for (key, value) in url::form_urlencoded::parse(query.as_bytes()) {
+            match key.as_ref() {
+                "xt" => {
+                    if let Some(hash) = value.strip_prefix("urn:btih:") {
+                        info_hash = Some(hash.parse()?);
+                    }
+                }
+                "dn" => name = Some(value.into_owned()),
+                "tr" => trackers.push(value.into_owned()),
+                "x.pe" => peers.push(
+                    value
+                        .parse()
+                        .with_context(|| format!("Invalid peer address {value}"))?,
+                ),
+                _ => {}
+            }
+        }
+        Ok(MagnetLink {
+            info_hash: info_hash.context("Magnet link has no btih info hash")?,
+            name,
+            trackers,
+            peers,
+        })
+    }
+}
+
+impl MagnetLink {
+    /// Fetch metadata of torrent from peers of the link and peers found by its trackers (BEP 9).
+    /// Returned torrent announces to the first tracker of the link.
+    pub async fn fetch_metadata(&self, peer_id: &PeerId, port: u16) -> Result<Metainfo> {
+        let mut peers = self.peers.clone();
+        // UDP trackers are not supported
+        for tracker in self.trackers.iter().filter(|url| url.starts_with("http")) {
+            // Size of torrent is not known yet, peer announces that it's missing something
+            let stats = AnnounceStats {
+                left: 1,
+                ..AnnounceStats::default()
+            };
+            let stub = self.tracker_stub(tracker);
+            if let Ok(response) = announce(&stub, &self.info_hash, peer_id, port, stats, None).await
+            {
+                peers.extend(response.peers.iter().map(|peer| peer.addr));
+            }
+        }
+        peers.sort();
+        peers.dedup();
+
+        let mut last_error = anyhow::anyhow!("No peers found for magnet link");
+        for addr in peers {
+            let fetch = fetch_from_peer(addr, &self.info_hash, peer_id);
+            match timeout(PEER_METADATA_TIMEOUT, fetch).await {
+                Ok(Ok(info)) => return self.metainfo(&info),
+                Ok(Err(err)) => last_error = err.context(format!("Peer {addr}")),
+                Err(_) => last_error = anyhow::anyhow!("Peer {addr} didn't send metadata in time"),
+            }
+        }
+        Err(last_error)
+    }
+
+    /// Build torrent from fetched info dictionary, with the first tracker of the link.
+    fn metainfo(&self, info: &[u8]) -> Result<Metainfo> {
+        let mut bytes = b"d".to_vec();
+        if let Some(tracker) = self.trackers.first() {
+            bytes.extend_from_slice(b"8:announce");
+            bytes.extend(BencodeElem::String(tracker.clone()).encode());
+        }
+        bytes.extend_from_slice(b"4:info");
+        bytes.extend_from_slice(info);
+        bytes.push(b'e');
+        Metainfo::read_from_bytes(&bytes)
+    }
+
+    /// Returns torrent, which is used only for announce to given tracker.
+    fn tracker_stub(&self, tracker: &str) -> Torrent {
+        Torrent {
+            announce: Some(tracker.to_string()),
+            announce_list: None,
+            length: 0,
+            files: None,
+            name: self.name.clone().unwrap_or_default(),
+            piece_length: 0,
+            pieces: Vec::new(),
+            extra_fields: None,
+            extra_info_fields: None,
+        }
+    }
+}
+
+/// Download info dictionary of torrent from one peer with `ut_metadata` extension,
+/// returned info dictionary is verified against info hash.
+async fn fetch_from_peer(
+    addr: SocketAddr,
+    info_hash: &InfoHash,
+    peer_id: &PeerId,
+) -> Result<Vec<u8>> {
+    let mut stream = match timeout(TIMEOUT, TcpStream::connect(addr)).await {
+        Ok(Ok(stream)) => stream,
+        _ => anyhow::bail!("Unable to open tcp connection"),
+    };
+    let mut handshake = Handshake::new(&info_hash.to_arr(), &peer_id.to_arr());
+    handshake.set_extension_support();
+    stream.write_all(&handshake.get_bytes()).await?;
+    let remote = read_handshake(&mut stream).await?;
+    anyhow::ensure!(
+        remote.info_hash == info_hash.to_arr(),
+        "Peer is not in swarm of torrent"
+    );
+    anyhow::ensure!(
+        remote.supports_extensions(),
+        "Peer doesn't support extension protocol"
+    );
+
+    let extensions = dictionary(vec![(
+        "m",
+        dictionary(vec![(
+            "ut_metadata",
+            BencodeElem::Integer(LOCAL_METADATA_ID.into()),
+        )]),
+    )]);
+    send_extended(&mut stream, EXTENDED_HANDSHAKE_ID, &extensions.encode()).await?;
+
+    // Peer sends id of its `ut_metadata` messages and size of metadata in its extended handshake
+    let (remote_id, size) = loop {
+        let (id, payload) = read_extended(&mut stream).await?;
+        if id != EXTENDED_HANDSHAKE_ID {
+            continue;
+        }
+        let (header, _) = split_header(&payload)?;
+        let remote_id = match header.get("m") {
+            Some(BencodeElem::Dictionary(extensions)) => integer(extensions, "ut_metadata"),
+            _ => None,
+        };
+        match (remote_id, integer(&header, "metadata_size")) {
+            (Some(remote_id @ 1..=255), Some(size)) => break (remote_id as u8, size as usize),
+            _ => anyhow::bail!("Peer doesn't share metadata"),
+        }
+    };
+    anyhow::ensure!(
+        size > 0 && size <= MAX_METADATA_SIZE,
+        "Invalid metadata size {size}"
+    );
+
+    let mut metadata = Vec::with_capacity(size);
+    for piece in 0..size.div_ceil(METADATA_PIECE_SIZE) {
+        let request = dictionary(vec![
+            ("msg_type", BencodeElem::Integer(0)),
+            ("piece", BencodeElem::Integer(piece as i64)),
+        ]);
+        send_extended(&mut stream, remote_id, &request.encode()).await?;
+        loop {
+            let (id, payload) = read_extended(&mut stream).await?;
+            if id != LOCAL_METADATA_ID {
+                continue;
+            }
+            let (header, data) = split_header(&payload)?;
+            match integer(&header, "msg_type") {
+                Some(1) if integer(&header, "piece") == Some(piece as i64) => {
+                    metadata.extend_from_slice(data);
+                    break;
+                }
+                Some(2) => anyhow::bail!("Peer rejected metadata request"),
+                _ => continue,
+            }
+        }
+        anyhow::ensure!(metadata.len() <= size, "Peer sent too much metadata");
+    }
+    anyhow::ensure!(metadata.len() == size, "Metadata are not complete");
+    anyhow::ensure!(
+        Sha1::digest(&metadata).as_slice() == info_hash.as_bytes(),
+        "Metadata don't match info hash"
+    );
+    Ok(metadata)
+}
+
+/// Send extension protocol message with given extended id.
+async fn send_extended(stream: &mut TcpStream, extended_id: u8, payload: &[u8]) -> Result<()> {
+    stream.write_u32(payload.len() as u32 + 2).await?;
+    stream
+        .write_all(&[EXTENDED_MESSAGE_ID, extended_id])
+        .await?;
+    stream.write_all(payload).await?;
+    stream.flush().await?;
+    Ok(())
+}
+
+/// Read messages until extension protocol message comes, returns its extended id and payload.
+/// Other messages are ignored, this client doesn't download pieces during metadata exchange.
+async fn read_extended(stream: &mut TcpStream) -> Result<(u8, Vec<u8>)> {
+    loop {
+        let length = timeout(TIMEOUT, stream.read_u32()).await?? as usize;
+        anyhow::ensure!(length <= MAX_MESSAGE_SIZE, "Message is too long");
+        let mut message = vec![0u8; length];
+        timeout(TIMEOUT, stream.read_exact(&mut message)).await??;
+        if message.len() >= 2 && message[0] == EXTENDED_MESSAGE_ID {
+            let payload = message.split_off(2);
+            return Ok((message[1], payload));
+        }
+    }
+}
+
+/// Split payload of extended message to bencoded dictionary and data following it.
+fn split_header(payload: &[u8]) -> Result<(HashMap<String, BencodeElem>, &[u8])> {
+    // Dictionary ends with `e`, the shortest valid prefix is the dictionary
+    for end in (0..payload.len()).filter(|&idx| payload[idx] == b'e') {
+        if let Ok(mut parsed) = BencodeElem::from_bytes(&payload[..=end]) {
+            if let (1, Some(BencodeElem::Dictionary(header))) = (parsed.len(), parsed.pop()) {
+                return Ok((header, &payload[end + 1..]));
+            }
+        }
+    }
+    anyhow::bail!("Extended message doesn't start with dictionary")
+}
+
+fn dictionary(entries: Vec<(&str, BencodeElem)>) -> BencodeElem {
+    BencodeElem::Dictionary(
+        entries
+            .into_iter()
+            .map(|(key, value)| (key.to_string(), value))
+            .collect(),
+    )
+}
+
+fn integer(dictionary: &HashMap<String, BencodeElem>, key: &str) -> Option<i64> {
+    match dictionary.get(key) {
+        Some(BencodeElem::Integer(value)) => Some(*value),
+        _ => None,
+    }
+}
+
+#[tokio::test]
+async fn magnet_metadata_from_peer() {
+    use tokio::net::TcpListener;
+
+    let mut torrent = crate::test_utils::test_torrent(32, 16);
+    // Hashes are not valid UTF-8, as in real torrents
+    torrent.pieces = vec![vec![0xffu8; 20], vec![0xfeu8; 20]];
+    let info = dictionary(vec![
+        ("length", BencodeElem::Integer(32)),
+        ("name", BencodeElem::String("data".to_string())),
+        ("piece length", BencodeElem::Integer(16)),
+        ("pieces", BencodeElem::Bytes(torrent.pieces.concat())),
+    ])
+    .encode();
+    let info_hash = InfoHash::new(Sha1::digest(&info).to_vec()).unwrap();
+
+    // Other peer sends metadata in two messages, second is the data
+    let peer = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let peer_addr = peer.local_addr().unwrap();
+    let peer_info = info.clone();
+    tokio::spawn(async move {
+        let (mut stream, _) = peer.accept().await.unwrap();
+        let remote = read_handshake(&mut stream).await.unwrap();
+        let mut answer = Handshake::new(&remote.info_hash, &[1; 20]);
+        answer.set_extension_support();
+        stream.write_all(&answer.get_bytes()).await.unwrap();
+        let extensions = dictionary(vec![
+            (
+                "m",
+                dictionary(vec![("ut_metadata", BencodeElem::Integer(3))]),
+            ),
+            (
+                "metadata_size",
+                BencodeElem::Integer(peer_info.len() as i64),
+            ),
+        ]);
+        send_extended(&mut stream, EXTENDED_HANDSHAKE_ID, &extensions.encode())
+            .await
+            .unwrap();
+        loop {
+            let (id, payload) = read_extended(&mut stream).await.unwrap();
+            if id == 3 {
+                let (request, _) = split_header(&payload).unwrap();
+                assert_eq!(integer(&request, "piece"), Some(0));
+                let mut answer = dictionary(vec![
+                    ("msg_type", BencodeElem::Integer(1)),
+                    ("piece", BencodeElem::Integer(0)),
+                    ("total_size", BencodeElem::Integer(peer_info.len() as i64)),
+                ])
+                .encode();
+                answer.extend_from_slice(&peer_info);
+                send_extended(&mut stream, LOCAL_METADATA_ID, &answer)
+                    .await
+                    .unwrap();
+            }
+        }
+    });
+
+    let link: MagnetLink = format!(
+        "magnet:?xt=urn:btih:{}&dn=data&tr=http%3A%2F%2F127.0.0.1%3A1%2Fannounce&x.pe={peer_addr}",
+        info_hash.to_base32()
+    )
+    .parse()
+    .unwrap();
+    assert_eq!(link.info_hash, info_hash);
+    assert_eq!(link.name.as_deref(), Some("data"));
+    assert_eq!(link.trackers, vec!["http://127.0.0.1:1/announce"]);
+    assert_eq!(link.peers, vec![peer_addr]);
+
+    let metainfo = link
+        .fetch_metadata(&PeerId::generate(), 6881)
+        .await
+        .unwrap();
+    assert_eq!(metainfo.info_hash(), info_hash);
+    assert_eq!(metainfo.torrent.pieces, torrent.pieces);
+    assert_eq!(
+        metainfo.torrent.announce.as_deref(),
+        Some("http://127.0.0.1:1/announce")
+    );
+}
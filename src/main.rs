@@ -1,14 +1,85 @@
 use std::{
     env::{self},
-    path::Path,
+    path::{Path, PathBuf},
+    time::Duration,
 };
 
-use anyhow::Ok;
-use torrent_client::tui::run_tui;
+use anyhow::{Context, Ok};
+use torrent_client::create::{create_torrent, CreateOptions};
+use torrent_client::daemon::{Daemon, DaemonOptions, RPC_PATH, TRANSMISSION_RPC_PATH};
+use torrent_client::file_layout::FilePriority;
+use torrent_client::piece_pool::PickMode;
+use torrent_client::rate_limit::{parse_kib_rate, RateSchedule};
+use torrent_client::session::SessionOptions;
+use torrent_client::storage::{AllocationMode, StorageMode, StorageOptions, DEFAULT_CACHE_SIZE};
+use torrent_client::tui::{run_tui, TuiOptions};
 
 #[tokio::main]
 async fn main() -> anyhow::Result<()> {
-    let args: Vec<String> = env::args().collect();
+    let mut args: Vec<String> = env::args().collect();
+
+    match args.get(1).map(String::as_str) {
+        Some("create") => return run_create(args),
+        Some("daemon") => return run_daemon(args).await,
+        _ => {}
+    }
+
+    // Optional arguments
+    let mut file_priorities = Vec::new();
+    while let Some(priority) = take_flag(&mut args, "--priority")? {
+        file_priorities.push(parse_file_priority(&priority)?);
+    }
+    let pick_mode = match take_flag(&mut args, "--streaming")? {
+        Some(readahead) => PickMode::Streaming {
+            readahead: readahead.parse()?,
+            piece_deadline: Duration::from_secs(2),
+        },
+        None if take_switch(&mut args, "--sequential") => PickMode::Sequential,
+        None => PickMode::RarestFirst,
+    };
+    let storage = StorageOptions {
+        mode: take_flag(&mut args, "--storage")?
+            .map(|mode| mode.parse::<StorageMode>())
+            .transpose()?
+            .unwrap_or_default(),
+        cache_size: take_flag(&mut args, "--cache-size")?
+            .map(|size| -> anyhow::Result<usize> {
+                size.parse::<usize>()?
+                    .checked_mul(1024 * 1024)
+                    .context("Cache size is too large")
+            })
+            .transpose()?
+            .unwrap_or(DEFAULT_CACHE_SIZE),
+        allocation: take_flag(&mut args, "--allocation")?
+            .map(|mode| mode.parse::<AllocationMode>())
+            .transpose()?
+            .unwrap_or_default(),
+        incomplete_folder: take_flag(&mut args, "--incomplete-dir")?.map(PathBuf::from),
+        part_suffix: take_switch(&mut args, "--part-suffix"),
+    };
+    let mut options = TuiOptions {
+        ban_file: take_flag(&mut args, "--ban-file")?.map(PathBuf::from),
+        ip_filter_file: take_flag(&mut args, "--ip-filter")?.map(PathBuf::from),
+        download_limit: take_flag(&mut args, "--download-limit")?
+            .map(|limit| parse_kib_rate(&limit).context("Invalid --download-limit"))
+            .transpose()?
+            .unwrap_or(0),
+        upload_limit: take_flag(&mut args, "--upload-limit")?
+            .map(|limit| parse_kib_rate(&limit).context("Invalid --upload-limit"))
+            .transpose()?
+            .unwrap_or(0),
+        schedule: take_flag(&mut args, "--schedule")?
+            .map(|schedule| RateSchedule::parse(&schedule))
+            .transpose()?,
+        file_priorities,
+        pick_mode,
+        stream_port: take_flag(&mut args, "--stream-port")?
+            .map(|port| port.parse::<u16>())
+            .transpose()?,
+        storage,
+        resume_folder: take_flag(&mut args, "--resume-dir")?.map(PathBuf::from),
+    };
+
     if args.len() < 2 {
         eprintln!("Usage: {} <torrent_file_path>", args[0]);
         anyhow::bail!("Invalid params");
@@ -31,7 +102,11 @@ async fn main() -> anyhow::Result<()> {
         }
     }
 
-    run_tui(torrent_file_path, download_folder_path).await?;
+    // Resume data are stored in download folder by default
+    options
+        .resume_folder
+        .get_or_insert_with(|| PathBuf::from(&download_folder_path));
+    run_tui(torrent_file_path, download_folder_path, options).await?;
     Ok(())
 
     // let torrent_path="/home/tom/VSB/ing/3-semestr/pvr/torrent_client/data/linuxmint-22-cinnamon-64bit.iso.torrent";
@@ -39,3 +114,139 @@ async fn main() -> anyhow::Result<()> {
     // let torrent_path ="/home/tom/VSB/ing/3-semestr/pvr/torrent_client/data/ubuntu-24.04.1-desktop-amd64.iso.torrent";
     // let torrent_path = "/home/tom/VSB/ing/3-semestr/pvr/torrent_client/data/music.torrent";
 }
+
+/// Parse priority of one file in format `<file index>=<skip|low|normal|high>`.
+fn parse_file_priority(value: &str) -> anyhow::Result<(usize, FilePriority)> {
+    let Some((file_index, priority)) = value.split_once('=') else {
+        anyhow::bail!("Invalid file priority {value}, expected <file index>=<priority>");
+    };
+    Ok((file_index.parse()?, priority.parse()?))
+}
+
+/// Create .torrent file from file or directory, `torrent_client create <path> [options]`.
+fn run_create(mut args: Vec<String>) -> anyhow::Result<()> {
+    let mut trackers = Vec::new();
+    while let Some(tracker) = take_flag(&mut args, "--announce")? {
+        trackers.push(tracker);
+    }
+    let mut web_seeds = Vec::new();
+    while let Some(web_seed) = take_flag(&mut args, "--web-seed")? {
+        web_seeds.push(web_seed);
+    }
+    let options = CreateOptions {
+        trackers,
+        piece_length: take_flag(&mut args, "--piece-length")?
+            .map(|length| -> anyhow::Result<u64> {
+                length
+                    .parse::<u64>()?
+                    .checked_mul(1024)
+                    .context("Piece length is too large")
+            })
+            .transpose()?,
+        comment: take_flag(&mut args, "--comment")?,
+        created_by: None,
+        private: take_switch(&mut args, "--private"),
+        web_seeds,
+        pad_files: take_switch(&mut args, "--pad-files"),
+        threads: 0,
+    };
+    let output = take_flag(&mut args, "--output")?.map(PathBuf::from);
+
+    if args.len() != 3 {
+        eprintln!("Usage: {} create <path> [--announce <url>]... [--piece-length <KiB>] [--comment <text>] [--private] [--web-seed <url>]... [--pad-files] [--output <file>]", args[0]);
+        anyhow::bail!("Invalid params");
+    }
+
+    let torrent = create_torrent(&args[2], &options)?;
+    let output = output.unwrap_or_else(|| PathBuf::from(format!("{}.torrent", torrent.name)));
+    // Torrent is consumed by writing, so summary is prepared before and printed only after success
+    let summary = format!(
+        "Created {} ({} pieces of {} KiB, info hash {})",
+        output.display(),
+        torrent.pieces.len(),
+        torrent.piece_length / 1024,
+        torrent.info_hash()
+    );
+    torrent.write_into_file(&output)?;
+    println!("{summary}");
+    Ok(())
+}
+
+/// Environment variable with token of daemon API, used when `--token` is not set.
+const TOKEN_VARIABLE: &str = "TORRENT_CLIENT_TOKEN";
+
+/// Run session without terminal UI controlled by JSON-RPC API, `torrent_client daemon <download folder> [options]`.
+/// Random token is generated, if it's not set by `--token` or by environment variable.
+async fn run_daemon(mut args: Vec<String>) -> anyhow::Result<()> {
+    let token = match take_flag(&mut args, "--token")? {
+        Some(token) => token,
+        None => {
+            env::var(TOKEN_VARIABLE).unwrap_or_else(|_| hex::encode(rand::random::<[u8; 16]>()))
+        }
+    };
+    let rpc_addr = take_flag(&mut args, "--rpc-addr")?
+        .map(|addr| addr.parse())
+        .transpose()?;
+    let rpc_socket = take_flag(&mut args, "--rpc-socket")?.map(PathBuf::from);
+    let resume_folder = take_flag(&mut args, "--resume-dir")?.map(PathBuf::from);
+    let listen_port = take_flag(&mut args, "--listen-port")?
+        .map(|port| port.parse::<u16>())
+        .transpose()?;
+
+    if args.len() != 3 {
+        eprintln!("Usage: {} daemon <download folder> [--rpc-addr <ip:port>] [--rpc-socket <path>] [--token <token>] [--resume-dir <folder>] [--listen-port <port>]", args[0]);
+        anyhow::bail!("Invalid params");
+    }
+    let download_folder = PathBuf::from(&args[2]);
+
+    let mut options = DaemonOptions::new(token, download_folder.clone());
+    if let Some(rpc_addr) = rpc_addr {
+        options.rpc_addr = rpc_addr;
+    }
+    options.rpc_socket = rpc_socket;
+    let mut session = SessionOptions {
+        // Resume data are stored in download folder by default
+        resume_folder: Some(resume_folder.unwrap_or(download_folder)),
+        ..SessionOptions::default()
+    };
+    if let Some(port) = listen_port {
+        session.listen_addr.set_port(port);
+    }
+    options.session = session;
+
+    let token = options.token.clone();
+    let daemon = Daemon::start(options).await?;
+    println!("JSON-RPC API on http://{}{RPC_PATH}", daemon.rpc_addr());
+    println!(
+        "Transmission RPC on http://{}{TRANSMISSION_RPC_PATH}",
+        daemon.rpc_addr()
+    );
+    println!("Token: {token}");
+    println!("Web UI on http://{}/#token={token}", daemon.rpc_addr());
+    tokio::signal::ctrl_c().await?;
+    daemon.shutdown().await;
+    Ok(())
+}
+
+/// Remove optional argument `flag` without value from arguments, and return if it was present.
+fn take_switch(args: &mut Vec<String>, flag: &str) -> bool {
+    let Some(position) = args.iter().position(|arg| arg == flag) else {
+        return false;
+    };
+    args.remove(position);
+    true
+}
+
+/// Remove optional argument `flag` together with its value from arguments, and return the value.
+fn take_flag(args: &mut Vec<String>, flag: &str) -> anyhow::Result<Option<String>> {
+    let Some(position) = args.iter().position(|arg| arg == flag) else {
+        return Ok(None);
+    };
+    if position + 1 >= args.len() {
+        eprintln!("Missing value after {flag}");
+        anyhow::bail!("Invalid params");
+    }
+    let value = args.remove(position + 1);
+    args.remove(position);
+    Ok(Some(value))
+}
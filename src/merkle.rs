@@ -0,0 +1,152 @@
+use sha2::{Digest, Sha256};
+
+use crate::hash::Hash256;
+
+/// Size of leaf block of merkle tree in BitTorrent v2.
+pub(crate) const BLOCK_SIZE: usize = 16 * 1024;
+
+/// Hash of two child nodes.
+fn hash_pair(left: &Hash256, right: &Hash256) -> Hash256 {
+    let mut hasher = Sha256::new();
+    hasher.update(left.as_bytes());
+    hasher.update(right.as_bytes());
+    Hash256::from_bytes(hasher.finalize().into())
+}
+
+/// Hash of subtree with given height, whose leaves are all zero hashes.
+pub(crate) fn pad_hash(height: u32) -> Hash256 {
+    let mut hash = Hash256::default();
+    for _ in 0..height {
+        hash = hash_pair(&hash, &hash);
+    }
+    hash
+}
+
+/// Returns SHA-256 hashes of 16 KiB blocks of data, last block can be shorter.
+pub(crate) fn block_hashes(data: &[u8]) -> Vec<Hash256> {
+    data.chunks(BLOCK_SIZE)
+        .map(|block| Hash256::from_bytes(Sha256::digest(block).into()))
+        .collect()
+}
+
+/// Returns root of tree, whose nodes in layer at `height` above leaves are `hashes`.
+/// The layer is padded by hashes of zero subtrees to `width`, which is power of two.
+pub(crate) fn root(hashes: &[Hash256], width: usize, height: u32) -> Hash256 {
+    let mut layer = hashes.to_vec();
+    layer.resize(width.max(hashes.len()).max(1), pad_hash(height));
+    while layer.len() > 1 {
+        layer = layer
+            .chunks(2)
+            .map(|pair| hash_pair(&pair[0], &pair[1]))
+            .collect();
+    }
+    layer[0]
+}
+
+/// Returns height of piece layer above leaf blocks.
+pub(crate) fn piece_layer_height(piece_length: u64) -> u32 {
+    (piece_length as usize / BLOCK_SIZE).trailing_zeros()
+}
+
+/// Returns hash of one piece in piece layer, `data` are without padding after the end of file.
+pub(crate) fn piece_root(data: &[u8], piece_length: u64) -> Hash256 {
+    root(&block_hashes(data), piece_length as usize / BLOCK_SIZE, 0)
+}
+
+/// Returns pieces root of file, which is not larger than one piece.
+pub(crate) fn file_root(data: &[u8]) -> Hash256 {
+    let blocks = block_hashes(data);
+    root(&blocks, blocks.len().next_power_of_two(), 0)
+}
+
+/// Returns pieces root of file computed from its piece layer.
+pub(crate) fn layer_root(layer: &[Hash256], piece_length: u64) -> Hash256 {
+    root(
+        layer,
+        layer.len().next_power_of_two(),
+        piece_layer_height(piece_length),
+    )
+}
+
+/// Returns `length` hashes from `layer` starting at `index`, followed by `proof_layers` uncle hashes
+/// needed to verify them against the root. `None` means that the request doesn't fit in the tree.
+pub(crate) fn hashes_with_proof(
+    layer: &[Hash256],
+    height: u32,
+    index: usize,
+    length: usize,
+    proof_layers: usize,
+) -> Option<Vec<Hash256>> {
+    let width = layer.len().next_power_of_two();
+    if !length.is_power_of_two() || !index.is_multiple_of(length) || index + length > width {
+        return None;
+    }
+
+    let mut nodes = layer.to_vec();
+    nodes.resize(width, pad_hash(height));
+    let mut hashes = nodes[index..index + length].to_vec();
+
+    // Go up to root of requested subtree, and then collect siblings of its ancestors
+    let mut span = 1;
+    while nodes.len() > 1 {
+        if span >= length && hashes.len() < length + proof_layers {
+            hashes.push(nodes[(index / span) ^ 1]);
+        }
+        nodes = nodes
+            .chunks(2)
+            .map(|pair| hash_pair(&pair[0], &pair[1]))
+            .collect();
+        span *= 2;
+    }
+    Some(hashes)
+}
+
+/// Verify hashes received in `hashes` message against root of file.
+/// `hashes` contains `length` nodes of layer at `height` starting at `index`, followed by uncle hashes.
+pub(crate) fn verify_hashes(
+    pieces_root: &Hash256,
+    height: u32,
+    index: usize,
+    length: usize,
+    hashes: &[Hash256],
+) -> bool {
+    if hashes.len() < length || !length.is_power_of_two() || !index.is_multiple_of(length) {
+        return false;
+    }
+    let mut node = root(&hashes[..length], length, height);
+    let mut position = index / length;
+    for uncle in &hashes[length..] {
+        node = if position.is_multiple_of(2) {
+            hash_pair(&node, uncle)
+        } else {
+            hash_pair(uncle, &node)
+        };
+        position /= 2;
+    }
+    node == *pieces_root
+}
+
+#[test]
+fn merkle_layer_and_proofs() {
+    let piece_length = 2 * BLOCK_SIZE as u64;
+    let data: Vec<u8> = (0..5 * BLOCK_SIZE + 100).map(|i| (i % 251) as u8).collect();
+    let layer: Vec<Hash256> = data
+        .chunks(piece_length as usize)
+        .map(|piece| piece_root(piece, piece_length))
+        .collect();
+    assert_eq!(layer.len(), 3);
+
+    // Root from piece layer is the same as root from all blocks padded to power of two
+    let pieces_root = layer_root(&layer, piece_length);
+    assert_eq!(pieces_root, root(&block_hashes(&data), 8, 0));
+
+    let height = piece_layer_height(piece_length);
+    let whole = hashes_with_proof(&layer, height, 0, 4, 0).unwrap();
+    assert!(verify_hashes(&pieces_root, height, 0, 4, &whole));
+
+    let part = hashes_with_proof(&layer, height, 2, 2, 1).unwrap();
+    assert_eq!(part.len(), 3);
+    assert!(verify_hashes(&pieces_root, height, 2, 2, &part));
+    assert!(!verify_hashes(&pieces_root, height, 0, 2, &part));
+    assert!(hashes_with_proof(&layer, height, 1, 2, 0).is_none());
+}
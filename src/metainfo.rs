@@ -0,0 +1,512 @@
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, MutexGuard};
+
+use anyhow::{Context, Result};
+use lava_torrent::bencode::BencodeElem;
+use lava_torrent::torrent::v1::{File, Torrent};
+use sha2::{Digest, Sha256};
+
+use crate::hash::{Hash256, InfoHash, InfoHashV2};
+use crate::merkle;
+
+/// Version of torrent metadata.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MetaVersion {
+    /// Original format with SHA-1 piece hashes (BEP 3).
+    V1,
+    /// Format with per-file SHA-256 merkle trees (BEP 52).
+    V2,
+    /// Torrent with both v1 and v2 metadata describing the same data.
+    Hybrid,
+}
+
+/// One file of v2 torrent.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct V2File {
+    pub path: PathBuf,
+    pub length: u64,
+    /// Root of merkle tree of file, empty files have no root.
+    pub pieces_root: Option<Hash256>,
+    /// Index of first piece of file, every file starts on piece boundary.
+    pub first_piece: usize,
+}
+
+/// Metadata of BitTorrent v2 (BEP 52), file tree and piece layers of all files.
+#[derive(Debug)]
+pub struct V2Metadata {
+    pub info_hash: InfoHashV2,
+    pub piece_length: u64,
+    pub files: Vec<V2File>,
+    /// Piece layers of files larger than one piece, missing layers can be received from peers.
+    piece_layers: Mutex<HashMap<Hash256, Vec<Hash256>>>,
+}
+
+/// Loaded torrent file of any version.
+/// For v2 torrents `torrent` contains file list and pieces in v1 layout (with padding files),
+/// but its v1 piece hashes are not valid and must not be used.
+#[derive(Debug, Clone)]
+pub struct Metainfo {
+    pub torrent: Torrent,
+    pub version: MetaVersion,
+    pub info_hash_v1: Option<InfoHash>,
+    pub v2: Option<Arc<V2Metadata>>,
+}
+
+impl Metainfo {
+    /// Read torrent file of any version.
+    pub fn read_from_file(path: impl AsRef<Path>) -> Result<Self> {
+        let path = path.as_ref();
+        let bytes =
+            std::fs::read(path).with_context(|| format!("Cannot read {}", path.display()))?;
+        Metainfo::read_from_bytes(&bytes)
+    }
+
+    /// Parse torrent file of any version.
+    pub fn read_from_bytes(bytes: &[u8]) -> Result<Self> {
+        let mut parsed = BencodeElem::from_bytes(bytes)?;
+        anyhow::ensure!(
+            parsed.len() == 1,
+            "Torrent should contain one top-level element"
+        );
+        let BencodeElem::Dictionary(mut root) = parsed.remove(0) else {
+            anyhow::bail!("Torrent's top-level element is not a dictionary");
+        };
+        let Some(BencodeElem::Dictionary(info)) = root.get("info") else {
+            anyhow::bail!("Torrent has no info dictionary");
+        };
+
+        if info.contains_key("pieces") {
+            return Metainfo::from_torrent(Torrent::read_from_bytes(bytes)?);
+        }
+
+        // Torrent has only v2 metadata, build v1 layout of pieces and files from file tree
+        let BencodeElem::Dictionary(info) = root.remove("info").context("No info")? else {
+            unreachable!()
+        };
+        let info_hash = InfoHashV2::from_bytes(
+            Sha256::digest(BencodeElem::Dictionary(info.clone()).encode()).into(),
+        );
+        let name = match info.get("name") {
+            Some(BencodeElem::String(name)) => name.clone(),
+            _ => anyhow::bail!("Torrent has no name"),
+        };
+        let piece_length = integer(info.get("piece length")).context("No piece length")?;
+        let v2 = V2Metadata::new(info_hash, &info, root.get("piece layers"), piece_length)?;
+
+        let mut files = Vec::new();
+        let mut length = 0u64;
+        for file in &v2.files {
+            if file.first_piece as u64 * piece_length > length {
+                let padding = file.first_piece as u64 * piece_length - length;
+                files.push(padding_file(padding));
+                length += padding;
+            }
+            files.push(File {
+                length: file.length as i64,
+                path: file.path.clone(),
+                extra_fields: None,
+            });
+            length += file.length;
+        }
+        let piece_count = length.div_ceil(piece_length) as usize;
+        let single_file = v2.files.len() == 1 && v2.files[0].path == Path::new(&name);
+
+        let announce = match root.remove("announce") {
+            Some(BencodeElem::String(url)) => Some(url),
+            _ => None,
+        };
+        let announce_list = match root.remove("announce-list") {
+            Some(BencodeElem::List(tiers)) => Some(
+                tiers
+                    .into_iter()
+                    .map(|tier| match tier {
+                        BencodeElem::List(urls) => urls
+                            .into_iter()
+                            .filter_map(|url| match url {
+                                BencodeElem::String(url) => Some(url),
+                                _ => None,
+                            })
+                            .collect(),
+                        _ => Vec::new(),
+                    })
+                    .collect(),
+            ),
+            _ => None,
+        };
+        let mut extra_info_fields = info;
+        extra_info_fields.remove("name");
+        extra_info_fields.remove("piece length");
+        let torrent = Torrent {
+            announce,
+            announce_list,
+            length: length as i64,
+            files: (!single_file).then_some(files),
+            name,
+            piece_length: piece_length as i64,
+            pieces: vec![vec![0u8; 20]; piece_count],
+            extra_fields: Some(root),
+            extra_info_fields: Some(extra_info_fields),
+        };
+
+        Ok(Metainfo {
+            torrent,
+            version: MetaVersion::V2,
+            info_hash_v1: None,
+            v2: Some(Arc::new(v2)),
+        })
+    }
+
+    /// Create metainfo from v1 torrent, v2 metadata of hybrid torrent are read from its extra fields.
+    pub fn from_torrent(torrent: Torrent) -> Result<Self> {
+        let info_hash_v1 = InfoHash::new(torrent.info_hash_bytes())?;
+        let is_hybrid = matches!(
+            torrent
+                .extra_info_fields
+                .as_ref()
+                .and_then(|fields| fields.get("meta version")),
+            Some(BencodeElem::Integer(2))
+        );
+        if !is_hybrid {
+            return Ok(Metainfo {
+                torrent,
+                version: MetaVersion::V1,
+                info_hash_v1: Some(info_hash_v1),
+                v2: None,
+            });
+        }
+
+        let BencodeElem::Dictionary(info) = torrent.construct_info() else {
+            unreachable!("Info of torrent is always dictionary")
+        };
+        let info_hash = InfoHashV2::from_bytes(
+            Sha256::digest(BencodeElem::Dictionary(info.clone()).encode()).into(),
+        );
+        let piece_layers = torrent
+            .extra_fields
+            .as_ref()
+            .and_then(|fields| fields.get("piece layers"));
+        let v2 = V2Metadata::new(info_hash, &info, piece_layers, torrent.piece_length as u64)?;
+        anyhow::ensure!(
+            v2.piece_count() == torrent.pieces.len(),
+            "v1 and v2 metadata of hybrid torrent describe different pieces"
+        );
+
+        Ok(Metainfo {
+            torrent,
+            version: MetaVersion::Hybrid,
+            info_hash_v1: Some(info_hash_v1),
+            v2: Some(Arc::new(v2)),
+        })
+    }
+
+    /// Returns info hash used by default in handshakes and tracker requests.
+    /// It is v1 info hash, or truncated v2 info hash for torrents with only v2 metadata.
+    pub fn info_hash(&self) -> InfoHash {
+        match (&self.info_hash_v1, &self.v2) {
+            (Some(info_hash), _) => *info_hash,
+            (None, Some(v2)) => v2.info_hash.truncated(),
+            (None, None) => unreachable!("Torrent has at least one version of metadata"),
+        }
+    }
+
+    /// Returns info hashes of all swarms of this torrent, hybrid torrent is in v1 and v2 swarm.
+    pub fn swarm_hashes(&self) -> Vec<InfoHash> {
+        self.info_hash_v1
+            .iter()
+            .copied()
+            .chain(self.v2.iter().map(|v2| v2.info_hash.truncated()))
+            .collect()
+    }
+}
+
+impl V2Metadata {
+    /// Parse file tree from info dictionary and piece layers from root of torrent.
+    fn new(
+        info_hash: InfoHashV2,
+        info: &HashMap<String, BencodeElem>,
+        piece_layers: Option<&BencodeElem>,
+        piece_length: u64,
+    ) -> Result<Self> {
+        anyhow::ensure!(
+            piece_length.is_power_of_two() && piece_length >= merkle::BLOCK_SIZE as u64,
+            "Piece length of v2 torrent has to be power of two and at least 16 KiB"
+        );
+        let file_tree = info.get("file tree").context("Torrent has no file tree")?;
+        let mut files = Vec::new();
+        collect_file_tree(file_tree, PathBuf::new(), &mut files)?;
+        anyhow::ensure!(!files.is_empty(), "File tree is empty");
+
+        let mut first_piece = 0;
+        for file in files.iter_mut() {
+            file.first_piece = first_piece;
+            first_piece += file.length.div_ceil(piece_length) as usize;
+        }
+
+        let metadata = V2Metadata {
+            info_hash,
+            piece_length,
+            files,
+            piece_layers: Mutex::new(HashMap::new()),
+        };
+
+        // Layers, which don't match roots of files, are ignored and requested from peers later
+        let layers: Vec<(Vec<u8>, &BencodeElem)> = match piece_layers {
+            Some(BencodeElem::RawDictionary(layers)) => {
+                layers.iter().map(|(k, v)| (k.clone(), v)).collect()
+            }
+            Some(BencodeElem::Dictionary(layers)) => layers
+                .iter()
+                .map(|(k, v)| (k.as_bytes().to_vec(), v))
+                .collect(),
+            _ => Vec::new(),
+        };
+        for (pieces_root, layer) in layers {
+            let (Ok(pieces_root), Some(layer)) =
+                (Hash256::try_from(pieces_root.as_slice()), bytes(layer))
+            else {
+                continue;
+            };
+            let layer = layer
+                .chunks_exact(32)
+                .map(Hash256::try_from)
+                .collect::<Result<Vec<_>>>()?;
+            let _ = metadata.set_piece_layer(pieces_root, layer);
+        }
+        Ok(metadata)
+    }
+
+    /// Returns number of pieces of torrent.
+    pub fn piece_count(&self) -> usize {
+        self.files
+            .iter()
+            .map(|file| file.length.div_ceil(self.piece_length) as usize)
+            .sum()
+    }
+
+    /// Returns file, that contains piece with given index.
+    pub fn file_of_piece(&self, piece_index: usize) -> Option<&V2File> {
+        self.files.iter().find(|file| {
+            let piece_count = file.length.div_ceil(self.piece_length) as usize;
+            (file.first_piece..file.first_piece + piece_count).contains(&piece_index)
+        })
+    }
+
+    /// Returns height of piece layer in merkle trees of files.
+    pub fn piece_layer_height(&self) -> u32 {
+        merkle::piece_layer_height(self.piece_length)
+    }
+
+    /// Returns piece layer of file with given root, if it is known.
+    pub fn piece_layer(&self, pieces_root: &Hash256) -> Option<Vec<Hash256>> {
+        self.lock_layers().get(pieces_root).cloned()
+    }
+
+    /// Store piece layer of file, the layer is checked against root of file.
+    pub fn set_piece_layer(&self, pieces_root: Hash256, layer: Vec<Hash256>) -> Result<()> {
+        let file = self
+            .files
+            .iter()
+            .find(|file| file.pieces_root == Some(pieces_root))
+            .context("Piece layer of unknown file")?;
+        anyhow::ensure!(
+            layer.len() == file.length.div_ceil(self.piece_length) as usize,
+            "Piece layer has wrong number of hashes"
+        );
+        anyhow::ensure!(
+            merkle::layer_root(&layer, self.piece_length) == pieces_root,
+            "Piece layer doesn't match root of file"
+        );
+        self.lock_layers().insert(pieces_root, layer);
+        Ok(())
+    }
+
+    /// Verify data of piece against merkle tree of its file.
+    /// Data can contain padding after the end of file, which is ignored.
+    /// Returns `None`, if piece layer of the file is not known yet.
+    pub fn verify_piece(&self, piece_index: usize, data: &[u8]) -> Option<bool> {
+        let Some(file) = self.file_of_piece(piece_index) else {
+            return Some(false);
+        };
+        let Some(pieces_root) = file.pieces_root else {
+            return Some(false);
+        };
+        let offset = (piece_index - file.first_piece) as u64 * self.piece_length;
+        let length = (file.length - offset).min(self.piece_length) as usize;
+        if data.len() < length {
+            return Some(false);
+        }
+        let data = &data[..length];
+
+        if file.length <= self.piece_length {
+            return Some(merkle::file_root(data) == pieces_root);
+        }
+        let layer = self.piece_layer(&pieces_root)?;
+        Some(merkle::piece_root(data, self.piece_length) == layer[piece_index - file.first_piece])
+    }
+
+    fn lock_layers(&self) -> MutexGuard<'_, HashMap<Hash256, Vec<Hash256>>> {
+        self.piece_layers
+            .lock()
+            .unwrap_or_else(|err| err.into_inner())
+    }
+}
+
+/// Walk file tree of v2 torrent, files are in order of their paths.
+fn collect_file_tree(node: &BencodeElem, path: PathBuf, files: &mut Vec<V2File>) -> Result<()> {
+    let BencodeElem::Dictionary(node) = node else {
+        anyhow::bail!("Node of file tree is not a dictionary");
+    };
+
+    // Empty key marks file, its value contains length and root of merkle tree
+    if let Some(BencodeElem::Dictionary(file)) = node.get("") {
+        let length = integer(file.get("length")).context("File in file tree has no length")?;
+        let pieces_root = match file.get("pieces root").and_then(bytes) {
+            Some(root) => Some(Hash256::try_from(root.as_slice())?),
+            None => None,
+        };
+        anyhow::ensure!(
+            length == 0 || pieces_root.is_some(),
+            "Non empty file {} has no pieces root",
+            path.display()
+        );
+        files.push(V2File {
+            path,
+            length,
+            pieces_root,
+            first_piece: 0,
+        });
+        return Ok(());
+    }
+
+    let mut names: Vec<&String> = node.keys().collect();
+    names.sort();
+    for name in names {
+        anyhow::ensure!(
+            !name.is_empty() && name != ".." && !name.contains('/'),
+            "Invalid file name {name} in file tree"
+        );
+        collect_file_tree(&node[name], path.join(name), files)?;
+    }
+    Ok(())
+}
+
+/// Returns non negative integer value.
+fn integer(elem: Option<&BencodeElem>) -> Option<u64> {
+    match elem {
+        Some(BencodeElem::Integer(value)) => u64::try_from(*value).ok(),
+        _ => None,
+    }
+}
+
+/// Returns bytes of string value.
+fn bytes(elem: &BencodeElem) -> Option<Vec<u8>> {
+    match elem {
+        BencodeElem::Bytes(bytes) => Some(bytes.clone()),
+        BencodeElem::String(string) => Some(string.as_bytes().to_vec()),
+        _ => None,
+    }
+}
+
+/// Returns padding file of given length (BEP 47).
+fn padding_file(length: u64) -> File {
+    File {
+        length: length as i64,
+        path: PathBuf::from(".pad").join(length.to_string()),
+        extra_fields: Some(HashMap::from([(
+            "attr".to_string(),
+            BencodeElem::String("p".to_string()),
+        )])),
+    }
+}
+
+#[test]
+fn metainfo_v2_and_hybrid() {
+    use crate::test_utils::test_torrent;
+
+    let piece_length = 2 * merkle::BLOCK_SIZE as u64;
+    let big: Vec<u8> = (0..70_000).map(|i| (i % 253) as u8).collect();
+    let small = vec![7u8; 1000];
+    let big_layer: Vec<Hash256> = big
+        .chunks(piece_length as usize)
+        .map(|piece| merkle::piece_root(piece, piece_length))
+        .collect();
+    let big_root = merkle::layer_root(&big_layer, piece_length);
+    let small_root = merkle::file_root(&small);
+
+    let file_node = |length: usize, root: &Hash256| {
+        BencodeElem::Dictionary(HashMap::from([(
+            String::new(),
+            BencodeElem::Dictionary(HashMap::from([
+                ("length".to_string(), BencodeElem::Integer(length as i64)),
+                (
+                    "pieces root".to_string(),
+                    BencodeElem::Bytes(root.as_bytes().to_vec()),
+                ),
+            ])),
+        )]))
+    };
+    let file_tree = BencodeElem::Dictionary(HashMap::from([
+        ("a.iso".to_string(), file_node(big.len(), &big_root)),
+        ("b.txt".to_string(), file_node(small.len(), &small_root)),
+    ]));
+    let info = BencodeElem::Dictionary(HashMap::from([
+        (
+            "name".to_string(),
+            BencodeElem::String("release".to_string()),
+        ),
+        (
+            "piece length".to_string(),
+            BencodeElem::Integer(piece_length as i64),
+        ),
+        ("meta version".to_string(), BencodeElem::Integer(2)),
+        ("file tree".to_string(), file_tree.clone()),
+    ]));
+    let piece_layers = BencodeElem::RawDictionary(HashMap::from([(
+        big_root.as_bytes().to_vec(),
+        BencodeElem::Bytes(big_layer.iter().flat_map(|hash| *hash.as_bytes()).collect()),
+    )]));
+    let torrent = BencodeElem::Dictionary(HashMap::from([
+        ("info".to_string(), info.clone()),
+        ("piece layers".to_string(), piece_layers.clone()),
+    ]));
+
+    let metainfo = Metainfo::read_from_bytes(&torrent.encode()).unwrap();
+    assert_eq!(metainfo.version, MetaVersion::V2);
+    let v2 = metainfo.v2.clone().unwrap();
+    assert_eq!(
+        v2.info_hash,
+        InfoHashV2::from_bytes(Sha256::digest(info.encode()).into())
+    );
+    assert_eq!(metainfo.info_hash(), v2.info_hash.truncated());
+    assert_eq!(v2.files[1].first_piece, 3);
+    assert_eq!(metainfo.torrent.pieces.len(), 4);
+    // Second file starts after padding file on piece boundary
+    let files = metainfo.torrent.files.as_ref().unwrap();
+    assert_eq!(files[1].length as u64, 3 * piece_length - big.len() as u64);
+
+    let mut last_piece = big[2 * piece_length as usize..].to_vec();
+    assert_eq!(v2.verify_piece(2, &last_piece), Some(true));
+    last_piece.resize(piece_length as usize, 0);
+    assert_eq!(v2.verify_piece(2, &last_piece), Some(true));
+    assert_eq!(v2.verify_piece(3, &small), Some(true));
+    assert_eq!(
+        v2.verify_piece(0, &big[..piece_length as usize - 1]),
+        Some(false)
+    );
+
+    // Hybrid torrent has the same v2 metadata next to v1 pieces
+    let mut hybrid = test_torrent(metainfo.torrent.length, piece_length as i64);
+    hybrid.files = metainfo.torrent.files.clone();
+    hybrid.name = "release".to_string();
+    hybrid.pieces = vec![vec![0xffu8; 20]; 4];
+    hybrid.extra_fields = Some(HashMap::from([("piece layers".to_string(), piece_layers)]));
+    hybrid.extra_info_fields = Some(HashMap::from([
+        ("meta version".to_string(), BencodeElem::Integer(2)),
+        ("file tree".to_string(), file_tree),
+    ]));
+    let hybrid = Metainfo::read_from_bytes(&hybrid.encode().unwrap()).unwrap();
+    assert_eq!(hybrid.version, MetaVersion::Hybrid);
+    assert_eq!(hybrid.swarm_hashes().len(), 2);
+    assert_eq!(hybrid.info_hash(), hybrid.info_hash_v1.unwrap());
+}
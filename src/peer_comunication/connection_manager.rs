@@ -0,0 +1,347 @@
+use std::collections::hash_map::Entry;
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::time::{Duration, Instant};
+
+/// Default maximal number of peers, that can be connected (or connecting) at the same time.
+pub const DEFAULT_MAX_ACTIVE_CONNECTIONS: usize = 50;
+/// Default waiting time before first reconnection to failed peer.
+const DEFAULT_BASE_BACKOFF: Duration = Duration::from_secs(10);
+/// Default maximal waiting time between two reconnection attempts.
+const DEFAULT_MAX_BACKOFF: Duration = Duration::from_secs(10 * 60);
+/// Number of failures in a row, after which peer is not tried anymore.
+const DEFAULT_MAX_FAILURES: u32 = 8;
+
+/// Source from which the address of peer was obtained.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PeerSource {
+    Tracker,
+    Incoming,
+    Pex,
+    Dht,
+}
+
+/// State of connection with one peer.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PeerState {
+    /// Peer was never tried, or it can be tried again after backoff.
+    Candidate,
+    /// TCP connection or handshake is in progress.
+    Connecting,
+    /// Bittorent connection is established.
+    Connected,
+    /// Last attempt failed, peer waits for backoff to expire.
+    Failed,
+    /// Peer failed too many times in a row, or it is duplicate of already connected peer.
+    Dead,
+}
+
+/// Record about one peer in peer table.
+#[derive(Debug, Clone)]
+pub struct PeerEntry {
+    pub addr: SocketAddr,
+    pub peer_id: Option<[u8; 20]>,
+    pub state: PeerState,
+    pub failures: u32,
+    pub last_attempt: Option<Instant>,
+    pub source: PeerSource,
+}
+
+impl PeerEntry {
+    fn new(addr: SocketAddr, source: PeerSource) -> Self {
+        PeerEntry {
+            addr,
+            peer_id: None,
+            state: PeerState::Candidate,
+            failures: 0,
+            last_attempt: None,
+            source,
+        }
+    }
+}
+
+/// Structure that manages peer table of one torrent.
+/// Limits number of active connections, schedules reconnections with exponential backoff,
+/// and deduplicate peers by address and peer-id.
+/// Peers from all sources (tracker, PEX, DHT) go through `next_candidates`,
+/// where the caller checks bans and IP filter before connecting.
+pub struct ConnectionManager {
+    peers: HashMap<SocketAddr, PeerEntry>,
+    max_active: usize,
+    max_failures: u32,
+    base_backoff: Duration,
+    max_backoff: Duration,
+}
+
+impl ConnectionManager {
+    /// Creates new empty peer table, that allows at most `max_active` connections at the same time.
+    pub fn new(max_active: usize) -> Self {
+        ConnectionManager {
+            peers: HashMap::new(),
+            max_active,
+            max_failures: DEFAULT_MAX_FAILURES,
+            base_backoff: DEFAULT_BASE_BACKOFF,
+            max_backoff: DEFAULT_MAX_BACKOFF,
+        }
+    }
+
+    /// Set waiting time before first reconnection, and maximal waiting time between reconnections.
+    pub fn set_backoff(&mut self, base: Duration, max: Duration) {
+        self.base_backoff = base;
+        self.max_backoff = max;
+    }
+
+    /// Set maximal number of active connections.
+    pub fn set_max_active(&mut self, max_active: usize) {
+        self.max_active = max_active;
+    }
+
+    /// Returns maximal number of active connections.
+    pub fn max_active(&self) -> usize {
+        self.max_active
+    }
+
+    /// Add peers into candidate pool, addresses that are already known are ignored.
+    /// Returns number of newly added peers.
+    pub fn add_peers(
+        &mut self,
+        addrs: impl IntoIterator<Item = SocketAddr>,
+        source: PeerSource,
+    ) -> usize {
+        let mut added = 0;
+        for addr in addrs {
+            if let Entry::Vacant(entry) = self.peers.entry(addr) {
+                entry.insert(PeerEntry::new(addr, source));
+                added += 1;
+            }
+        }
+        added
+    }
+
+    /// Returns number of peers, that are connected or connecting right now.
+    pub fn active_count(&self) -> usize {
+        self.peers
+            .values()
+            .filter(|peer| matches!(peer.state, PeerState::Connecting | PeerState::Connected))
+            .count()
+    }
+
+    /// Returns number of peers in state `Connected`.
+    pub fn connected_count(&self) -> usize {
+        self.peers
+            .values()
+            .filter(|peer| peer.state == PeerState::Connected)
+            .count()
+    }
+
+    /// Choose peers that should be connected now, so the number of active connections stays under limit.
+    /// Peers that never failed are preferred, then peers with smaller number of failures.
+    /// Chosen peers are marked as `Connecting`.
+    pub fn next_candidates(&mut self, now: Instant) -> Vec<SocketAddr> {
+        let free_slots = self.max_active.saturating_sub(self.active_count());
+        if free_slots == 0 {
+            return Vec::new();
+        }
+
+        let mut ready: Vec<_> = self
+            .peers
+            .values()
+            .filter(|peer| self.is_ready(peer, now))
+            .map(|peer| (peer.failures, peer.addr))
+            .collect();
+        ready.sort();
+
+        ready
+            .into_iter()
+            .take(free_slots)
+            .map(|(_, addr)| {
+                if let Some(peer) = self.peers.get_mut(&addr) {
+                    peer.state = PeerState::Connecting;
+                    peer.last_attempt = Some(now);
+                }
+                addr
+            })
+            .collect()
+    }
+
+    /// Returns time after which some failed peer will be ready for reconnection.
+    /// `None` means, that no peer is waiting for backoff.
+    pub fn next_retry_in(&self, now: Instant) -> Option<Duration> {
+        self.peers
+            .values()
+            .filter(|peer| peer.state == PeerState::Failed)
+            .filter_map(|peer| {
+                let ready_at = peer.last_attempt? + self.backoff(peer.failures);
+                Some(ready_at.saturating_duration_since(now))
+            })
+            .min()
+    }
+
+    /// Mark peer as connected after successful handshake.
+    /// Returns `false` if peer with the same peer-id is already connected from other address,
+    /// in that case this connection should be closed.
+    pub fn on_connected(&mut self, addr: SocketAddr, peer_id: [u8; 20]) -> bool {
+        let duplicate = self.peers.values().any(|peer| {
+            peer.addr != addr && peer.state == PeerState::Connected && peer.peer_id == Some(peer_id)
+        });
+
+        let peer = self
+            .peers
+            .entry(addr)
+            .or_insert_with(|| PeerEntry::new(addr, PeerSource::Incoming));
+        peer.peer_id = Some(peer_id);
+        if duplicate {
+            peer.state = PeerState::Dead;
+            return false;
+        }
+        peer.state = PeerState::Connected;
+        peer.failures = 0;
+        true
+    }
+
+    /// Return peer chosen by `next_candidates` back to candidates, when it wasn't connected
+    /// because of limit shared with other torrents.
+    pub fn on_postponed(&mut self, addr: SocketAddr) {
+        if let Some(peer) = self.peers.get_mut(&addr) {
+            if peer.state == PeerState::Connecting {
+                peer.state = PeerState::Candidate;
+            }
+        }
+    }
+
+    /// Record failed connection attempt, or connection that ended with error.
+    pub fn on_failed(&mut self, addr: SocketAddr) {
+        if self.remove_incoming(addr) {
+            return;
+        }
+        let max_failures = self.max_failures;
+        if let Some(peer) = self.peers.get_mut(&addr) {
+            if peer.state == PeerState::Dead {
+                return;
+            }
+            peer.failures += 1;
+            peer.state = if peer.failures >= max_failures {
+                PeerState::Dead
+            } else {
+                PeerState::Failed
+            };
+        }
+    }
+
+    /// Record connection that ended without error, peer can be connected again later.
+    pub fn on_disconnected(&mut self, addr: SocketAddr) {
+        if self.remove_incoming(addr) {
+            return;
+        }
+        if let Some(peer) = self.peers.get_mut(&addr) {
+            if peer.state != PeerState::Dead {
+                peer.state = PeerState::Failed;
+                peer.failures = 1;
+            }
+        }
+    }
+
+    /// Record that peer was banned or blocked by IP filter, it will never be connected again.
+    pub fn on_banned(&mut self, addr: SocketAddr) {
+        if let Some(peer) = self.peers.get_mut(&addr) {
+            peer.state = PeerState::Dead;
+        }
+    }
+
+    /// Returns iterator over all known peers.
+    pub fn peers(&self) -> impl Iterator<Item = &PeerEntry> {
+        self.peers.values()
+    }
+
+    /// Remove peer, that connected to this client. Its address has port chosen by its system,
+    /// so it can't be connected again.
+    fn remove_incoming(&mut self, addr: SocketAddr) -> bool {
+        match self.peers.get(&addr) {
+            Some(peer) if peer.source == PeerSource::Incoming && peer.state != PeerState::Dead => {
+                self.peers.remove(&addr);
+                true
+            }
+            _ => false,
+        }
+    }
+
+    /// Returns time, that have to pass after last attempt before next reconnection.
+    fn backoff(&self, failures: u32) -> Duration {
+        let exponent = failures.saturating_sub(1).min(16);
+        self.base_backoff
+            .saturating_mul(1 << exponent)
+            .min(self.max_backoff)
+    }
+
+    /// Returns if peer can be connected right now.
+    fn is_ready(&self, peer: &PeerEntry, now: Instant) -> bool {
+        match peer.state {
+            PeerState::Candidate => true,
+            PeerState::Failed => match peer.last_attempt {
+                Some(last_attempt) => now >= last_attempt + self.backoff(peer.failures),
+                None => true,
+            },
+            _ => false,
+        }
+    }
+}
+
+impl Default for ConnectionManager {
+    fn default() -> Self {
+        ConnectionManager::new(DEFAULT_MAX_ACTIVE_CONNECTIONS)
+    }
+}
+
+#[cfg(test)]
+fn test_addr(port: u16) -> SocketAddr {
+    SocketAddr::from(([127, 0, 0, 1], port))
+}
+
+#[test]
+fn connection_manager_limit_and_dedup() {
+    let mut manager = ConnectionManager::new(2);
+    assert_eq!(
+        manager.add_peers(
+            [test_addr(1), test_addr(2), test_addr(3)],
+            PeerSource::Tracker
+        ),
+        3
+    );
+    assert_eq!(manager.add_peers([test_addr(1)], PeerSource::Tracker), 0);
+
+    let now = Instant::now();
+    let first = manager.next_candidates(now);
+    assert_eq!(first.len(), 2);
+    assert!(manager.next_candidates(now).is_empty());
+
+    assert!(manager.on_connected(first[0], [1; 20]));
+    assert!(!manager.on_connected(first[1], [1; 20]));
+
+    let replacement = manager.next_candidates(now);
+    assert_eq!(replacement.len(), 1);
+    assert!(!first.contains(&replacement[0]));
+}
+
+#[test]
+fn connection_manager_backoff() {
+    let mut manager = ConnectionManager::new(10);
+    manager.set_backoff(Duration::from_secs(1), Duration::from_secs(3));
+    manager.add_peers([test_addr(1)], PeerSource::Tracker);
+
+    let now = Instant::now();
+    assert_eq!(manager.next_candidates(now), vec![test_addr(1)]);
+    manager.on_failed(test_addr(1));
+    assert!(manager.next_candidates(now).is_empty());
+    assert_eq!(manager.next_retry_in(now), Some(Duration::from_secs(1)));
+
+    let now = now + Duration::from_secs(1);
+    assert_eq!(manager.next_candidates(now), vec![test_addr(1)]);
+    manager.on_failed(test_addr(1));
+    assert_eq!(manager.next_retry_in(now), Some(Duration::from_secs(2)));
+
+    let now = now + Duration::from_secs(2);
+    assert_eq!(manager.next_candidates(now), vec![test_addr(1)]);
+    manager.on_failed(test_addr(1));
+    // Backoff is capped by maximal value
+    assert_eq!(manager.next_retry_in(now), Some(Duration::from_secs(3)));
+}
@@ -1,4 +1,10 @@
+use crate::peer_id::PeerId;
+
 pub const BITTORRENT_PROTOCOL: [u8; 19] = *b"BitTorrent protocol";
+/// Bit in last reserved byte, that signals support of BitTorrent v2 (BEP 52).
+const V2_SUPPORT_BIT: u8 = 0x10;
+/// Bit in sixth reserved byte, that signals support of extension protocol (BEP 10).
+const EXTENSION_SUPPORT_BIT: u8 = 0x10;
 
 /// Structure representing bittorent handshake/
 pub struct Handshake {
@@ -57,4 +63,29 @@ impl Handshake {
         // Copy the peer_id field
         self.peer_id.copy_from_slice(&bytes[48..68]);
     }
+
+    /// Set reserved bit signaling, that this client supports v2 protocol for the torrent.
+    pub fn set_v2_support(&mut self) {
+        self.reserve[7] |= V2_SUPPORT_BIT;
+    }
+
+    /// Returns true, if peer supports v2 protocol for the torrent.
+    pub fn supports_v2(&self) -> bool {
+        self.reserve[7] & V2_SUPPORT_BIT != 0
+    }
+
+    /// Set reserved bit signaling, that this client supports extension protocol, e.g. for metadata exchange.
+    pub fn set_extension_support(&mut self) {
+        self.reserve[5] |= EXTENSION_SUPPORT_BIT;
+    }
+
+    /// Returns true, if peer supports extension protocol.
+    pub fn supports_extensions(&self) -> bool {
+        self.reserve[5] & EXTENSION_SUPPORT_BIT != 0
+    }
+
+    /// Returns peer-id of peer, that sent this handshake.
+    pub fn remote_peer_id(&self) -> PeerId {
+        PeerId::from_bytes(self.peer_id)
+    }
 }
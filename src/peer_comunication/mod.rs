@@ -1,4 +1,7 @@
-mod bitfield;
-mod handshake;
+pub(crate) mod bitfield;
+pub mod connection_manager;
+pub mod handshake;
 pub mod peer_connection;
 mod peer_msg;
+pub mod peer_score;
+pub mod peer_stats;
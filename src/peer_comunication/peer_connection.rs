@@ -1,84 +1,160 @@
-use std::collections::HashMap;
-use std::sync::atomic::AtomicUsize;
+use std::net::SocketAddr;
 use std::sync::Arc;
 use std::time::Duration;
 
 use anyhow::{Context, Result};
+use sha1::{Digest, Sha1};
 use tokio::io::{AsyncReadExt, AsyncWriteExt};
 use tokio::net::TcpStream;
 use tokio::sync::mpsc::Sender;
 use tokio::sync::Mutex;
 use tokio::time::timeout;
 
+use crate::file_layout::FileLayout;
+use crate::hash::{Hash256, InfoHash, PieceHash};
+use crate::merkle;
+use crate::metainfo::{MetaVersion, V2Metadata};
 use crate::peer_comunication::bitfield::Bitfield;
 use crate::peer_comunication::handshake::{Handshake, BITTORRENT_PROTOCOL};
-use crate::peer_comunication::peer_msg::PeerMessage;
+use crate::peer_comunication::peer_msg::{HashRequest, PeerMessage};
+use crate::peer_comunication::peer_score::{PeerScores, ProtocolViolation};
+use crate::peer_comunication::peer_stats::{ConnectionDirection, PeerStats, PeerStatsRegistry};
+use crate::peer_id::PeerId;
 use crate::piece::{Piece, PieceData};
+use crate::piece_pool::{PieceAvailability, PiecePool};
+use crate::rate_limit::TransferLimiters;
+use crate::storage::Storage;
 
 pub(crate) const TIMEOUT: Duration = Duration::from_secs(5);
 const MAX_BLOCK_SIZE: usize = 1024; //16384;
+/// Length of pieces root and four integers, which are at start of all hash messages.
+const HASH_REQUEST_LENGTH: usize = 48;
+/// Length of handshake message.
+const HANDSHAKE_LENGTH: usize = 68;
+/// Maximal length of block requested by other peer, longer requests are protocol violation.
+const MAX_REQUEST_LENGTH: u32 = 128 * 1024;
+/// Connection with peer, that doesn't send any message (even keep-alive) for this time, is closed.
+const SEED_IDLE_TIMEOUT: Duration = Duration::from_secs(180);
+
+/// Informations about downloaded torrent and structures shared by all its peer connections.
+#[derive(Clone)]
+pub struct TorrentContext {
+    /// Info hash of swarm, in which the peer is connected.
+    pub info_hash: InfoHash,
+    pub version: MetaVersion,
+    /// Merkle trees of files, for v2 and hybrid torrents.
+    pub v2: Option<Arc<V2Metadata>>,
+    /// Mapping of pieces to files, blocks in padding files are not requested.
+    pub layout: Arc<FileLayout>,
+    /// Peer-id of this client.
+    pub peer_id: [u8; 20],
+    pub piece_count: usize,
+    /// Channel used to send downloaded pieces to writer.
+    pub piece_channel: Sender<PieceData>,
+    /// Pieces waiting for download, ordered by file priorities and pick mode.
+    pub piece_pool: Arc<Mutex<PiecePool>>,
+    /// Availability of pieces in swarm, used by rarest first picking.
+    pub availability: Arc<PieceAvailability>,
+    pub peer_scores: Arc<Mutex<PeerScores>>,
+    pub limiters: TransferLimiters,
+    pub peer_stats: Arc<PeerStatsRegistry>,
+    /// Storage of downloaded data, blocks requested by other peers are read from it.
+    pub storage: Arc<dyn Storage>,
+}
 
 /// Structure representing all informations about P2P connection with one peer.
 #[allow(unused)]
 pub struct PeerConnection {
     stream: TcpStream,
-    peer_id: [u8; 20],
+    addr: SocketAddr,
+    peer_id: PeerId,
     bitfield: Mutex<Bitfield>,
     am_choking: bool,
     am_interested: bool,
     peer_choking: bool,
     peer_interested: bool,
-    piece_channel: Sender<PieceData>,
     total_pieces: usize,
-    piece_pool: Arc<Mutex<HashMap<usize, Piece>>>,
+    announced_pieces: Bitfield,
+    peer_supports_v2: bool,
+    /// Request for hashes sent to peer, that was not answered yet.
+    requested_hashes: Option<HashRequest>,
+    stats: Arc<PeerStats>,
+    context: TorrentContext,
 }
 
 impl PeerConnection {
     /// Create a new bittorent conection with peer, with wich TCP connection was already done.
-    /// Exchange handshake with other pear, and try to get bitfield of pieces from second
-    pub async fn new(
+    /// Exchange handshake with other pear.
+    pub async fn new(mut stream: TcpStream, context: TorrentContext) -> Result<Self> {
+        let handshake = local_handshake(&context);
+        send_handshake(&mut stream, &handshake).await?;
+
+        let remote = read_handshake(&mut stream)
+            .await
+            .context("Failed to read handshake answer")?;
+        anyhow::ensure!(remote.info_hash == handshake.info_hash);
+
+        PeerConnection::from_handshake(stream, remote, ConnectionDirection::Outgoing, context).await
+    }
+
+    /// Create a new bittorent connection with peer, which connected to this client
+    /// and already sent its handshake. Handshake of this client is sent as answer.
+    pub async fn accept(
         mut stream: TcpStream,
-        info_hash: [u8; 20],
-        peer_id: [u8; 20],
-        piece_count: usize,
-        sender: Sender<PieceData>,
-        piece_pool: Arc<Mutex<HashMap<usize, Piece>>>,
+        remote: Handshake,
+        context: TorrentContext,
     ) -> Result<Self> {
-        // Protocol handshake implementation
-        let mut handshake = Handshake::new(&info_hash, &peer_id);
-        let _ = timeout(TIMEOUT, stream.write_all(&handshake.get_bytes()))
-            .await
-            .context("Failed to write handshake")?;
-        let _ = timeout(TIMEOUT, stream.flush())
-            .await
-            .context("Failed to flush handshake")?;
+        anyhow::ensure!(remote.info_hash == context.info_hash.to_arr());
+        send_handshake(&mut stream, &local_handshake(&context)).await?;
 
-        let mut response: [u8; 68] = [0u8; 68];
-        timeout(TIMEOUT, stream.read_exact(&mut response))
-            .await
-            .context("Failed to read handshake answer")??;
+        PeerConnection::from_handshake(stream, remote, ConnectionDirection::Incoming, context).await
+    }
+
+    /// Finish connection after handshakes were exchanged.
+    async fn from_handshake(
+        stream: TcpStream,
+        remote: Handshake,
+        direction: ConnectionDirection,
+        context: TorrentContext,
+    ) -> Result<Self> {
+        let addr = stream.peer_addr()?;
+        let piece_count = context.piece_count;
 
-        handshake.set_bytes(&response);
-        anyhow::ensure!(handshake.length == 19);
-        anyhow::ensure!(handshake.bittorrent == BITTORRENT_PROTOCOL);
-        anyhow::ensure!(handshake.info_hash == info_hash);
+        let stats = context.peer_stats.register(addr, remote.peer_id, direction);
+        stats.on_sent(HANDSHAKE_LENGTH, 0);
+        stats.on_received(HANDSHAKE_LENGTH, 0);
 
-        let mut peer_conn = PeerConnection {
+        Ok(PeerConnection {
             stream,
-            peer_id: handshake.peer_id,
+            addr,
+            peer_id: remote.remote_peer_id(),
             bitfield: Mutex::new(Bitfield::empty_with_piece_capacity(piece_count)),
             am_choking: true,
             am_interested: false,
             peer_choking: true,
             peer_interested: false,
-            piece_channel: sender,
             total_pieces: piece_count,
-            piece_pool,
-        };
+            announced_pieces: Bitfield::empty_with_piece_capacity(piece_count),
+            peer_supports_v2: remote.supports_v2(),
+            requested_hashes: None,
+            stats,
+            context,
+        })
+    }
 
-        peer_conn.try_get_bitfield().await?;
+    /// Returns peer-id of other peer, received in handshake.
+    pub fn peer_id(&self) -> PeerId {
+        self.peer_id
+    }
 
-        Ok(peer_conn)
+    /// Returns transfer statistics of this connection.
+    pub fn stats(&self) -> Arc<PeerStats> {
+        self.stats.clone()
+    }
+
+    /// Returns address of other peer.
+    pub fn addr(&self) -> SocketAddr {
+        self.addr
     }
 
     /// Get information about at least one piece that other peer has, and waiting for `unchoke` message.
@@ -118,6 +194,7 @@ impl PeerConnection {
     /// Send message to other peer.
     pub async fn send_message(&mut self, message: PeerMessage) -> Result<()> {
         let mut payload = Vec::new();
+        let mut piece_payload = 0;
 
         match message {
             PeerMessage::Choke => payload.push(0),
@@ -137,6 +214,7 @@ impl PeerConnection {
                 begin,
                 length,
             } => {
+                self.stats.on_request(index, begin);
                 payload.push(6);
                 payload.extend_from_slice(&index.to_be_bytes());
                 payload.extend_from_slice(&begin.to_be_bytes());
@@ -147,6 +225,8 @@ impl PeerConnection {
                 begin,
                 block,
             } => {
+                self.context.limiters.upload.acquire(block.len()).await;
+                piece_payload = block.len();
                 payload.push(7);
                 payload.extend_from_slice(&index.to_be_bytes());
                 payload.extend_from_slice(&begin.to_be_bytes());
@@ -162,6 +242,21 @@ impl PeerConnection {
                 payload.extend_from_slice(&begin.to_be_bytes());
                 payload.extend_from_slice(&length.to_be_bytes());
             }
+            PeerMessage::HashRequest { request } => {
+                payload.push(21);
+                extend_hash_request(&mut payload, &request);
+            }
+            PeerMessage::Hashes { request, hashes } => {
+                payload.push(22);
+                extend_hash_request(&mut payload, &request);
+                for hash in hashes {
+                    payload.extend_from_slice(hash.as_bytes());
+                }
+            }
+            PeerMessage::HashReject { request } => {
+                payload.push(23);
+                extend_hash_request(&mut payload, &request);
+            }
         }
 
         // Send prepared payload
@@ -171,11 +266,13 @@ impl PeerConnection {
         msg.extend(payload);
         self.stream.write_all(&msg).await?;
         self.stream.flush().await?;
+        self.stats.on_sent(msg.len(), piece_payload);
 
         Ok(())
     }
 
     /// Receive message from other peer.
+    /// Malformed messages are reported as `ProtocolViolation` error.
     pub async fn receive_message(&mut self) -> Result<PeerMessage> {
         // Read message length
         let mut length_bytes = [0u8; 4];
@@ -183,50 +280,75 @@ impl PeerConnection {
         let length = u32::from_be_bytes(length_bytes);
 
         if length == 0 {
+            self.stats.on_received(length_bytes.len(), 0);
             return Ok(PeerMessage::Choke); // Keep-alive message
         }
 
         // Read message type
         let mut msg_type = [0u8; 1];
         self.stream.read_exact(&mut msg_type).await?;
+        let payload_length = length as usize - 1;
+        let message_length = length_bytes.len() + length as usize;
+        if msg_type[0] != 7 {
+            self.stats.on_received(message_length, 0);
+        }
 
         match msg_type[0] {
             0 => {
+                expect_payload_length(payload_length, 0, "choke")?;
                 self.am_choking = true;
+                self.stats.clear_requests();
                 Ok(PeerMessage::Choke)
             }
             1 => {
+                expect_payload_length(payload_length, 0, "unchoke")?;
                 self.am_choking = false;
                 Ok(PeerMessage::Unchoke)
             }
             2 => {
+                expect_payload_length(payload_length, 0, "interested")?;
                 self.peer_interested = true;
                 Ok(PeerMessage::Interested)
             }
             3 => {
+                expect_payload_length(payload_length, 0, "not interested")?;
                 self.peer_interested = false;
                 Ok(PeerMessage::NotInterested)
             }
             4 => {
+                expect_payload_length(payload_length, 4, "have")?;
                 let mut piece_index_bytes = [0u8; 4];
                 self.stream.read_exact(&mut piece_index_bytes).await?;
                 let piece_index = u32::from_be_bytes(piece_index_bytes);
-                self.bitfield.lock().await.set_piece(piece_index as usize);
+                self.check_piece_index(piece_index)?;
+                let mut bitfield = self.bitfield.lock().await;
+                if !bitfield.has_piece(piece_index as usize) {
+                    bitfield.set_piece(piece_index as usize);
+                    self.context.availability.add_piece(piece_index as usize);
+                }
+                drop(bitfield);
                 Ok(PeerMessage::Have { piece_index })
             }
             5 => {
-                let mut bitfield = Vec::new();
-                for _ in 0..length as usize {
-                    let mut byte = [0u8; 1];
-                    self.stream.read_exact(&mut byte).await?;
-                    bitfield.push(byte[0]);
-                }
-                self.bitfield = Mutex::new(Bitfield::new(bitfield.clone()));
-                Ok(PeerMessage::Bitfield {
-                    bitfield: Bitfield::new(bitfield),
-                })
+                expect_payload_length(
+                    payload_length,
+                    Bitfield::empty_with_piece_capacity(self.total_pieces)
+                        .as_bytes()
+                        .len(),
+                    "bitfield",
+                )?;
+                let mut bitfield = vec![0u8; payload_length];
+                self.stream.read_exact(&mut bitfield).await?;
+                let bitfield = Bitfield::new(bitfield);
+                self.context
+                    .availability
+                    .remove_bitfield(self.bitfield.get_mut());
+                self.context.availability.add_bitfield(&bitfield);
+                self.bitfield = Mutex::new(bitfield.clone());
+                Ok(PeerMessage::Bitfield { bitfield })
             }
             6 => {
+                expect_payload_length(payload_length, 12, "request")?;
                 let mut index_bytes = [0u8; 4];
                 let mut begin_bytes = [0u8; 4];
                 let mut length_bytes = [0u8; 4];
@@ -235,30 +357,58 @@ impl PeerConnection {
                 self.stream.read_exact(&mut begin_bytes).await?;
                 self.stream.read_exact(&mut length_bytes).await?;
 
+                let index = u32::from_be_bytes(index_bytes);
+                if !self.announced_pieces.has_piece(index as usize) {
+                    return Err(ProtocolViolation(format!(
+                        "request for piece {index}, that was not announced"
+                    ))
+                    .into());
+                }
+
                 Ok(PeerMessage::Request {
-                    index: u32::from_be_bytes(index_bytes),
+                    index,
                     begin: u32::from_be_bytes(begin_bytes),
                     length: u32::from_be_bytes(length_bytes),
                 })
             }
             7 => {
+                if payload_length < 8 {
+                    return Err(ProtocolViolation(format!(
+                        "piece message with payload of {payload_length} bytes"
+                    ))
+                    .into());
+                }
                 let mut index_bytes = [0u8; 4];
                 let mut begin_bytes = [0u8; 4];
 
                 self.stream.read_exact(&mut index_bytes).await?;
                 self.stream.read_exact(&mut begin_bytes).await?;
-
-                let block_length = length as usize - 9;
+                let index = u32::from_be_bytes(index_bytes);
+                self.check_piece_index(index)?;
+
+                // Blocks are requested with at most `MAX_BLOCK_SIZE` bytes, longer are never allocated
+                let block_length = payload_length - 8;
+                if block_length > MAX_BLOCK_SIZE {
+                    return Err(ProtocolViolation(format!(
+                        "block of {block_length} bytes is longer than requested"
+                    ))
+                    .into());
+                }
+                self.context.limiters.download.acquire(block_length).await;
                 let mut block = vec![0u8; block_length];
                 self.stream.read_exact(&mut block).await?;
+                let begin = u32::from_be_bytes(begin_bytes);
+                self.stats.on_received(message_length, block_length);
+                self.stats.on_block(index, begin);
 
                 Ok(PeerMessage::Piece {
-                    index: u32::from_be_bytes(index_bytes),
-                    begin: u32::from_be_bytes(begin_bytes),
+                    index,
+                    begin,
                     block,
                 })
             }
             8 => {
+                expect_payload_length(payload_length, 12, "cancel")?;
                 let mut index_bytes = [0u8; 4];
                 let mut begin_bytes = [0u8; 4];
                 let mut length_bytes = [0u8; 4];
@@ -273,8 +423,180 @@ impl PeerConnection {
                     length: u32::from_be_bytes(length_bytes),
                 })
             }
-            _ => anyhow::bail!("Unknown message type"),
+            21 => {
+                expect_payload_length(payload_length, HASH_REQUEST_LENGTH, "hash request")?;
+                let request = self.read_hash_request().await?;
+                // Hash requests are answered immediately, piece layers are known from metadata
+                self.answer_hash_request(request).await?;
+                Ok(PeerMessage::HashRequest { request })
+            }
+            22 => {
+                if payload_length < HASH_REQUEST_LENGTH
+                    || !(payload_length - HASH_REQUEST_LENGTH).is_multiple_of(32)
+                {
+                    return Err(ProtocolViolation(format!(
+                        "hashes message with payload of {payload_length} bytes"
+                    ))
+                    .into());
+                }
+                let request = self.read_hash_request().await?;
+                // Only answer to sent request is read, its length is limited by requested hashes and proof
+                if self.requested_hashes != Some(request) {
+                    return Err(ProtocolViolation(format!(
+                        "hashes of file {}, that were not requested",
+                        request.pieces_root
+                    ))
+                    .into());
+                }
+                let hashes_length = payload_length - HASH_REQUEST_LENGTH;
+                if hashes_length > 32 * (request.length as usize + request.proof_layers as usize) {
+                    return Err(ProtocolViolation(format!(
+                        "hashes message with {hashes_length} bytes of hashes"
+                    ))
+                    .into());
+                }
+                self.requested_hashes = None;
+                let mut hashes_bytes = vec![0u8; hashes_length];
+                self.stream.read_exact(&mut hashes_bytes).await?;
+                let hashes = hashes_bytes
+                    .chunks_exact(32)
+                    .map(Hash256::try_from)
+                    .collect::<Result<Vec<_>>>()?;
+                Ok(PeerMessage::Hashes { request, hashes })
+            }
+            23 => {
+                expect_payload_length(payload_length, HASH_REQUEST_LENGTH, "hash reject")?;
+                let request = self.read_hash_request().await?;
+                if self.requested_hashes == Some(request) {
+                    self.requested_hashes = None;
+                }
+                Ok(PeerMessage::HashReject { request })
+            }
+            msg_type => Err(ProtocolViolation(format!("unknown message type {msg_type}")).into()),
+        }
+    }
+
+    /// Read common part of hash request, hashes and hash reject messages.
+    async fn read_hash_request(&mut self) -> Result<HashRequest> {
+        let mut bytes = [0u8; HASH_REQUEST_LENGTH];
+        self.stream.read_exact(&mut bytes).await?;
+        let field = |idx: usize| {
+            let offset = 32 + idx * 4;
+            u32::from_be_bytes(bytes[offset..offset + 4].try_into().unwrap())
+        };
+        Ok(HashRequest {
+            pieces_root: Hash256::try_from(&bytes[..32])?,
+            base_layer: field(0),
+            index: field(1),
+            length: field(2),
+            proof_layers: field(3),
+        })
+    }
+
+    /// Send requested hashes from piece layer of file, or reject the request if they are not known.
+    async fn answer_hash_request(&mut self, request: HashRequest) -> Result<()> {
+        let hashes = self.context.v2.as_ref().and_then(|v2| {
+            if request.base_layer != v2.piece_layer_height() {
+                return None;
+            }
+            merkle::hashes_with_proof(
+                &v2.piece_layer(&request.pieces_root)?,
+                request.base_layer,
+                request.index as usize,
+                request.length as usize,
+                request.proof_layers as usize,
+            )
+        });
+        let message = match hashes {
+            Some(hashes) => PeerMessage::Hashes { request, hashes },
+            None => PeerMessage::HashReject { request },
+        };
+        timeout(TIMEOUT, self.send_message(message)).await?
+    }
+
+    /// Request whole piece layer of file from other peer, and store it after verification.
+    async fn request_piece_layer(&mut self, v2: &V2Metadata, pieces_root: Hash256) -> Result<()> {
+        anyhow::ensure!(self.peer_supports_v2, "Peer doesn't support v2 protocol");
+        let file = v2
+            .files
+            .iter()
+            .find(|file| file.pieces_root == Some(pieces_root))
+            .context("Unknown pieces root")?;
+        let piece_count = file.length.div_ceil(v2.piece_length) as usize;
+        let request = HashRequest {
+            pieces_root,
+            base_layer: v2.piece_layer_height(),
+            index: 0,
+            length: piece_count.next_power_of_two().max(2) as u32,
+            proof_layers: 0,
+        };
+        self.requested_hashes = Some(request);
+        timeout(
+            TIMEOUT,
+            self.send_message(PeerMessage::HashRequest { request }),
+        )
+        .await??;
+
+        loop {
+            match timeout(Duration::from_secs(60), self.receive_message()).await?? {
+                PeerMessage::Hashes {
+                    request: answered,
+                    hashes,
+                } if answered == request => {
+                    if !merkle::verify_hashes(
+                        &pieces_root,
+                        request.base_layer,
+                        0,
+                        request.length as usize,
+                        &hashes,
+                    ) {
+                        return Err(ProtocolViolation(format!(
+                            "hashes of file {pieces_root} don't match its root"
+                        ))
+                        .into());
+                    }
+                    return v2.set_piece_layer(pieces_root, hashes[..piece_count].to_vec());
+                }
+                PeerMessage::HashReject { request: rejected } if rejected == request => {
+                    anyhow::bail!("Peer rejected request for hashes of file {pieces_root}");
+                }
+                _ => continue,
+            }
+        }
+    }
+
+    /// Verify downloaded piece with SHA-1 hash from v1 metadata and merkle tree from v2 metadata.
+    /// Hybrid torrents have to pass both checks.
+    async fn verify_piece(&mut self, piece: &Piece, data: &[u8]) -> Result<bool> {
+        if self.context.version != MetaVersion::V2
+            && PieceHash::from_bytes(Sha1::digest(data).into()) != piece.hash()
+        {
+            return Ok(false);
+        }
+        let Some(v2) = self.context.v2.clone() else {
+            return Ok(true);
+        };
+        if let Some(valid) = v2.verify_piece(piece.index(), data) {
+            return Ok(valid);
+        }
+
+        // Piece layer is missing in metadata, so it is requested from this peer
+        let pieces_root = v2
+            .file_of_piece(piece.index())
+            .and_then(|file| file.pieces_root)
+            .context("Piece is not in any file")?;
+        self.request_piece_layer(&v2, pieces_root).await?;
+        Ok(v2.verify_piece(piece.index(), data).unwrap_or(false))
+    }
+
+    /// Check that piece index received from other peer exists in downloaded torrent.
+    fn check_piece_index(&self, piece_index: u32) -> Result<()> {
+        if piece_index as usize >= self.total_pieces {
+            return Err(
+                ProtocolViolation(format!("piece index {piece_index} out of range")).into(),
+            );
         }
+        Ok(())
     }
 
     /// Download one piece with given index from other peer.
@@ -296,10 +618,21 @@ impl PeerConnection {
 
         // Download piece in blocks
         let mut piece_data = Vec::new();
+        let piece_offset = self.context.layout.piece_offset(piece_index);
         for block_offset in (0..piece_length).step_by(MAX_BLOCK_SIZE) {
             let remaining = piece.length() - block_offset;
             let this_block_len = MAX_BLOCK_SIZE.min(remaining);
 
+            // Padding is zeros, so it is not requested
+            if self
+                .context
+                .layout
+                .is_padding(piece_offset + block_offset as u64, this_block_len)
+            {
+                piece_data.resize(piece_data.len() + this_block_len, 0);
+                continue;
+            }
+
             // Sending request for block
             timeout(
                 TIMEOUT,
@@ -334,10 +667,25 @@ impl PeerConnection {
             }
         }
 
-        /* TODO: Check piece hash and compare it, to verify that the downloaded piece is correct */
+        // Check piece hash, to verify that the downloaded piece is correct
+        // All blocks of piece were received from this peer, so it's blamed directly
+        if !self.verify_piece(&piece, &piece_data).await? {
+            self.context
+                .peer_scores
+                .lock()
+                .await
+                .piece_failed(self.addr.ip());
+            anyhow::bail!("Piece {piece_index} failed hash check");
+        }
+        self.context
+            .peer_scores
+            .lock()
+            .await
+            .piece_verified(self.addr.ip());
 
         // Send whole downloaded piece to writer
-        self.piece_channel
+        self.context
+            .piece_channel
             .send(PieceData {
                 piece_idx: piece.index(),
                 data: piece_data,
@@ -347,35 +695,76 @@ impl PeerConnection {
     }
 }
 
+impl Drop for PeerConnection {
+    fn drop(&mut self) {
+        self.context.peer_stats.remove(&self.addr);
+        self.context
+            .availability
+            .remove_bitfield(self.bitfield.get_mut());
+    }
+}
+
 /// Function that manage downloading pieces that are not downloaded, if peer give us information that it has this piece.
-pub async fn downloading_pieces_from_pear(
-    stream: TcpStream,
-    info_hash: [u8; 20],
-    peer_id: [u8; 20],
-    piece_count: usize,
-    sender: Sender<PieceData>,
-    piece_pool: Arc<Mutex<HashMap<usize, Piece>>>,
-    pieces_downloaded: Arc<AtomicUsize>,
-) -> Result<()> {
-    let pool = piece_pool.clone();
-    let mut peer_conncetion =
-        PeerConnection::new(stream, info_hash, peer_id, piece_count, sender, piece_pool).await?;
+/// Piece taken from pool by connection. It's put back to pool, unless it was downloaded,
+/// also when connection is dropped in the middle of download (e.g. torrent was paused).
+struct TakenPiece {
+    pool: Arc<Mutex<PiecePool>>,
+    index: usize,
+    downloaded: bool,
+}
+
+impl TakenPiece {
+    fn new(pool: Arc<Mutex<PiecePool>>, index: usize) -> Self {
+        TakenPiece {
+            pool,
+            index,
+            downloaded: false,
+        }
+    }
+}
+
+impl Drop for TakenPiece {
+    fn drop(&mut self) {
+        if self.downloaded {
+            return;
+        }
+        if let Ok(mut pool) = self.pool.try_lock() {
+            pool.put_back(self.index);
+        } else if let Ok(runtime) = tokio::runtime::Handle::try_current() {
+            let (pool, index) = (self.pool.clone(), self.index);
+            runtime.spawn(async move { pool.lock().await.put_back(index) });
+        }
+    }
+}
+
+pub async fn downloading_pieces_from_pear(mut peer_conncetion: PeerConnection) -> Result<()> {
+    let pool = peer_conncetion.context.piece_pool.clone();
+    peer_conncetion.try_get_bitfield().await?;
 
     // Loop while there is at least one undownloaded piece.
     loop {
-        let pieces_have = peer_conncetion.bitfield.lock().await.clone();
-        for piece_idx in pieces_have.pieces() {
-            let mut lock_pool = pool.lock().await;
-            if let Some(piece) = lock_pool.remove(&piece_idx) {
-                drop(lock_pool);
-                match peer_conncetion.download_piece(piece.clone()).await {
-                    Ok(()) => {
-                        pieces_downloaded.fetch_add(1, std::sync::atomic::Ordering::SeqCst);
-                    }
-                    Err(_) => {
-                        pool.lock().await.insert(piece_idx, piece);
-                    }
+        // Pool gives pieces of this peer in order of pick mode and file priorities
+        loop {
+            let pieces_have = peer_conncetion.bitfield.lock().await.clone();
+            let Some(piece) = pool.lock().await.take(&pieces_have) else {
+                break;
+            };
+            let mut taken = TakenPiece::new(pool.clone(), piece.index());
+            if peer_conncetion.download_piece(piece).await.is_ok() {
+                taken.downloaded = true;
+            } else {
+                drop(taken);
+                let ip = peer_conncetion.addr.ip();
+                if peer_conncetion
+                    .context
+                    .peer_scores
+                    .lock()
+                    .await
+                    .is_banned(&ip)
+                {
+                    anyhow::bail!("Peer {ip} was banned");
                 }
+                break;
             }
         }
         // Try to get information, that this peer has new piece
@@ -395,11 +784,128 @@ pub async fn downloading_pieces_from_pear(
             }
         }
 
-        // End if all pieces are downloaded
-        if pieces_downloaded.load(std::sync::atomic::Ordering::SeqCst) == piece_count {
+        // End if all wanted pieces are downloaded
+        if pool.lock().await.is_complete() {
             break;
         }
     }
 
     Ok(())
 }
+
+/// Upload complete pieces to peer, until it disconnects or stays idle for too long.
+/// Peer is unchoked, when it's interested. Blocks are read from storage of torrent.
+pub async fn seeding_to_peer(mut peer_connection: PeerConnection) -> Result<()> {
+    let bitfield = peer_connection
+        .context
+        .piece_pool
+        .lock()
+        .await
+        .complete_pieces();
+    peer_connection.announced_pieces = bitfield.clone();
+    timeout(
+        TIMEOUT,
+        peer_connection.send_message(PeerMessage::Bitfield { bitfield }),
+    )
+    .await??;
+
+    let mut choking = true;
+    loop {
+        let message = timeout(SEED_IDLE_TIMEOUT, peer_connection.receive_message())
+            .await
+            .context("Peer is idle")??;
+        match message {
+            PeerMessage::Interested if choking => {
+                peer_connection.send_message(PeerMessage::Unchoke).await?;
+                choking = false;
+            }
+            PeerMessage::NotInterested if !choking => {
+                peer_connection.send_message(PeerMessage::Choke).await?;
+                choking = true;
+            }
+            // Requests sent before peer was unchoked are dropped
+            PeerMessage::Request {
+                index,
+                begin,
+                length,
+            } if !choking => {
+                let piece_length = peer_connection.context.layout.piece_size(index as usize);
+                if length == 0
+                    || length > MAX_REQUEST_LENGTH
+                    || begin as usize + length as usize > piece_length
+                {
+                    return Err(ProtocolViolation(format!(
+                        "request of {length} bytes at {begin} in piece {index}"
+                    ))
+                    .into());
+                }
+                let block = peer_connection
+                    .context
+                    .storage
+                    .read_block(index as usize, begin as usize, length as usize)
+                    .await?;
+                peer_connection
+                    .send_message(PeerMessage::Piece {
+                        index,
+                        begin,
+                        block,
+                    })
+                    .await?;
+            }
+            _ => {}
+        }
+    }
+}
+
+/// Handshake of this client for torrent.
+fn local_handshake(context: &TorrentContext) -> Handshake {
+    let mut handshake = Handshake::new(&context.info_hash.to_arr(), &context.peer_id);
+    if context.v2.is_some() {
+        handshake.set_v2_support();
+    }
+    handshake
+}
+
+/// Read handshake of other peer and check its protocol.
+/// Peer, which connected to this client, is routed to torrent by info hash from its handshake.
+pub async fn read_handshake(stream: &mut TcpStream) -> Result<Handshake> {
+    let mut bytes = [0u8; HANDSHAKE_LENGTH];
+    timeout(TIMEOUT, stream.read_exact(&mut bytes)).await??;
+
+    let mut handshake = Handshake::new(&[0; 20], &[0; 20]);
+    handshake.set_bytes(&bytes);
+    anyhow::ensure!(handshake.length == 19);
+    anyhow::ensure!(handshake.bittorrent == BITTORRENT_PROTOCOL);
+    Ok(handshake)
+}
+
+/// Send handshake of this client to other peer.
+async fn send_handshake(stream: &mut TcpStream, handshake: &Handshake) -> Result<()> {
+    let _ = timeout(TIMEOUT, stream.write_all(&handshake.get_bytes()))
+        .await
+        .context("Failed to write handshake")?;
+    let _ = timeout(TIMEOUT, stream.flush())
+        .await
+        .context("Failed to flush handshake")?;
+    Ok(())
+}
+
+/// Append common part of hash messages to payload.
+fn extend_hash_request(payload: &mut Vec<u8>, request: &HashRequest) {
+    payload.extend_from_slice(request.pieces_root.as_bytes());
+    payload.extend_from_slice(&request.base_layer.to_be_bytes());
+    payload.extend_from_slice(&request.index.to_be_bytes());
+    payload.extend_from_slice(&request.length.to_be_bytes());
+    payload.extend_from_slice(&request.proof_layers.to_be_bytes());
+}
+
+/// Returns `ProtocolViolation` error, if message payload doesn't have expected length.
+fn expect_payload_length(length: usize, expected: usize, message: &str) -> Result<()> {
+    if length != expected {
+        return Err(ProtocolViolation(format!(
+            "{message} message with payload of {length} bytes, expected {expected}"
+        ))
+        .into());
+    }
+    Ok(())
+}
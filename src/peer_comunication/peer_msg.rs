@@ -1,5 +1,18 @@
+use crate::hash::Hash256;
 use crate::peer_comunication::bitfield::Bitfield;
 
+/// Range of hashes in merkle tree of one file, used by hash messages of BitTorrent v2 (BEP 52).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct HashRequest {
+    pub pieces_root: Hash256,
+    /// Layer of tree, from which hashes are requested, `0` is layer of 16 KiB blocks.
+    pub base_layer: u32,
+    pub index: u32,
+    pub length: u32,
+    /// Number of uncle hashes, which should be sent to verify requested hashes.
+    pub proof_layers: u32,
+}
+
 /// Enum representing P2P bittorent message.
 #[derive(Debug)]
 pub enum PeerMessage {
@@ -28,4 +41,14 @@ pub enum PeerMessage {
         begin: u32,
         length: u32,
     },
+    HashRequest {
+        request: HashRequest,
+    },
+    Hashes {
+        request: HashRequest,
+        hashes: Vec<Hash256>,
+    },
+    HashReject {
+        request: HashRequest,
+    },
 }
@@ -0,0 +1,275 @@
+use std::collections::HashMap;
+use std::fmt::Display;
+use std::net::IpAddr;
+use std::path::PathBuf;
+
+use anyhow::Result;
+
+/// Trust score of peer, that was not seen yet.
+const INITIAL_TRUST: i32 = 0;
+/// Trust added for every piece, that passed hash check.
+const GOOD_PIECE_REWARD: i32 = 1;
+/// Trust removed for every piece, that failed hash check.
+const HASH_FAILURE_PENALTY: i32 = 10;
+/// Trust removed for every protocol violation.
+const PROTOCOL_VIOLATION_PENALTY: i32 = 20;
+/// Number of failed pieces after which peer is banned.
+const MAX_HASH_FAILURES: u32 = 3;
+/// Number of protocol violations after which peer is banned.
+const MAX_PROTOCOL_VIOLATIONS: u32 = 3;
+
+/// Error returned from peer connection, when other peer breaks bittorent protocol.
+#[derive(Debug, Clone)]
+pub struct ProtocolViolation(pub String);
+
+impl Display for ProtocolViolation {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Protocol violation: {}", self.0)
+    }
+}
+
+impl std::error::Error for ProtocolViolation {}
+
+/// Reason why peer was banned.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum BanReason {
+    /// Peer sent data of pieces, that failed hash check.
+    HashFailures,
+    /// Peer repeatedly breaks bittorent protocol.
+    ProtocolViolations,
+    /// Ban was loaded from ban file, which doesn't contain the reason.
+    Persisted,
+}
+
+impl BanReason {
+    /// Returns name of reason in ban file.
+    fn key(&self) -> &'static str {
+        match self {
+            BanReason::HashFailures => "hash_failures",
+            BanReason::ProtocolViolations => "protocol_violations",
+            BanReason::Persisted => "persisted",
+        }
+    }
+
+    /// Parse reason from ban file, returns `None` for unknown reason.
+    fn from_key(key: &str) -> Option<Self> {
+        [
+            BanReason::HashFailures,
+            BanReason::ProtocolViolations,
+            BanReason::Persisted,
+        ]
+        .into_iter()
+        .find(|reason| reason.key() == key)
+    }
+}
+
+impl Display for BanReason {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let reason = match self {
+            BanReason::HashFailures => "hash failures",
+            BanReason::ProtocolViolations => "protocol violations",
+            BanReason::Persisted => "persisted",
+        };
+        write!(f, "{}", reason)
+    }
+}
+
+/// Statistics about behaviour of one peer.
+#[derive(Debug, Clone, Default)]
+pub struct PeerScore {
+    pub trust: i32,
+    pub good_pieces: u32,
+    pub hash_failures: u32,
+    pub protocol_violations: u32,
+}
+
+/// Structure that keeps trust score of peers, and decides which peers should be banned.
+/// Every piece is downloaded whole by one connection, also time critical pieces raced by more peers
+/// are downloaded whole by each of them. So the peer which sent failed piece is always known exactly.
+pub struct PeerScores {
+    scores: HashMap<IpAddr, PeerScore>,
+    banned: HashMap<IpAddr, BanReason>,
+    ban_file: Option<PathBuf>,
+    /// Malformed lines of ban file, that were skipped.
+    invalid_lines: usize,
+}
+
+impl PeerScores {
+    /// Creates empty score table, without persisted bans.
+    pub fn new() -> Self {
+        PeerScores {
+            scores: HashMap::new(),
+            banned: HashMap::new(),
+            ban_file: None,
+            invalid_lines: 0,
+        }
+    }
+
+    /// Creates score table, that persists bans to given file.
+    /// Bans already stored in the file are loaded, every line has IP address and optional reason.
+    /// Malformed lines are skipped, so one bad line doesn't stop the client, see `invalid_lines`.
+    pub fn with_ban_file(ban_file: PathBuf) -> Result<Self> {
+        let mut scores = PeerScores::new();
+        if ban_file.exists() {
+            for line in std::fs::read_to_string(&ban_file)?.lines() {
+                let line = line.trim();
+                if line.is_empty() || line.starts_with('#') {
+                    continue;
+                }
+                match parse_ban(line) {
+                    Some((ip, reason)) => {
+                        scores.banned.insert(ip, reason);
+                    }
+                    None => scores.invalid_lines += 1,
+                }
+            }
+        }
+        scores.ban_file = Some(ban_file);
+        Ok(scores)
+    }
+
+    /// Returns if peer with given IP address is banned.
+    pub fn is_banned(&self, ip: &IpAddr) -> bool {
+        self.banned.contains_key(ip)
+    }
+
+    /// Returns all banned IP addresses, together with reason of ban.
+    pub fn banned(&self) -> impl Iterator<Item = (&IpAddr, &BanReason)> {
+        self.banned.iter()
+    }
+
+    /// Returns number of malformed ban file lines, that were skipped.
+    pub fn invalid_lines(&self) -> usize {
+        self.invalid_lines
+    }
+
+    /// Returns score of peer with given IP address.
+    pub fn score(&self, ip: &IpAddr) -> PeerScore {
+        self.scores.get(ip).cloned().unwrap_or(PeerScore {
+            trust: INITIAL_TRUST,
+            ..Default::default()
+        })
+    }
+
+    /// Record piece that passed hash check, it was received whole from peer with given IP address.
+    pub fn piece_verified(&mut self, ip: IpAddr) {
+        let score = self.scores.entry(ip).or_default();
+        score.good_pieces += 1;
+        score.trust += GOOD_PIECE_REWARD;
+    }
+
+    /// Record piece that failed hash check, it was received whole from peer with given IP address.
+    /// Peer is banned after repeated failures.
+    pub fn piece_failed(&mut self, ip: IpAddr) {
+        let score = self.scores.entry(ip).or_default();
+        score.hash_failures += 1;
+        score.trust -= HASH_FAILURE_PENALTY;
+        if score.hash_failures >= MAX_HASH_FAILURES {
+            self.ban(ip, BanReason::HashFailures);
+        }
+    }
+
+    /// Record protocol violation of peer with given IP address.
+    pub fn protocol_violation(&mut self, ip: IpAddr) {
+        let score = self.scores.entry(ip).or_default();
+        score.protocol_violations += 1;
+        score.trust -= PROTOCOL_VIOLATION_PENALTY;
+        if score.protocol_violations >= MAX_PROTOCOL_VIOLATIONS {
+            self.ban(ip, BanReason::ProtocolViolations);
+        }
+    }
+
+    /// Ban given IP address, and store the ban to ban file if it is set.
+    fn ban(&mut self, ip: IpAddr, reason: BanReason) {
+        if self.banned.insert(ip, reason).is_some() {
+            return;
+        }
+        // Failing to persist the ban is not fatal, ban is still active for this run
+        let _ = self.save();
+    }
+
+    /// Store all bans to ban file, one IP address with reason of ban per line.
+    fn save(&self) -> Result<()> {
+        let Some(ban_file) = &self.ban_file else {
+            return Ok(());
+        };
+        let content: String = self
+            .banned
+            .iter()
+            .map(|(ip, reason)| format!("{ip} {}\n", reason.key()))
+            .collect();
+        std::fs::write(ban_file, content)?;
+        Ok(())
+    }
+}
+
+/// Parse line of ban file `<ip> [reason]`, ban without reason is `BanReason::Persisted`.
+fn parse_ban(line: &str) -> Option<(IpAddr, BanReason)> {
+    let mut parts = line.split_whitespace();
+    let ip = parts.next()?.parse().ok()?;
+    let reason = match parts.next() {
+        Some(key) => BanReason::from_key(key)?,
+        None => BanReason::Persisted,
+    };
+    if parts.next().is_some() {
+        return None;
+    }
+    Some((ip, reason))
+}
+
+impl Default for PeerScores {
+    fn default() -> Self {
+        PeerScores::new()
+    }
+}
+
+#[test]
+fn peer_scores_ban_after_hash_failures() {
+    let ip: IpAddr = [10, 0, 0, 1].into();
+    let mut scores = PeerScores::new();
+    scores.piece_verified(ip);
+    assert_eq!(scores.score(&ip).trust, GOOD_PIECE_REWARD);
+    for _ in 0..MAX_HASH_FAILURES {
+        assert!(!scores.is_banned(&ip));
+        scores.piece_failed(ip);
+    }
+    assert!(scores.is_banned(&ip));
+    assert_eq!(
+        scores.banned().next(),
+        Some((&ip, &BanReason::HashFailures))
+    );
+}
+
+#[test]
+fn peer_scores_ban_file() {
+    let dir = tempfile::tempdir().unwrap();
+    let ban_file = dir.path().join("bans.txt");
+    std::fs::write(
+        &ban_file,
+        "# bans\n10.0.0.1\n10.0.0.2 protocol_violations\nnot-an-ip\n10.0.0.3 bored\n",
+    )
+    .unwrap();
+
+    // Malformed lines are skipped, old lines without reason are still loaded
+    let mut scores = PeerScores::with_ban_file(ban_file.clone()).unwrap();
+    assert_eq!(scores.invalid_lines(), 2);
+    assert_eq!(scores.banned().count(), 2);
+    let old: IpAddr = [10, 0, 0, 1].into();
+    let violator: IpAddr = [10, 0, 0, 2].into();
+    assert!(scores.is_banned(&old));
+
+    // Reasons of bans survive restart
+    let cheater: IpAddr = [10, 0, 0, 4].into();
+    for _ in 0..MAX_HASH_FAILURES {
+        scores.piece_failed(cheater);
+    }
+    let scores = PeerScores::with_ban_file(ban_file).unwrap();
+    assert_eq!(scores.invalid_lines(), 0);
+    let banned: HashMap<IpAddr, BanReason> = scores
+        .banned()
+        .map(|(ip, reason)| (*ip, reason.clone()))
+        .collect();
+    assert_eq!(banned[&old], BanReason::Persisted);
+    assert_eq!(banned[&violator], BanReason::ProtocolViolations);
+    assert_eq!(banned[&cheater], BanReason::HashFailures);
+}
@@ -0,0 +1,288 @@
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::time::{Duration, Instant};
+
+use crate::peer_id::PeerId;
+
+/// Time constant of moving average of transfer rates.
+const RATE_TIME_CONSTANT: Duration = Duration::from_secs(5);
+/// Weight of newest sample in moving average of request latency.
+const LATENCY_WEIGHT: f64 = 0.2;
+/// Peer is snubbed, if it doesn't send any requested data for this time.
+const SNUB_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// Direction in which the connection with peer was opened.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ConnectionDirection {
+    /// This client connected to other peer.
+    Outgoing,
+    /// Other peer connected to this client.
+    Incoming,
+}
+
+/// Counter of transferred bytes with moving average of transfer rate.
+#[derive(Debug, Clone, Default)]
+struct RateCounter {
+    total: u64,
+    since_update: u64,
+    rate: f64,
+}
+
+impl RateCounter {
+    fn add(&mut self, bytes: usize) {
+        self.total += bytes as u64;
+        self.since_update += bytes as u64;
+    }
+
+    /// Update exponential moving average with bytes transferred in last `elapsed` time.
+    fn update(&mut self, elapsed: Duration) {
+        if elapsed.is_zero() {
+            return;
+        }
+        let weight = 1.0 - (-elapsed.as_secs_f64() / RATE_TIME_CONSTANT.as_secs_f64()).exp();
+        let current = self.since_update as f64 / elapsed.as_secs_f64();
+        self.rate += (current - self.rate) * weight;
+        self.since_update = 0;
+    }
+}
+
+/// Mutable statistics of one connection.
+struct StatsState {
+    payload_down: RateCounter,
+    payload_up: RateCounter,
+    protocol_down: RateCounter,
+    protocol_up: RateCounter,
+    pending_requests: HashMap<(u32, u32), Instant>,
+    latency: Option<Duration>,
+    last_block: Instant,
+    last_update: Instant,
+}
+
+/// Transfer statistics of one peer connection.
+/// Updated by peer connection, read by other components through `snapshot`.
+pub struct PeerStats {
+    addr: SocketAddr,
+    peer_id: [u8; 20],
+    direction: ConnectionDirection,
+    connected_at: Instant,
+    state: Mutex<StatsState>,
+}
+
+/// Copy of peer statistics in one moment. Rates are in bytes per second.
+#[derive(Debug, Clone)]
+pub struct PeerStatsSnapshot {
+    pub addr: SocketAddr,
+    pub peer_id: [u8; 20],
+    pub client: String,
+    pub direction: ConnectionDirection,
+    pub connected_for: Duration,
+    pub payload_downloaded: u64,
+    pub payload_uploaded: u64,
+    pub protocol_downloaded: u64,
+    pub protocol_uploaded: u64,
+    pub download_rate: f64,
+    pub upload_rate: f64,
+    pub outstanding_requests: usize,
+    pub request_latency: Option<Duration>,
+    pub snubbed: bool,
+}
+
+impl PeerStats {
+    /// Creates empty statistics of connection with given peer.
+    pub fn new(addr: SocketAddr, peer_id: [u8; 20], direction: ConnectionDirection) -> Self {
+        let now = Instant::now();
+        PeerStats {
+            addr,
+            peer_id,
+            direction,
+            connected_at: now,
+            state: Mutex::new(StatsState {
+                payload_down: RateCounter::default(),
+                payload_up: RateCounter::default(),
+                protocol_down: RateCounter::default(),
+                protocol_up: RateCounter::default(),
+                pending_requests: HashMap::new(),
+                latency: None,
+                last_block: now,
+                last_update: now,
+            }),
+        }
+    }
+
+    /// Record received message, `payload` is size of piece data in it, `total` is size of whole message.
+    pub fn on_received(&self, total: usize, payload: usize) {
+        let mut state = self.lock_state();
+        state.payload_down.add(payload);
+        state.protocol_down.add(total - payload);
+    }
+
+    /// Record sent message, `payload` is size of piece data in it, `total` is size of whole message.
+    pub fn on_sent(&self, total: usize, payload: usize) {
+        let mut state = self.lock_state();
+        state.payload_up.add(payload);
+        state.protocol_up.add(total - payload);
+    }
+
+    /// Record sent request for block.
+    pub fn on_request(&self, index: u32, begin: u32) {
+        let mut state = self.lock_state();
+        if state.pending_requests.is_empty() {
+            // Snub timeout is measured from the first request, not from last block
+            state.last_block = Instant::now();
+        }
+        state
+            .pending_requests
+            .insert((index, begin), Instant::now());
+    }
+
+    /// Record received block, updates request latency if the block was requested.
+    pub fn on_block(&self, index: u32, begin: u32) {
+        let mut state = self.lock_state();
+        let now = Instant::now();
+        state.last_block = now;
+        if let Some(requested_at) = state.pending_requests.remove(&(index, begin)) {
+            let sample = now.duration_since(requested_at);
+            state.latency = Some(match state.latency {
+                Some(latency) => {
+                    latency.mul_f64(1.0 - LATENCY_WEIGHT) + sample.mul_f64(LATENCY_WEIGHT)
+                }
+                None => sample,
+            });
+        }
+    }
+
+    /// Forget all outstanding requests, for example after choke.
+    pub fn clear_requests(&self) {
+        self.lock_state().pending_requests.clear();
+    }
+
+    /// Returns copy of current statistics, moving averages of rates are updated.
+    pub fn snapshot(&self) -> PeerStatsSnapshot {
+        let mut state = self.lock_state();
+        let now = Instant::now();
+        let elapsed = now.duration_since(state.last_update);
+        state.payload_down.update(elapsed);
+        state.payload_up.update(elapsed);
+        state.protocol_down.update(elapsed);
+        state.protocol_up.update(elapsed);
+        state.last_update = now;
+
+        PeerStatsSnapshot {
+            addr: self.addr,
+            peer_id: self.peer_id,
+            client: PeerId::from(self.peer_id).client_label(),
+            direction: self.direction,
+            connected_for: now.duration_since(self.connected_at),
+            payload_downloaded: state.payload_down.total,
+            payload_uploaded: state.payload_up.total,
+            protocol_downloaded: state.protocol_down.total,
+            protocol_uploaded: state.protocol_up.total,
+            download_rate: state.payload_down.rate,
+            upload_rate: state.payload_up.rate,
+            outstanding_requests: state.pending_requests.len(),
+            request_latency: state.latency,
+            snubbed: !state.pending_requests.is_empty()
+                && now.duration_since(state.last_block) > SNUB_TIMEOUT,
+        }
+    }
+
+    fn lock_state(&self) -> MutexGuard<'_, StatsState> {
+        self.state.lock().unwrap_or_else(|err| err.into_inner())
+    }
+}
+
+/// Statistics of all connected peers of one torrent.
+/// Payload transferred by already disconnected peers is kept in totals.
+#[derive(Default)]
+pub struct PeerStatsRegistry {
+    peers: Mutex<HashMap<SocketAddr, Arc<PeerStats>>>,
+    closed_totals: Mutex<(u64, u64)>,
+}
+
+impl PeerStatsRegistry {
+    /// Creates empty registry.
+    pub fn new() -> Self {
+        PeerStatsRegistry::default()
+    }
+
+    /// Register new connection, and returns its statistics.
+    pub fn register(
+        &self,
+        addr: SocketAddr,
+        peer_id: [u8; 20],
+        direction: ConnectionDirection,
+    ) -> Arc<PeerStats> {
+        let stats = Arc::new(PeerStats::new(addr, peer_id, direction));
+        lock(&self.peers).insert(addr, stats.clone());
+        stats
+    }
+
+    /// Remove closed connection, its transferred payload is added to totals.
+    pub fn remove(&self, addr: &SocketAddr) {
+        if let Some(stats) = lock(&self.peers).remove(addr) {
+            let snapshot = stats.snapshot();
+            let mut closed_totals = lock(&self.closed_totals);
+            closed_totals.0 += snapshot.payload_downloaded;
+            closed_totals.1 += snapshot.payload_uploaded;
+        }
+    }
+
+    /// Add payload transferred before this registry was created, e.g. before restart of client.
+    pub fn add_closed_totals(&self, downloaded: u64, uploaded: u64) {
+        let mut closed_totals = lock(&self.closed_totals);
+        closed_totals.0 += downloaded;
+        closed_totals.1 += uploaded;
+    }
+
+    /// Returns statistics of all connected peers.
+    pub fn snapshot(&self) -> Vec<PeerStatsSnapshot> {
+        let peers: Vec<_> = lock(&self.peers).values().cloned().collect();
+        peers.iter().map(|stats| stats.snapshot()).collect()
+    }
+
+    /// Returns total downloaded and uploaded payload bytes of all connections, including closed ones.
+    pub fn totals(&self) -> (u64, u64) {
+        let (mut downloaded, mut uploaded) = *lock(&self.closed_totals);
+        for snapshot in self.snapshot() {
+            downloaded += snapshot.payload_downloaded;
+            uploaded += snapshot.payload_uploaded;
+        }
+        (downloaded, uploaded)
+    }
+}
+
+/// Lock mutex, poisoned mutex is still used, because statistics are not critical.
+fn lock<T>(mutex: &Mutex<T>) -> MutexGuard<'_, T> {
+    mutex.lock().unwrap_or_else(|err| err.into_inner())
+}
+
+#[test]
+fn peer_stats_counters() {
+    let addr = SocketAddr::from(([127, 0, 0, 1], 6881));
+    let registry = PeerStatsRegistry::new();
+    let stats = registry.register(
+        addr,
+        *b"-qB4500-abcdefghijkl",
+        ConnectionDirection::Outgoing,
+    );
+
+    stats.on_request(0, 0);
+    stats.on_received(13 + 1024, 1024);
+    stats.on_block(0, 0);
+    stats.on_sent(17, 0);
+    stats.on_request(0, 1024);
+
+    let snapshot = &registry.snapshot()[0];
+    assert_eq!(snapshot.client, "qBittorrent 4.5.0");
+    assert_eq!(snapshot.payload_downloaded, 1024);
+    assert_eq!(snapshot.protocol_downloaded, 13);
+    assert_eq!(snapshot.protocol_uploaded, 17);
+    assert_eq!(snapshot.outstanding_requests, 1);
+    assert!(snapshot.request_latency.is_some());
+    assert!(!snapshot.snubbed);
+
+    registry.remove(&addr);
+    assert!(registry.snapshot().is_empty());
+    assert_eq!(registry.totals(), (1024, 0));
+}
@@ -5,9 +5,92 @@ use rand::{thread_rng, Rng};
 /// Prefix defined by me for peer-id of peers using my torrent client
 const CLIENT_PREFIX: &[u8] = b"-PVR001-";
 
+/// Known two letter client codes used in Azureus-style peer-ids (`-XX1234-`).
+const AZUREUS_CLIENTS: &[(&str, &str)] = &[
+    ("7T", "aTorrent"),
+    ("AG", "Ares"),
+    ("AZ", "Vuze"),
+    ("BB", "BitBuddy"),
+    ("BC", "BitComet"),
+    ("BF", "Bitflu"),
+    ("BI", "BiglyBT"),
+    ("BT", "BitTorrent"),
+    ("BW", "BitWombat"),
+    ("CD", "Enhanced CTorrent"),
+    ("DE", "Deluge"),
+    ("FD", "Free Download Manager"),
+    ("FW", "FrostWire"),
+    ("FX", "Freebox BitTorrent"),
+    ("HL", "Halite"),
+    ("KG", "KGet"),
+    ("KT", "KTorrent"),
+    ("LH", "LH-ABC"),
+    ("LT", "libtorrent"),
+    ("lt", "libTorrent"),
+    ("LW", "LimeWire"),
+    ("MO", "MonoTorrent"),
+    ("MR", "Miro"),
+    ("PB", "Picotorrent"),
+    ("PI", "PicoTorrent"),
+    ("PT", "PopcornTime"),
+    ("qB", "qBittorrent"),
+    ("QD", "QQDownload"),
+    ("RT", "Retriever"),
+    ("SD", "Thunder"),
+    ("SZ", "Shareaza"),
+    ("TB", "Torch"),
+    ("TL", "Tribler"),
+    ("TR", "Transmission"),
+    ("TS", "Torrentstorm"),
+    ("TT", "TuoTu"),
+    ("UL", "uLeecher!"),
+    ("UM", "uTorrent for Mac"),
+    ("UT", "uTorrent"),
+    ("UW", "uTorrent Web"),
+    ("VG", "Vagaa"),
+    ("WD", "WebTorrent Desktop"),
+    ("WT", "BitLet"),
+    ("WW", "WebTorrent"),
+    ("XL", "Xunlei"),
+    ("XT", "XanTorrent"),
+    ("ZT", "ZipTorrent"),
+];
+
+/// Known one letter client codes used in Shadow-style peer-ids (`S58B-----`).
+const SHADOW_CLIENTS: &[(u8, &str)] = &[
+    (b'A', "ABC"),
+    (b'O', "Osprey Permaseed"),
+    (b'Q', "BTQueue"),
+    (b'R', "Tribler"),
+    (b'S', "Shadow's client"),
+    (b'T', "BitTornado"),
+    (b'U', "UPnP NAT Bit Torrent"),
+];
+
+/// Known one letter client codes used in Mainline-style peer-ids (`M4-3-6--`).
+const MAINLINE_CLIENTS: &[(u8, &str)] = &[(b'M', "Mainline"), (b'Q', "Queen Bee")];
+
 /// Structure that represents peer-id, which is used as idetificator in torrent protocol comunication.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
 pub struct PeerId {
-    bytes: Vec<u8>,
+    bytes: [u8; 20],
+}
+
+/// Client software and its version, decoded from peer-id.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ClientInfo {
+    pub name: String,
+    pub version: String,
+}
+
+impl Display for ClientInfo {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        if self.version.is_empty() {
+            write!(f, "{}", self.name)
+        } else {
+            write!(f, "{} {}", self.name, self.version)
+        }
+    }
 }
 
 impl PeerId {
@@ -16,28 +99,172 @@ impl PeerId {
         let mut rng = thread_rng();
 
         // Generate a 20-byte peer ID following the convention:
-        let mut peer_id = Vec::with_capacity(20);
+        let mut peer_id = [0u8; 20];
 
         // Add client prefix
-        peer_id.extend_from_slice(CLIENT_PREFIX);
+        peer_id[..CLIENT_PREFIX.len()].copy_from_slice(CLIENT_PREFIX);
 
         // Generate remaining random bytes
-        while peer_id.len() < 20 {
+        for byte in peer_id.iter_mut().skip(CLIENT_PREFIX.len()) {
             // Use a mix of alphanumeric characters
-            let random_char = rng.gen_range(b'a'..=b'z');
-            peer_id.push(random_char);
+            *byte = rng.gen_range(b'a'..=b'z');
         }
 
-        // Ensure exactly 20 bytes
-        assert_eq!(peer_id.len(), 20, "Peer ID must be exactly 20 bytes");
-
         PeerId { bytes: peer_id }
     }
+
+    /// Creates peer-id from 20 bytes, for example received in handshake.
+    pub fn from_bytes(bytes: [u8; 20]) -> Self {
+        PeerId { bytes }
+    }
+
+    /// Decode client software and version from peer-id.
+    /// Azureus-style, Shadow-style and Mainline-style conventions are supported.
+    pub fn client(&self) -> Option<ClientInfo> {
+        self.azureus_client()
+            .or_else(|| self.mainline_client())
+            .or_else(|| self.shadow_client())
+    }
+
+    /// Returns human readable label of client, like `qBittorrent 4.5.0`.
+    /// For unknown clients printable prefix of peer-id is returned.
+    pub fn client_label(&self) -> String {
+        match self.client() {
+            Some(client) => client.to_string(),
+            None => {
+                let prefix: String = self
+                    .bytes
+                    .iter()
+                    .take(8)
+                    .map(|&byte| escape_byte(byte))
+                    .collect();
+                format!("Unknown ({prefix})")
+            }
+        }
+    }
+
+    /// Azureus-style: `-` two letters client code, four characters of version and `-`.
+    fn azureus_client(&self) -> Option<ClientInfo> {
+        let bytes = &self.bytes;
+        if bytes[0] != b'-' || bytes[7] != b'-' {
+            return None;
+        }
+        if !bytes[1..7].iter().all(u8::is_ascii_alphanumeric) {
+            return None;
+        }
+
+        // This client uses three letter code
+        if &bytes[..4] == b"-PVR" {
+            return Some(ClientInfo {
+                name: "PVR torrent client".to_string(),
+                version: dotted_version(&bytes[4..7]),
+            });
+        }
+
+        let code = std::str::from_utf8(&bytes[1..3]).ok()?;
+        let version = &bytes[3..7];
+        let name = AZUREUS_CLIENTS
+            .iter()
+            .find(|(known, _)| *known == code)
+            .map(|(_, name)| name.to_string())
+            .unwrap_or_else(|| format!("Unknown client {code}"));
+
+        let version = match code {
+            // Transmission: major version and two digits of minor version
+            "TR" => {
+                let major = char_value(version[0])?;
+                let minor = std::str::from_utf8(&version[1..3]).ok()?;
+                format!("{major}.{minor}")
+            }
+            _ => dotted_version(version),
+        };
+        Some(ClientInfo { name, version })
+    }
+
+    /// Shadow-style: one letter client code, version characters, and `-` padding.
+    fn shadow_client(&self) -> Option<ClientInfo> {
+        let bytes = &self.bytes;
+        let (_, name) = SHADOW_CLIENTS.iter().find(|(code, _)| *code == bytes[0])?;
+
+        let version_chars: Vec<u8> = bytes[1..6]
+            .iter()
+            .copied()
+            .take_while(|&byte| byte != b'-')
+            .collect();
+        // Shadow-style peer-ids end version with padding of `---`
+        let version_len = version_chars.len();
+        if version_len == 0 || bytes[1 + version_len..4 + version_len] != *b"---" {
+            return None;
+        }
+        let version = version_chars
+            .iter()
+            .map(|&byte| shadow_char_value(byte).map(|value| value.to_string()))
+            .collect::<Option<Vec<_>>>()?
+            .join(".");
+
+        Some(ClientInfo {
+            name: name.to_string(),
+            version,
+        })
+    }
+
+    /// Mainline-style: one letter client code and version numbers separated by `-`, like `M4-3-6--`.
+    fn mainline_client(&self) -> Option<ClientInfo> {
+        let bytes = &self.bytes;
+        let (_, name) = MAINLINE_CLIENTS
+            .iter()
+            .find(|(code, _)| *code == bytes[0])?;
+
+        let prefix = &bytes[1..10];
+        let end = prefix.windows(2).position(|window| window == b"--")?;
+        let parts: Vec<&[u8]> = prefix[..end].split(|&byte| byte == b'-').collect();
+        if parts.len() != 3
+            || parts
+                .iter()
+                .any(|part| part.is_empty() || !part.iter().all(u8::is_ascii_digit))
+        {
+            return None;
+        }
+        let version = parts
+            .iter()
+            .map(|part| String::from_utf8_lossy(part).into_owned())
+            .collect::<Vec<_>>()
+            .join(".");
+
+        Some(ClientInfo {
+            name: name.to_string(),
+            version,
+        })
+    }
+}
+
+impl From<[u8; 20]> for PeerId {
+    fn from(bytes: [u8; 20]) -> Self {
+        PeerId::from_bytes(bytes)
+    }
+}
+
+impl TryFrom<&[u8]> for PeerId {
+    type Error = anyhow::Error;
+
+    fn try_from(bytes: &[u8]) -> Result<Self, Self::Error> {
+        let bytes: [u8; 20] = bytes.try_into().map_err(|_| {
+            anyhow::Error::msg(format!(
+                "Expected len of peer-id 20, but get {}",
+                bytes.len()
+            ))
+        })?;
+        Ok(PeerId { bytes })
+    }
 }
 
+/// Printable ASCII characters are shown as they are, other bytes are escaped as `\xNN`.
 impl Display for PeerId {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        write!(f, "{}", String::from_utf8(self.bytes.clone()).unwrap())
+        for &byte in &self.bytes {
+            write!(f, "{}", escape_byte(byte))?;
+        }
+        Ok(())
     }
 }
 
@@ -50,17 +277,80 @@ impl AsRef<[u8]> for PeerId {
 impl PeerId {
     /// Returns clone of iner bytes, represented as vectore.
     pub fn to_vec(&self) -> Vec<u8> {
-        self.bytes.clone()
+        self.bytes.to_vec()
     }
 
     /// Returns iner bytes as array of length 20.
     pub fn to_arr(&self) -> [u8; 20] {
-        let array: [u8; 20] = match self.bytes.clone().try_into() {
-            Ok(arr) => arr,
-            Err(_) => {
-                panic!("The Vec<u8> does not have exactly 20 elements");
-            }
-        };
-        array
+        self.bytes
     }
 }
+
+/// Returns printable form of one byte of peer-id.
+fn escape_byte(byte: u8) -> String {
+    if byte.is_ascii_graphic() {
+        (byte as char).to_string()
+    } else {
+        format!("\\x{byte:02x}")
+    }
+}
+
+/// Returns value of one version character, digits and then letters (`A` = 10).
+fn char_value(byte: u8) -> Option<u32> {
+    match byte {
+        b'0'..=b'9' => Some((byte - b'0') as u32),
+        b'A'..=b'Z' => Some((byte - b'A') as u32 + 10),
+        b'a'..=b'z' => Some((byte - b'a') as u32 + 10),
+        _ => None,
+    }
+}
+
+/// Returns value of one Shadow-style version character.
+fn shadow_char_value(byte: u8) -> Option<u32> {
+    match byte {
+        b'0'..=b'9' => Some((byte - b'0') as u32),
+        b'A'..=b'Z' => Some((byte - b'A') as u32 + 10),
+        b'a'..=b'z' => Some((byte - b'a') as u32 + 36),
+        b'.' => Some(62),
+        _ => None,
+    }
+}
+
+/// Version in form `1.2.3`, each character is one number, trailing zeros after third number are dropped.
+fn dotted_version(version: &[u8]) -> String {
+    let mut numbers: Vec<String> = version
+        .iter()
+        .map(|&byte| {
+            char_value(byte)
+                .map(|value| value.to_string())
+                .unwrap_or_else(|| (byte as char).to_string())
+        })
+        .collect();
+    while numbers.len() > 3 && numbers.last().map(String::as_str) == Some("0") {
+        numbers.pop();
+    }
+    numbers.join(".")
+}
+
+#[test]
+fn peer_id_client_decoding() {
+    let label = |id: &[u8; 20]| PeerId::from(*id).client_label();
+    assert_eq!(label(b"-qB4500-abcdefghijkl"), "qBittorrent 4.5.0");
+    assert_eq!(label(b"-TR2940-abcdefghijkl"), "Transmission 2.94");
+    assert_eq!(label(b"-LT1234-abcdefghijkl"), "libtorrent 1.2.3.4");
+    assert_eq!(label(b"-PVR001-abcdefghijkl"), "PVR torrent client 0.0.1");
+    assert_eq!(label(b"M4-3-6--abcdefghijkl"), "Mainline 4.3.6");
+    assert_eq!(label(b"S58B-----abcdefghijk"), "Shadow's client 5.8.11");
+    assert_eq!(label(b"T03I-----abcdefghijk"), "BitTornado 0.3.18");
+    assert_eq!(
+        label(&[0xff; 20]),
+        "Unknown (\\xff\\xff\\xff\\xff\\xff\\xff\\xff\\xff)"
+    );
+}
+
+#[test]
+fn peer_id_display_non_utf8() {
+    let mut bytes = *b"-XX0000-abcdefghijkl";
+    bytes[19] = 0x80;
+    assert_eq!(PeerId::from(bytes).to_string(), "-XX0000-abcdefghijk\\x80");
+}
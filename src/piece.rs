@@ -1,4 +1,4 @@
-use crate::hash::Hash;
+use crate::hash::PieceHash;
 use lava_torrent::torrent::v1::Torrent;
 
 /// Structure representing data of one downloaded piece of downloaded file.
@@ -15,7 +15,7 @@ pub struct PieceData {
 pub struct Piece {
     piece_idx: usize,
     length: usize,
-    hash: [u8; 20],
+    hash: PieceHash,
 }
 
 impl Piece {
@@ -37,7 +37,7 @@ impl Piece {
             // peers,
             piece_idx,
             length: piece_size as usize,
-            hash: Hash::new(piece_hash)?.to_arr(),
+            hash: PieceHash::new(piece_hash)?,
         })
     }
 
@@ -47,8 +47,7 @@ impl Piece {
     }
 
     /// Returns hash of piece data.
-    #[allow(dead_code)]
-    pub(crate) fn hash(&self) -> [u8; 20] {
+    pub(crate) fn hash(&self) -> PieceHash {
         self.hash
     }
 
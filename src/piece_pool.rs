@@ -0,0 +1,436 @@
+use std::cmp::Reverse;
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use anyhow::Result;
+use tokio::sync::watch;
+
+use crate::file_layout::{FileLayout, FilePriority};
+use crate::peer_comunication::bitfield::Bitfield;
+use crate::piece::Piece;
+
+/// Download state of one piece.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum PieceState {
+    Missing,
+    /// Piece is downloaded by some peer, or waits for writer.
+    InProgress,
+    Written,
+    /// Piece is written only to wanted files, bytes of skipped files were dropped.
+    PartiallyWritten,
+}
+
+/// Maximal number of peers downloading the same piece, when time critical piece is raced.
+const MAX_PIECE_DOWNLOADERS: u8 = 2;
+
+/// Order in which pieces are picked for download.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum PickMode {
+    /// Pieces owned by the fewest peers are downloaded first.
+    #[default]
+    RarestFirst,
+    /// Pieces are downloaded in order, starting from read cursor.
+    Sequential,
+    /// Pieces in readahead window after read cursor are downloaded first and in order,
+    /// pieces outside of the window are picked rarest first.
+    Streaming {
+        /// Number of pieces after read cursor, which are time critical.
+        readahead: usize,
+        /// Time for downloading one piece of the window, the first piece has to be downloaded in this time,
+        /// the second one in double of it, etc. Piece after its deadline is downloaded also from other peer.
+        piece_deadline: Duration,
+    },
+}
+
+/// Number of connected peers, that have each piece.
+/// Counters are atomic, so peer can be removed also from `Drop`.
+pub struct PieceAvailability {
+    counts: Vec<AtomicU32>,
+}
+
+impl PieceAvailability {
+    /// Creates availability of pieces, no peer has any piece.
+    pub fn new(piece_count: usize) -> Self {
+        PieceAvailability {
+            counts: (0..piece_count).map(|_| AtomicU32::new(0)).collect(),
+        }
+    }
+
+    /// Add one peer having given piece.
+    pub fn add_piece(&self, piece_index: usize) {
+        if let Some(count) = self.counts.get(piece_index) {
+            count.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    /// Add all pieces of peer.
+    pub fn add_bitfield(&self, bitfield: &Bitfield) {
+        for piece_index in bitfield.pieces() {
+            self.add_piece(piece_index);
+        }
+    }
+
+    /// Remove all pieces of peer, when peer disconnects or sends new bitfield.
+    pub fn remove_bitfield(&self, bitfield: &Bitfield) {
+        for piece_index in bitfield.pieces() {
+            if let Some(count) = self.counts.get(piece_index) {
+                let _ = count.fetch_update(Ordering::Relaxed, Ordering::Relaxed, |count| {
+                    Some(count.saturating_sub(1))
+                });
+            }
+        }
+    }
+
+    /// Returns number of connected peers having given piece.
+    pub fn get(&self, piece_index: usize) -> u32 {
+        self.counts
+            .get(piece_index)
+            .map_or(0, |count| count.load(Ordering::Relaxed))
+    }
+}
+
+/// Pieces, which should be downloaded, ordered by priorities of files they belong to and by pick mode.
+/// Piece shared by more files has the highest priority of them, so it is downloaded if any of them is wanted.
+pub struct PiecePool {
+    pieces: Vec<Piece>,
+    states: Vec<PieceState>,
+    /// Number of peers downloading the piece and time when the first of them started.
+    downloads: Vec<(u8, Option<Instant>)>,
+    piece_priorities: Vec<FilePriority>,
+    file_priorities: Vec<FilePriority>,
+    layout: Arc<FileLayout>,
+    availability: Arc<PieceAvailability>,
+    mode: PickMode,
+    /// Index of piece, which will be read next by consumer of data.
+    cursor: usize,
+    /// Time when read cursor was moved, deadlines of window pieces are counted from it.
+    cursor_moved: Instant,
+    /// Notifies readers waiting for pieces, when any piece is written.
+    written: watch::Sender<()>,
+    /// Notifies writer, when priority of some file changes, so skipping files can complete the torrent.
+    priorities_changed: watch::Sender<()>,
+}
+
+impl PiecePool {
+    /// Creates pool of all pieces, all files have normal priority.
+    pub fn new(pieces: Vec<Piece>, layout: Arc<FileLayout>) -> Self {
+        let mut pool = PiecePool {
+            states: vec![PieceState::Missing; pieces.len()],
+            downloads: vec![(0, None); pieces.len()],
+            availability: Arc::new(PieceAvailability::new(pieces.len())),
+            mode: PickMode::default(),
+            cursor: 0,
+            cursor_moved: Instant::now(),
+            written: watch::Sender::new(()),
+            priorities_changed: watch::Sender::new(()),
+            piece_priorities: vec![FilePriority::Normal; pieces.len()],
+            file_priorities: vec![FilePriority::Normal; layout.files().len()],
+            pieces,
+            layout,
+        };
+        pool.update_piece_priorities();
+        pool
+    }
+
+    /// Returns availability of pieces, which is updated by peer connections.
+    pub fn availability(&self) -> Arc<PieceAvailability> {
+        self.availability.clone()
+    }
+
+    pub fn mode(&self) -> PickMode {
+        self.mode
+    }
+
+    pub fn set_mode(&mut self, mode: PickMode) {
+        self.mode = mode;
+    }
+
+    /// Returns index of piece, which will be read next.
+    pub fn cursor(&self) -> usize {
+        self.cursor
+    }
+
+    /// Move read cursor to piece with given index, deadlines of readahead window start again.
+    pub fn set_cursor(&mut self, piece_index: usize) {
+        let piece_index = piece_index.min(self.pieces.len().saturating_sub(1));
+        if piece_index != self.cursor {
+            self.cursor = piece_index;
+            self.cursor_moved = Instant::now();
+        }
+    }
+
+    /// Returns priority of file with given index.
+    pub fn file_priority(&self, file_index: usize) -> FilePriority {
+        self.file_priorities[file_index]
+    }
+
+    /// Returns priorities of all files.
+    pub fn file_priorities(&self) -> &[FilePriority] {
+        &self.file_priorities
+    }
+
+    /// Change priority of file, can be done while downloading.
+    /// Pieces shared with file, that was skipped before, are downloaded again,
+    /// because their bytes belonging to the skipped file were not written.
+    pub fn set_file_priority(&mut self, file_index: usize, priority: FilePriority) -> Result<()> {
+        anyhow::ensure!(
+            file_index < self.file_priorities.len(),
+            "File index {file_index} out of range"
+        );
+        let previous = std::mem::replace(&mut self.file_priorities[file_index], priority);
+        if previous == FilePriority::Skip && priority != FilePriority::Skip {
+            for piece_index in self.file_pieces(file_index) {
+                if self.states[piece_index] == PieceState::PartiallyWritten {
+                    self.states[piece_index] = PieceState::Missing;
+                }
+            }
+        }
+        self.update_piece_priorities();
+        self.priorities_changed.send_replace(());
+        Ok(())
+    }
+
+    /// Take piece, that is available from peer, first by pick mode and then by file priorities.
+    /// Missing pieces are taken, time critical pieces after their deadline are taken also when already downloading.
+    pub fn take(&mut self, available: &Bitfield) -> Option<Piece> {
+        let now = Instant::now();
+        let piece_index = available
+            .pieces()
+            .filter(|&idx| {
+                idx < self.pieces.len()
+                    && self.piece_priorities[idx] != FilePriority::Skip
+                    && (self.states[idx] == PieceState::Missing || self.can_race(idx, now))
+            })
+            .max_by_key(|&idx| self.pick_key(idx))?;
+        self.states[piece_index] = PieceState::InProgress;
+        let (downloaders, started) = &mut self.downloads[piece_index];
+        *downloaders += 1;
+        started.get_or_insert(now);
+        Some(self.pieces[piece_index].clone())
+    }
+
+    /// Return piece, that failed to download, back to pool.
+    /// Piece stays in progress, if other peer still downloads it.
+    pub fn put_back(&mut self, piece_index: usize) {
+        let (downloaders, started) = &mut self.downloads[piece_index];
+        *downloaders = downloaders.saturating_sub(1);
+        if *downloaders == 0 {
+            *started = None;
+            if self.states[piece_index] == PieceState::InProgress {
+                self.states[piece_index] = PieceState::Missing;
+            }
+        }
+    }
+
+    /// Mark piece as written to disk, `partially` means that bytes of skipped files were not written.
+    pub fn mark_written(&mut self, piece_index: usize, partially: bool) {
+        self.states[piece_index] = if partially {
+            PieceState::PartiallyWritten
+        } else {
+            PieceState::Written
+        };
+        self.downloads[piece_index] = (0, None);
+        self.written.send_replace(());
+    }
+
+    /// Returns receiver, which is notified whenever some piece is written.
+    pub fn subscribe_written(&self) -> watch::Receiver<()> {
+        self.written.subscribe()
+    }
+
+    /// Returns receiver, which is notified whenever priority of some file changes.
+    pub fn subscribe_priorities(&self) -> watch::Receiver<()> {
+        self.priorities_changed.subscribe()
+    }
+
+    /// Returns true, if piece was already written, so its another copy from racing peer can be dropped.
+    pub fn is_written(&self, piece_index: usize) -> bool {
+        matches!(
+            self.states.get(piece_index),
+            Some(PieceState::Written | PieceState::PartiallyWritten)
+        )
+    }
+
+    /// Returns bitfield of pieces, which are written whole, so they can be uploaded to other peers.
+    pub(crate) fn complete_pieces(&self) -> Bitfield {
+        let mut bitfield = Bitfield::empty_with_piece_capacity(self.pieces.len());
+        for (piece_index, state) in self.states.iter().enumerate() {
+            if *state == PieceState::Written {
+                bitfield.set_piece(piece_index);
+            }
+        }
+        bitfield
+    }
+
+    /// Returns number of wanted pieces, which are not written yet.
+    pub fn remaining(&self) -> usize {
+        (0..self.pieces.len())
+            .filter(|&idx| {
+                matches!(
+                    self.states[idx],
+                    PieceState::Missing | PieceState::InProgress
+                ) && self.piece_priorities[idx] != FilePriority::Skip
+            })
+            .count()
+    }
+
+    /// Returns true, if all wanted pieces are written.
+    pub fn is_complete(&self) -> bool {
+        self.remaining() == 0
+    }
+
+    /// Returns position of piece in readahead window, if the piece is in it.
+    fn window_position(&self, piece_index: usize) -> Option<usize> {
+        match self.mode {
+            PickMode::Streaming { readahead, .. } => piece_index
+                .checked_sub(self.cursor)
+                .filter(|&position| position < readahead),
+            _ => None,
+        }
+    }
+
+    /// Returns true, if piece in readahead window missed its deadline, so it can be downloaded also by other peer.
+    fn can_race(&self, piece_index: usize, now: Instant) -> bool {
+        let PickMode::Streaming { piece_deadline, .. } = self.mode else {
+            return false;
+        };
+        let Some(position) = self.window_position(piece_index) else {
+            return false;
+        };
+        let (downloaders, started) = self.downloads[piece_index];
+        let deadline = self.cursor_moved + piece_deadline * (position as u32 + 1);
+        self.states[piece_index] == PieceState::InProgress
+            && downloaders < MAX_PIECE_DOWNLOADERS
+            && now > deadline
+            && started.is_some_and(|started| now > started + piece_deadline)
+    }
+
+    /// Returns key of piece, piece with the highest key is picked first.
+    fn pick_key(&self, piece_index: usize) -> (bool, FilePriority, usize, Reverse<usize>) {
+        let order = match self.mode {
+            PickMode::RarestFirst => usize::MAX - self.availability.get(piece_index) as usize,
+            // Pieces after cursor go first, then pieces before it, both in order
+            PickMode::Sequential => match piece_index.checked_sub(self.cursor) {
+                Some(distance) => usize::MAX - distance,
+                None => usize::MAX / 2 - piece_index,
+            },
+            PickMode::Streaming { .. } => match self.window_position(piece_index) {
+                Some(position) => usize::MAX - position,
+                None => usize::MAX - self.availability.get(piece_index) as usize,
+            },
+        };
+        // Time critical pieces of window go before any priority, ties are broken by lower index
+        (
+            self.window_position(piece_index).is_some(),
+            self.piece_priorities[piece_index],
+            order,
+            Reverse(piece_index),
+        )
+    }
+
+    /// Returns indexes of pieces, that contain some bytes of file.
+    fn file_pieces(&self, file_index: usize) -> std::ops::Range<usize> {
+        let file = &self.layout.files()[file_index];
+        let piece_length = self.layout.piece_length();
+        let first = (file.offset / piece_length) as usize;
+        let last = (file.offset + file.length).div_ceil(piece_length) as usize;
+        first..last.min(self.pieces.len())
+    }
+
+    fn update_piece_priorities(&mut self) {
+        self.piece_priorities.fill(FilePriority::Skip);
+        for (file_index, file) in self.layout.files().iter().enumerate() {
+            if file.attributes.padding {
+                continue;
+            }
+            let priority = self.file_priorities[file_index];
+            for piece_index in self.file_pieces(file_index) {
+                self.piece_priorities[piece_index] =
+                    self.piece_priorities[piece_index].max(priority);
+            }
+        }
+    }
+}
+
+#[test]
+fn piece_pool_priorities() {
+    use crate::test_utils::files_torrent;
+
+    let torrent = files_torrent(&[("a", 40), ("b", 30), ("c", 30)], 20);
+    let layout = Arc::new(FileLayout::from_torrent(&torrent).unwrap());
+    let pieces = crate::piece::pieces_from_torrent(&torrent).unwrap();
+    let mut pool = PiecePool::new(pieces, layout);
+    let all = Bitfield::new(vec![0xff]);
+
+    // Only file `b` is wanted, pieces 2 and 3 are shared with skipped files
+    pool.set_file_priority(0, FilePriority::Skip).unwrap();
+    pool.set_file_priority(2, FilePriority::Skip).unwrap();
+    assert_eq!(pool.remaining(), 2);
+    assert_eq!(pool.take(&all).unwrap().index(), 2);
+    pool.mark_written(2, false);
+    pool.set_file_priority(1, FilePriority::High).unwrap();
+    assert_eq!(pool.take(&all).unwrap().index(), 3);
+    pool.mark_written(3, true);
+    assert!(pool.is_complete());
+
+    // Bytes of file `c` in piece 3 were dropped, so the piece is needed again
+    pool.set_file_priority(2, FilePriority::Low).unwrap();
+    assert_eq!(pool.remaining(), 2);
+
+    // Changing priority of running download, high priority file goes first
+    pool.set_file_priority(1, FilePriority::Normal).unwrap();
+    pool.set_file_priority(2, FilePriority::High).unwrap();
+    assert_eq!(pool.take(&all).unwrap().index(), 3);
+    assert_eq!(pool.take(&all).unwrap().index(), 4);
+    pool.set_file_priority(0, FilePriority::Low).unwrap();
+    assert_eq!(pool.remaining(), 4);
+    assert!(!pool.is_complete());
+}
+
+#[test]
+fn piece_pool_pick_modes() {
+    let torrent = crate::test_utils::test_torrent(100, 10);
+    let layout = Arc::new(FileLayout::from_torrent(&torrent).unwrap());
+    let pieces = crate::piece::pieces_from_torrent(&torrent).unwrap();
+    let mut pool = PiecePool::new(pieces, layout);
+    let all = Bitfield::new(vec![0xff, 0xff]);
+
+    // Piece 7 is owned only by one peer, others by two
+    let availability = pool.availability();
+    availability.add_bitfield(&all);
+    availability.add_bitfield(&all);
+    availability.remove_bitfield(&Bitfield::new(vec![0b00000001, 0]));
+    assert_eq!(pool.take(&all).unwrap().index(), 7);
+    pool.put_back(7);
+
+    pool.set_mode(PickMode::Sequential);
+    pool.set_cursor(8);
+    assert_eq!(pool.take(&all).unwrap().index(), 8);
+    assert_eq!(pool.take(&all).unwrap().index(), 9);
+    assert_eq!(pool.take(&all).unwrap().index(), 0);
+
+    // Window pieces go first in order, rarest piece outside of it after them
+    pool.set_mode(PickMode::Streaming {
+        readahead: 2,
+        piece_deadline: Duration::from_millis(20),
+    });
+    pool.set_cursor(3);
+    assert_eq!(pool.take(&all).unwrap().index(), 3);
+    assert_eq!(pool.take(&all).unwrap().index(), 4);
+    // Deadline of window pieces passed, so they are raced by other peer
+    std::thread::sleep(Duration::from_millis(50));
+    assert_eq!(pool.take(&all).unwrap().index(), 3);
+    assert_eq!(pool.take(&all).unwrap().index(), 4);
+    assert_eq!(pool.take(&all).unwrap().index(), 7);
+
+    // The second copy of raced piece is dropped
+    pool.mark_written(3, false);
+    assert!(pool.is_written(3));
+    pool.put_back(3);
+    assert!(pool.is_written(3));
+    // Racing peer failed, piece is late, so it is raced again
+    pool.put_back(4);
+    assert_eq!(pool.take(&all).unwrap().index(), 4);
+    assert_eq!(pool.take(&all).unwrap().index(), 1);
+}
@@ -0,0 +1,324 @@
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use anyhow::Context;
+use chrono::{Local, NaiveTime};
+use tokio::task::JoinHandle;
+use tokio::time::sleep;
+
+/// Part of one second, that can be transferred at once after a period without traffic.
+const BURST_SECONDS: f64 = 0.1;
+/// Maximal time of one sleep in `acquire`, so rate changes are applied quickly.
+const MAX_WAIT: Duration = Duration::from_millis(250);
+/// How often time-of-day schedule of limits is checked.
+const SCHEDULE_CHECK_INTERVAL: Duration = Duration::from_secs(30);
+
+/// Mutable state of token bucket.
+struct BucketState {
+    /// Allowed rate in bytes per second, `0` means unlimited.
+    rate: u64,
+    /// Available bytes, can be negative after larger transfer.
+    tokens: f64,
+    last_refill: Instant,
+}
+
+impl BucketState {
+    /// Add tokens for time elapsed since last refill.
+    fn refill(&mut self, now: Instant) {
+        let elapsed = now
+            .saturating_duration_since(self.last_refill)
+            .as_secs_f64();
+        let capacity = self.rate as f64 * BURST_SECONDS;
+        self.tokens = (self.tokens + elapsed * self.rate as f64).min(capacity);
+        self.last_refill = now;
+    }
+}
+
+/// Token bucket limiting transfer rate in bytes per second.
+/// Waiting transfers are served in FIFO order, so the bandwidth is shared fairly between peers.
+pub struct TokenBucket {
+    state: std::sync::Mutex<BucketState>,
+    queue: tokio::sync::Mutex<()>,
+}
+
+impl TokenBucket {
+    /// Creates new token bucket with given rate in bytes per second, `0` means unlimited.
+    pub fn new(rate: u64) -> Self {
+        TokenBucket {
+            state: std::sync::Mutex::new(BucketState {
+                rate,
+                tokens: 0.0,
+                last_refill: Instant::now(),
+            }),
+            queue: tokio::sync::Mutex::new(()),
+        }
+    }
+
+    /// Creates token bucket without limit.
+    pub fn unlimited() -> Self {
+        TokenBucket::new(0)
+    }
+
+    /// Returns current rate in bytes per second, `0` means unlimited.
+    pub fn rate(&self) -> u64 {
+        self.lock_state().rate
+    }
+
+    /// Change rate in bytes per second, `0` means unlimited.
+    pub fn set_rate(&self, rate: u64) {
+        let mut state = self.lock_state();
+        state.refill(Instant::now());
+        state.rate = rate;
+        state.tokens = state.tokens.min(rate as f64 * BURST_SECONDS);
+    }
+
+    /// Wait until `amount` of bytes can be transferred.
+    pub async fn acquire(&self, amount: usize) {
+        if self.rate() == 0 {
+            return;
+        }
+
+        let _turn = self.queue.lock().await;
+        loop {
+            let wait = {
+                let mut state = self.lock_state();
+                if state.rate == 0 {
+                    return;
+                }
+                state.refill(Instant::now());
+                if state.tokens >= 0.0 {
+                    state.tokens -= amount as f64;
+                    return;
+                }
+                Duration::from_secs_f64(-state.tokens / state.rate as f64)
+            };
+            sleep(wait.min(MAX_WAIT)).await;
+        }
+    }
+
+    fn lock_state(&self) -> std::sync::MutexGuard<'_, BucketState> {
+        self.state.lock().unwrap_or_else(|err| err.into_inner())
+    }
+}
+
+/// Pair of token buckets, limiting download and upload.
+#[derive(Clone)]
+pub struct BandwidthLimits {
+    pub download: Arc<TokenBucket>,
+    pub upload: Arc<TokenBucket>,
+}
+
+impl BandwidthLimits {
+    /// Creates new limits, rates are in bytes per second and `0` means unlimited.
+    pub fn new(download: u64, upload: u64) -> Self {
+        BandwidthLimits {
+            download: Arc::new(TokenBucket::new(download)),
+            upload: Arc::new(TokenBucket::new(upload)),
+        }
+    }
+
+    /// Creates limits that don't limit anything.
+    pub fn unlimited() -> Self {
+        BandwidthLimits::new(0, 0)
+    }
+
+    /// Change both rates in bytes per second, `0` means unlimited.
+    pub fn set(&self, download: u64, upload: u64) {
+        self.download.set_rate(download);
+        self.upload.set_rate(upload);
+    }
+}
+
+impl Default for BandwidthLimits {
+    fn default() -> Self {
+        BandwidthLimits::unlimited()
+    }
+}
+
+/// Chain of token buckets, transfer has to pass all of them (for example global and per torrent limit).
+#[derive(Clone, Default)]
+pub struct RateLimiter {
+    buckets: Vec<Arc<TokenBucket>>,
+}
+
+impl RateLimiter {
+    /// Creates rate limiter from given buckets.
+    pub fn new(buckets: Vec<Arc<TokenBucket>>) -> Self {
+        RateLimiter { buckets }
+    }
+
+    /// Wait until `amount` of bytes can be transferred through all buckets.
+    pub async fn acquire(&self, amount: usize) {
+        for bucket in &self.buckets {
+            bucket.acquire(amount).await;
+        }
+    }
+}
+
+/// Download and upload rate limiters used by one peer connection.
+#[derive(Clone, Default)]
+pub struct TransferLimiters {
+    pub download: RateLimiter,
+    pub upload: RateLimiter,
+}
+
+impl TransferLimiters {
+    /// Creates limiters, that apply all given limits at once.
+    pub fn from_limits(limits: &[&BandwidthLimits]) -> Self {
+        TransferLimiters {
+            download: RateLimiter::new(limits.iter().map(|l| l.download.clone()).collect()),
+            upload: RateLimiter::new(limits.iter().map(|l| l.upload.clone()).collect()),
+        }
+    }
+}
+
+/// One entry of time-of-day schedule, rates are in bytes per second and `0` means unlimited.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ScheduleEntry {
+    pub start: NaiveTime,
+    pub end: NaiveTime,
+    pub download: u64,
+    pub upload: u64,
+}
+
+impl ScheduleEntry {
+    /// Returns if the entry is active in given time, entries can go over midnight.
+    fn is_active(&self, time: NaiveTime) -> bool {
+        if self.start <= self.end {
+            self.start <= time && time < self.end
+        } else {
+            time >= self.start || time < self.end
+        }
+    }
+}
+
+/// Time-of-day schedule of bandwidth limits.
+/// First entry active at the current local time is used, default rates are used outside of all entries.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct RateSchedule {
+    pub entries: Vec<ScheduleEntry>,
+    pub default_download: u64,
+    pub default_upload: u64,
+}
+
+impl RateSchedule {
+    /// Parse schedule entries separated by `,`, in format `HH:MM-HH:MM=download/upload`,
+    /// where rates are in KiB/s. For example `08:00-18:00=512/128,22:00-06:00=0/0`.
+    pub fn parse(schedule: &str) -> anyhow::Result<Self> {
+        let mut entries = Vec::new();
+        for entry in schedule.split(',').map(str::trim).filter(|e| !e.is_empty()) {
+            let (times, rates) = entry.split_once('=').context("Missing '=' in schedule")?;
+            let (start, end) = times.split_once('-').context("Missing '-' in schedule")?;
+            let (download, upload) = rates.split_once('/').context("Missing '/' in schedule")?;
+            entries.push(ScheduleEntry {
+                start: NaiveTime::parse_from_str(start.trim(), "%H:%M")?,
+                end: NaiveTime::parse_from_str(end.trim(), "%H:%M")?,
+                download: parse_kib_rate(download)?,
+                upload: parse_kib_rate(upload)?,
+            });
+        }
+        Ok(RateSchedule {
+            entries,
+            ..Default::default()
+        })
+    }
+
+    /// Returns download and upload rate for given time.
+    pub fn rates_at(&self, time: NaiveTime) -> (u64, u64) {
+        self.entries
+            .iter()
+            .find(|entry| entry.is_active(time))
+            .map(|entry| (entry.download, entry.upload))
+            .unwrap_or((self.default_download, self.default_upload))
+    }
+
+    /// Spawn task, that periodically applies schedule to given limits.
+    pub fn spawn(self, limits: BandwidthLimits) -> JoinHandle<()> {
+        tokio::spawn(async move {
+            loop {
+                let (download, upload) = self.rates_at(Local::now().time());
+                limits.set(download, upload);
+                sleep(SCHEDULE_CHECK_INTERVAL).await;
+            }
+        })
+    }
+}
+
+/// Parse rate in KiB/s into bytes per second, `0` means unlimited.
+pub fn parse_kib_rate(rate: &str) -> anyhow::Result<u64> {
+    let rate = rate.trim();
+    rate.parse::<u64>()
+        .with_context(|| format!("Invalid rate {rate}"))?
+        .checked_mul(1024)
+        .with_context(|| format!("Rate {rate} KiB/s is too high"))
+}
+
+#[test]
+fn rate_schedule_over_midnight() {
+    let schedule = RateSchedule::parse("08:00-18:00=512/128, 22:00-06:00=0/64").unwrap();
+    let time = |h, m| NaiveTime::from_hms_opt(h, m, 0).unwrap();
+    assert_eq!(schedule.rates_at(time(12, 0)), (512 * 1024, 128 * 1024));
+    assert_eq!(schedule.rates_at(time(23, 30)), (0, 64 * 1024));
+    assert_eq!(schedule.rates_at(time(3, 0)), (0, 64 * 1024));
+    assert_eq!(schedule.rates_at(time(19, 0)), (0, 0));
+    assert!(RateSchedule::parse("08:00-18:00=18014398509481984/0").is_err());
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+async fn token_bucket_throttled_transfer() {
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::{TcpListener, TcpStream};
+
+    const RATE: u64 = 1024 * 1024;
+    const TOTAL_PER_PEER: usize = 1024 * 1024;
+    const BLOCK: usize = 16 * 1024;
+    const PEERS: usize = 2;
+
+    // Two peers share one global limit, each has its own connection
+    let global = BandwidthLimits::new(0, RATE);
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+
+    let start = Instant::now();
+    let mut senders = Vec::new();
+    for _ in 0..PEERS {
+        let limiters = TransferLimiters::from_limits(&[&global]);
+        senders.push(tokio::spawn(async move {
+            let mut stream = TcpStream::connect(addr).await.unwrap();
+            let block = vec![0u8; BLOCK];
+            for _ in 0..TOTAL_PER_PEER / BLOCK {
+                limiters.upload.acquire(BLOCK).await;
+                stream.write_all(&block).await.unwrap();
+            }
+        }));
+    }
+
+    let mut receivers = Vec::new();
+    for _ in 0..PEERS {
+        let (mut stream, _) = listener.accept().await.unwrap();
+        receivers.push(tokio::spawn(async move {
+            let mut buffer = vec![0u8; TOTAL_PER_PEER];
+            stream.read_exact(&mut buffer).await.unwrap();
+            Instant::now()
+        }));
+    }
+
+    let mut finished = Vec::new();
+    for receiver in receivers {
+        finished.push(receiver.await.unwrap());
+    }
+    for sender in senders {
+        sender.await.unwrap();
+    }
+
+    // Last block is sent without waiting, so it is not counted
+    let elapsed = (finished.iter().max().unwrap().duration_since(start)).as_secs_f64();
+    let measured = (PEERS * TOTAL_PER_PEER - BLOCK) as f64 / elapsed;
+    let error = (measured - RATE as f64).abs() / RATE as f64;
+    assert!(error < 0.05, "measured rate {measured} B/s, error {error}");
+
+    // Both peers got similar share of bandwidth
+    let first = finished[0].duration_since(start).as_secs_f64();
+    let second = finished[1].duration_since(start).as_secs_f64();
+    assert!((first - second).abs() / elapsed < 0.1);
+}
@@ -0,0 +1,113 @@
+use std::path::{Path, PathBuf};
+
+use anyhow::Result;
+use serde::{Deserialize, Serialize};
+
+use crate::hash::InfoHash;
+
+/// Suffix of files with resume data, name of file is info hash of torrent.
+const RESUME_SUFFIX: &str = "resume";
+
+/// State of torrent stored on shutdown, so written pieces are not downloaded again after restart.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ResumeData {
+    pub info_hash: InfoHash,
+    /// Indexes of pieces, which are written whole to storage.
+    pub pieces: Vec<usize>,
+    /// Payload bytes downloaded from peers since torrent was added, also in previous runs.
+    #[serde(default)]
+    pub downloaded: u64,
+    /// Payload bytes uploaded to peers since torrent was added, also in previous runs.
+    #[serde(default)]
+    pub uploaded: u64,
+    /// Seconds of seeding since download completed, also in previous runs.
+    #[serde(default)]
+    pub seeding_time: u64,
+}
+
+impl ResumeData {
+    /// Returns path of file with resume data of torrent in given folder.
+    pub fn file_path(folder: &Path, info_hash: &InfoHash) -> PathBuf {
+        folder.join(format!("{info_hash}.{RESUME_SUFFIX}"))
+    }
+
+    /// Load resume data from file, returns `None` if the file doesn't exist.
+    pub fn load(path: &Path) -> Result<Option<Self>> {
+        if !path.exists() {
+            return Ok(None);
+        }
+        Ok(Some(serde_json::from_str(&std::fs::read_to_string(path)?)?))
+    }
+
+    /// Store resume data to file, file is replaced at once so it's never half written.
+    pub fn save(&self, path: &Path) -> Result<()> {
+        if let Some(parent) = path.parent() {
+            std::fs::create_dir_all(parent)?;
+        }
+        let temporary = path.with_extension("tmp");
+        std::fs::write(&temporary, serde_json::to_string_pretty(self)?)?;
+        std::fs::rename(temporary, path)?;
+        Ok(())
+    }
+}
+
+#[tokio::test]
+async fn session_shutdown_stores_resume_data() {
+    use crate::metainfo::Metainfo;
+    use crate::session::{Session, SessionOptions};
+    use crate::storage::StorageOptions;
+    use crate::test_utils::{data_torrent, spawn_partial_seeder, tracker_peer};
+    use std::net::SocketAddr;
+    use std::time::Duration;
+
+    let data: Vec<u8> = (0..32).collect();
+    let torrent = data_torrent(&data, 16);
+    let folder = tempfile::tempdir().unwrap();
+    let resume_folder = tempfile::tempdir().unwrap();
+    let options = SessionOptions {
+        listen_addr: SocketAddr::from(([127, 0, 0, 1], 0)),
+        resume_folder: Some(resume_folder.path().to_path_buf()),
+        ..SessionOptions::default()
+    };
+    let metainfo = Metainfo::from_torrent(torrent).unwrap();
+    let info_hash = metainfo.info_hash();
+
+    // Other peer has only the first piece
+    let seed_addr = spawn_partial_seeder(info_hash, data.clone(), 16, &[0]).await;
+
+    let session = Session::new(options.clone()).await.unwrap();
+    let handle = session
+        .add_torrent(metainfo.clone(), folder.path(), &StorageOptions::default())
+        .await
+        .unwrap();
+    handle
+        .downloader()
+        .add_swarm_peers(&[tracker_peer(seed_addr)], info_hash)
+        .await;
+    tokio::time::timeout(Duration::from_secs(10), async {
+        while handle.status().await.remaining_pieces != 1 {
+            tokio::time::sleep(Duration::from_millis(20)).await;
+        }
+    })
+    .await
+    .unwrap();
+    session.shutdown().await;
+
+    // Written piece is flushed to disk and stored in resume data
+    let resume_file = ResumeData::file_path(resume_folder.path(), &info_hash);
+    let resume_data = ResumeData::load(&resume_file).unwrap().unwrap();
+    assert_eq!(resume_data.pieces, vec![0]);
+    assert_eq!(resume_data.downloaded, 16);
+    let written = std::fs::read(folder.path().join("data")).unwrap();
+    assert_eq!(written[..16], data[..16]);
+
+    // Restarted session doesn't download the piece again, and counts bytes downloaded before
+    let session = Session::new(options).await.unwrap();
+    let handle = session
+        .add_torrent(metainfo, folder.path(), &StorageOptions::default())
+        .await
+        .unwrap();
+    let status = handle.status().await;
+    assert_eq!(status.remaining_pieces, 1);
+    assert_eq!(status.downloaded, 16);
+}
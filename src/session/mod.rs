@@ -0,0 +1,879 @@
+use std::fmt::Display;
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex, MutexGuard, OnceLock};
+use std::time::{Duration, Instant};
+
+use anyhow::{Context, Result};
+use lava_torrent::torrent::v1::Torrent;
+use lava_torrent::tracker::Peer;
+use tokio::net::TcpListener;
+use tokio::sync::{mpsc, Notify, Semaphore};
+use tokio::task::JoinHandle;
+use tokio::time::{sleep, timeout};
+use tokio_util::sync::CancellationToken;
+
+use crate::download::TorrentDownloader;
+use crate::hash::InfoHash;
+use crate::ip_filter::IpFilter;
+use crate::metainfo::Metainfo;
+use crate::peer_comunication::connection_manager::DEFAULT_MAX_ACTIVE_CONNECTIONS;
+use crate::peer_comunication::peer_connection::read_handshake;
+use crate::peer_comunication::peer_score::PeerScores;
+use crate::peer_id::PeerId;
+use crate::rate_limit::BandwidthLimits;
+use crate::resume::ResumeData;
+use crate::storage::StorageOptions;
+use crate::tracker_connection::get_peers::{announce, AnnounceEvent, STOPPED_ANNOUNCE_TIMEOUT};
+use crate::writer::DISK_FULL_REASON;
+
+mod queue;
+mod seeding;
+
+use queue::{Queue, QueueEntry};
+pub use queue::{
+    QueueMove, QueueOptions, DEFAULT_MAX_ACTIVE_DOWNLOADS, DEFAULT_MAX_ACTIVE_SEEDS, QUEUED_REASON,
+};
+use seeding::share_ratio;
+pub use seeding::{SeedingAction, SeedingLimits};
+
+/// Default port of listener for incoming peer connections.
+pub const DEFAULT_PORT: u16 = 6881;
+/// Default maximal number of peer connections of all torrents together.
+pub const DEFAULT_MAX_CONNECTIONS: usize = 200;
+/// Waiting time after listener fails to accept connection, e.g. when there are too many open files.
+const ACCEPT_RETRY_INTERVAL: Duration = Duration::from_millis(100);
+/// Reason of pause requested by user.
+const USER_PAUSE_REASON: &str = "Paused by user";
+/// Maximal time between two updates of queue, transfer rates of torrents are checked in them.
+const QUEUE_INTERVAL: Duration = Duration::from_secs(5);
+/// Time between two checks of seeding goals.
+const SEEDING_CHECK_INTERVAL: Duration = Duration::from_secs(1);
+/// Reason of pause of torrent, which reached its seeding goal.
+pub const SEEDING_GOAL_REASON: &str = "Seeding goal reached";
+
+/// Settings of session shared by all its torrents.
+#[derive(Debug, Clone)]
+pub struct SessionOptions {
+    /// Address of listener for incoming peer connections, port `0` lets system choose free port.
+    pub listen_addr: SocketAddr,
+    /// Maximal number of peer connections of all torrents together.
+    pub max_connections: usize,
+    /// Maximal number of peer connections of one torrent.
+    pub max_torrent_connections: usize,
+    /// Download limit of all torrents in bytes per second, `0` means unlimited.
+    pub download_limit: u64,
+    /// Upload limit of all torrents in bytes per second, `0` means unlimited.
+    pub upload_limit: u64,
+    /// Limits of active torrents and location of stored queue order.
+    pub queue: QueueOptions,
+    /// Seeding goals of torrents, which don't have their own.
+    pub seeding: SeedingLimits,
+    /// Folder with resume data of torrents, they are stored on shutdown and loaded when torrent is added.
+    pub resume_folder: Option<PathBuf>,
+}
+
+impl Default for SessionOptions {
+    fn default() -> Self {
+        SessionOptions {
+            listen_addr: SocketAddr::from(([0, 0, 0, 0], DEFAULT_PORT)),
+            max_connections: DEFAULT_MAX_CONNECTIONS,
+            max_torrent_connections: DEFAULT_MAX_ACTIVE_CONNECTIONS,
+            download_limit: 0,
+            upload_limit: 0,
+            queue: QueueOptions::default(),
+            seeding: SeedingLimits::default(),
+            resume_folder: None,
+        }
+    }
+}
+
+/// Error returned when torrent with the same info hash is already in session.
+#[derive(Debug)]
+pub struct DuplicateTorrent(pub InfoHash);
+
+impl Display for DuplicateTorrent {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Torrent {} is already added", self.0)
+    }
+}
+
+impl std::error::Error for DuplicateTorrent {}
+
+/// Client running many torrents with one peer id and one listener for incoming connections.
+/// Rate limits, connection limit, bans and IP filter are shared by all torrents.
+/// TUI, daemon and other front ends control torrents through `TorrentHandle`s of session.
+pub struct Session {
+    peer_id: PeerId,
+    local_addr: SocketAddr,
+    bandwidth: BandwidthLimits,
+    connection_slots: Arc<Semaphore>,
+    max_torrent_connections: usize,
+    peer_scores: Arc<tokio::sync::Mutex<PeerScores>>,
+    ip_filter: Arc<IpFilter>,
+    /// Torrents in order in which they were added.
+    torrents: Arc<Mutex<Vec<TorrentHandle>>>,
+    queue: Arc<Mutex<Queue>>,
+    /// Notified, when queue should be updated before next regular update.
+    queue_changed: Arc<Notify>,
+    seeding_limits: Arc<Mutex<SeedingLimits>>,
+    resume_folder: Option<PathBuf>,
+    /// Cancelled on shutdown, torrents have its child tokens.
+    shutdown: CancellationToken,
+    listener_task: JoinHandle<()>,
+    queue_task: JoinHandle<()>,
+}
+
+impl Session {
+    /// Create session with new peer id, and start listener for incoming connections.
+    /// Queue order is loaded from its state file.
+    pub async fn new(options: SessionOptions) -> Result<Self> {
+        let queue = Arc::new(Mutex::new(Queue::new(options.queue)?));
+        let queue_changed = Arc::new(Notify::new());
+        let listener = TcpListener::bind(options.listen_addr).await?;
+        let local_addr = listener.local_addr()?;
+        let torrents = Arc::new(Mutex::new(Vec::new()));
+        let listener_task = tokio::spawn(accept_peers(listener, torrents.clone()));
+        let queue_task = tokio::spawn(manage_queue(
+            torrents.clone(),
+            queue.clone(),
+            queue_changed.clone(),
+        ));
+
+        Ok(Session {
+            peer_id: PeerId::generate(),
+            local_addr,
+            bandwidth: BandwidthLimits::new(options.download_limit, options.upload_limit),
+            connection_slots: Arc::new(Semaphore::new(options.max_connections)),
+            max_torrent_connections: options.max_torrent_connections,
+            peer_scores: Arc::new(tokio::sync::Mutex::new(PeerScores::new())),
+            ip_filter: Arc::new(IpFilter::new()),
+            torrents,
+            queue,
+            queue_changed,
+            seeding_limits: Arc::new(Mutex::new(options.seeding)),
+            resume_folder: options.resume_folder,
+            shutdown: CancellationToken::new(),
+            listener_task,
+            queue_task,
+        })
+    }
+
+    /// Returns peer id used in all torrents of session.
+    pub fn peer_id(&self) -> PeerId {
+        self.peer_id
+    }
+
+    /// Returns address of listener for incoming connections, port is announced to trackers.
+    pub fn local_addr(&self) -> SocketAddr {
+        self.local_addr
+    }
+
+    /// Returns bandwidth limits shared by all torrents, they can be changed while torrents run.
+    pub fn bandwidth(&self) -> BandwidthLimits {
+        self.bandwidth.clone()
+    }
+
+    /// Returns seeding goals of torrents, which don't have their own goals.
+    pub fn seeding_limits(&self) -> SeedingLimits {
+        *lock(&self.seeding_limits)
+    }
+
+    /// Change seeding goals of torrents, which don't have their own goals, including running torrents.
+    pub fn set_seeding_limits(&self, limits: SeedingLimits) {
+        *lock(&self.seeding_limits) = limits;
+    }
+
+    /// Returns trust scores and bans of peers shared by all torrents.
+    pub fn peer_scores(&self) -> Arc<tokio::sync::Mutex<PeerScores>> {
+        self.peer_scores.clone()
+    }
+
+    /// Replace trust scores of peers, e.g. by scores with ban file.
+    /// Torrents added before keep the old scores.
+    pub fn set_peer_scores(&mut self, peer_scores: Arc<tokio::sync::Mutex<PeerScores>>) {
+        self.peer_scores = peer_scores;
+    }
+
+    /// Returns IP filter shared by all torrents.
+    pub fn ip_filter(&self) -> Arc<IpFilter> {
+        self.ip_filter.clone()
+    }
+
+    /// Set IP filter applied to peers of torrents added later.
+    pub fn set_ip_filter(&mut self, ip_filter: Arc<IpFilter>) {
+        self.ip_filter = ip_filter;
+    }
+
+    /// Add torrent and start its download into given folder, torrent waits in queue if limits are reached.
+    /// Pieces written before shutdown are loaded from resume data, they are not downloaded again.
+    /// Returns `DuplicateTorrent` error, if torrent with some of its info hashes is already in session.
+    pub async fn add_torrent(
+        &self,
+        metainfo: Metainfo,
+        folder: impl AsRef<Path>,
+        storage: &StorageOptions,
+    ) -> Result<TorrentHandle> {
+        let folder = folder.as_ref().to_path_buf();
+        let torrent = metainfo.torrent.clone();
+        let mut downloader = TorrentDownloader::from_metainfo(metainfo)?;
+        downloader.set_storage(storage.create(folder.clone(), downloader.layout()));
+        downloader.set_global_bandwidth(self.bandwidth.clone());
+        downloader.set_connection_slots(self.connection_slots.clone());
+        downloader.set_peer_scores(self.peer_scores.clone());
+        downloader.set_ip_filter(self.ip_filter.clone());
+        downloader.set_shutdown_token(self.shutdown.child_token());
+        downloader
+            .connection_manager()
+            .lock()
+            .await
+            .set_max_active(self.max_torrent_connections);
+        let resume_file = self
+            .resume_folder
+            .as_ref()
+            .map(|resume_folder| ResumeData::file_path(resume_folder, &downloader.info_hash()));
+        let resume_data = resume_file.as_deref().map(ResumeData::load).transpose()?;
+        let mut seeded_before = Duration::ZERO;
+        if let Some(resume_data) = resume_data.flatten() {
+            downloader.load_resume_data(&resume_data).await?;
+            seeded_before = Duration::from_secs(resume_data.seeding_time);
+        }
+
+        let handle = TorrentHandle {
+            inner: Arc::new(TorrentEntry {
+                name: torrent.name.clone(),
+                folder,
+                trackers: tracker_urls(&torrent),
+                downloader: Arc::new(downloader),
+                task: Mutex::new(None),
+                result: OnceLock::new(),
+                tracker_error: Mutex::new(None),
+                queue_changed: self.queue_changed.clone(),
+                global_seeding: self.seeding_limits.clone(),
+                seeding_limits: Mutex::new(None),
+                seeding_since: Mutex::new(None),
+                seeded_before,
+                resume_file,
+                announced: AtomicBool::new(false),
+            }),
+        };
+        {
+            let mut torrents = lock(&self.torrents);
+            if let Some(existing) = torrents
+                .iter()
+                .find(|existing| existing.shares_swarm(&handle))
+            {
+                return Err(DuplicateTorrent(existing.info_hash()).into());
+            }
+            lock(&self.queue).add(handle.info_hash())?;
+            torrents.push(handle.clone());
+            update_queue(&torrents, &self.queue);
+            let task = tokio::spawn(run_torrent(
+                handle.inner.clone(),
+                torrent,
+                self.peer_id,
+                self.local_addr.port(),
+            ));
+            *lock(&handle.inner.task) = Some(task);
+        }
+        Ok(handle)
+    }
+
+    /// Stop torrent and remove it from session, its downloaded data are deleted if `delete_data` is set.
+    pub async fn remove_torrent(&self, info_hash: &InfoHash, delete_data: bool) -> Result<()> {
+        let handle = {
+            let mut torrents = lock(&self.torrents);
+            let Some(position) = torrents
+                .iter()
+                .position(|handle| handle.info_hash() == *info_hash)
+            else {
+                anyhow::bail!("Torrent {info_hash} is not in session");
+            };
+            torrents.remove(position)
+        };
+        // Writer stops writing while torrent is paused, so data are not written after delete
+        handle.inner.downloader.pause("Removed");
+        handle.inner.stop().await;
+        // Torrent is removed from queue, even if the state file can't be written, error is returned after cleanup
+        let saved = lock(&self.queue).remove(info_hash);
+        self.queue_changed.notify_one();
+        if delete_data {
+            if let Some(storage) = handle.inner.downloader.storage() {
+                storage.delete().await?;
+            }
+        }
+        if let Some(resume_file) = &handle.inner.resume_file {
+            match std::fs::remove_file(resume_file) {
+                Err(err) if err.kind() != std::io::ErrorKind::NotFound => return Err(err.into()),
+                _ => {}
+            }
+        }
+        saved.context("Unable to save queue state")
+    }
+
+    /// Shut down session, listener stops accepting peers and all torrents stop.
+    /// Torrents flush written data, store their resume data and announce `stopped` to trackers,
+    /// connections with peers are closed. Returns after all torrents stopped.
+    pub async fn shutdown(&self) {
+        self.listener_task.abort();
+        self.queue_task.abort();
+        self.shutdown.cancel();
+        let tasks: Vec<JoinHandle<()>> = lock(&self.torrents)
+            .iter()
+            .filter_map(|handle| lock(&handle.inner.task).take())
+            .collect();
+        for task in tasks {
+            let _ = task.await;
+        }
+    }
+
+    /// Returns torrent with given info hash, hybrid torrents can be found by both their info hashes.
+    pub fn torrent(&self, info_hash: &InfoHash) -> Option<TorrentHandle> {
+        lock(&self.torrents)
+            .iter()
+            .find(|handle| handle.inner.downloader.swarm_hashes().contains(info_hash))
+            .cloned()
+    }
+
+    /// Returns all torrents of session in order in which they were added.
+    pub fn torrents(&self) -> Vec<TorrentHandle> {
+        lock(&self.torrents).clone()
+    }
+
+    /// Returns position of torrent in queue, `0` is started first.
+    pub fn queue_position(&self, info_hash: &InfoHash) -> Option<usize> {
+        lock(&self.queue).position(info_hash)
+    }
+
+    /// Move torrent in queue, torrents are started and stopped according to the new order.
+    pub fn move_in_queue(&self, info_hash: &InfoHash, movement: QueueMove) -> Result<()> {
+        lock(&self.queue).move_torrent(info_hash, movement)?;
+        update_queue(&lock(&self.torrents), &self.queue);
+        Ok(())
+    }
+}
+
+impl Drop for Session {
+    fn drop(&mut self) {
+        self.listener_task.abort();
+        self.queue_task.abort();
+        for handle in lock(&self.torrents).iter() {
+            if let Some(task) = lock(&handle.inner.task).take() {
+                task.abort();
+            }
+        }
+    }
+}
+
+/// State of torrent in session.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum TorrentState {
+    Downloading,
+    /// Torrent waits in queue, until some active torrent finishes.
+    Queued,
+    /// Download completed, torrent uploads to other peers until its seeding goal is reached.
+    Seeding,
+    /// Torrent is paused by user or because of full disk, with the reason.
+    Paused(String),
+    /// All wanted pieces are downloaded, and seeding stopped.
+    Finished,
+    /// Download stopped with error.
+    Failed(String),
+}
+
+/// Current state and progress of torrent.
+#[derive(Debug, Clone)]
+pub struct TorrentStatus {
+    pub info_hash: InfoHash,
+    pub name: String,
+    pub folder: PathBuf,
+    pub state: TorrentState,
+    pub piece_count: usize,
+    /// Number of wanted pieces, which are not downloaded yet.
+    pub remaining_pieces: usize,
+    pub connected_peers: usize,
+    pub download_rate: f64,
+    pub upload_rate: f64,
+    /// Total downloaded payload in bytes.
+    pub downloaded: u64,
+    /// Total uploaded payload in bytes.
+    pub uploaded: u64,
+    /// Error of last announce to tracker, torrent continues with incoming peers.
+    pub tracker_error: Option<String>,
+    /// Share ratio, uploaded bytes divided by downloaded bytes.
+    pub ratio: f64,
+    /// Time since download completed.
+    pub seeding_time: Option<Duration>,
+}
+
+/// Handle of torrent in session, it can be cloned and used from other tasks.
+#[derive(Clone)]
+pub struct TorrentHandle {
+    inner: Arc<TorrentEntry>,
+}
+
+impl TorrentHandle {
+    /// Returns info hash identifying torrent.
+    pub fn info_hash(&self) -> InfoHash {
+        self.inner.downloader.info_hash()
+    }
+
+    /// Returns name of torrent.
+    pub fn name(&self) -> &str {
+        &self.inner.name
+    }
+
+    /// Returns announce URLs of trackers, only the first one is announced.
+    pub fn trackers(&self) -> &[String] {
+        &self.inner.trackers
+    }
+
+    /// Returns downloader of torrent, for file priorities, pick mode, streaming and other settings.
+    pub fn downloader(&self) -> Arc<TorrentDownloader> {
+        self.inner.downloader.clone()
+    }
+
+    /// Pause torrent, no new peers are connected and downloaded pieces are not written.
+    /// Paused torrent is taken out of queue, so next torrent in queue can start.
+    pub fn pause(&self) {
+        self.inner.downloader.pause(USER_PAUSE_REASON);
+        self.inner.queue_changed.notify_one();
+    }
+
+    /// Resume torrent paused by user or by reached seeding goal, it's returned to its position in queue.
+    /// It starts immediately, if limits of active torrents are not reached. Running torrent is not changed.
+    pub fn resume(&self) {
+        let reasons = [USER_PAUSE_REASON, SEEDING_GOAL_REASON];
+        if self
+            .inner
+            .downloader
+            .replace_pause_reason(&reasons, QUEUED_REASON)
+        {
+            self.inner.queue_changed.notify_one();
+        }
+    }
+
+    /// Returns seeding goals of torrent, goals of session are used if torrent doesn't have its own.
+    pub fn seeding_limits(&self) -> SeedingLimits {
+        self.inner.seeding_limits()
+    }
+
+    /// Set own seeding goals of torrent, `None` means goals of session.
+    pub fn set_seeding_limits(&self, limits: Option<SeedingLimits>) {
+        *lock(&self.inner.seeding_limits) = limits;
+    }
+
+    /// Returns current state and progress of torrent.
+    pub async fn status(&self) -> TorrentStatus {
+        let downloader = &self.inner.downloader;
+        let seeding_time = self.inner.seeding_time();
+        let state = match (self.inner.result.get(), downloader.pause_reason()) {
+            (Some(Ok(())), _) => TorrentState::Finished,
+            (Some(Err(err)), _) => TorrentState::Failed(err.clone()),
+            (None, Some(reason)) if reason == QUEUED_REASON => TorrentState::Queued,
+            (None, Some(reason)) => TorrentState::Paused(reason),
+            (None, None) if seeding_time.is_some() => TorrentState::Seeding,
+            (None, None) => TorrentState::Downloading,
+        };
+        let peer_stats = downloader.peer_stats();
+        let peers = peer_stats.snapshot();
+        let (downloaded, uploaded) = peer_stats.totals();
+
+        TorrentStatus {
+            info_hash: self.info_hash(),
+            name: self.inner.name.clone(),
+            folder: self.inner.folder.clone(),
+            state,
+            piece_count: downloader.piece_count(),
+            remaining_pieces: downloader.remaining_pieces().await,
+            connected_peers: peers.len(),
+            download_rate: peers.iter().map(|peer| peer.download_rate).sum(),
+            upload_rate: peers.iter().map(|peer| peer.upload_rate).sum(),
+            downloaded,
+            uploaded,
+            tracker_error: lock(&self.inner.tracker_error).clone(),
+            ratio: self.inner.ratio(),
+            seeding_time,
+        }
+    }
+
+    /// Returns informations about torrent needed by queue.
+    fn queue_entry(&self) -> QueueEntry {
+        let downloader = &self.inner.downloader;
+        let seeding = self.inner.seeding_time().is_some();
+        let stopped = self.inner.result.get().is_some();
+        let reason = downloader.pause_reason();
+        let peers = downloader.peer_stats().snapshot();
+        QueueEntry {
+            info_hash: self.info_hash(),
+            seeding,
+            managed: !stopped
+                && matches!(
+                    reason.as_deref(),
+                    None | Some(QUEUED_REASON | DISK_FULL_REASON)
+                ),
+            active: reason.as_deref() != Some(QUEUED_REASON),
+            rate: if seeding {
+                peers.iter().map(|peer| peer.upload_rate).sum()
+            } else {
+                peers.iter().map(|peer| peer.download_rate).sum()
+            },
+        }
+    }
+
+    /// Returns true, if both torrents have some common swarm.
+    fn shares_swarm(&self, other: &TorrentHandle) -> bool {
+        let swarm_hashes = self.inner.downloader.swarm_hashes();
+        other
+            .inner
+            .downloader
+            .swarm_hashes()
+            .iter()
+            .any(|swarm_hash| swarm_hashes.contains(swarm_hash))
+    }
+}
+
+/// Torrent of session, shared by its handles and its download task.
+struct TorrentEntry {
+    name: String,
+    folder: PathBuf,
+    /// Announce URLs of trackers from torrent file.
+    trackers: Vec<String>,
+    downloader: Arc<TorrentDownloader>,
+    task: Mutex<Option<JoinHandle<()>>>,
+    /// Result of finished download task, error is kept as text.
+    result: OnceLock<std::result::Result<(), String>>,
+    tracker_error: Mutex<Option<String>>,
+    queue_changed: Arc<Notify>,
+    /// Seeding goals of session.
+    global_seeding: Arc<Mutex<SeedingLimits>>,
+    /// Own seeding goals of torrent.
+    seeding_limits: Mutex<Option<SeedingLimits>>,
+    /// Time when download completed.
+    seeding_since: Mutex<Option<Instant>>,
+    /// Time of seeding in previous runs, from resume data.
+    seeded_before: Duration,
+    /// File, to which resume data are stored on shutdown.
+    resume_file: Option<PathBuf>,
+    /// Torrent was announced to trackers, and `stopped` wasn't announced since then.
+    announced: AtomicBool,
+}
+
+impl TorrentEntry {
+    /// Shut down torrent and wait, until its task ends.
+    /// Written data are flushed and `stopped` is announced, connections with peers are closed.
+    async fn stop(&self) {
+        self.downloader.shutdown();
+        let task = lock(&self.task).take();
+        if let Some(task) = task {
+            let _ = task.await;
+        }
+    }
+
+    fn seeding_limits(&self) -> SeedingLimits {
+        lock(&self.seeding_limits).unwrap_or_else(|| *lock(&self.global_seeding))
+    }
+
+    fn seeding_time(&self) -> Option<Duration> {
+        lock(&self.seeding_since).map(|since| self.seeded_before + since.elapsed())
+    }
+
+    fn ratio(&self) -> f64 {
+        let (downloaded, uploaded) = self.downloader.peer_stats().totals();
+        share_ratio(
+            uploaded,
+            downloaded,
+            self.downloader.layout().total_length(),
+        )
+    }
+
+    /// Wait until some seeding goal is reached.
+    async fn seeding_goal(&self) {
+        loop {
+            let seeding_time = self.seeding_time().unwrap_or_default();
+            if self.seeding_limits().is_reached(self.ratio(), seeding_time) {
+                return;
+            }
+            sleep(SEEDING_CHECK_INTERVAL).await;
+        }
+    }
+
+    /// Wait until torrent is resumed by user or by queue, or until it's shut down.
+    async fn wait_for_resume(&self) {
+        let mut pause = self.downloader.subscribe_pause();
+        let shutdown = self.downloader.shutdown_token();
+        tokio::select! {
+            _ = pause.wait_for(Option::is_none) => {}
+            _ = shutdown.cancelled() => {}
+        }
+    }
+}
+
+/// Returns announce URL and URLs from announce list of torrent, without duplicates.
+fn tracker_urls(torrent: &Torrent) -> Vec<String> {
+    let mut trackers: Vec<String> = torrent.announce.iter().cloned().collect();
+    for url in torrent.announce_list.iter().flatten().flatten() {
+        if !trackers.contains(url) {
+            trackers.push(url.clone());
+        }
+    }
+    trackers
+}
+
+/// Run torrent until it's finished, and record its result.
+async fn run_torrent(entry: Arc<TorrentEntry>, torrent: Torrent, peer_id: PeerId, port: u16) {
+    let result = download_and_seed(&entry, &torrent, peer_id, port).await;
+    if entry.downloader.is_shut_down() {
+        shut_down_torrent(&entry, &torrent, &peer_id, port).await;
+        return;
+    }
+    let _ = entry.result.set(result.map_err(|err| format!("{err:#}")));
+    // Finished torrent frees its slot in queue
+    entry.queue_changed.notify_one();
+}
+
+/// Download torrent from peers found by trackers and peers connecting to session listener,
+/// then seed it until its seeding goal is reached.
+async fn download_and_seed(
+    entry: &TorrentEntry,
+    torrent: &Torrent,
+    peer_id: PeerId,
+    port: u16,
+) -> Result<()> {
+    let downloader = &entry.downloader;
+    // Torrent waiting in queue is not announced
+    entry.wait_for_resume().await;
+    if downloader.is_shut_down() {
+        return Ok(());
+    }
+    let peers = announce_torrent(entry, torrent, &peer_id, port, AnnounceEvent::Started).await;
+
+    // Indexes of written pieces are not needed, progress is read from piece pool
+    let (downloaded_sender, mut downloaded_receiver) = mpsc::channel(16);
+    let folder = entry.folder.to_string_lossy().into_owned();
+    let (result, ()) = tokio::join!(
+        downloader.download_torrent(peers, &peer_id, folder, downloaded_sender),
+        async { while downloaded_receiver.recv().await.is_some() {} }
+    );
+    result?;
+    if downloader.is_shut_down() {
+        return Ok(());
+    }
+    *lock(&entry.seeding_since) = Some(Instant::now());
+    entry.queue_changed.notify_one();
+    announce_torrent(entry, torrent, &peer_id, port, AnnounceEvent::Completed).await;
+
+    loop {
+        downloader
+            .seed_torrent(&peer_id, entry.seeding_goal())
+            .await?;
+        if downloader.is_shut_down() {
+            return Ok(());
+        }
+        announce_torrent(entry, torrent, &peer_id, port, AnnounceEvent::Stopped).await;
+        if entry.seeding_limits().action == SeedingAction::Stop {
+            return Ok(());
+        }
+        downloader.pause(SEEDING_GOAL_REASON);
+        entry.queue_changed.notify_one();
+        entry.wait_for_resume().await;
+        if downloader.is_shut_down() {
+            return Ok(());
+        }
+        announce_torrent(entry, torrent, &peer_id, port, AnnounceEvent::Started).await;
+    }
+}
+
+/// Store resume data of torrent, which was shut down, and announce `stopped` to trackers with short deadline.
+/// Failure to store resume data is recorded as result of torrent.
+async fn shut_down_torrent(entry: &TorrentEntry, torrent: &Torrent, peer_id: &PeerId, port: u16) {
+    if let Some(resume_file) = &entry.resume_file {
+        let mut resume_data = entry.downloader.resume_data().await;
+        resume_data.seeding_time = entry
+            .seeding_time()
+            .unwrap_or(entry.seeded_before)
+            .as_secs();
+        if let Err(err) = resume_data.save(resume_file) {
+            let _ = entry
+                .result
+                .set(Err(format!("Failed to store resume data: {err:#}")));
+        }
+    }
+    if entry.announced.load(Ordering::Relaxed) {
+        let stopped = announce_torrent(entry, torrent, peer_id, port, AnnounceEvent::Stopped);
+        let _ = timeout(STOPPED_ANNOUNCE_TIMEOUT, stopped).await;
+    }
+}
+
+/// Announce event with transferred bytes to trackers of all swarms of torrent.
+/// Returns peers of swarm with default info hash, peers of other swarms are added to downloader.
+async fn announce_torrent(
+    entry: &TorrentEntry,
+    torrent: &Torrent,
+    peer_id: &PeerId,
+    port: u16,
+    event: AnnounceEvent,
+) -> Vec<Peer> {
+    let downloader = &entry.downloader;
+    let stats = downloader.announce_stats().await;
+
+    entry
+        .announced
+        .store(event != AnnounceEvent::Stopped, Ordering::Relaxed);
+    let mut peers = Vec::new();
+    for &swarm_hash in downloader.swarm_hashes() {
+        match announce(torrent, &swarm_hash, peer_id, port, stats, Some(event)).await {
+            Ok(response) if swarm_hash == downloader.info_hash() => {
+                *lock(&entry.tracker_error) = None;
+                peers = response.peers;
+            }
+            Ok(response) => {
+                downloader
+                    .add_swarm_peers(&response.peers, swarm_hash)
+                    .await
+            }
+            Err(err) => *lock(&entry.tracker_error) = Some(format!("{err:#}")),
+        }
+    }
+    peers
+}
+
+/// Update queue regularly and after every change, so transfer rates of torrents are checked.
+async fn manage_queue(
+    torrents: Arc<Mutex<Vec<TorrentHandle>>>,
+    queue: Arc<Mutex<Queue>>,
+    queue_changed: Arc<Notify>,
+) {
+    loop {
+        tokio::select! {
+            _ = queue_changed.notified() => {}
+            _ = sleep(QUEUE_INTERVAL) => {}
+        }
+        update_queue(&lock(&torrents), &queue);
+    }
+}
+
+/// Start torrents, which got slot in queue, and stop torrents, which lost it.
+/// Torrents paused by user or for other reason are left untouched.
+fn update_queue(torrents: &[TorrentHandle], queue: &Mutex<Queue>) {
+    let entries: Vec<QueueEntry> = torrents.iter().map(TorrentHandle::queue_entry).collect();
+    let plan = lock(queue).plan(&entries, Instant::now());
+    for (info_hash, active) in plan {
+        let Some(handle) = torrents
+            .iter()
+            .find(|handle| handle.info_hash() == info_hash)
+        else {
+            continue;
+        };
+        if active {
+            handle.inner.downloader.resume_if_paused_by(QUEUED_REASON);
+        } else {
+            handle.inner.downloader.pause_if_running(QUEUED_REASON);
+        }
+    }
+}
+
+/// Accept incoming connections, and hand them over to torrent with info hash from their handshake.
+async fn accept_peers(listener: TcpListener, torrents: Arc<Mutex<Vec<TorrentHandle>>>) {
+    loop {
+        let mut stream = match listener.accept().await {
+            Ok((stream, _)) => stream,
+            Err(_) => {
+                sleep(ACCEPT_RETRY_INTERVAL).await;
+                continue;
+            }
+        };
+        let torrents = torrents.clone();
+        tokio::spawn(async move {
+            let Ok(handshake) = read_handshake(&mut stream).await else {
+                return;
+            };
+            let handle = lock(&torrents)
+                .iter()
+                .find(|handle| {
+                    handle
+                        .inner
+                        .downloader
+                        .swarm_hashes()
+                        .iter()
+                        .any(|swarm_hash| swarm_hash.to_arr() == handshake.info_hash)
+                })
+                .cloned();
+            if let Some(handle) = handle {
+                let _ = handle.inner.downloader.add_incoming_peer(stream, handshake);
+            }
+        });
+    }
+}
+
+fn lock<T>(mutex: &Mutex<T>) -> MutexGuard<'_, T> {
+    mutex.lock().unwrap_or_else(|err| err.into_inner())
+}
+
+#[tokio::test]
+async fn session_routes_incoming_peers() {
+    use crate::peer_comunication::handshake::Handshake;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::TcpStream;
+
+    let torrent = crate::test_utils::test_torrent(32, 16);
+    let folder = tempfile::tempdir().unwrap();
+    let session = Session::new(SessionOptions {
+        listen_addr: SocketAddr::from(([127, 0, 0, 1], 0)),
+        ..SessionOptions::default()
+    })
+    .await
+    .unwrap();
+    let storage = StorageOptions::default();
+
+    let metainfo = Metainfo::from_torrent(torrent.clone()).unwrap();
+    let info_hash = metainfo.info_hash();
+    let handle = session
+        .add_torrent(metainfo, folder.path(), &storage)
+        .await
+        .unwrap();
+    let err = session
+        .add_torrent(
+            Metainfo::from_torrent(torrent).unwrap(),
+            folder.path(),
+            &storage,
+        )
+        .await
+        .err()
+        .unwrap();
+    assert!(err.downcast_ref::<DuplicateTorrent>().is_some());
+    assert_eq!(session.torrents().len(), 1);
+    assert_eq!(session.queue_position(&info_hash), Some(0));
+
+    // Peer connecting to session gets handshake of the torrent
+    let mut stream = TcpStream::connect(session.local_addr()).await.unwrap();
+    let handshake = Handshake::new(&info_hash.to_arr(), &[7; 20]);
+    stream.write_all(&handshake.get_bytes()).await.unwrap();
+    let mut answer = [0u8; 68];
+    stream.read_exact(&mut answer).await.unwrap();
+    let mut remote = Handshake::new(&[0; 20], &[0; 20]);
+    remote.set_bytes(&answer);
+    assert_eq!(remote.info_hash, info_hash.to_arr());
+    assert_eq!(remote.peer_id, session.peer_id().to_arr());
+
+    handle.pause();
+    let status = handle.status().await;
+    assert_eq!(
+        status.state,
+        TorrentState::Paused(USER_PAUSE_REASON.to_string())
+    );
+    assert_eq!(status.remaining_pieces, 2);
+    assert!(status.tracker_error.is_some());
+    handle.resume();
+    while handle.status().await.state != TorrentState::Downloading {
+        tokio::time::sleep(Duration::from_millis(10)).await;
+    }
+
+    // Resuming of running torrent doesn't move it back to queue
+    handle.resume();
+    assert_eq!(handle.status().await.state, TorrentState::Downloading);
+
+    session.remove_torrent(&info_hash, true).await.unwrap();
+    assert!(session.torrent(&info_hash).is_none());
+}
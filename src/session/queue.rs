@@ -0,0 +1,275 @@
+use std::collections::{HashMap, HashSet};
+use std::path::PathBuf;
+use std::time::{Duration, Instant};
+
+use anyhow::Result;
+use serde::{Deserialize, Serialize};
+
+use crate::hash::InfoHash;
+
+/// Default maximal number of torrents downloading at the same time.
+pub const DEFAULT_MAX_ACTIVE_DOWNLOADS: usize = 3;
+/// Default maximal number of torrents seeding at the same time.
+pub const DEFAULT_MAX_ACTIVE_SEEDS: usize = 5;
+/// Default transfer rate in bytes per second, under which active torrent is slow.
+const DEFAULT_SLOW_RATE: f64 = 2.0 * 1024.0;
+/// Default time after start of torrent, before it can be found slow.
+const DEFAULT_SLOW_TIMEOUT: Duration = Duration::from_secs(60);
+/// Reason of pause of torrents waiting in queue.
+pub const QUEUED_REASON: &str = "Queued";
+
+/// Settings of queue, which limits number of active torrents.
+#[derive(Debug, Clone)]
+pub struct QueueOptions {
+    pub max_active_downloads: usize,
+    pub max_active_seeds: usize,
+    /// Slow and stalled torrents don't take slot in limits, so next torrent in queue is started.
+    pub auto_manage: bool,
+    /// Transfer rate in bytes per second, under which active torrent is slow.
+    /// Download rate is used for downloading torrents, upload rate for seeds.
+    pub slow_rate: f64,
+    /// Time after start of torrent, before it can be found slow, so it has time to connect peers.
+    pub slow_timeout: Duration,
+    /// File, from which queue order is loaded and to which it's stored after every change.
+    pub state_file: Option<PathBuf>,
+}
+
+impl Default for QueueOptions {
+    fn default() -> Self {
+        QueueOptions {
+            max_active_downloads: DEFAULT_MAX_ACTIVE_DOWNLOADS,
+            max_active_seeds: DEFAULT_MAX_ACTIVE_SEEDS,
+            auto_manage: true,
+            slow_rate: DEFAULT_SLOW_RATE,
+            slow_timeout: DEFAULT_SLOW_TIMEOUT,
+            state_file: None,
+        }
+    }
+}
+
+/// Movement of torrent in queue.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum QueueMove {
+    Up,
+    Down,
+    Top,
+    Bottom,
+}
+
+/// Informations about torrent, needed to decide if it should be active.
+#[derive(Debug, Clone)]
+pub(super) struct QueueEntry {
+    pub info_hash: InfoHash,
+    /// All wanted pieces are downloaded.
+    pub seeding: bool,
+    /// Torrent is not paused by user and didn't fail, so queue can start and stop it.
+    pub managed: bool,
+    /// Torrent runs now, it's not waiting in queue.
+    pub active: bool,
+    /// Download rate of downloading torrent, upload rate of seed.
+    pub rate: f64,
+}
+
+/// Queue order, as it's stored in state file.
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct QueueState {
+    order: Vec<InfoHash>,
+}
+
+/// Order of torrents of session, first torrents are started first.
+/// Order contains also torrents loaded from state file, which were not added to session yet,
+/// so they get their position back when they are added.
+pub(super) struct Queue {
+    options: QueueOptions,
+    order: Vec<InfoHash>,
+    added: HashSet<InfoHash>,
+    active_since: HashMap<InfoHash, Instant>,
+}
+
+impl Queue {
+    /// Create queue, order is loaded from state file, if it exists.
+    pub(super) fn new(options: QueueOptions) -> Result<Self> {
+        let mut state = QueueState::default();
+        if let Some(state_file) = &options.state_file {
+            if state_file.exists() {
+                state = serde_json::from_str(&std::fs::read_to_string(state_file)?)?;
+            }
+        }
+        Ok(Queue {
+            options,
+            order: state.order,
+            added: HashSet::new(),
+            active_since: HashMap::new(),
+        })
+    }
+
+    /// Add torrent to the end of queue, or to its stored position.
+    pub(super) fn add(&mut self, info_hash: InfoHash) -> Result<()> {
+        if !self.order.contains(&info_hash) {
+            self.order.push(info_hash);
+        }
+        self.added.insert(info_hash);
+        self.save()
+    }
+
+    /// Remove torrent from queue.
+    pub(super) fn remove(&mut self, info_hash: &InfoHash) -> Result<()> {
+        self.order.retain(|queued| queued != info_hash);
+        self.added.remove(info_hash);
+        self.active_since.remove(info_hash);
+        self.save()
+    }
+
+    /// Returns position of torrent among torrents of session, `0` is the first.
+    pub(super) fn position(&self, info_hash: &InfoHash) -> Option<usize> {
+        self.added_order().position(|queued| queued == info_hash)
+    }
+
+    /// Move torrent in queue, torrents loaded from state file and not added yet are skipped.
+    pub(super) fn move_torrent(&mut self, info_hash: &InfoHash, movement: QueueMove) -> Result<()> {
+        let added: Vec<usize> = (0..self.order.len())
+            .filter(|&idx| self.added.contains(&self.order[idx]))
+            .collect();
+        let Some(position) = added.iter().position(|&idx| self.order[idx] == *info_hash) else {
+            anyhow::bail!("Torrent {info_hash} is not in queue");
+        };
+        let target = match movement {
+            QueueMove::Up => position.saturating_sub(1),
+            QueueMove::Down => (position + 1).min(added.len() - 1),
+            QueueMove::Top => 0,
+            QueueMove::Bottom => added.len() - 1,
+        };
+        // Torrent moved down is inserted after the target, which shifted by one after removal
+        let torrent = self.order.remove(added[position]);
+        self.order.insert(added[target], torrent);
+        self.save()
+    }
+
+    /// Decide which torrents should run, torrents are started in queue order until limits are reached.
+    /// Returns info hashes of managed torrents with `true` for torrents, which should be active.
+    pub(super) fn plan(&mut self, entries: &[QueueEntry], now: Instant) -> Vec<(InfoHash, bool)> {
+        let mut entries: Vec<&QueueEntry> = entries.iter().filter(|entry| entry.managed).collect();
+        entries.sort_by_key(|entry| self.position(&entry.info_hash).unwrap_or(usize::MAX));
+
+        let mut downloads = 0;
+        let mut seeds = 0;
+        let mut plan = Vec::new();
+        for entry in entries {
+            let (count, limit) = if entry.seeding {
+                (&mut seeds, self.options.max_active_seeds)
+            } else {
+                (&mut downloads, self.options.max_active_downloads)
+            };
+            let active = *count < limit;
+            if active && !(self.options.auto_manage && self.is_slow(entry, now)) {
+                *count += 1;
+            }
+
+            if active && entry.active {
+                self.active_since.entry(entry.info_hash).or_insert(now);
+            } else if active {
+                self.active_since.insert(entry.info_hash, now);
+            } else {
+                self.active_since.remove(&entry.info_hash);
+            }
+            plan.push((entry.info_hash, active));
+        }
+        plan
+    }
+
+    /// Returns true, if torrent runs long enough and its transfer rate stays under the limit.
+    fn is_slow(&self, entry: &QueueEntry, now: Instant) -> bool {
+        let Some(active_since) = self.active_since.get(&entry.info_hash) else {
+            return false;
+        };
+        entry.active
+            && now.duration_since(*active_since) >= self.options.slow_timeout
+            && entry.rate < self.options.slow_rate
+    }
+
+    /// Returns info hashes of torrents of session in queue order.
+    fn added_order(&self) -> impl Iterator<Item = &InfoHash> {
+        self.order
+            .iter()
+            .filter(|info_hash| self.added.contains(info_hash))
+    }
+
+    /// Store queue order to state file, if it's set.
+    fn save(&self) -> Result<()> {
+        let Some(state_file) = &self.options.state_file else {
+            return Ok(());
+        };
+        let state = QueueState {
+            order: self.order.clone(),
+        };
+        std::fs::write(state_file, serde_json::to_string_pretty(&state)?)?;
+        Ok(())
+    }
+}
+
+#[test]
+fn queue_limits_and_order() {
+    let dir = tempfile::tempdir().unwrap();
+    let options = QueueOptions {
+        max_active_downloads: 2,
+        max_active_seeds: 1,
+        state_file: Some(dir.path().join("queue.json")),
+        ..QueueOptions::default()
+    };
+    let hashes: Vec<InfoHash> = (1..=4).map(|byte| InfoHash::from([byte; 20])).collect();
+    let entry = |info_hash: InfoHash, seeding: bool, active: bool, rate: f64| QueueEntry {
+        info_hash,
+        seeding,
+        managed: true,
+        active,
+        rate,
+    };
+
+    let mut queue = Queue::new(options.clone()).unwrap();
+    for info_hash in &hashes {
+        queue.add(*info_hash).unwrap();
+    }
+    queue.move_torrent(&hashes[3], QueueMove::Top).unwrap();
+    queue.move_torrent(&hashes[0], QueueMove::Down).unwrap();
+    assert_eq!(queue.position(&hashes[3]), Some(0));
+    assert_eq!(queue.position(&hashes[1]), Some(1));
+    assert_eq!(queue.position(&hashes[0]), Some(2));
+
+    let now = Instant::now();
+    let entries = vec![
+        entry(hashes[0], false, false, 0.0),
+        entry(hashes[1], false, false, 0.0),
+        entry(hashes[2], true, false, 0.0),
+        entry(hashes[3], false, false, 0.0),
+    ];
+    let plan = queue.plan(&entries, now);
+    assert_eq!(
+        plan,
+        vec![
+            (hashes[3], true),
+            (hashes[1], true),
+            (hashes[0], false),
+            (hashes[2], true),
+        ]
+    );
+
+    // Stalled download yields its slot to next torrent in queue
+    let later = now + DEFAULT_SLOW_TIMEOUT;
+    let entries = vec![
+        entry(hashes[0], false, false, 0.0),
+        entry(hashes[1], false, true, 0.0),
+        entry(hashes[2], true, true, 0.0),
+        entry(hashes[3], false, true, 100_000.0),
+    ];
+    let plan = queue.plan(&entries, later);
+    assert!(plan.contains(&(hashes[0], true)));
+    assert!(plan.contains(&(hashes[1], true)));
+
+    // Order is restored after restart, when torrents are added in other order
+    let mut queue = Queue::new(options).unwrap();
+    for info_hash in hashes.iter().rev() {
+        queue.add(*info_hash).unwrap();
+    }
+    assert_eq!(queue.position(&hashes[3]), Some(0));
+    assert_eq!(queue.position(&hashes[2]), Some(3));
+}
@@ -0,0 +1,114 @@
+use std::time::Duration;
+
+/// What happens with torrent, when its seeding goal is reached.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum SeedingAction {
+    /// Torrent stops and it's finished, it can't be resumed.
+    #[default]
+    Stop,
+    /// Torrent is paused, it seeds again when it's resumed and its goal is raised.
+    Pause,
+}
+
+/// Goals of seeding after download completes, torrent seeds forever if no goal is set.
+/// Seeding ends, when any of the goals is reached.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct SeedingLimits {
+    /// Share ratio, uploaded bytes divided by downloaded bytes, e.g. `2.0`.
+    pub ratio: Option<f64>,
+    /// Time of seeding after download completed.
+    pub seed_time: Option<Duration>,
+    pub action: SeedingAction,
+}
+
+impl SeedingLimits {
+    /// Returns true, if torrent with given share ratio, which seeds for given time, reached some goal.
+    pub fn is_reached(&self, ratio: f64, seeding_for: Duration) -> bool {
+        self.ratio.is_some_and(|goal| ratio >= goal)
+            || self.seed_time.is_some_and(|goal| seeding_for >= goal)
+    }
+}
+
+/// Returns share ratio from transferred payload.
+/// Size of torrent is used instead of downloaded bytes, if its data were not downloaded by this client.
+pub(super) fn share_ratio(uploaded: u64, downloaded: u64, size: u64) -> f64 {
+    let downloaded = if downloaded == 0 { size } else { downloaded };
+    if downloaded == 0 {
+        return 0.0;
+    }
+    uploaded as f64 / downloaded as f64
+}
+
+#[tokio::test]
+async fn torrent_seeds_until_ratio() {
+    use super::{Session, SessionOptions, TorrentState};
+    use crate::metainfo::Metainfo;
+    use crate::peer_comunication::handshake::Handshake;
+    use crate::storage::StorageOptions;
+    use crate::test_utils::{data_torrent, read_message, send_message, spawn_seeder, tracker_peer};
+    use std::net::SocketAddr;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::TcpStream;
+
+    let data: Vec<u8> = (0..32).collect();
+    let torrent = data_torrent(&data, 16);
+    let metainfo = Metainfo::from_torrent(torrent).unwrap();
+    let info_hash = metainfo.info_hash().to_arr();
+    let folder = tempfile::tempdir().unwrap();
+    let session = Session::new(SessionOptions {
+        listen_addr: SocketAddr::from(([127, 0, 0, 1], 0)),
+        seeding: SeedingLimits {
+            ratio: Some(0.5),
+            ..SeedingLimits::default()
+        },
+        ..SessionOptions::default()
+    })
+    .await
+    .unwrap();
+
+    // Other peer has all pieces, and uploads them to session
+    let seed_addr = spawn_seeder(metainfo.info_hash(), data.clone(), 16).await;
+
+    let handle = session
+        .add_torrent(metainfo, folder.path(), &StorageOptions::default())
+        .await
+        .unwrap();
+    handle
+        .downloader()
+        .add_swarm_peers(&[tracker_peer(seed_addr)], handle.info_hash())
+        .await;
+    while handle.status().await.state != TorrentState::Seeding {
+        tokio::time::sleep(Duration::from_millis(20)).await;
+    }
+
+    // Session uploads one piece to leecher, it's half of downloaded data
+    let mut leecher = TcpStream::connect(session.local_addr()).await.unwrap();
+    let handshake = Handshake::new(&info_hash, &[2; 20]);
+    leecher.write_all(&handshake.get_bytes()).await.unwrap();
+    let mut answer = [0u8; 68];
+    leecher.read_exact(&mut answer).await.unwrap();
+    assert_eq!(
+        read_message(&mut leecher).await.unwrap(),
+        (5, vec![0b1100_0000])
+    );
+    send_message(&mut leecher, 2, &[]).await.unwrap();
+    assert_eq!(read_message(&mut leecher).await.unwrap(), (1, vec![]));
+    let mut request = Vec::new();
+    for value in [1u32, 0, 16] {
+        request.extend_from_slice(&value.to_be_bytes());
+    }
+    send_message(&mut leecher, 6, &request).await.unwrap();
+    let (id, payload) = read_message(&mut leecher).await.unwrap();
+    assert_eq!(id, 7);
+    assert_eq!(payload[8..], data[16..]);
+
+    tokio::time::timeout(Duration::from_secs(10), async {
+        while handle.status().await.state != TorrentState::Finished {
+            tokio::time::sleep(Duration::from_millis(20)).await;
+        }
+    })
+    .await
+    .unwrap();
+    let status = handle.status().await;
+    assert_eq!((status.downloaded, status.uploaded), (32, 16));
+}
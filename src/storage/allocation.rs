@@ -0,0 +1,202 @@
+use std::fs::File;
+use std::path::Path;
+use std::str::FromStr;
+
+use anyhow::Result;
+
+use super::location::StoragePaths;
+use crate::file_layout::{FileLayout, FilePriority};
+
+const MIB: u64 = 1024 * 1024;
+
+/// How files are allocated on disk, before data are written into them.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum AllocationMode {
+    /// Files grow as data are written.
+    None,
+    /// Files are extended to full length, but disk blocks are allocated when data are written.
+    #[default]
+    Sparse,
+    /// All disk blocks of file are allocated when file is created (`fallocate`), where it's supported.
+    Full,
+}
+
+impl FromStr for AllocationMode {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "none" => Ok(AllocationMode::None),
+            "sparse" => Ok(AllocationMode::Sparse),
+            "full" => Ok(AllocationMode::Full),
+            _ => anyhow::bail!("Unknown allocation mode {s}"),
+        }
+    }
+}
+
+/// Allocate file with given length according to mode, file is never shortened.
+pub(super) fn allocate_file(file: &File, length: u64, mode: AllocationMode) -> std::io::Result<()> {
+    match mode {
+        AllocationMode::None => Ok(()),
+        AllocationMode::Sparse => {
+            if file.metadata()?.len() < length {
+                file.set_len(length)?;
+            }
+            Ok(())
+        }
+        AllocationMode::Full => {
+            if file.metadata()?.len() < length {
+                file.set_len(length)?;
+            }
+            reserve_range(file, 0, length)
+        }
+    }
+}
+
+/// Allocate disk blocks of range of file, so writing into the range can't fail on full disk.
+#[cfg(any(target_os = "linux", target_os = "android", target_os = "freebsd"))]
+pub(super) fn reserve_range(file: &File, offset: u64, length: u64) -> std::io::Result<()> {
+    use std::os::fd::AsRawFd;
+
+    if length == 0 {
+        return Ok(());
+    }
+    // SAFETY: File descriptor is valid, while `file` lives.
+    let result = unsafe {
+        libc::posix_fallocate(
+            file.as_raw_fd(),
+            offset as libc::off_t,
+            length as libc::off_t,
+        )
+    };
+    match result {
+        0 => Ok(()),
+        // File system doesn't support allocation, blocks are allocated on write
+        libc::EOPNOTSUPP | libc::EINVAL => Ok(()),
+        err => Err(std::io::Error::from_raw_os_error(err)),
+    }
+}
+
+/// Space can't be reserved on this system, blocks are allocated on write.
+#[cfg(not(any(target_os = "linux", target_os = "android", target_os = "freebsd")))]
+pub(super) fn reserve_range(_file: &File, _offset: u64, _length: u64) -> std::io::Result<()> {
+    Ok(())
+}
+
+/// Returns free space available to this process on file system with given folder,
+/// `None` if it can't be found out. Folder doesn't have to exist yet.
+#[cfg(unix)]
+#[allow(clippy::unnecessary_cast)]
+pub fn free_space(folder: &Path) -> Option<u64> {
+    use std::os::unix::ffi::OsStrExt;
+
+    let folder = folder
+        .ancestors()
+        .map(|dir| {
+            if dir.as_os_str().is_empty() {
+                Path::new(".")
+            } else {
+                dir
+            }
+        })
+        .find(|dir| dir.exists())?;
+    let path = std::ffi::CString::new(folder.as_os_str().as_bytes()).ok()?;
+    let mut stat = std::mem::MaybeUninit::<libc::statvfs>::zeroed();
+    // SAFETY: Path is valid C string and `stat` is large enough for result.
+    if unsafe { libc::statvfs(path.as_ptr(), stat.as_mut_ptr()) } != 0 {
+        return None;
+    }
+    // SAFETY: `statvfs` succeeded, so the structure is filled.
+    let stat = unsafe { stat.assume_init() };
+    Some(stat.f_bavail as u64 * stat.f_frsize as u64)
+}
+
+#[cfg(not(unix))]
+pub fn free_space(_folder: &Path) -> Option<u64> {
+    None
+}
+
+/// Returns error, if there isn't enough free space in current folder for not yet allocated parts of wanted files.
+pub(super) fn check_free_space(
+    layout: &FileLayout,
+    paths: &StoragePaths,
+    file_priorities: &[FilePriority],
+) -> Result<()> {
+    let folder = paths.current_folder();
+    let Some(available) = free_space(folder) else {
+        return Ok(());
+    };
+    let needed: u64 = layout
+        .files()
+        .iter()
+        .zip(file_priorities)
+        .filter(|(entry, priority)| {
+            **priority != FilePriority::Skip
+                && !entry.attributes.padding
+                && entry.attributes.symlink.is_none()
+        })
+        .map(|(entry, _)| {
+            entry
+                .length
+                .saturating_sub(allocated_size(&paths.file_path(entry)))
+        })
+        .sum();
+    anyhow::ensure!(
+        needed <= available,
+        "Not enough free space in {}: {} MiB is needed, but only {} MiB is available",
+        folder.display(),
+        needed.div_ceil(MIB),
+        available / MIB
+    );
+    Ok(())
+}
+
+/// Returns number of bytes of file allocated on disk, sparse files take less than their length.
+fn allocated_size(path: &Path) -> u64 {
+    let Ok(metadata) = std::fs::metadata(path) else {
+        return 0;
+    };
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::MetadataExt;
+        metadata.len().min(metadata.blocks() * 512)
+    }
+    #[cfg(not(unix))]
+    metadata.len()
+}
+
+/// Returns true, if error was caused by full disk or exceeded disk quota.
+pub fn is_disk_full(err: &anyhow::Error) -> bool {
+    err.chain()
+        .filter_map(|cause| cause.downcast_ref::<std::io::Error>())
+        .any(|err| {
+            matches!(
+                err.kind(),
+                std::io::ErrorKind::StorageFull | std::io::ErrorKind::QuotaExceeded
+            )
+        })
+}
+
+#[test]
+fn allocation_modes_and_free_space() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("file");
+    let file = File::create(&path).unwrap();
+    allocate_file(&file, 1024 * 1024, AllocationMode::None).unwrap();
+    assert_eq!(file.metadata().unwrap().len(), 0);
+    allocate_file(&file, 1024 * 1024, AllocationMode::Sparse).unwrap();
+    assert_eq!(file.metadata().unwrap().len(), 1024 * 1024);
+    allocate_file(&file, 1024 * 1024, AllocationMode::Full).unwrap();
+    #[cfg(target_os = "linux")]
+    assert_eq!(allocated_size(&path), 1024 * 1024);
+
+    // Torrent larger than any disk
+    let torrent = crate::test_utils::test_torrent(1 << 60, 1 << 50);
+    let layout = FileLayout::from_torrent(&torrent).unwrap();
+    let paths = StoragePaths::new(dir.path().to_path_buf());
+    if free_space(dir.path()).is_some() {
+        let err = check_free_space(&layout, &paths, &[FilePriority::Normal]).unwrap_err();
+        assert!(err.to_string().starts_with("Not enough free space"));
+    }
+    assert!(check_free_space(&layout, &paths, &[FilePriority::Skip]).is_ok());
+}
@@ -0,0 +1,386 @@
+use std::collections::BTreeMap;
+use std::path::Path;
+use std::sync::Arc;
+
+use anyhow::Result;
+use async_trait::async_trait;
+use sha1::{Digest, Sha1};
+use tokio::sync::{Mutex, Notify, Semaphore};
+use tokio::task::JoinHandle;
+
+use super::{check_block, Storage};
+use crate::file_layout::{FileLayout, FilePriority};
+use crate::hash::PieceHash;
+
+/// Default memory budget of write cache.
+pub const DEFAULT_CACHE_SIZE: usize = 32 * 1024 * 1024;
+
+/// Blocks smaller than this are merged with adjacent blocks, when they are written.
+const COALESCE_LIMIT: usize = 256 * 1024;
+/// Maximal size of one write of merged blocks.
+const MAX_RUN_SIZE: usize = 1024 * 1024;
+
+/// Written blocks, not yet stored in inner storage, indexed by offset in torrent data.
+/// Blocks never overlap, adjacent blocks are merged into one run, when they are written.
+type Runs = BTreeMap<u64, Vec<u8>>;
+
+/// Write cache in front of other storage.
+/// Adjacent blocks and pieces are coalesced and written to inner storage in large sequential writes,
+/// when half of memory budget is used. Writing to inner storage runs in background task,
+/// and writers wait, when whole budget is used, so downloads are slowed down by disk.
+/// Data are persistent only after `flush`, which is used as checkpoint.
+/// Data, which couldn't be written (e.g. on full disk), stay in cache and error is returned to the next writer.
+pub struct CachedStorage {
+    inner: Arc<dyn Storage>,
+    layout: Arc<FileLayout>,
+    memory_budget: usize,
+    /// Free bytes of memory budget.
+    budget: Arc<Semaphore>,
+    /// Notifies writers waiting for memory, that background writing failed.
+    flush_failed: Arc<Notify>,
+    state: Mutex<CacheState>,
+}
+
+struct CacheState {
+    dirty: Runs,
+    dirty_bytes: usize,
+    /// Permits of budget taken by dirty runs.
+    dirty_permits: u32,
+    /// Runs written in background, they are visible for reads until the writing ends.
+    flushing: Arc<Runs>,
+    /// Permits of budget taken by runs written in background.
+    flushing_permits: u32,
+    flush_task: Option<JoinHandle<Result<()>>>,
+}
+
+impl CachedStorage {
+    /// Creates cache of `inner` storage, using at most `memory_budget` bytes for written data.
+    pub fn new(inner: Arc<dyn Storage>, layout: Arc<FileLayout>, memory_budget: usize) -> Self {
+        let memory_budget = memory_budget.clamp(2, Semaphore::MAX_PERMITS.min(u32::MAX as usize));
+        CachedStorage {
+            inner,
+            layout,
+            memory_budget,
+            budget: Arc::new(Semaphore::new(memory_budget)),
+            flush_failed: Arc::new(Notify::new()),
+            state: Mutex::new(CacheState {
+                dirty: BTreeMap::new(),
+                dirty_bytes: 0,
+                dirty_permits: 0,
+                flushing: Arc::new(BTreeMap::new()),
+                flushing_permits: 0,
+                flush_task: None,
+            }),
+        }
+    }
+
+    /// Returns inner storage.
+    pub fn inner(&self) -> Arc<dyn Storage> {
+        self.inner.clone()
+    }
+
+    /// Start writing of dirty runs in background, after the previous writing ends.
+    async fn start_flush(&self, state: &mut CacheState) -> Result<()> {
+        self.wait_flush(state).await?;
+        let runs = Arc::new(std::mem::take(&mut state.dirty));
+        let permits = std::mem::take(&mut state.dirty_permits);
+        state.dirty_bytes = 0;
+        state.flushing = runs.clone();
+        state.flushing_permits = permits;
+
+        let inner = self.inner.clone();
+        let layout = self.layout.clone();
+        let budget = self.budget.clone();
+        let flush_failed = self.flush_failed.clone();
+        state.flush_task = Some(tokio::spawn(async move {
+            if let Err(err) = write_runs(&*inner, &layout, &runs).await {
+                flush_failed.notify_one();
+                return Err(err);
+            }
+            budget.add_permits(permits as usize);
+            Ok(())
+        }));
+        Ok(())
+    }
+
+    /// Wait until background writing ends.
+    /// If writing failed, its runs are returned to dirty runs, so they are written again later.
+    async fn wait_flush(&self, state: &mut CacheState) -> Result<()> {
+        let result = match state.flush_task.take() {
+            Some(flush_task) => flush_task.await?,
+            None => Ok(()),
+        };
+        let flushing = std::mem::replace(&mut state.flushing, Arc::new(BTreeMap::new()));
+        if result.is_err() {
+            let mut runs = Arc::try_unwrap(flushing).unwrap_or_else(|runs| (*runs).clone());
+            let mut bytes = runs.values().map(Vec::len).sum();
+            // Dirty runs are newer than failed ones
+            for (offset, block) in std::mem::take(&mut state.dirty) {
+                bytes = insert_block(&mut runs, offset, &block, bytes);
+            }
+            state.dirty = runs;
+            state.dirty_bytes = bytes;
+            state.dirty_permits += std::mem::take(&mut state.flushing_permits);
+        }
+        result
+    }
+
+    /// Write all cached data to inner storage, without syncing it.
+    async fn write_back(&self) -> Result<()> {
+        let mut state = self.state.lock().await;
+        self.start_flush(&mut state).await?;
+        self.wait_flush(&mut state).await
+    }
+}
+
+#[async_trait]
+impl Storage for CachedStorage {
+    async fn write_block(&self, piece_index: usize, begin: usize, data: &[u8]) -> Result<()> {
+        check_block(&self.layout, piece_index, begin, data.len())?;
+        {
+            let mut state = self.state.lock().await;
+            // Failed writing is reported, before new data are accepted
+            if state
+                .flush_task
+                .as_ref()
+                .is_some_and(|flush_task| flush_task.is_finished())
+            {
+                self.wait_flush(&mut state).await?;
+            }
+            // Data returned by failed writing can take whole budget, so they are written again first
+            if state.flush_task.is_none() && state.dirty_permits as usize >= self.memory_budget / 2
+            {
+                self.start_flush(&mut state).await?;
+            }
+        }
+        // Wait for free memory, large block can use only half of budget, so it never waits forever
+        let permits = data.len().min(self.memory_budget / 2) as u32;
+        loop {
+            tokio::select! {
+                permit = self.budget.acquire_many(permits) => {
+                    permit?.forget();
+                    break;
+                }
+                _ = self.flush_failed.notified() => {
+                    let mut state = self.state.lock().await;
+                    self.wait_flush(&mut state).await?;
+                }
+            }
+        }
+
+        let mut guard = self.state.lock().await;
+        let state = &mut *guard;
+        let offset = self.layout.piece_offset(piece_index) + begin as u64;
+        state.dirty_bytes = insert_block(&mut state.dirty, offset, data, state.dirty_bytes);
+        state.dirty_permits += permits;
+        // Writer waits for running flush only through memory budget
+        let flushing = state
+            .flush_task
+            .as_ref()
+            .is_some_and(|flush_task| !flush_task.is_finished());
+        // Overwritten blocks take permits without growing dirty runs, so used budget decides
+        if state.dirty_permits as usize >= self.memory_budget / 2 && !flushing {
+            self.start_flush(state).await?;
+        }
+        Ok(())
+    }
+
+    async fn read_block(&self, piece_index: usize, begin: usize, length: usize) -> Result<Vec<u8>> {
+        check_block(&self.layout, piece_index, begin, length)?;
+        let offset = self.layout.piece_offset(piece_index) + begin as u64;
+        let state = self.state.lock().await;
+        // Cached data are newer than data in inner storage, which don't have to exist yet
+        let covered = is_covered(&[&state.flushing, &state.dirty], offset, length);
+        let mut data = match self.inner.read_block(piece_index, begin, length).await {
+            Ok(data) => data,
+            Err(_) if covered => vec![0u8; length],
+            Err(err) => return Err(err),
+        };
+        overlay_runs(&state.flushing, offset, &mut data);
+        overlay_runs(&state.dirty, offset, &mut data);
+        Ok(data)
+    }
+
+    async fn hash_piece(&self, piece_index: usize) -> Result<PieceHash> {
+        let piece = self
+            .read_block(piece_index, 0, self.layout.piece_size(piece_index))
+            .await?;
+        Ok(PieceHash::from_bytes(Sha1::digest(piece).into()))
+    }
+
+    async fn prepare(&self, file_priorities: &[FilePriority]) -> Result<()> {
+        self.inner.prepare(file_priorities).await
+    }
+
+    /// Checkpoint, all cached data are written and synced to disk.
+    async fn flush(&self) -> Result<()> {
+        self.write_back().await?;
+        self.inner.flush().await
+    }
+
+    async fn finish(&self, file_priorities: &[FilePriority]) -> Result<()> {
+        self.write_back().await?;
+        self.inner.finish(file_priorities).await
+    }
+
+    async fn move_to(&self, folder: &Path) -> Result<()> {
+        self.write_back().await?;
+        self.inner.move_to(folder).await
+    }
+
+    async fn delete(&self) -> Result<()> {
+        let mut state = self.state.lock().await;
+        // Data are deleted, so failed writing doesn't matter
+        let _ = self.wait_flush(&mut state).await;
+        state.dirty.clear();
+        state.dirty_bytes = 0;
+        self.budget
+            .add_permits(std::mem::take(&mut state.dirty_permits) as usize);
+        self.inner.delete().await
+    }
+}
+
+/// Insert block into runs, overlapping parts of older blocks are replaced.
+/// Blocks are not merged here, so no data are copied, adjacent blocks are merged when they are written.
+/// Returns new number of bytes in runs.
+fn insert_block(runs: &mut Runs, offset: u64, data: &[u8], mut bytes: usize) -> usize {
+    let end = offset + data.len() as u64;
+    let overlapping: Vec<u64> = runs
+        .range(..end)
+        .rev()
+        .take_while(|(&start, run)| start + run.len() as u64 > offset)
+        .map(|(&start, _)| start)
+        .collect();
+    let mut block = data.to_vec();
+    let mut start = offset;
+    for run_start in overlapping {
+        let old = runs.remove(&run_start).expect("Run exists");
+        bytes -= old.len();
+        let old_end = run_start + old.len() as u64;
+        // Older data outside of new block are kept
+        if run_start < start {
+            let mut merged = old[..(start - run_start) as usize].to_vec();
+            merged.extend_from_slice(&block);
+            block = merged;
+            start = run_start;
+        }
+        if old_end > end {
+            block.extend_from_slice(&old[(end - run_start) as usize..]);
+        }
+    }
+    bytes += block.len();
+    runs.insert(start, block);
+    bytes
+}
+
+/// Copy parts of runs, which overlap with data at given offset, into data.
+fn overlay_runs(runs: &Runs, offset: u64, data: &mut [u8]) {
+    let end = offset + data.len() as u64;
+    for (&start, run) in runs.range(..end) {
+        let stop = start + run.len() as u64;
+        if stop <= offset {
+            continue;
+        }
+        let from = start.max(offset);
+        let to = stop.min(end);
+        data[(from - offset) as usize..(to - offset) as usize]
+            .copy_from_slice(&run[(from - start) as usize..(to - start) as usize]);
+    }
+}
+
+/// Returns true, if whole range is in runs.
+fn is_covered(runs: &[&Runs], offset: u64, length: usize) -> bool {
+    let end = offset + length as u64;
+    let mut ranges: Vec<(u64, u64)> = runs
+        .iter()
+        .flat_map(|runs| runs.range(..end))
+        .map(|(&start, run)| (start, start + run.len() as u64))
+        .filter(|&(_, stop)| stop > offset)
+        .collect();
+    ranges.sort_unstable();
+    let mut position = offset;
+    for (start, stop) in ranges {
+        if start > position {
+            break;
+        }
+        position = position.max(stop);
+    }
+    position >= end
+}
+
+/// Write runs to storage in order of their offsets.
+/// Adjacent small blocks are merged into one write, large blocks are written without copying.
+async fn write_runs(storage: &dyn Storage, layout: &FileLayout, runs: &Runs) -> Result<()> {
+    let mut blocks = runs.iter().peekable();
+    while let Some((&offset, block)) = blocks.next() {
+        let mut end = offset + block.len() as u64;
+        let mut run: Option<Vec<u8>> = None;
+        if block.len() < COALESCE_LIMIT {
+            while let Some((_, next)) = blocks.next_if(|(&next, next_block)| {
+                next == end && (end - offset) as usize + next_block.len() <= MAX_RUN_SIZE
+            }) {
+                run.get_or_insert_with(|| block.clone())
+                    .extend_from_slice(next);
+                end += next.len() as u64;
+            }
+        }
+        let piece_index = (offset / layout.piece_length()) as usize;
+        let begin = (offset - layout.piece_offset(piece_index)) as usize;
+        storage
+            .write_block(piece_index, begin, run.as_deref().unwrap_or(block))
+            .await?;
+    }
+    Ok(())
+}
+
+#[tokio::test]
+async fn cached_storage_coalesces_blocks() {
+    use crate::test_utils::{test_torrent, TestStorage};
+
+    let torrent = test_torrent(64, 16);
+    let layout = Arc::new(FileLayout::from_torrent(&torrent).unwrap());
+    let inner = Arc::new(TestStorage::new(layout.clone()));
+    let cache = CachedStorage::new(inner.clone(), layout, 1024);
+
+    // Blocks of two pieces written out of order, with one block written twice
+    for (piece_index, begin) in [(1, 8), (0, 0), (1, 0), (0, 8), (1, 8)] {
+        let data = [piece_index as u8 * 16 + begin as u8; 8];
+        cache.write_block(piece_index, begin, &data).await.unwrap();
+    }
+    cache.write_block(3, 0, &[9u8; 16]).await.unwrap();
+    assert!(inner.writes().is_empty());
+    assert_eq!(
+        cache.read_block(0, 4, 8).await.unwrap(),
+        [0, 0, 0, 0, 8, 8, 8, 8]
+    );
+    assert_eq!(cache.read_block(1, 8, 8).await.unwrap(), [24u8; 8]);
+
+    // Adjacent pieces are written at once, the gap is kept
+    cache.flush().await.unwrap();
+    assert_eq!(inner.writes(), [(0, 0, 32), (3, 0, 16)]);
+    assert_eq!(inner.read_block(1, 0, 16).await.unwrap()[8..], [24u8; 8]);
+    assert_eq!(cache.budget.available_permits(), 1024);
+}
+
+#[tokio::test]
+async fn cached_storage_rewrites_block() {
+    use crate::test_utils::{test_torrent, TestStorage};
+
+    let torrent = test_torrent(64, 16);
+    let layout = Arc::new(FileLayout::from_torrent(&torrent).unwrap());
+    let inner = Arc::new(TestStorage::new(layout.clone()));
+    let cache = CachedStorage::new(inner.clone(), layout, 64);
+
+    // Every rewrite takes budget, although cached data don't grow
+    let rewrites = async {
+        for value in 0..10u8 {
+            cache.write_block(0, 0, &[value; 16]).await.unwrap();
+        }
+    };
+    tokio::time::timeout(std::time::Duration::from_secs(5), rewrites)
+        .await
+        .expect("writes should not wait forever");
+    cache.flush().await.unwrap();
+    assert_eq!(inner.read_block(0, 0, 16).await.unwrap(), [9u8; 16]);
+    assert_eq!(cache.budget.available_permits(), 64);
+}
@@ -0,0 +1,271 @@
+use std::collections::{HashMap, HashSet};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+use anyhow::Result;
+use async_trait::async_trait;
+use sha1::{Digest, Sha1};
+use tokio::fs::{File, OpenOptions};
+use tokio::io::{AsyncReadExt, AsyncSeekExt, AsyncWriteExt};
+use tokio::sync::Mutex;
+
+use super::allocation::{allocate_file, check_free_space};
+use super::location::StoragePaths;
+use super::{check_block, AllocationMode, Storage};
+use crate::file_layout::{FileEntry, FileLayout, FilePriority};
+use crate::hash::PieceHash;
+
+/// Storage writing data of torrent to files in folder.
+/// Files are created and allocated on the first write into them, so skipped files are never created.
+/// Padding files are never created, other file attributes are applied, when download finishes.
+/// Unfinished files can be kept in incomplete folder or under `.part` suffix, until download finishes.
+pub struct FileStorage {
+    layout: Arc<FileLayout>,
+    state: Mutex<FileState>,
+}
+
+/// Location of files and opened files, file is opened on the first access.
+struct FileState {
+    paths: StoragePaths,
+    files: HashMap<usize, File>,
+    allocation: AllocationMode,
+    /// Files allocated since they were opened for writing.
+    allocated: HashSet<usize>,
+}
+
+impl FileStorage {
+    /// Creates storage, files of `layout` are placed into `folder`.
+    pub fn new(folder: PathBuf, layout: Arc<FileLayout>) -> Self {
+        FileStorage {
+            layout,
+            state: Mutex::new(FileState {
+                paths: StoragePaths::new(folder),
+                files: HashMap::new(),
+                allocation: AllocationMode::default(),
+                allocated: HashSet::new(),
+            }),
+        }
+    }
+
+    /// Set how files are allocated, before data are written into them.
+    pub fn set_allocation(&mut self, allocation: AllocationMode) {
+        self.state.get_mut().allocation = allocation;
+    }
+
+    /// Place files into `folder` during download, they are moved to final folder when download finishes.
+    pub fn set_incomplete_folder(&mut self, folder: Option<PathBuf>) {
+        self.state.get_mut().paths.set_incomplete_folder(folder);
+    }
+
+    /// Add `.part` suffix to names of files during download.
+    pub fn set_part_suffix(&mut self, part_suffix: bool) {
+        self.state.get_mut().paths.set_part_suffix(part_suffix);
+    }
+
+    /// Returns folder, in which files are stored now.
+    pub async fn folder(&self) -> PathBuf {
+        self.state.lock().await.paths.current_folder().to_path_buf()
+    }
+}
+
+impl FileState {
+    /// Returns opened file with given index, file and its parent directories are created if `create` is set.
+    async fn open_file(
+        &mut self,
+        entry: &FileEntry,
+        file_index: usize,
+        create: bool,
+    ) -> Result<&mut File> {
+        if !self.files.contains_key(&file_index) {
+            let path = self.paths.file_path(entry);
+            // Ensure parent directory exists
+            if create {
+                if let Some(parent) = path.parent() {
+                    tokio::fs::create_dir_all(parent).await?;
+                }
+            }
+            let mut options = OpenOptions::new();
+            options
+                .read(true)
+                .write(true)
+                .create(create)
+                .truncate(false);
+            #[cfg(windows)]
+            if entry.attributes.hidden {
+                // FILE_ATTRIBUTE_HIDDEN
+                options.attributes(0x2);
+            }
+            let file = options.open(path).await?;
+            self.files.insert(file_index, file);
+        }
+        let file = self.files.get_mut(&file_index).expect("File was opened");
+        if create && self.allocated.insert(file_index) {
+            let std_file = file.try_clone().await?.into_std().await;
+            allocate_file(&std_file, entry.length, self.allocation)?;
+        }
+        Ok(file)
+    }
+
+    /// Sync and close all opened files.
+    async fn close_files(&mut self) -> Result<()> {
+        for (_, file) in self.files.drain() {
+            file.sync_all().await?;
+        }
+        self.allocated.clear();
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl Storage for FileStorage {
+    async fn write_block(&self, piece_index: usize, begin: usize, data: &[u8]) -> Result<()> {
+        check_block(&self.layout, piece_index, begin, data.len())?;
+        let offset = self.layout.piece_offset(piece_index) + begin as u64;
+        let mut state = self.state.lock().await;
+        // Block can be split between multiple files, data in padding files are dropped
+        for slice in self.layout.map_range(offset, data.len()) {
+            let entry = &self.layout.files()[slice.file_index];
+            if entry.attributes.padding || entry.attributes.symlink.is_some() {
+                continue;
+            }
+            let file = state.open_file(entry, slice.file_index, true).await?;
+            file.seek(std::io::SeekFrom::Start(slice.file_offset))
+                .await?;
+            file.write_all(&data[slice.range_offset..slice.range_offset + slice.length])
+                .await?;
+            file.flush().await?;
+        }
+        Ok(())
+    }
+
+    async fn read_block(&self, piece_index: usize, begin: usize, length: usize) -> Result<Vec<u8>> {
+        check_block(&self.layout, piece_index, begin, length)?;
+        let offset = self.layout.piece_offset(piece_index) + begin as u64;
+        let mut data = vec![0u8; length];
+        let mut state = self.state.lock().await;
+        for slice in self.layout.map_range(offset, length) {
+            let entry = &self.layout.files()[slice.file_index];
+            if entry.attributes.padding || entry.attributes.symlink.is_some() {
+                continue;
+            }
+            let file = state.open_file(entry, slice.file_index, false).await?;
+            file.seek(std::io::SeekFrom::Start(slice.file_offset))
+                .await?;
+            file.read_exact(&mut data[slice.range_offset..slice.range_offset + slice.length])
+                .await?;
+        }
+        Ok(data)
+    }
+
+    async fn hash_piece(&self, piece_index: usize) -> Result<PieceHash> {
+        let piece = self
+            .read_block(piece_index, 0, self.layout.piece_size(piece_index))
+            .await?;
+        Ok(PieceHash::from_bytes(Sha1::digest(piece).into()))
+    }
+
+    async fn prepare(&self, file_priorities: &[FilePriority]) -> Result<()> {
+        let state = self.state.lock().await;
+        check_free_space(&self.layout, &state.paths, file_priorities)
+    }
+
+    async fn flush(&self) -> Result<()> {
+        for file in self.state.lock().await.files.values() {
+            file.sync_all().await?;
+        }
+        Ok(())
+    }
+
+    /// Move files to final location, then apply executable flag and create symbolic links of wanted files.
+    async fn finish(&self, file_priorities: &[FilePriority]) -> Result<()> {
+        let mut state = self.state.lock().await;
+        state.close_files().await?;
+        state.paths.complete(&self.layout).await?;
+        for (entry, priority) in self.layout.files().iter().zip(file_priorities) {
+            if *priority != FilePriority::Skip {
+                finish_file(state.paths.current_folder(), entry).await?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Move all existing files into other folder, files are copied if they can't be renamed.
+    async fn move_to(&self, folder: &Path) -> Result<()> {
+        let mut state = self.state.lock().await;
+        state.close_files().await?;
+        state.paths.move_to(&self.layout, folder).await
+    }
+
+    async fn delete(&self) -> Result<()> {
+        let mut state = self.state.lock().await;
+        state.close_files().await?;
+        state.paths.delete(&self.layout).await
+    }
+}
+
+/// Apply attributes of one file.
+/// Hidden files need no action on unix, names of hidden files start with dot there.
+pub(super) async fn finish_file(folder: &Path, entry: &FileEntry) -> Result<()> {
+    let path = folder.join(&entry.path);
+    if let Some(target) = &entry.attributes.symlink {
+        // Target of link is relative to root of torrent, link is relative to its own directory
+        let depth = entry.path.components().count().saturating_sub(2);
+        let mut relative = PathBuf::new();
+        for _ in 0..depth {
+            relative.push("..");
+        }
+        relative.push(target);
+        if let Some(parent) = path.parent() {
+            tokio::fs::create_dir_all(parent).await?;
+        }
+        let _ = tokio::fs::remove_file(&path).await;
+        #[cfg(unix)]
+        tokio::fs::symlink(relative, &path).await?;
+        #[cfg(windows)]
+        tokio::fs::symlink_file(relative, &path).await?;
+        return Ok(());
+    }
+
+    #[cfg(unix)]
+    if entry.attributes.executable && !entry.attributes.padding {
+        use std::os::unix::fs::PermissionsExt;
+        let mut permissions = tokio::fs::metadata(&path).await?.permissions();
+        permissions.set_mode(permissions.mode() | 0o111);
+        tokio::fs::set_permissions(&path, permissions).await?;
+    }
+    Ok(())
+}
+
+#[tokio::test]
+async fn file_storage_move_and_delete() {
+    use crate::test_utils::files_torrent;
+
+    let torrent = files_torrent(&[("sub/a", 10), ("b", 14)], 16);
+    let first = tempfile::tempdir().unwrap();
+    let second = tempfile::tempdir().unwrap();
+    let storage = FileStorage::new(
+        first.path().to_path_buf(),
+        Arc::new(FileLayout::from_torrent(&torrent).unwrap()),
+    );
+
+    // Block crosses boundary of files
+    storage.write_block(0, 8, &[5u8; 4]).await.unwrap();
+    assert_eq!(
+        std::fs::read(first.path().join("bundle/sub/a")).unwrap()[8..],
+        [5u8; 2]
+    );
+    assert_eq!(
+        std::fs::metadata(first.path().join("bundle/b"))
+            .unwrap()
+            .len(),
+        14
+    );
+
+    storage.move_to(second.path()).await.unwrap();
+    assert!(!first.path().join("bundle").exists());
+    assert_eq!(storage.read_block(0, 8, 4).await.unwrap(), [5u8; 4]);
+    assert_eq!(storage.folder().await, second.path());
+
+    storage.delete().await.unwrap();
+    assert!(!second.path().join("bundle").exists());
+}
@@ -0,0 +1,203 @@
+use std::ffi::OsString;
+use std::path::{Path, PathBuf};
+
+use anyhow::Result;
+
+use crate::file_layout::{FileEntry, FileLayout};
+
+/// Suffix of files, which are not downloaded yet.
+const PART_SUFFIX: &str = ".part";
+
+/// Location of files of torrent on disk.
+/// During download files can be in incomplete folder or have `.part` suffix,
+/// so unfinished files are never opened by mistake. They are moved to final location, when download completes.
+pub(super) struct StoragePaths {
+    /// Folder with files of finished download.
+    folder: PathBuf,
+    /// Folder with files during download, `folder` is used if not set.
+    incomplete_folder: Option<PathBuf>,
+    part_suffix: bool,
+    /// Files are in their final location.
+    complete: bool,
+}
+
+impl StoragePaths {
+    pub(super) fn new(folder: PathBuf) -> Self {
+        StoragePaths {
+            folder,
+            incomplete_folder: None,
+            part_suffix: false,
+            complete: false,
+        }
+    }
+
+    pub(super) fn set_incomplete_folder(&mut self, folder: Option<PathBuf>) {
+        self.incomplete_folder = folder;
+    }
+
+    pub(super) fn set_part_suffix(&mut self, part_suffix: bool) {
+        self.part_suffix = part_suffix;
+    }
+
+    /// Returns folder, in which files are now.
+    pub(super) fn current_folder(&self) -> &Path {
+        match &self.incomplete_folder {
+            Some(folder) if !self.complete => folder,
+            _ => &self.folder,
+        }
+    }
+
+    /// Returns path, where data of file are now.
+    pub(super) fn file_path(&self, entry: &FileEntry) -> PathBuf {
+        self.path_in(self.current_folder(), entry, self.complete)
+    }
+
+    /// Returns path of file in given folder, `.part` suffix is used only for incomplete files.
+    fn path_in(&self, folder: &Path, entry: &FileEntry, complete: bool) -> PathBuf {
+        let path = folder.join(&entry.path);
+        if self.part_suffix && !complete {
+            with_part_suffix(&path)
+        } else {
+            path
+        }
+    }
+
+    /// Move all files to final location after download completes, `.part` suffixes are removed.
+    /// Location is changed only after all files were moved, already moved files are skipped when it's retried.
+    pub(super) async fn complete(&mut self, layout: &FileLayout) -> Result<()> {
+        if self.complete {
+            return Ok(());
+        }
+        let source = self.current_folder().to_path_buf();
+        for entry in layout.files() {
+            let to = self.path_in(&self.folder, entry, true);
+            move_file(&self.file_path(entry), &to).await?;
+        }
+        self.complete = true;
+        if source != self.folder {
+            remove_empty_dirs(&source, layout).await;
+        }
+        Ok(())
+    }
+
+    /// Move all files to other folder, which becomes final folder and folder used during download.
+    /// Folder is changed only after all files were moved, already moved files are skipped when it's retried.
+    pub(super) async fn move_to(&mut self, layout: &FileLayout, folder: &Path) -> Result<()> {
+        let source = self.current_folder().to_path_buf();
+        for entry in layout.files() {
+            let to = self.path_in(folder, entry, self.complete);
+            move_file(&self.file_path(entry), &to).await?;
+        }
+        self.folder = folder.to_path_buf();
+        self.incomplete_folder = None;
+        remove_empty_dirs(&source, layout).await;
+        Ok(())
+    }
+
+    /// Delete existing files of torrent.
+    pub(super) async fn delete(&self, layout: &FileLayout) -> Result<()> {
+        for entry in layout.files() {
+            match tokio::fs::remove_file(self.file_path(entry)).await {
+                Err(err) if err.kind() != std::io::ErrorKind::NotFound => return Err(err.into()),
+                _ => {}
+            }
+        }
+        remove_empty_dirs(self.current_folder(), layout).await;
+        Ok(())
+    }
+}
+
+fn with_part_suffix(path: &Path) -> PathBuf {
+    let mut path = OsString::from(path);
+    path.push(PART_SUFFIX);
+    PathBuf::from(path)
+}
+
+/// Move file, if it exists. File is renamed, or copied if target is on other file system.
+/// Copy is created under temporary name, so file with target name is always complete.
+async fn move_file(from: &Path, to: &Path) -> Result<()> {
+    if from == to || tokio::fs::symlink_metadata(from).await.is_err() {
+        return Ok(());
+    }
+    if let Some(parent) = to.parent() {
+        tokio::fs::create_dir_all(parent).await?;
+    }
+    if tokio::fs::rename(from, to).await.is_err() {
+        let temporary = with_part_suffix(to);
+        let copied = match tokio::fs::copy(from, &temporary).await {
+            Ok(_) => tokio::fs::rename(&temporary, to).await,
+            Err(err) => Err(err),
+        };
+        if let Err(err) = copied {
+            // Incomplete copy would take space until the next attempt
+            let _ = tokio::fs::remove_file(&temporary).await;
+            return Err(err.into());
+        }
+        tokio::fs::remove_file(from).await?;
+    }
+    Ok(())
+}
+
+/// Remove directories of torrent, which are empty after its files were moved or deleted.
+async fn remove_empty_dirs(folder: &Path, layout: &FileLayout) {
+    let mut dirs: Vec<&Path> = layout
+        .files()
+        .iter()
+        .flat_map(|entry| entry.path.ancestors().skip(1))
+        .filter(|dir| !dir.as_os_str().is_empty())
+        .collect();
+    // The deepest directories are removed first
+    dirs.sort_by_key(|dir| (std::cmp::Reverse(dir.components().count()), *dir));
+    dirs.dedup();
+    for dir in dirs {
+        let _ = tokio::fs::remove_dir(folder.join(dir)).await;
+    }
+}
+
+#[tokio::test]
+async fn incomplete_files_are_moved_on_finish() {
+    use super::{FileStorage, Storage};
+    use crate::file_layout::FilePriority;
+    use crate::test_utils::files_torrent;
+    use std::sync::Arc;
+
+    let torrent = files_torrent(&[("sub/a", 10), ("b", 14)], 16);
+    let incomplete = tempfile::tempdir().unwrap();
+    let moved = tempfile::tempdir().unwrap();
+    let done = tempfile::tempdir().unwrap();
+    let mut storage = FileStorage::new(
+        done.path().to_path_buf(),
+        Arc::new(FileLayout::from_torrent(&torrent).unwrap()),
+    );
+    storage.set_incomplete_folder(Some(incomplete.path().to_path_buf()));
+    storage.set_part_suffix(true);
+
+    storage.write_block(0, 8, &[5u8; 4]).await.unwrap();
+    assert!(incomplete.path().join("bundle/sub/a.part").exists());
+    assert!(incomplete.path().join("bundle/b.part").exists());
+    assert!(!done.path().join("bundle").exists());
+
+    // Running download is moved, it continues in the new folder
+    storage.move_to(moved.path()).await.unwrap();
+    assert!(!incomplete.path().join("bundle").exists());
+    storage.write_block(1, 0, &[6u8; 8]).await.unwrap();
+    assert_eq!(storage.read_block(0, 8, 4).await.unwrap(), [5u8; 4]);
+
+    // Failed move keeps the old folder, files moved before the failure are skipped on retry
+    let other = tempfile::tempdir().unwrap();
+    std::fs::create_dir_all(other.path().join("bundle/b.part/blocker")).unwrap();
+    assert!(storage.move_to(other.path()).await.is_err());
+    assert_eq!(storage.folder().await, moved.path());
+    assert!(other.path().join("bundle/sub/a.part").exists());
+    std::fs::remove_dir_all(other.path().join("bundle/b.part")).unwrap();
+    storage.move_to(other.path()).await.unwrap();
+    assert!(!other.path().join("bundle/b.part.part").exists());
+
+    storage
+        .finish(&[FilePriority::Normal, FilePriority::Normal])
+        .await
+        .unwrap();
+    assert!(!other.path().join("bundle/b.part").exists());
+    let data = std::fs::read(other.path().join("bundle/b")).unwrap();
+    assert_eq!(data[..10], [5, 5, 0, 0, 0, 0, 6, 6, 6, 6]);
+}
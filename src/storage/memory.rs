@@ -0,0 +1,121 @@
+use std::collections::HashMap;
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+
+use anyhow::Result;
+use async_trait::async_trait;
+use sha1::{Digest, Sha1};
+
+use super::{check_block, Storage};
+use crate::file_layout::FileLayout;
+use crate::hash::PieceHash;
+
+/// Storage keeping all data in memory, for tests and transient downloads.
+/// Pieces are allocated on the first write, not written data are zeros.
+pub struct MemoryStorage {
+    layout: Arc<FileLayout>,
+    pieces: Mutex<HashMap<usize, Vec<u8>>>,
+}
+
+impl MemoryStorage {
+    pub fn new(layout: Arc<FileLayout>) -> Self {
+        MemoryStorage {
+            layout,
+            pieces: Mutex::new(HashMap::new()),
+        }
+    }
+
+    fn pieces(&self) -> std::sync::MutexGuard<'_, HashMap<usize, Vec<u8>>> {
+        self.pieces.lock().unwrap_or_else(|err| err.into_inner())
+    }
+
+    /// Split block into parts in single pieces, returns piece index, offset in piece and range of block.
+    fn piece_parts(
+        &self,
+        mut piece_index: usize,
+        mut begin: usize,
+        length: usize,
+    ) -> Vec<(usize, usize, std::ops::Range<usize>)> {
+        let mut parts = Vec::new();
+        let mut position = 0;
+        while position < length {
+            let part = (self.layout.piece_size(piece_index) - begin).min(length - position);
+            parts.push((piece_index, begin, position..position + part));
+            position += part;
+            piece_index += 1;
+            begin = 0;
+        }
+        parts
+    }
+}
+
+#[async_trait]
+impl Storage for MemoryStorage {
+    async fn write_block(&self, piece_index: usize, begin: usize, data: &[u8]) -> Result<()> {
+        check_block(&self.layout, piece_index, begin, data.len())?;
+        let mut pieces = self.pieces();
+        for (piece_index, begin, range) in self.piece_parts(piece_index, begin, data.len()) {
+            let piece_size = self.layout.piece_size(piece_index);
+            pieces
+                .entry(piece_index)
+                .or_insert_with(|| vec![0u8; piece_size])[begin..begin + range.len()]
+                .copy_from_slice(&data[range]);
+        }
+        Ok(())
+    }
+
+    async fn read_block(&self, piece_index: usize, begin: usize, length: usize) -> Result<Vec<u8>> {
+        check_block(&self.layout, piece_index, begin, length)?;
+        let mut data = vec![0u8; length];
+        let pieces = self.pieces();
+        for (piece_index, begin, range) in self.piece_parts(piece_index, begin, length) {
+            if let Some(piece) = pieces.get(&piece_index) {
+                data[range.clone()].copy_from_slice(&piece[begin..begin + range.len()]);
+            }
+        }
+        Ok(data)
+    }
+
+    async fn hash_piece(&self, piece_index: usize) -> Result<PieceHash> {
+        let piece = self
+            .read_block(piece_index, 0, self.layout.piece_size(piece_index))
+            .await?;
+        Ok(PieceHash::from_bytes(Sha1::digest(piece).into()))
+    }
+
+    async fn flush(&self) -> Result<()> {
+        Ok(())
+    }
+
+    /// Data are not on disk, so there is nothing to move.
+    async fn move_to(&self, _folder: &Path) -> Result<()> {
+        Ok(())
+    }
+
+    async fn delete(&self) -> Result<()> {
+        self.pieces().clear();
+        Ok(())
+    }
+}
+
+#[tokio::test]
+async fn memory_storage_blocks() {
+    let torrent = crate::test_utils::test_torrent(40, 16);
+    let storage = MemoryStorage::new(Arc::new(FileLayout::from_torrent(&torrent).unwrap()));
+    storage.write_block(2, 4, &[7u8; 4]).await.unwrap();
+    assert_eq!(
+        storage.read_block(2, 0, 8).await.unwrap(),
+        [0, 0, 0, 0, 7, 7, 7, 7]
+    );
+    assert_eq!(
+        storage.hash_piece(2).await.unwrap(),
+        PieceHash::from_bytes(Sha1::digest([0, 0, 0, 0, 7, 7, 7, 7]).into())
+    );
+    // The last piece has only 8 bytes, block can continue into following piece
+    assert!(storage.write_block(2, 4, &[7u8; 5]).await.is_err());
+    storage.write_block(1, 12, &[3u8; 8]).await.unwrap();
+    assert_eq!(storage.read_block(2, 0, 4).await.unwrap(), [3u8; 4]);
+    assert!(storage.read_block(3, 0, 1).await.is_err());
+    storage.delete().await.unwrap();
+    assert_eq!(storage.read_block(2, 4, 4).await.unwrap(), [0u8; 4]);
+}
@@ -0,0 +1,506 @@
+use std::collections::HashMap;
+use std::fs::{File, OpenOptions};
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::ops::Range;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+use anyhow::Result;
+use async_trait::async_trait;
+use bytes::{Bytes, BytesMut};
+use memmap2::{MmapOptions, MmapRaw};
+use sha1::{Digest, Sha1};
+use tokio::sync::Mutex;
+
+use super::allocation::{allocate_file, check_free_space, reserve_range};
+use super::file::finish_file;
+use super::location::StoragePaths;
+use super::{check_block, AllocationMode, Storage};
+use crate::file_layout::{FileEntry, FileLayout, FilePriority};
+use crate::hash::PieceHash;
+
+/// Files are mapped in segments of this size.
+const SEGMENT_SIZE: u64 = 16 * 1024 * 1024;
+/// Limit of mapped bytes, the least recently used segments are unmapped above it.
+/// Address space of 32-bit process is small, so only few segments are mapped there at once.
+const MAX_MAPPED_BYTES: u64 = if cfg!(target_pointer_width = "64") {
+    16 * 1024 * 1024 * 1024
+} else {
+    256 * 1024 * 1024
+};
+
+/// Storage mapping files of torrent into memory, blocks for peers and hashing are read without copying.
+/// Files are mapped in segments on the first access, files are allocated before the first write into them.
+/// Disk blocks are reserved before data are written into mapping, so full disk is reported as error.
+/// Segment, which can't be mapped (e.g. address space of 32-bit process is exhausted), is accessed by ordinary file I/O.
+/// Files must not be truncated by other programs, while they are mapped.
+pub struct MmapStorage {
+    layout: Arc<FileLayout>,
+    state: Mutex<MmapState>,
+}
+
+/// Location of files, opened files and their mapped segments.
+struct MmapState {
+    paths: StoragePaths,
+    files: HashMap<usize, OpenedFile>,
+    /// Segments by file index and index of segment in file.
+    segments: HashMap<(usize, u64), Segment>,
+    mapped_bytes: u64,
+    /// Counter of accesses, used to find the least recently used segment.
+    clock: u64,
+    allocation: AllocationMode,
+}
+
+struct OpenedFile {
+    file: File,
+    /// Current length of file on disk, it's shorter than length in torrent, until the file is written.
+    length: u64,
+    /// File was allocated since it was opened for writing.
+    allocated: bool,
+}
+
+struct Segment {
+    map: Arc<MmapRaw>,
+    last_access: u64,
+}
+
+/// Part of accessed range, which is inside of one segment of one file.
+struct Part {
+    /// Range inside of accessed block.
+    range: Range<usize>,
+    location: Location,
+}
+
+enum Location {
+    /// Mapped segment and offset inside of it.
+    Mapped(Arc<MmapRaw>, usize),
+    /// Index of file and offset inside of it, segment couldn't be mapped.
+    Unmapped(usize, u64),
+    /// Padding file or symlink, which has no data on disk.
+    Padding,
+}
+
+/// Block inside of mapped segment, it keeps segment mapped while the block is used.
+struct MappedBlock {
+    map: Arc<MmapRaw>,
+    range: Range<usize>,
+}
+
+impl AsRef<[u8]> for MappedBlock {
+    fn as_ref(&self) -> &[u8] {
+        // SAFETY: Range is inside of mapping, which lives as long as `map`.
+        // Only written pieces are read, and written pieces are not modified anymore.
+        unsafe {
+            std::slice::from_raw_parts(self.map.as_ptr().add(self.range.start), self.range.len())
+        }
+    }
+}
+
+impl MmapStorage {
+    /// Creates storage, files of `layout` are placed into `folder`.
+    pub fn new(folder: PathBuf, layout: Arc<FileLayout>) -> Self {
+        MmapStorage {
+            layout,
+            state: Mutex::new(MmapState {
+                paths: StoragePaths::new(folder),
+                files: HashMap::new(),
+                segments: HashMap::new(),
+                mapped_bytes: 0,
+                clock: 0,
+                allocation: AllocationMode::default(),
+            }),
+        }
+    }
+
+    /// Set how files are allocated, before data are written into them.
+    pub fn set_allocation(&mut self, allocation: AllocationMode) {
+        self.state.get_mut().allocation = allocation;
+    }
+
+    /// Place files into `folder` during download, they are moved to final folder when download finishes.
+    pub fn set_incomplete_folder(&mut self, folder: Option<PathBuf>) {
+        self.state.get_mut().paths.set_incomplete_folder(folder);
+    }
+
+    /// Add `.part` suffix to names of files during download.
+    pub fn set_part_suffix(&mut self, part_suffix: bool) {
+        self.state.get_mut().paths.set_part_suffix(part_suffix);
+    }
+
+    /// Returns block split into parts, mapped parts are not copied.
+    async fn read_parts(
+        &self,
+        piece_index: usize,
+        begin: usize,
+        length: usize,
+    ) -> Result<Vec<Bytes>> {
+        check_block(&self.layout, piece_index, begin, length)?;
+        let offset = self.layout.piece_offset(piece_index) + begin as u64;
+        let mut state = self.state.lock().await;
+        let parts = state.parts(&self.layout, offset, length, false)?;
+        parts
+            .into_iter()
+            .map(|part| match part.location {
+                Location::Mapped(map, start) => Ok(Bytes::from_owner(MappedBlock {
+                    map,
+                    range: start..start + part.range.len(),
+                })),
+                Location::Unmapped(file_index, file_offset) => state
+                    .read_at(file_index, file_offset, part.range.len())
+                    .map(Bytes::from),
+                Location::Padding => Ok(Bytes::from(vec![0u8; part.range.len()])),
+            })
+            .collect()
+    }
+}
+
+impl MmapState {
+    /// Open file with given index, if `create` is set, file is created, allocated and extended to at least `end` bytes.
+    /// Returns current length of the file.
+    fn open_file(
+        &mut self,
+        entry: &FileEntry,
+        file_index: usize,
+        create: bool,
+        end: u64,
+    ) -> Result<u64> {
+        if !self.files.contains_key(&file_index) {
+            let path = self.paths.file_path(entry);
+            if create {
+                if let Some(parent) = path.parent() {
+                    std::fs::create_dir_all(parent)?;
+                }
+            }
+            let mut options = OpenOptions::new();
+            options
+                .read(true)
+                .write(true)
+                .create(create)
+                .truncate(false);
+            #[cfg(windows)]
+            if entry.attributes.hidden {
+                use std::os::windows::fs::OpenOptionsExt;
+                // FILE_ATTRIBUTE_HIDDEN
+                options.attributes(0x2);
+            }
+            let file = options.open(path)?;
+            let length = file.metadata()?.len();
+            self.files.insert(
+                file_index,
+                OpenedFile {
+                    file,
+                    length,
+                    allocated: false,
+                },
+            );
+        }
+        let opened = self.files.get_mut(&file_index).expect("File was opened");
+        if create && !opened.allocated {
+            allocate_file(&opened.file, entry.length, self.allocation)?;
+            opened.allocated = true;
+            opened.length = opened.file.metadata()?.len();
+        }
+        // Data can't be written through mapping behind end of file, so the file grows first
+        if create && opened.length < end {
+            opened.file.set_len(end)?;
+            opened.length = end;
+        }
+        Ok(opened.length)
+    }
+
+    /// Split range of torrent data into parts in single segments, mapping the segments.
+    fn parts(
+        &mut self,
+        layout: &FileLayout,
+        offset: u64,
+        length: usize,
+        create: bool,
+    ) -> Result<Vec<Part>> {
+        let mut parts = Vec::new();
+        for slice in layout.map_range(offset, length) {
+            let entry = &layout.files()[slice.file_index];
+            let range = slice.range_offset..slice.range_offset + slice.length;
+            if entry.attributes.padding || entry.attributes.symlink.is_some() {
+                parts.push(Part {
+                    range,
+                    location: Location::Padding,
+                });
+                continue;
+            }
+            let end = slice.file_offset + slice.length as u64;
+            let file_length = self.open_file(entry, slice.file_index, create, end)?;
+            anyhow::ensure!(
+                end <= file_length,
+                "Data of {} are not written",
+                entry.path.display()
+            );
+            if create {
+                // Writing into hole of sparse file on full disk would crash the process
+                reserve_range(
+                    &self.files[&slice.file_index].file,
+                    slice.file_offset,
+                    slice.length as u64,
+                )?;
+            }
+            let mut position = 0;
+            while position < slice.length {
+                let file_offset = slice.file_offset + position as u64;
+                let segment = file_offset / SEGMENT_SIZE;
+                let start = (file_offset % SEGMENT_SIZE) as usize;
+                let length = (SEGMENT_SIZE as usize - start).min(slice.length - position);
+                let location = match self.map_segment(slice.file_index, segment, start + length) {
+                    Some(map) => Location::Mapped(map, start),
+                    None => Location::Unmapped(slice.file_index, file_offset),
+                };
+                parts.push(Part {
+                    range: range.start + position..range.start + position + length,
+                    location,
+                });
+                position += length;
+            }
+        }
+        Ok(parts)
+    }
+
+    /// Returns mapped segment of file, which is at least `end` bytes long, `None` if it can't be mapped.
+    fn map_segment(&mut self, file_index: usize, segment: u64, end: usize) -> Option<Arc<MmapRaw>> {
+        self.clock += 1;
+        let key = (file_index, segment);
+        if let Some(mapped) = self.segments.get_mut(&key) {
+            if mapped.map.len() >= end {
+                mapped.last_access = self.clock;
+                return Some(mapped.map.clone());
+            }
+            // File has grown since the segment was mapped
+            self.mapped_bytes -= mapped.map.len() as u64;
+            self.segments.remove(&key);
+        }
+
+        let start = segment * SEGMENT_SIZE;
+        let length = (self.files[&file_index].length - start).min(SEGMENT_SIZE);
+        self.unmap_segments(length);
+        let map = match self.map_raw(file_index, start, length) {
+            Ok(map) => map,
+            Err(_) => {
+                // Address space can be exhausted, so try it again with all other segments unmapped
+                self.unmap_segments(MAX_MAPPED_BYTES);
+                self.map_raw(file_index, start, length).ok()?
+            }
+        };
+        let map = Arc::new(map);
+        self.mapped_bytes += length;
+        self.segments.insert(
+            key,
+            Segment {
+                map: map.clone(),
+                last_access: self.clock,
+            },
+        );
+        Some(map)
+    }
+
+    fn map_raw(&self, file_index: usize, start: u64, length: u64) -> std::io::Result<MmapRaw> {
+        MmapOptions::new()
+            .offset(start)
+            .len(length as usize)
+            .map_raw(&self.files[&file_index].file)
+    }
+
+    /// Unmap the least recently used segments, until `length` bytes can be mapped.
+    /// Segments stay mapped until blocks read from them are dropped.
+    fn unmap_segments(&mut self, length: u64) {
+        while self.mapped_bytes + length > MAX_MAPPED_BYTES {
+            let Some(key) = self
+                .segments
+                .iter()
+                .min_by_key(|(_, segment)| segment.last_access)
+                .map(|(key, _)| *key)
+            else {
+                break;
+            };
+            let segment = self.segments.remove(&key).expect("Segment exists");
+            self.mapped_bytes -= segment.map.len() as u64;
+        }
+    }
+
+    fn read_at(&mut self, file_index: usize, offset: u64, length: usize) -> Result<Vec<u8>> {
+        let file = &mut self
+            .files
+            .get_mut(&file_index)
+            .expect("File was opened")
+            .file;
+        let mut data = vec![0u8; length];
+        file.seek(SeekFrom::Start(offset))?;
+        file.read_exact(&mut data)?;
+        Ok(data)
+    }
+
+    fn write_at(&mut self, file_index: usize, offset: u64, data: &[u8]) -> Result<()> {
+        let file = &mut self
+            .files
+            .get_mut(&file_index)
+            .expect("File was opened")
+            .file;
+        file.seek(SeekFrom::Start(offset))?;
+        file.write_all(data)?;
+        Ok(())
+    }
+
+    fn flush(&self) -> Result<()> {
+        for segment in self.segments.values() {
+            segment.map.flush()?;
+        }
+        for opened in self.files.values() {
+            opened.file.sync_all()?;
+        }
+        Ok(())
+    }
+
+    /// Sync data, unmap all segments and close files.
+    fn close(&mut self) -> Result<()> {
+        self.flush()?;
+        self.segments.clear();
+        self.mapped_bytes = 0;
+        self.files.clear();
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl Storage for MmapStorage {
+    async fn write_block(&self, piece_index: usize, begin: usize, data: &[u8]) -> Result<()> {
+        check_block(&self.layout, piece_index, begin, data.len())?;
+        let offset = self.layout.piece_offset(piece_index) + begin as u64;
+        let mut state = self.state.lock().await;
+        for part in state.parts(&self.layout, offset, data.len(), true)? {
+            let block = &data[part.range];
+            match part.location {
+                // SAFETY: Block is inside of mapping, and the piece is not read, until it is written.
+                Location::Mapped(map, start) => unsafe {
+                    std::ptr::copy_nonoverlapping(
+                        block.as_ptr(),
+                        map.as_mut_ptr().add(start),
+                        block.len(),
+                    );
+                },
+                Location::Unmapped(file_index, file_offset) => {
+                    state.write_at(file_index, file_offset, block)?
+                }
+                Location::Padding => {}
+            }
+        }
+        Ok(())
+    }
+
+    async fn read_block(&self, piece_index: usize, begin: usize, length: usize) -> Result<Vec<u8>> {
+        Ok(self.read_bytes(piece_index, begin, length).await?.into())
+    }
+
+    /// Block inside of one segment is returned without copying.
+    async fn read_bytes(&self, piece_index: usize, begin: usize, length: usize) -> Result<Bytes> {
+        let mut parts = self.read_parts(piece_index, begin, length).await?;
+        if parts.len() == 1 {
+            return Ok(parts.remove(0));
+        }
+        let mut block = BytesMut::with_capacity(length);
+        for part in parts {
+            block.extend_from_slice(&part);
+        }
+        Ok(block.freeze())
+    }
+
+    /// Mapped data are hashed in blocking task, because reading them can wait for disk.
+    async fn hash_piece(&self, piece_index: usize) -> Result<PieceHash> {
+        let parts = self
+            .read_parts(piece_index, 0, self.layout.piece_size(piece_index))
+            .await?;
+        let hash = tokio::task::spawn_blocking(move || {
+            let mut hasher = Sha1::new();
+            for part in &parts {
+                hasher.update(part);
+            }
+            hasher.finalize()
+        })
+        .await?;
+        Ok(PieceHash::from_bytes(hash.into()))
+    }
+
+    async fn prepare(&self, file_priorities: &[FilePriority]) -> Result<()> {
+        let state = self.state.lock().await;
+        check_free_space(&self.layout, &state.paths, file_priorities)
+    }
+
+    async fn flush(&self) -> Result<()> {
+        self.state.lock().await.flush()
+    }
+
+    /// Move files to final location, then apply executable flag and create symbolic links of wanted files.
+    async fn finish(&self, file_priorities: &[FilePriority]) -> Result<()> {
+        let mut state = self.state.lock().await;
+        state.close()?;
+        state.paths.complete(&self.layout).await?;
+        for (entry, priority) in self.layout.files().iter().zip(file_priorities) {
+            if *priority != FilePriority::Skip {
+                finish_file(state.paths.current_folder(), entry).await?;
+            }
+        }
+        Ok(())
+    }
+
+    async fn move_to(&self, folder: &Path) -> Result<()> {
+        let mut state = self.state.lock().await;
+        state.close()?;
+        state.paths.move_to(&self.layout, folder).await
+    }
+
+    async fn delete(&self) -> Result<()> {
+        let mut state = self.state.lock().await;
+        state.close()?;
+        state.paths.delete(&self.layout).await
+    }
+}
+
+#[tokio::test]
+async fn mmap_storage_blocks() {
+    use crate::test_utils::files_torrent;
+
+    let torrent = files_torrent(&[("a", 10), ("b", 14)], 16);
+    let folder = tempfile::tempdir().unwrap();
+    // Partial file from previous download is shorter than its length in torrent
+    std::fs::create_dir_all(folder.path().join("bundle")).unwrap();
+    std::fs::write(folder.path().join("bundle/a"), [1u8; 4]).unwrap();
+    let storage = MmapStorage::new(
+        folder.path().to_path_buf(),
+        Arc::new(FileLayout::from_torrent(&torrent).unwrap()),
+    );
+
+    assert_eq!(storage.read_bytes(0, 0, 4).await.unwrap(), [1u8; 4][..]);
+    assert!(storage.read_bytes(0, 4, 4).await.is_err());
+
+    // Block crosses boundary of files, mapped segment of the first file grows
+    storage.write_block(0, 8, &[5u8; 8]).await.unwrap();
+    assert_eq!(
+        storage.read_bytes(0, 2, 10).await.unwrap(),
+        [1, 1, 0, 0, 0, 0, 5, 5, 5, 5][..]
+    );
+    let mut piece = vec![1u8; 4];
+    piece.extend([0u8; 4]);
+    piece.extend([5u8; 8]);
+    assert_eq!(
+        storage.hash_piece(0).await.unwrap(),
+        PieceHash::from_bytes(Sha1::digest(&piece).into())
+    );
+
+    storage.flush().await.unwrap();
+    assert_eq!(
+        std::fs::read(folder.path().join("bundle/a")).unwrap(),
+        [1, 1, 1, 1, 0, 0, 0, 0, 5, 5]
+    );
+    assert_eq!(
+        std::fs::read(folder.path().join("bundle/b")).unwrap()[..6],
+        [5u8; 6]
+    );
+
+    storage.delete().await.unwrap();
+    assert!(!folder.path().join("bundle").exists());
+}
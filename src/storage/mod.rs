@@ -0,0 +1,147 @@
+mod allocation;
+mod cache;
+mod file;
+mod location;
+mod memory;
+mod mmap;
+
+pub use allocation::{free_space, is_disk_full, AllocationMode};
+pub use cache::{CachedStorage, DEFAULT_CACHE_SIZE};
+pub use file::FileStorage;
+pub use memory::MemoryStorage;
+pub use mmap::MmapStorage;
+
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::Arc;
+
+use anyhow::Result;
+use async_trait::async_trait;
+use bytes::Bytes;
+
+use crate::file_layout::{FileLayout, FilePriority};
+use crate::hash::PieceHash;
+
+/// Storage of torrent data, blocks are addressed by piece index and offset inside of piece.
+/// Block can continue into following pieces, so adjacent blocks can be written at once.
+/// Storage is shared by writer and readers, so all methods take `&self`.
+#[async_trait]
+pub trait Storage: Send + Sync {
+    /// Write block of data at offset `begin` of piece.
+    async fn write_block(&self, piece_index: usize, begin: usize, data: &[u8]) -> Result<()>;
+
+    /// Read `length` bytes from offset `begin` of piece.
+    async fn read_block(&self, piece_index: usize, begin: usize, length: usize) -> Result<Vec<u8>>;
+
+    /// Read block as shared buffer, storage with data in memory can return it without copying.
+    async fn read_bytes(&self, piece_index: usize, begin: usize, length: usize) -> Result<Bytes> {
+        Ok(self.read_block(piece_index, begin, length).await?.into())
+    }
+
+    /// Returns SHA1 hash of stored data of piece.
+    async fn hash_piece(&self, piece_index: usize) -> Result<PieceHash>;
+
+    /// Make all written data persistent.
+    async fn flush(&self) -> Result<()>;
+
+    /// Called before download starts, storage checks that there is enough free space for wanted files.
+    async fn prepare(&self, file_priorities: &[FilePriority]) -> Result<()> {
+        let _ = file_priorities;
+        Ok(())
+    }
+
+    /// Called once, after all wanted pieces are written, storage can apply file attributes.
+    async fn finish(&self, file_priorities: &[FilePriority]) -> Result<()> {
+        let _ = file_priorities;
+        self.flush().await
+    }
+
+    /// Move stored data to other folder, it's used also for data written later.
+    async fn move_to(&self, folder: &Path) -> Result<()>;
+
+    /// Delete all stored data.
+    async fn delete(&self) -> Result<()>;
+}
+
+/// Backend storing downloaded data in files on disk.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum StorageMode {
+    /// Buffered file I/O behind write cache.
+    #[default]
+    File,
+    /// Memory-mapped files, blocks are read without copying, which is faster for seeding.
+    Mmap,
+}
+
+impl FromStr for StorageMode {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "file" => Ok(StorageMode::File),
+            "mmap" => Ok(StorageMode::Mmap),
+            _ => anyhow::bail!("Unknown storage mode {s}"),
+        }
+    }
+}
+
+/// Settings of storage for downloaded files.
+#[derive(Debug, Clone)]
+pub struct StorageOptions {
+    pub mode: StorageMode,
+    /// Memory of write cache in bytes, used by file I/O.
+    pub cache_size: usize,
+    pub allocation: AllocationMode,
+    /// Folder with files during download, files are moved to download folder when download finishes.
+    pub incomplete_folder: Option<PathBuf>,
+    /// Names of files have `.part` suffix during download.
+    pub part_suffix: bool,
+}
+
+impl Default for StorageOptions {
+    fn default() -> Self {
+        StorageOptions {
+            mode: StorageMode::default(),
+            cache_size: DEFAULT_CACHE_SIZE,
+            allocation: AllocationMode::default(),
+            incomplete_folder: None,
+            part_suffix: false,
+        }
+    }
+}
+
+impl StorageOptions {
+    /// Creates storage of files, which are placed in `folder` after download finishes.
+    /// Mapped files are already cached by the system, so they are used without write cache.
+    pub fn create(&self, folder: PathBuf, layout: Arc<FileLayout>) -> Arc<dyn Storage> {
+        match self.mode {
+            StorageMode::File => {
+                let mut files = FileStorage::new(folder, layout.clone());
+                files.set_allocation(self.allocation);
+                files.set_incomplete_folder(self.incomplete_folder.clone());
+                files.set_part_suffix(self.part_suffix);
+                Arc::new(CachedStorage::new(Arc::new(files), layout, self.cache_size))
+            }
+            StorageMode::Mmap => {
+                let mut files = MmapStorage::new(folder, layout);
+                files.set_allocation(self.allocation);
+                files.set_incomplete_folder(self.incomplete_folder.clone());
+                files.set_part_suffix(self.part_suffix);
+                Arc::new(files)
+            }
+        }
+    }
+}
+
+/// Returns error, if block starts out of piece or ends after end of torrent data.
+fn check_block(layout: &FileLayout, piece_index: usize, begin: usize, length: usize) -> Result<()> {
+    let piece_count = layout.total_length().div_ceil(layout.piece_length()) as usize;
+    let end = layout.piece_offset(piece_index) + (begin + length) as u64;
+    anyhow::ensure!(
+        piece_index < piece_count
+            && begin <= layout.piece_size(piece_index)
+            && end <= layout.total_length(),
+        "Block {begin}+{length} of piece {piece_index} is out of torrent data"
+    );
+    Ok(())
+}
@@ -0,0 +1,478 @@
+use std::net::SocketAddr;
+use std::path::Path;
+use std::sync::Arc;
+use std::time::Duration;
+
+use anyhow::{Context, Result};
+use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::time::timeout;
+
+use crate::download::TorrentDownloader;
+use crate::file_layout::{FileEntry, FilePriority};
+use crate::storage::Storage;
+
+/// Maximal size of request line and headers.
+const MAX_HEADER_SIZE: usize = 8 * 1024;
+/// Time for receiving next request on kept-alive connection.
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(60);
+/// Size of data read from disk and sent to client at once.
+const CHUNK_SIZE: usize = 64 * 1024;
+
+/// HTTP server, which streams files of running torrent, so media players can play them while they download.
+/// Every file is available at `/files/<file index>/<file name>`, list of all urls is at `/`.
+/// Readers are blocked until needed pieces are verified and written, and they move read cursor of downloader.
+pub struct StreamServer {
+    listener: TcpListener,
+    downloader: Arc<TorrentDownloader>,
+    storage: Arc<dyn Storage>,
+}
+
+/// Parsed HTTP request, only parts needed for streaming.
+struct Request {
+    method: String,
+    path: String,
+    range: Option<String>,
+    keep_alive: bool,
+}
+
+/// Response without body, body is streamed after it.
+struct Response {
+    status: &'static str,
+    headers: Vec<(&'static str, String)>,
+}
+
+impl StreamServer {
+    /// Bind server to given address, which has to be local.
+    /// Data are read from storage of downloader, so the storage has to be set.
+    pub async fn bind(addr: SocketAddr, downloader: Arc<TorrentDownloader>) -> Result<Self> {
+        anyhow::ensure!(
+            addr.ip().is_loopback(),
+            "Stream server can be bound only to localhost, not to {addr}"
+        );
+        let storage = downloader
+            .storage()
+            .context("Storage of downloader has to be set before streaming")?;
+        Ok(StreamServer {
+            listener: TcpListener::bind(addr).await?,
+            downloader,
+            storage,
+        })
+    }
+
+    /// Returns address, on which the server listens.
+    pub fn local_addr(&self) -> Result<SocketAddr> {
+        Ok(self.listener.local_addr()?)
+    }
+
+    /// Returns urls of all streamable files with their indexes, padding files and symlinks are not served.
+    pub fn file_urls(&self) -> Result<Vec<(usize, String)>> {
+        let addr = self.local_addr()?;
+        Ok(self
+            .downloader
+            .layout()
+            .files()
+            .iter()
+            .enumerate()
+            .filter(|(_, entry)| is_streamable(entry))
+            .map(|(file_index, entry)| (file_index, file_url(addr, file_index, entry)))
+            .collect())
+    }
+
+    /// Accept connections until the task is aborted, every connection is handled in its own task.
+    pub async fn run(self) -> Result<()> {
+        let addr = self.local_addr()?;
+        loop {
+            let (stream, _) = self.listener.accept().await?;
+            let downloader = self.downloader.clone();
+            let storage = self.storage.clone();
+            tokio::spawn(async move {
+                let _ = handle_connection(stream, addr, downloader, storage).await;
+            });
+        }
+    }
+}
+
+/// Serve requests of one connection, until client closes it or asks for closing.
+async fn handle_connection(
+    stream: TcpStream,
+    addr: SocketAddr,
+    downloader: Arc<TorrentDownloader>,
+    storage: Arc<dyn Storage>,
+) -> Result<()> {
+    let mut stream = BufReader::new(stream);
+    loop {
+        let request = match timeout(REQUEST_TIMEOUT, read_request(&mut stream)).await {
+            Ok(Ok(Some(request))) => request,
+            Ok(Ok(None)) | Err(_) => return Ok(()),
+            Ok(Err(err)) => {
+                let response = Response::error("400 Bad Request");
+                write_head(stream.get_mut(), &response, false).await?;
+                return Err(err);
+            }
+        };
+        let keep_alive = request.keep_alive;
+        serve_request(stream.get_mut(), request, addr, &downloader, &*storage).await?;
+        if !keep_alive {
+            return Ok(());
+        }
+    }
+}
+
+/// Read request line and headers, returns `None` if client closed connection.
+async fn read_request(stream: &mut BufReader<TcpStream>) -> Result<Option<Request>> {
+    let mut lines = Vec::new();
+    let mut size = 0;
+    loop {
+        let mut line = String::new();
+        let read = (&mut *stream)
+            .take((MAX_HEADER_SIZE - size) as u64)
+            .read_line(&mut line)
+            .await?;
+        if read == 0 {
+            anyhow::ensure!(lines.is_empty(), "Request is not complete");
+            return Ok(None);
+        }
+        size += read;
+        anyhow::ensure!(line.ends_with('\n'), "Request header is too long");
+        let line = line.trim_end().to_string();
+        if line.is_empty() {
+            break;
+        }
+        lines.push(line);
+    }
+
+    let mut request_line = lines
+        .first()
+        .map(|line| line.split(' '))
+        .into_iter()
+        .flatten();
+    let (Some(method), Some(path), Some(version)) = (
+        request_line.next(),
+        request_line.next(),
+        request_line.next(),
+    ) else {
+        anyhow::bail!("Invalid request line");
+    };
+    let mut request = Request {
+        method: method.to_string(),
+        path: path.to_string(),
+        range: None,
+        keep_alive: version == "HTTP/1.1",
+    };
+    for line in &lines[1..] {
+        let Some((name, value)) = line.split_once(':') else {
+            anyhow::bail!("Invalid header {line}");
+        };
+        let value = value.trim();
+        match name.trim().to_ascii_lowercase().as_str() {
+            "range" => request.range = Some(value.to_string()),
+            "connection" => request.keep_alive = !value.eq_ignore_ascii_case("close"),
+            _ => {}
+        }
+    }
+    Ok(Some(request))
+}
+
+/// Answer one request, body of file is streamed as its pieces are written.
+async fn serve_request(
+    stream: &mut TcpStream,
+    request: Request,
+    addr: SocketAddr,
+    downloader: &TorrentDownloader,
+    storage: &dyn Storage,
+) -> Result<()> {
+    let keep_alive = request.keep_alive;
+    let head_only = match request.method.as_str() {
+        "GET" => false,
+        "HEAD" => true,
+        _ => {
+            let mut response = Response::error("405 Method Not Allowed");
+            response.headers.push(("Allow", "GET, HEAD".to_string()));
+            return write_head(stream, &response, keep_alive).await;
+        }
+    };
+
+    let layout = downloader.layout();
+    if request.path == "/" {
+        let list: String = layout
+            .files()
+            .iter()
+            .enumerate()
+            .filter(|(_, entry)| is_streamable(entry))
+            .map(|(file_index, entry)| format!("{}\n", file_url(addr, file_index, entry)))
+            .collect();
+        let response = Response::ok("200 OK", "text/plain; charset=utf-8", list.len() as u64);
+        write_head(stream, &response, keep_alive).await?;
+        if !head_only {
+            stream.write_all(list.as_bytes()).await?;
+        }
+        return Ok(());
+    }
+
+    let Some(file_index) = parse_file_path(&request.path)
+        .filter(|&file_index| layout.files().get(file_index).is_some_and(is_streamable))
+    else {
+        return write_head(stream, &Response::error("404 Not Found"), keep_alive).await;
+    };
+    let entry = &layout.files()[file_index];
+
+    let (status, start, end) = match request.range.as_deref() {
+        None => ("200 OK", 0, entry.length),
+        Some(range) => match parse_range(range, entry.length) {
+            Some((start, end)) => ("206 Partial Content", start, end),
+            None => {
+                let mut response = Response::error("416 Range Not Satisfiable");
+                response
+                    .headers
+                    .push(("Content-Range", format!("bytes */{}", entry.length)));
+                return write_head(stream, &response, keep_alive).await;
+            }
+        },
+    };
+    let mut response = Response::ok(status, content_type(&entry.path), end - start);
+    if request.range.is_some() {
+        response.headers.push((
+            "Content-Range",
+            format!("bytes {start}-{}/{}", end - 1, entry.length),
+        ));
+    }
+    write_head(stream, &response, keep_alive).await?;
+    if head_only || start == end {
+        return Ok(());
+    }
+
+    // Skipped file is requested, so it is wanted now
+    if downloader.file_priorities().await[file_index] == FilePriority::Skip {
+        downloader
+            .set_file_priority(file_index, FilePriority::Normal)
+            .await?;
+    }
+    stream_file(stream, downloader, storage, file_index, start, end).await
+}
+
+/// Send bytes `start..end` of file, every piece is awaited before reading it from storage.
+async fn stream_file(
+    stream: &mut TcpStream,
+    downloader: &TorrentDownloader,
+    storage: &dyn Storage,
+    file_index: usize,
+    start: u64,
+    end: u64,
+) -> Result<()> {
+    let layout = downloader.layout();
+    let entry = &layout.files()[file_index];
+    let piece_length = layout.piece_length();
+    let mut position = start;
+    while position < end {
+        let offset = entry.offset + position;
+        let piece_index = (offset / piece_length) as usize;
+        downloader.set_read_cursor(offset).await;
+        downloader.wait_for_piece(piece_index).await?;
+
+        let piece_offset = layout.piece_offset(piece_index);
+        let piece_end = piece_offset + piece_length - entry.offset;
+        while position < end.min(piece_end) {
+            let length = CHUNK_SIZE.min((end.min(piece_end) - position) as usize);
+            let begin = (entry.offset + position - piece_offset) as usize;
+            let block = storage.read_bytes(piece_index, begin, length).await?;
+            stream.write_all(&block).await?;
+            position += length as u64;
+        }
+    }
+    stream.flush().await?;
+    Ok(())
+}
+
+impl Response {
+    fn ok(status: &'static str, content_type: &str, content_length: u64) -> Self {
+        Response {
+            status,
+            headers: vec![
+                ("Content-Type", content_type.to_string()),
+                ("Content-Length", content_length.to_string()),
+                ("Accept-Ranges", "bytes".to_string()),
+            ],
+        }
+    }
+
+    fn error(status: &'static str) -> Self {
+        Response {
+            status,
+            headers: vec![("Content-Length", "0".to_string())],
+        }
+    }
+}
+
+/// Write status line and headers of response.
+async fn write_head(stream: &mut TcpStream, response: &Response, keep_alive: bool) -> Result<()> {
+    let mut head = format!("HTTP/1.1 {}\r\n", response.status);
+    for (name, value) in &response.headers {
+        head.push_str(&format!("{name}: {value}\r\n"));
+    }
+    let connection = if keep_alive { "keep-alive" } else { "close" };
+    head.push_str(&format!("Connection: {connection}\r\n\r\n"));
+    stream.write_all(head.as_bytes()).await?;
+    Ok(())
+}
+
+/// Padding files are never written and symlinks point to other files, so they can't be streamed.
+fn is_streamable(entry: &FileEntry) -> bool {
+    !entry.attributes.padding && entry.attributes.symlink.is_none()
+}
+
+fn file_url(addr: SocketAddr, file_index: usize, entry: &FileEntry) -> String {
+    let name = entry
+        .path
+        .file_name()
+        .map(|name| name.to_string_lossy().into_owned())
+        .unwrap_or_default();
+    format!(
+        "http://{addr}/files/{file_index}/{}",
+        urlencoding::encode(&name)
+    )
+}
+
+/// Parse index of file from `/files/<file index>/<file name>`, name is only informative.
+fn parse_file_path(path: &str) -> Option<usize> {
+    let path = path.split('?').next()?;
+    let mut parts = path.strip_prefix("/files/")?.split('/');
+    parts.next()?.parse().ok()
+}
+
+/// Parse single range of `Range` header, returns range `start..end` of file with given length.
+/// Returns `None`, if range is invalid or not satisfiable.
+fn parse_range(header: &str, length: u64) -> Option<(u64, u64)> {
+    let range = header.trim().strip_prefix("bytes=")?;
+    // Multiple ranges are not supported
+    if range.contains(',') {
+        return None;
+    }
+    let (start, end) = range.split_once('-')?;
+    let (start, end) = match (start.trim(), end.trim()) {
+        ("", suffix) => {
+            let suffix: u64 = suffix.parse().ok()?;
+            (length.saturating_sub(suffix), length)
+        }
+        (start, "") => (start.parse().ok()?, length),
+        (start, end) => (
+            start.parse().ok()?,
+            end.parse::<u64>().ok()?.saturating_add(1),
+        ),
+    };
+    let end = end.min(length);
+    (start < end).then_some((start, end))
+}
+
+/// Returns MIME type of file by its extension.
+fn content_type(path: &Path) -> &'static str {
+    let extension = path
+        .extension()
+        .map(|extension| extension.to_string_lossy().to_ascii_lowercase())
+        .unwrap_or_default();
+    match extension.as_str() {
+        "mp4" | "m4v" => "video/mp4",
+        "mkv" => "video/x-matroska",
+        "webm" => "video/webm",
+        "avi" => "video/x-msvideo",
+        "mov" => "video/quicktime",
+        "ts" => "video/mp2t",
+        "mp3" => "audio/mpeg",
+        "flac" => "audio/flac",
+        "ogg" | "oga" => "audio/ogg",
+        "opus" => "audio/opus",
+        "m4a" => "audio/mp4",
+        "wav" => "audio/wav",
+        "srt" => "application/x-subrip",
+        "vtt" => "text/vtt",
+        "txt" | "nfo" => "text/plain; charset=utf-8",
+        "jpg" | "jpeg" => "image/jpeg",
+        "png" => "image/png",
+        "pdf" => "application/pdf",
+        _ => "application/octet-stream",
+    }
+}
+
+#[test]
+fn stream_server_range_parsing() {
+    assert_eq!(parse_range("bytes=0-99", 1000), Some((0, 100)));
+    assert_eq!(parse_range("bytes=900-", 1000), Some((900, 1000)));
+    assert_eq!(parse_range("bytes=-100", 1000), Some((900, 1000)));
+    assert_eq!(parse_range("bytes=500-5000", 1000), Some((500, 1000)));
+    assert_eq!(parse_range("bytes=1000-", 1000), None);
+    assert_eq!(parse_range("bytes=0-1,5-6", 1000), None);
+    assert_eq!(parse_range("items=0-1", 1000), None);
+    assert_eq!(parse_file_path("/files/3/movie%20one.mkv"), Some(3));
+    assert_eq!(parse_file_path("/other/3"), None);
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+async fn stream_server_streams_from_seeder() {
+    use crate::peer_id::PeerId;
+    use crate::piece_pool::PickMode;
+    use crate::storage::MemoryStorage;
+    use crate::test_utils::{data_torrent, spawn_seeder, tracker_peer};
+
+    const PIECE_LENGTH: usize = 32 * 1024;
+    let data: Vec<u8> = (0..80_000u32).map(|i| (i * 7 % 251) as u8).collect();
+    let mut torrent = data_torrent(&data, PIECE_LENGTH);
+    torrent.name = "movie.mp4".to_string();
+
+    // Data are kept only in memory
+    let mut downloader = TorrentDownloader::new(torrent).unwrap();
+    let seeder_addr = spawn_seeder(downloader.info_hash(), data.clone(), PIECE_LENGTH).await;
+    let storage = Arc::new(MemoryStorage::new(downloader.layout()));
+    downloader.set_storage(storage);
+    let downloader = Arc::new(downloader);
+    downloader
+        .set_pick_mode(PickMode::Streaming {
+            readahead: 2,
+            piece_deadline: Duration::from_secs(2),
+        })
+        .await;
+    let server = StreamServer::bind("127.0.0.1:0".parse().unwrap(), downloader.clone())
+        .await
+        .unwrap();
+    let url = server.file_urls().unwrap()[0].1.clone();
+    tokio::spawn(server.run());
+
+    // Request is sent before download starts, so it waits for pieces
+    let client = reqwest::Client::new();
+    let range_request = client.get(&url).header("Range", "bytes=40000-49999").send();
+    let (downloaded_sender, _downloaded_receiver) = tokio::sync::mpsc::channel(16);
+    let download = tokio::spawn(async move {
+        let peers = vec![tracker_peer(seeder_addr)];
+        downloader
+            .download_torrent(peers, &PeerId::generate(), String::new(), downloaded_sender)
+            .await
+    });
+
+    let response = timeout(Duration::from_secs(30), range_request)
+        .await
+        .unwrap()
+        .unwrap();
+    assert_eq!(response.status(), 206);
+    assert_eq!(response.headers()["content-type"], "video/mp4");
+    assert_eq!(response.headers()["content-length"], "10000");
+    assert_eq!(
+        response.headers()["content-range"],
+        "bytes 40000-49999/80000"
+    );
+    assert_eq!(response.bytes().await.unwrap(), &data[40000..50000]);
+
+    let response = client.get(&url).send().await.unwrap();
+    assert_eq!(response.status(), 200);
+    assert_eq!(response.bytes().await.unwrap(), &data[..]);
+    let response = client
+        .get(&url)
+        .header("Range", "bytes=90000-")
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status(), 416);
+    timeout(Duration::from_secs(30), download)
+        .await
+        .unwrap()
+        .unwrap()
+        .unwrap();
+}
@@ -0,0 +1,216 @@
+//! Fixtures shared by tests: torrents, fake peers and storage wrapper.
+//! Integration tests and benches use it through `test-utils` feature.
+
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+
+use anyhow::Result;
+use async_trait::async_trait;
+use lava_torrent::torrent::v1::{File, Torrent};
+use lava_torrent::tracker::Peer;
+use sha1::{Digest, Sha1};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+
+use crate::file_layout::FileLayout;
+use crate::hash::{InfoHash, PieceHash};
+use crate::peer_comunication::handshake::Handshake;
+use crate::storage::{MemoryStorage, Storage};
+
+/// Peer-id used by peers spawned by `spawn_seeder`.
+pub const SEEDER_PEER_ID: [u8; 20] = [1; 20];
+
+/// Returns single file torrent `data`, hashes of its pieces are zeros.
+pub fn test_torrent(length: i64, piece_length: i64) -> Torrent {
+    Torrent {
+        announce: None,
+        announce_list: None,
+        length,
+        files: None,
+        name: "data".to_string(),
+        piece_length,
+        pieces: vec![vec![0u8; 20]; (length as u64).div_ceil(piece_length as u64) as usize],
+        extra_fields: None,
+        extra_info_fields: None,
+    }
+}
+
+/// Returns single file torrent `data` with hashes of pieces of given data.
+pub fn data_torrent(data: &[u8], piece_length: usize) -> Torrent {
+    let mut torrent = test_torrent(data.len() as i64, piece_length as i64);
+    torrent.pieces = data
+        .chunks(piece_length)
+        .map(|piece| Sha1::digest(piece).to_vec())
+        .collect();
+    torrent
+}
+
+/// Returns torrent `bundle` with files of given paths and lengths, hashes of its pieces are zeros.
+pub fn files_torrent(files: &[(&str, i64)], piece_length: i64) -> Torrent {
+    let length = files.iter().map(|(_, length)| length).sum();
+    let mut torrent = test_torrent(length, piece_length);
+    torrent.name = "bundle".to_string();
+    torrent.files = Some(
+        files
+            .iter()
+            .map(|(path, length)| File {
+                length: *length,
+                path: PathBuf::from(path),
+                extra_fields: None,
+            })
+            .collect(),
+    );
+    torrent
+}
+
+/// Returns peer with given address, as it's returned by tracker.
+pub fn tracker_peer(addr: SocketAddr) -> Peer {
+    Peer {
+        id: None,
+        addr,
+        extra_fields: None,
+    }
+}
+
+/// Send message of peer protocol with given id and payload.
+pub async fn send_message(stream: &mut TcpStream, id: u8, payload: &[u8]) -> std::io::Result<()> {
+    stream.write_u32(payload.len() as u32 + 1).await?;
+    stream.write_u8(id).await?;
+    stream.write_all(payload).await
+}
+
+/// Read message of peer protocol, returns its id and payload. Keep-alive has id 0 and no payload.
+pub async fn read_message(stream: &mut TcpStream) -> std::io::Result<(u8, Vec<u8>)> {
+    let length = stream.read_u32().await? as usize;
+    if length == 0 {
+        return Ok((0, Vec::new()));
+    }
+    let mut message = vec![0u8; length];
+    stream.read_exact(&mut message).await?;
+    Ok((message[0], message[1..].to_vec()))
+}
+
+/// Spawn peer with all pieces of `data`, which answers every request. It accepts any number of connections.
+pub async fn spawn_seeder(info_hash: InfoHash, data: Vec<u8>, piece_length: usize) -> SocketAddr {
+    let pieces: Vec<usize> = (0..data.len().div_ceil(piece_length)).collect();
+    spawn_partial_seeder(info_hash, data, piece_length, &pieces).await
+}
+
+/// Spawn peer, which has only given pieces of `data`.
+pub async fn spawn_partial_seeder(
+    info_hash: InfoHash,
+    data: Vec<u8>,
+    piece_length: usize,
+    pieces: &[usize],
+) -> SocketAddr {
+    let mut bitfield = vec![0u8; data.len().div_ceil(piece_length).div_ceil(8)];
+    for piece_index in pieces {
+        bitfield[piece_index / 8] |= 0x80 >> (piece_index % 8);
+    }
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    let data = Arc::new(data);
+    tokio::spawn(async move {
+        while let Ok((stream, _)) = listener.accept().await {
+            let seed = serve_seed(
+                stream,
+                info_hash,
+                data.clone(),
+                piece_length,
+                bitfield.clone(),
+            );
+            tokio::spawn(seed);
+        }
+    });
+    addr
+}
+
+/// Answer handshake, send bitfield and unchoke, then send requested blocks until peer disconnects.
+async fn serve_seed(
+    mut stream: TcpStream,
+    info_hash: InfoHash,
+    data: Arc<Vec<u8>>,
+    piece_length: usize,
+    bitfield: Vec<u8>,
+) -> std::io::Result<()> {
+    let mut handshake = [0u8; 68];
+    stream.read_exact(&mut handshake).await?;
+    let answer = Handshake::new(&info_hash.to_arr(), &SEEDER_PEER_ID);
+    stream.write_all(&answer.get_bytes()).await?;
+    send_message(&mut stream, 5, &bitfield).await?;
+    send_message(&mut stream, 1, &[]).await?;
+    loop {
+        let (id, payload) = read_message(&mut stream).await?;
+        if id != 6 {
+            continue;
+        }
+        let field = |idx: usize| u32::from_be_bytes(payload[idx..idx + 4].try_into().unwrap());
+        let offset = field(0) as usize * piece_length + field(4) as usize;
+        let mut block = payload[..8].to_vec();
+        block.extend_from_slice(&data[offset..offset + field(8) as usize]);
+        send_message(&mut stream, 7, &block).await?;
+    }
+}
+
+/// Memory storage, which records all writes and fails with full disk error while `full` is set.
+pub struct TestStorage {
+    memory: MemoryStorage,
+    /// Piece index, offset and length of every successful write.
+    writes: Mutex<Vec<(usize, usize, usize)>>,
+    full: AtomicBool,
+}
+
+impl TestStorage {
+    pub fn new(layout: Arc<FileLayout>) -> Self {
+        TestStorage {
+            memory: MemoryStorage::new(layout),
+            writes: Mutex::new(Vec::new()),
+            full: AtomicBool::new(false),
+        }
+    }
+
+    /// Returns recorded writes.
+    pub fn writes(&self) -> Vec<(usize, usize, usize)> {
+        self.writes.lock().unwrap().clone()
+    }
+
+    pub fn set_full(&self, full: bool) {
+        self.full.store(full, Ordering::SeqCst);
+    }
+}
+
+#[async_trait]
+impl Storage for TestStorage {
+    async fn write_block(&self, piece_index: usize, begin: usize, data: &[u8]) -> Result<()> {
+        if self.full.load(Ordering::SeqCst) {
+            return Err(std::io::Error::from(std::io::ErrorKind::StorageFull).into());
+        }
+        self.writes
+            .lock()
+            .unwrap()
+            .push((piece_index, begin, data.len()));
+        self.memory.write_block(piece_index, begin, data).await
+    }
+
+    async fn read_block(&self, piece_index: usize, begin: usize, length: usize) -> Result<Vec<u8>> {
+        self.memory.read_block(piece_index, begin, length).await
+    }
+
+    async fn hash_piece(&self, piece_index: usize) -> Result<PieceHash> {
+        self.memory.hash_piece(piece_index).await
+    }
+
+    async fn flush(&self) -> Result<()> {
+        Ok(())
+    }
+
+    async fn move_to(&self, folder: &Path) -> Result<()> {
+        self.memory.move_to(folder).await
+    }
+
+    async fn delete(&self) -> Result<()> {
+        self.memory.delete().await
+    }
+}
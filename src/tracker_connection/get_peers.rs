@@ -1,17 +1,61 @@
+use std::time::Duration;
+
 use anyhow::Error;
 use lava_torrent::torrent::v1::Torrent;
 use reqwest::Url;
+use serde::Serialize;
 
+use crate::hash::InfoHash;
 use crate::peer_id::PeerId;
 use crate::tracker_connection::tracker_response::TrackerResponse;
 
+/// Maximal time of `stopped` announce on shutdown, unreachable tracker doesn't delay exit.
+pub const STOPPED_ANNOUNCE_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Event reported to tracker, announce without event is regular update.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum AnnounceEvent {
+    Started,
+    /// Download was completed, it's sent only once.
+    Completed,
+    /// Client stopped downloading or seeding the torrent.
+    Stopped,
+}
+
+/// Transferred bytes of torrent reported to tracker.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct AnnounceStats {
+    pub uploaded: u64,
+    pub downloaded: u64,
+    /// Number of bytes, which are not downloaded yet.
+    pub left: u64,
+}
+
 /// Discover available peers from tracker.
-/// Done based on informations from `torrent_file`.
+/// Done based on informations from `torrent_file`, peers are from swarm with given `info_hash`.
 /// User `peer_id` and `port` is needed.
 pub async fn discover_peers(
     torrent_file: &Torrent,
+    info_hash: &InfoHash,
+    peer_id: &PeerId,
+    port: u16,
+) -> anyhow::Result<TrackerResponse> {
+    let stats = AnnounceStats {
+        left: torrent_file.length as u64,
+        ..AnnounceStats::default()
+    };
+    announce(torrent_file, info_hash, peer_id, port, stats, None).await
+}
+
+/// Announce progress of torrent to tracker, and get peers from swarm with given `info_hash`.
+pub async fn announce(
+    torrent_file: &Torrent,
+    info_hash: &InfoHash,
     peer_id: &PeerId,
     port: u16,
+    stats: AnnounceStats,
+    event: Option<AnnounceEvent>,
 ) -> anyhow::Result<TrackerResponse> {
     let url = torrent_file.announce.clone();
     let url = match url {
@@ -24,9 +68,12 @@ pub async fn discover_peers(
     let announce_url = Url::parse(&url)?;
 
     let tracker_response = match announce_url.scheme() {
-        "http" | "https" => TrackerResponse::get_from_http(torrent_file, peer_id, port).await,
+        "http" | "https" => {
+            TrackerResponse::get_from_http(torrent_file, info_hash, peer_id, port, stats, event)
+                .await
+        }
         // UDP is not working for now, will fail on todo!()
-        "udp" => TrackerResponse::get_from_udp(torrent_file, peer_id).await,
+        "udp" => TrackerResponse::get_from_udp(torrent_file, info_hash, peer_id).await,
         _ => Err(Error::msg(format!(
             "Unsupported tracker protocol: {}",
             announce_url.scheme()
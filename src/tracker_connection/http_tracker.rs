@@ -1,4 +1,6 @@
+use crate::hash::InfoHash;
 use crate::peer_id::PeerId;
+use crate::tracker_connection::get_peers::{AnnounceEvent, AnnounceStats};
 use crate::tracker_connection::tracker_response::TrackerResponse;
 use anyhow::Context;
 use lava_torrent::torrent::v1::Torrent;
@@ -8,20 +10,21 @@ use serde::Serialize;
 /// Structure representing HTTP request to torrent tracker
 #[derive(Debug, Clone, Serialize)]
 pub struct HttpTrackerRequest {
-    /// 20 character long string
-    peer_id: String,
-
     /// Port client listening on
     port: u16,
 
-    /// The total amount downloaded
-    uploaded: usize,
+    /// The total amount uploaded
+    uploaded: u64,
 
     /// The total amount yet downloaded
-    downloaded: usize,
+    downloaded: u64,
 
     /// The number left to download
-    left: usize,
+    left: u64,
+
+    /// Started, completed or stopped, regular announce has no event
+    #[serde(skip_serializing_if = "Option::is_none")]
+    event: Option<AnnounceEvent>,
 
     /// set to one if the peer list should bee compact
     /// set to 1 if compact should be used
@@ -30,14 +33,15 @@ pub struct HttpTrackerRequest {
 }
 
 impl HttpTrackerRequest {
-    /// Creates new HTTP request based on information from `torrent file`, given `peer id` and `port`.
-    pub fn new(torrent: &Torrent, peer_id: &PeerId, port: u16) -> Self {
+    /// Creates new HTTP request with given `port`, transferred bytes and event.
+    /// `peer_id` and `info_hash` are binary, therefore they are added to URL separately.
+    pub fn new(port: u16, stats: AnnounceStats, event: Option<AnnounceEvent>) -> Self {
         HttpTrackerRequest {
-            peer_id: peer_id.to_string(),
             port,
-            uploaded: 0,
-            downloaded: 0,
-            left: torrent.length as usize,
+            uploaded: stats.uploaded,
+            downloaded: stats.downloaded,
+            left: stats.left,
+            event,
             compact: 1,
         }
     }
@@ -46,20 +50,24 @@ impl HttpTrackerRequest {
 /// Send request to torrent tracker and accept response
 async fn tracker_request(
     torrent: &Torrent,
+    info_hash: &InfoHash,
     peer_id: &PeerId,
     port: u16,
+    stats: AnnounceStats,
+    event: Option<AnnounceEvent>,
 ) -> anyhow::Result<TrackerResponse> {
-    let request = HttpTrackerRequest::new(torrent, peer_id, port);
+    let request = HttpTrackerRequest::new(port, stats, event);
     let url_params =
         serde_urlencoded::to_string(&request).context("Failed to urlencode parameters")?;
     let tracker_url = format!(
-        "{}?{}&info_hash={}",
+        "{}?{}&info_hash={}&peer_id={}",
         torrent
             .announce
             .clone()
             .context("No announced in torrent file")?,
         url_params,
-        &urlencode(&torrent.info_hash_bytes())
+        &urlencode(&info_hash.to_vec()),
+        &urlencode(&peer_id.to_vec())
     );
 
     let response = reqwest::get(tracker_url)
@@ -91,15 +99,18 @@ impl TrackerResponse {
     /// Get tracker response from http torrent tracker
     pub async fn get_from_http(
         torrent: &Torrent,
+        info_hash: &InfoHash,
         peer_id: &PeerId,
         port: u16,
+        stats: AnnounceStats,
+        event: Option<AnnounceEvent>,
     ) -> anyhow::Result<Self> {
-        tracker_request(torrent, peer_id, port).await
+        tracker_request(torrent, info_hash, peer_id, port, stats, event).await
     }
 }
 
 /// Function that encode byte array to string correctly for URL request.
-/// Neccesary for sending `info_hash`.
+/// Neccesary for sending `info_hash` and `peer_id`.
 fn urlencode(t: &Vec<u8>) -> String {
     let mut encoded = String::with_capacity(3 * t.len());
     for &byte in t {
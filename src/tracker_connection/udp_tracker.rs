@@ -1,4 +1,5 @@
 use super::tracker_response::TrackerResponse;
+use crate::hash::InfoHash;
 use crate::peer_id::PeerId;
 use anyhow::Result;
 use lava_torrent::{torrent::v1::Torrent, tracker::Peer};
@@ -11,8 +12,14 @@ use url::Url;
 
 /// TODO: finish work on this, not working for now
 impl TrackerResponse {
+    /// Announce to UDP tracker is not supported yet, it always returns error,
+    /// so the session records it as tracker error and continues with other peers.
     #[allow(dead_code, unused)]
-    pub async fn get_from_udp(torrent: &Torrent, peer_id: &PeerId) -> Result<Self> {
+    pub async fn get_from_udp(
+        torrent: &Torrent,
+        info_hash: &InfoHash,
+        peer_id: &PeerId,
+    ) -> Result<Self> {
         let announce = torrent.announce.clone();
         let announce = match announce {
             Some(url) => url,
@@ -20,14 +27,15 @@ impl TrackerResponse {
                 anyhow::bail!("No announce in torrent file");
             }
         };
-        todo!()
-        // discover_udp_peers(torrent, &announce, peer_id).await
+        anyhow::bail!("UDP trackers are not supported yet: {announce}")
+        // discover_udp_peers(torrent, info_hash, &announce, peer_id).await
     }
 }
 
 #[allow(dead_code)]
 async fn discover_udp_peers(
     torrent: &Torrent,
+    info_hash: &InfoHash,
     announce: &str,
     peer_id: &PeerId,
 ) -> anyhow::Result<TrackerResponse> {
@@ -71,7 +79,7 @@ async fn discover_udp_peers(
     announce_req.extend_from_slice(&resp_connection_id.to_be_bytes());
     announce_req.extend_from_slice(&(1u32.to_be_bytes())); // Announce action
     announce_req.extend_from_slice(&transaction_id.to_be_bytes());
-    announce_req.extend_from_slice(&torrent.info_hash_bytes());
+    announce_req.extend_from_slice(info_hash.as_bytes());
     announce_req.extend_from_slice(peer_id.as_ref());
     announce_req.extend_from_slice(&0u64.to_be_bytes()); // downloaded
     announce_req.extend_from_slice(&(torrent.length as u64).to_be_bytes()); // left
@@ -157,3 +165,17 @@ fn parse_udp_response(response: &[u8]) -> anyhow::Result<TrackerResponse> {
         }
     }
 }
+
+#[tokio::test]
+async fn udp_announce_returns_error() {
+    let mut torrent = crate::test_utils::test_torrent(16, 16);
+    torrent.announce = Some("udp://tracker.example.com:6969/announce".to_string());
+    let info_hash = InfoHash::new(vec![0u8; 20]).unwrap();
+    let response = TrackerResponse::get_from_udp(&torrent, &info_hash, &PeerId::generate()).await;
+    let Err(err) = response else {
+        panic!("UDP announce should fail");
+    };
+    assert!(err
+        .to_string()
+        .starts_with("UDP trackers are not supported"));
+}
@@ -1,50 +1,189 @@
 use crate::{
     download::TorrentDownloader,
+    file_layout::FilePriority,
+    ip_filter::IpFilter,
+    metainfo::Metainfo,
+    peer_comunication::{peer_score::PeerScores, peer_stats::PeerStatsSnapshot},
     peer_id::PeerId,
-    tracker_connection::{get_peers::discover_peers, tracker_response::TrackerResponse},
+    piece_pool::PickMode,
+    rate_limit::{BandwidthLimits, RateSchedule},
+    resume::ResumeData,
+    storage::StorageOptions,
+    stream_server::StreamServer,
+    tracker_connection::{
+        get_peers::{announce, discover_peers, AnnounceEvent, STOPPED_ANNOUNCE_TIMEOUT},
+        tracker_response::TrackerResponse,
+    },
 };
 use anyhow::Result;
+use lava_torrent::torrent::v1::Torrent;
 use ratatui::{
     layout::{Constraint, Direction, Layout},
     style::{Color, Style, Stylize},
     widgets::{Block, Borders, Gauge, List, Paragraph},
     Terminal,
 };
-use std::io;
-use tokio::{sync::mpsc, task::JoinHandle};
+use std::{io, net::SocketAddr, path::PathBuf, sync::Arc, time::Duration};
+use tokio::{
+    sync::{mpsc, Mutex},
+    task::JoinHandle,
+    time::{sleep, timeout},
+};
+
+/// Time between two redraws of TUI.
+const REDRAW_INTERVAL: Duration = Duration::from_millis(100);
+
+/// Optional settings of TUI client.
+#[derive(Debug, Clone, Default)]
+pub struct TuiOptions {
+    /// File, from which banned peers are loaded and to which new bans are stored.
+    pub ban_file: Option<PathBuf>,
+    /// Blocklist file, peers from blocked address ranges are never connected.
+    pub ip_filter_file: Option<PathBuf>,
+    /// Download limit in bytes per second, `0` means unlimited.
+    pub download_limit: u64,
+    /// Upload limit in bytes per second, `0` means unlimited.
+    pub upload_limit: u64,
+    /// Time-of-day schedule of limits, overrides `download_limit` and `upload_limit` in its time ranges.
+    pub schedule: Option<RateSchedule>,
+    /// Priorities of files by their index in torrent, other files have normal priority.
+    pub file_priorities: Vec<(usize, FilePriority)>,
+    /// Order in which pieces are downloaded.
+    pub pick_mode: PickMode,
+    /// Local port of HTTP server streaming files of torrent, server is not started if not set.
+    pub stream_port: Option<u16>,
+    /// Storage of downloaded files, write cache and location of unfinished files.
+    pub storage: StorageOptions,
+    /// Folder with resume data, written pieces are stored on exit and not downloaded again after restart.
+    pub resume_folder: Option<PathBuf>,
+}
 
 /// TUI that display information about current downloading in "nicer" format, than just print
-/// No interactions from user are supported.
-pub async fn run_tui(torrent_file_path: &str, download_folder_path: String) -> Result<()> {
+/// No interactions from user are supported, except of Ctrl-C, which shuts down the download.
+/// On exit written data are flushed, resume data are stored and trackers get `stopped` announce.
+pub async fn run_tui(
+    torrent_file_path: &str,
+    download_folder_path: String,
+    options: TuiOptions,
+) -> Result<()> {
     let backend = ratatui::backend::CrosstermBackend::new(io::stdout());
     let mut terminal = Terminal::new(backend)?;
     terminal.clear()?;
-    let torrent_file = lava_torrent::torrent::v1::Torrent::read_from_file(torrent_file_path)?;
+    let metainfo = Metainfo::read_from_file(torrent_file_path)?;
+    let torrent_file = metainfo.torrent.clone();
     let peer_id = PeerId::generate();
     let port: u16 = 6881;
     let TrackerResponse { interval: _, peers } =
-        discover_peers(&torrent_file, &peer_id, port).await?;
+        discover_peers(&torrent_file, &metainfo.info_hash(), &peer_id, port).await?;
 
     let (tx, mut rx) = mpsc::channel::<usize>(100);
 
     let tracker_announce = torrent_file.announce.clone().unwrap();
-    let info_hash = torrent_file.info_hash();
+    let info_hash = match (&metainfo.info_hash_v1, &metainfo.v2) {
+        (Some(v1), Some(v2)) => format!("{v1} (v1), {} (v2)", v2.info_hash),
+        (_, Some(v2)) => format!("{} (v2)", v2.info_hash),
+        _ => metainfo.info_hash().to_string(),
+    };
     let tui_peers = peers.clone();
     let num_pieces = torrent_file.pieces.len();
     let target_name = torrent_file.name.clone();
-    let mut downloaded_pieces = Vec::new();
+    let mut downloaded_pieces: Vec<usize> = Vec::new();
 
     let download_folder_path = download_folder_path.to_string();
 
+    // Hybrid torrent joins also the v2 swarm, its tracker errors are not fatal
+    let mut tui_peers = tui_peers;
+    let mut swarm_peers = Vec::new();
+    for swarm_hash in metainfo.swarm_hashes() {
+        if swarm_hash == metainfo.info_hash() {
+            continue;
+        }
+        if let Ok(response) = discover_peers(&torrent_file, &swarm_hash, &peer_id, port).await {
+            tui_peers.extend(response.peers.iter().cloned());
+            swarm_peers.push((swarm_hash, response.peers));
+        }
+    }
+
+    let mut downloader = TorrentDownloader::from_metainfo(metainfo)?;
+    downloader.set_storage(
+        options
+            .storage
+            .create(PathBuf::from(&download_folder_path), downloader.layout()),
+    );
+    for (swarm_hash, peers) in swarm_peers {
+        downloader.add_swarm_peers(&peers, swarm_hash).await;
+    }
+    let resume_file = options
+        .resume_folder
+        .as_ref()
+        .map(|resume_folder| ResumeData::file_path(resume_folder, &downloader.info_hash()));
+    let resume_data = resume_file.as_deref().map(ResumeData::load).transpose()?;
+    if let Some(resume_data) = resume_data.flatten() {
+        downloader.load_resume_data(&resume_data).await?;
+        downloaded_pieces.extend(resume_data.pieces);
+    }
+    downloader.set_pick_mode(options.pick_mode).await;
+    for (file_index, priority) in options.file_priorities {
+        downloader.set_file_priority(file_index, priority).await?;
+    }
+    if let Some(ban_file) = options.ban_file {
+        downloader.set_peer_scores(Arc::new(Mutex::new(PeerScores::with_ban_file(ban_file)?)));
+    }
+    if let Some(ip_filter_file) = options.ip_filter_file {
+        downloader.set_ip_filter(Arc::new(IpFilter::from_file(ip_filter_file)?));
+    }
+    let global_bandwidth = BandwidthLimits::new(options.download_limit, options.upload_limit);
+    downloader.set_global_bandwidth(global_bandwidth.clone());
+    let schedule_task = options.schedule.map(|mut schedule| {
+        schedule.default_download = options.download_limit;
+        schedule.default_upload = options.upload_limit;
+        schedule.spawn(global_bandwidth)
+    });
+    let peer_scores = downloader.peer_scores();
+    let peer_stats = downloader.peer_stats();
+    let mut connected_peers: Vec<PeerStatsSnapshot> = Vec::new();
+    let ip_filter = downloader.ip_filter();
+    let invalid_filter_lines = match ip_filter.invalid_lines() {
+        0 => String::new(),
+        lines => format!(", skipped blocklist lines: {lines}"),
+    };
+    let invalid_ban_lines = match peer_scores.lock().await.invalid_lines() {
+        0 => String::new(),
+        lines => format!(", skipped ban file lines: {lines}"),
+    };
+    let mut banned_peers = Vec::new();
+
+    let downloader = Arc::new(downloader);
+    let mut stream_urls = String::new();
+    let stream_task = match options.stream_port {
+        Some(port) => {
+            let server =
+                StreamServer::bind(SocketAddr::from(([127, 0, 0, 1], port)), downloader.clone())
+                    .await?;
+            for (_, url) in server.file_urls()? {
+                stream_urls.push_str(&format!("\nStreaming: {url}"));
+            }
+            Some(tokio::spawn(server.run()))
+        }
+        None => None,
+    };
+
+    let pause = downloader.subscribe_pause();
+    let task_downloader = downloader.clone();
     let download_task: JoinHandle<Result<()>> = tokio::spawn(async move {
-        let downloader = TorrentDownloader::new(torrent_file)?;
-        downloader
+        task_downloader
             .download_torrent(peers, &peer_id, download_folder_path, tx)
             .await?;
         Ok(())
     });
+    let ctrl_c = tokio::signal::ctrl_c();
+    tokio::pin!(ctrl_c);
 
     loop {
+        let paused = match pause.borrow().as_deref() {
+            Some(reason) => format!("\nPaused: {reason}"),
+            None => String::new(),
+        };
         terminal.draw(|f| {
             let size = f.area();
             let chunks = Layout::default()
@@ -71,10 +210,11 @@ pub async fn run_tui(torrent_file_path: &str, download_folder_path: String) -> R
 
             // Downloading
             let downloading_block = Block::default().title("Downloading").borders(Borders::ALL);
-            let downloading_paragraph =
-                Paragraph::new(format!("{torrent_file_path} -> {target_name}"))
-                    .alignment(ratatui::layout::Alignment::Left)
-                    .block(downloading_block);
+            let downloading_paragraph = Paragraph::new(format!(
+                "{torrent_file_path} -> {target_name}{stream_urls}{paused}"
+            ))
+            .alignment(ratatui::layout::Alignment::Left)
+            .block(downloading_block);
             f.render_widget(downloading_paragraph, chunks[1]);
 
             // Tracker Announce
@@ -91,18 +231,62 @@ pub async fn run_tui(torrent_file_path: &str, download_folder_path: String) -> R
                 Paragraph::new(info_hash.as_str()).block(tracker_announce_block);
             f.render_widget(tracker_announce_paragraf, chunks[3]);
 
-            // Peers
+            let peer_chunks = Layout::default()
+                .direction(Direction::Horizontal)
+                .constraints([Constraint::Percentage(70), Constraint::Percentage(30)].as_ref())
+                .split(chunks[4]);
+
+            // Peers, connected peers with the fastest download are first
             let peers_block = Block::default()
-                .title(format!("Peers ({})", tui_peers.len()))
+                .title(format!(
+                    "Peers ({}, connected: {}, blocked by IP filter: {}{})",
+                    tui_peers.len(),
+                    connected_peers.len(),
+                    ip_filter.blocked_count(),
+                    invalid_filter_lines
+                ))
                 .borders(Borders::ALL);
             let peers_list = List::new(
-                tui_peers
+                connected_peers
                     .iter()
-                    .map(|peer| format!("{}", peer.addr))
+                    .map(|peer| {
+                        format!(
+                            "{} {} down: {} up: {} requests: {}{}",
+                            peer.addr,
+                            peer.client,
+                            format_rate(peer.download_rate),
+                            format_rate(peer.upload_rate),
+                            peer.outstanding_requests,
+                            if peer.snubbed { " (snubbed)" } else { "" }
+                        )
+                    })
+                    .chain(
+                        tui_peers
+                            .iter()
+                            .filter(|peer| {
+                                !connected_peers
+                                    .iter()
+                                    .any(|connected| connected.addr == peer.addr)
+                            })
+                            .map(|peer| format!("{}", peer.addr)),
+                    )
                     .collect::<Vec<_>>(),
             )
             .block(peers_block);
-            f.render_widget(peers_list, chunks[4]);
+            f.render_widget(peers_list, peer_chunks[0]);
+
+            // Banned peers
+            let banned_block = Block::default()
+                .title(format!(
+                    "Banned ({}{})",
+                    banned_peers.len(),
+                    invalid_ban_lines
+                ))
+                .borders(Borders::ALL);
+            let banned_list = List::new(banned_peers.clone())
+                .style(Style::default().fg(Color::Red))
+                .block(banned_block);
+            f.render_widget(banned_list, peer_chunks[1]);
 
             let downloaded_gauge = Gauge::default()
                 .gauge_style(Style::default().fg(Color::Green))
@@ -137,11 +321,71 @@ pub async fn run_tui(torrent_file_path: &str, download_folder_path: String) -> R
         while let Ok(piece) = rx.try_recv() {
             downloaded_pieces.push(piece);
         }
+        connected_peers = peer_stats.snapshot();
+        connected_peers.sort_by(|a, b| b.download_rate.total_cmp(&a.download_rate));
+        if let Ok(scores) = peer_scores.try_lock() {
+            banned_peers = scores
+                .banned()
+                .map(|(ip, reason)| format!("{ip} ({reason})"))
+                .collect();
+        }
 
-        // End the app when download ends
+        // End the app when download ends, or shut it down on Ctrl-C
         if let Err(mpsc::error::TryRecvError::Disconnected) = rx.try_recv() {
-            download_task.await??;
-            return Ok(());
+            break;
+        }
+        tokio::select! {
+            _ = &mut ctrl_c => {
+                downloader.shutdown();
+                break;
+            }
+            _ = sleep(REDRAW_INTERVAL) => {}
+        }
+    }
+
+    if let Some(schedule_task) = schedule_task {
+        schedule_task.abort();
+    }
+    if let Some(stream_task) = stream_task {
+        stream_task.abort();
+    }
+    let result = async {
+        // Download task flushes written data, before they are stored in resume data
+        let result = download_task.await?;
+        if let Some(resume_file) = resume_file {
+            downloader.resume_data().await.save(&resume_file)?;
         }
+        announce_stopped(&torrent_file, &downloader, &peer_id, port).await;
+        result
+    }
+    .await;
+    // Cursor is restored also when download or storing of resume data fails
+    terminal.show_cursor()?;
+    result
+}
+
+/// Announce `stopped` to trackers of all swarms of torrent, unreachable tracker doesn't delay exit.
+async fn announce_stopped(
+    torrent_file: &Torrent,
+    downloader: &TorrentDownloader,
+    peer_id: &PeerId,
+    port: u16,
+) {
+    let stats = downloader.announce_stats().await;
+    let stopped = async {
+        for swarm_hash in downloader.swarm_hashes() {
+            let event = Some(AnnounceEvent::Stopped);
+            let _ = announce(torrent_file, swarm_hash, peer_id, port, stats, event).await;
+        }
+    };
+    let _ = timeout(STOPPED_ANNOUNCE_TIMEOUT, stopped).await;
+}
+
+/// Format transfer rate in bytes per second to human readable form.
+fn format_rate(rate: f64) -> String {
+    if rate >= 1024.0 * 1024.0 {
+        format!("{:.1} MiB/s", rate / (1024.0 * 1024.0))
+    } else {
+        format!("{:.1} KiB/s", rate / 1024.0)
     }
 }
@@ -1,78 +1,153 @@
+use crate::file_layout::{FileLayout, FilePriority};
 use crate::piece::PieceData;
+use crate::piece_pool::PiecePool;
+use crate::storage::{is_disk_full, Storage};
 use anyhow::{Ok, Result};
-use std::path::PathBuf;
-use tokio::fs::{File, OpenOptions};
-use tokio::io::{AsyncSeekExt, AsyncWriteExt, BufWriter};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
 use tokio::sync::mpsc::{Receiver, Sender};
+use tokio::sync::{watch, Mutex};
+use tokio_util::sync::CancellationToken;
 
-/// Structure that represents writer, which stores the downloaded pieces during download to final file.
-pub struct PieceFileWriter {
-    file: BufWriter<File>,
+/// Time between two checkpoints, in which written data are synced to disk.
+const CHECKPOINT_INTERVAL: Duration = Duration::from_secs(30);
+/// Time between two attempts to write piece, while disk is full.
+const DISK_FULL_RETRY_INTERVAL: Duration = Duration::from_secs(30);
+/// Reason of pause, which is set by writer when disk is full.
+pub const DISK_FULL_REASON: &str = "Disk is full";
+
+/// Structure that represents writer, which stores the downloaded pieces during download to storage.
+/// Bytes of padding and skipped files are never stored.
+pub struct PieceWriter {
+    storage: Arc<dyn Storage>,
+    layout: Arc<FileLayout>,
+    piece_pool: Arc<Mutex<PiecePool>>,
     total_pieces: usize,
-    piece_length: usize,
-    total_file_size: u64,
     piece_channel: Receiver<PieceData>,
     downloaded_sender: Sender<usize>,
+    /// Reason, why torrent is paused, writer doesn't write while torrent is paused.
+    pause: Arc<watch::Sender<Option<String>>>,
+    /// Cancelled on shutdown, writer flushes written data and ends without finishing the storage.
+    shutdown: CancellationToken,
 }
 
-impl PieceFileWriter {
-    /// Creates new `PieceFileWriter`,
+impl PieceWriter {
+    /// Creates new `PieceWriter`, pieces of `layout` are written into `storage`.
+    /// Written pieces are marked in `piece_pool`, writing ends when all wanted pieces are written.
     /// `piece_channel` is used to receive data of already downloaded pieces,
     /// `downloaded_sender` is used to notifie TUI about pieces that were already writen to file.
-    pub async fn new(
-        file_path: PathBuf,
+    /// When disk is full, torrent is paused through `pause` until the piece can be written.
+    pub fn new(
+        storage: Arc<dyn Storage>,
+        layout: Arc<FileLayout>,
+        piece_pool: Arc<Mutex<PiecePool>>,
         total_pieces: usize,
-        piece_length: usize,
-        total_file_size: u64,
         piece_channel: Receiver<PieceData>,
         downloaded_sender: Sender<usize>,
-    ) -> Result<Self> {
-        // Ensure parent directory exists
-        if let Some(parent) = file_path.parent() {
-            tokio::fs::create_dir_all(parent).await?;
-        }
-        // Open file with write and create options
-        let file = OpenOptions::new()
-            .write(true)
-            .create(true)
-            .truncate(true)
-            .open(file_path)
-            .await?;
-
-        // Pre-allocate file space
-        file.set_len(total_file_size).await?;
-
-        let file_writer = BufWriter::new(file);
-
-        Ok(PieceFileWriter {
-            file: file_writer,
+        pause: Arc<watch::Sender<Option<String>>>,
+    ) -> Self {
+        PieceWriter {
+            storage,
+            layout,
+            piece_pool,
             total_pieces,
-            piece_length,
-            total_file_size,
             piece_channel,
             downloaded_sender,
-        })
+            pause,
+            shutdown: CancellationToken::new(),
+        }
+    }
+
+    /// Set token, which stops writing on shutdown.
+    pub fn set_shutdown_token(&mut self, shutdown: CancellationToken) {
+        self.shutdown = shutdown;
     }
 
-    /// Write all the received pieces to the correct position in final file.
+    /// Write all the received pieces to the storage.
     pub async fn write_file(&mut self) -> Result<()> {
-        let mut saved = 0;
-        while let Some(piece_data) = self.piece_channel.recv().await {
+        let mut last_checkpoint = Instant::now();
+        let mut pause = self.pause.subscribe();
+        let shutdown = self.shutdown.clone();
+        let mut priorities = self.piece_pool.lock().await.subscribe_priorities();
+        while !self.piece_pool.lock().await.is_complete() {
+            let piece_data = tokio::select! {
+                _ = shutdown.cancelled() => None,
+                // Skipping of remaining files completes torrent without any new piece
+                Result::Ok(()) = priorities.changed() => continue,
+                piece_data = async {
+                    // Peers wait for writer, while torrent is paused
+                    pause.wait_for(Option::is_none).await.ok()?;
+                    self.piece_channel.recv().await
+                } => piece_data,
+            };
+            let Some(piece_data) = piece_data else {
+                // Pieces waiting in channel are not marked as written, they are downloaded again after restart
+                return self.storage.flush().await;
+            };
             let piece_idx = piece_data.piece_idx;
-            self.write_piece(piece_data).await?;
+            // Time critical piece can be downloaded by more peers, only the first copy is written
+            if self.piece_pool.lock().await.is_written(piece_idx) {
+                continue;
+            }
+            let partially = loop {
+                match self.write_piece(&piece_data).await {
+                    Result::Ok(partially) => break partially,
+                    Err(err) if is_disk_full(&err) && !shutdown.is_cancelled() => {
+                        self.wait_for_space(&mut pause).await
+                    }
+                    Err(err) => return Err(err),
+                }
+            };
+            self.clear_disk_full();
+            self.piece_pool
+                .lock()
+                .await
+                .mark_written(piece_idx, partially);
             self.downloaded_sender.send(piece_idx).await?;
-            saved += 1;
-            if saved == self.total_pieces {
-                break;
+            if last_checkpoint.elapsed() >= CHECKPOINT_INTERVAL {
+                match self.storage.flush().await {
+                    Result::Ok(()) => last_checkpoint = Instant::now(),
+                    // Checkpoint is tried again after the next piece
+                    Err(err) if is_disk_full(&err) => self.wait_for_space(&mut pause).await,
+                    Err(err) => return Err(err),
+                }
             }
         }
         self.piece_channel.close();
+        let priorities = self.piece_pool.lock().await.file_priorities().to_vec();
+        loop {
+            match self.storage.finish(&priorities).await {
+                Result::Ok(()) => break,
+                Err(err) if is_disk_full(&err) => self.wait_for_space(&mut pause).await,
+                Err(err) => return Err(err),
+            }
+        }
+        self.clear_disk_full();
 
         Ok(())
     }
 
-    /// Writes the given piece to the final file, on correct position.
-    async fn write_piece(&mut self, piece_data: PieceData) -> Result<()> {
+    /// Pause torrent because disk is full, and wait until the next attempt to write.
+    /// Writing is attempted again after a while, or when torrent is resumed.
+    async fn wait_for_space(&self, pause: &mut watch::Receiver<Option<String>>) {
+        self.pause.send_replace(Some(DISK_FULL_REASON.to_string()));
+        pause.mark_unchanged();
+        let _ =
+            tokio::time::timeout(DISK_FULL_RETRY_INTERVAL, pause.wait_for(Option::is_none)).await;
+    }
+
+    /// Resume torrent paused by full disk, after data were written.
+    fn clear_disk_full(&self) {
+        self.pause
+            .send_if_modified(|reason| match reason.as_deref() {
+                Some(DISK_FULL_REASON) => reason.take().is_some(),
+                _ => false,
+            });
+    }
+
+    /// Writes the given piece to the storage, without bytes of padding and skipped files.
+    /// Returns true, if some bytes were not written, because they belong to skipped file.
+    async fn write_piece(&mut self, piece_data: &PieceData) -> Result<bool> {
         let piece_index = piece_data.piece_idx;
         let piece_data = &piece_data.data;
         // Validate piece index and data
@@ -80,22 +155,154 @@ impl PieceFileWriter {
             anyhow::bail!("Invalid piece index");
         }
 
-        // Calculate file offset for this piece
-        let offset = (piece_index * self.piece_length) as u64;
+        let priorities = self.piece_pool.lock().await.file_priorities().to_vec();
+        let offset = self.layout.piece_offset(piece_index);
+        let slices = self.layout.map_range(offset, piece_data.len());
+        let mut partially = false;
+        for slice in slices {
+            let entry = &self.layout.files()[slice.file_index];
+            if entry.attributes.padding || entry.attributes.symlink.is_some() {
+                continue;
+            }
+            if priorities[slice.file_index] == FilePriority::Skip {
+                partially = true;
+                continue;
+            }
+            self.storage
+                .write_block(
+                    piece_index,
+                    slice.range_offset,
+                    &piece_data[slice.range_offset..slice.range_offset + slice.length],
+                )
+                .await?;
+        }
+
+        Ok(partially)
+    }
+}
 
-        // Determine actual bytes to write (handle last piece potentially being smaller)
-        let bytes_to_write = if piece_index == self.total_pieces - 1 {
-            let remaining = self.total_file_size as usize - (piece_index * self.piece_length);
-            &piece_data[..remaining.min(piece_data.len())]
-        } else {
-            &piece_data[..]
-        };
+#[tokio::test]
+async fn writer_skips_padding_and_applies_attributes() {
+    use crate::storage::FileStorage;
+    use crate::test_utils::files_torrent;
+    use lava_torrent::bencode::BencodeElem;
 
-        // Acquire file lock and write piece
-        self.file.seek(std::io::SeekFrom::Start(offset)).await?;
-        self.file.write_all(bytes_to_write).await?;
-        self.file.flush().await?;
+    let extra = |fields: Vec<(&str, BencodeElem)>| {
+        Some(
+            fields
+                .into_iter()
+                .map(|(key, value)| (key.to_string(), value))
+                .collect(),
+        )
+    };
+    let mut torrent = files_torrent(
+        &[("bin/run", 10), (".pad/6", 6), ("link", 0), ("data", 32)],
+        16,
+    );
+    let files = torrent.files.as_mut().unwrap();
+    files[0].extra_fields = extra(vec![("attr", BencodeElem::String("x".into()))]);
+    files[1].extra_fields = extra(vec![("attr", BencodeElem::String("p".into()))]);
+    files[2].extra_fields = extra(vec![
+        ("attr", BencodeElem::String("l".into())),
+        (
+            "symlink path",
+            BencodeElem::List(vec![
+                BencodeElem::String("bin".into()),
+                BencodeElem::String("run".into()),
+            ]),
+        ),
+    ]);
+    let dir = tempfile::tempdir().unwrap();
+    let layout = Arc::new(FileLayout::from_torrent(&torrent).unwrap());
+    let pieces = crate::piece::pieces_from_torrent(&torrent).unwrap();
+    let piece_pool = Arc::new(Mutex::new(PiecePool::new(pieces, layout.clone())));
+    let (piece_sender, piece_receiver) = tokio::sync::mpsc::channel(8);
+    let (downloaded_sender, mut downloaded_receiver) = tokio::sync::mpsc::channel(8);
+    let storage = Arc::new(FileStorage::new(dir.path().to_path_buf(), layout.clone()));
+    let mut writer = PieceWriter::new(
+        storage,
+        layout,
+        piece_pool,
+        3,
+        piece_receiver,
+        downloaded_sender,
+        Arc::new(watch::channel(None).0),
+    );
 
-        Ok(())
+    for piece_idx in 0..3 {
+        let mut data = vec![piece_idx as u8 + 1; 16];
+        if piece_idx == 0 {
+            data[10..].fill(0);
+        }
+        piece_sender
+            .send(PieceData { piece_idx, data })
+            .await
+            .unwrap();
     }
+    writer.write_file().await.unwrap();
+    assert_eq!(downloaded_receiver.recv().await, Some(0));
+
+    let root = dir.path().join("bundle");
+    assert_eq!(std::fs::read(root.join("bin/run")).unwrap(), vec![1u8; 10]);
+    assert!(!root.join(".pad").exists());
+    let data = std::fs::read(root.join("data")).unwrap();
+    assert_eq!(&data[..16], &[2u8; 16]);
+    assert_eq!(&data[16..], &[3u8; 16]);
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        let mode = std::fs::metadata(root.join("bin/run"))
+            .unwrap()
+            .permissions()
+            .mode();
+        assert_ne!(mode & 0o111, 0);
+        assert_eq!(std::fs::read(root.join("link")).unwrap(), vec![1u8; 10]);
+    }
+}
+
+#[tokio::test]
+async fn writer_pauses_on_full_disk() {
+    use crate::test_utils::{test_torrent, TestStorage};
+
+    let torrent = test_torrent(32, 16);
+    let layout = Arc::new(FileLayout::from_torrent(&torrent).unwrap());
+    let pieces = crate::piece::pieces_from_torrent(&torrent).unwrap();
+    let piece_pool = Arc::new(Mutex::new(PiecePool::new(pieces, layout.clone())));
+    let (piece_sender, piece_receiver) = tokio::sync::mpsc::channel(8);
+    let (downloaded_sender, _downloaded_receiver) = tokio::sync::mpsc::channel(8);
+    let storage = Arc::new(TestStorage::new(layout.clone()));
+    storage.set_full(true);
+    let pause = Arc::new(watch::channel(None).0);
+    let mut pause_receiver = pause.subscribe();
+    let mut writer = PieceWriter::new(
+        storage.clone(),
+        layout,
+        piece_pool,
+        2,
+        piece_receiver,
+        downloaded_sender,
+        pause.clone(),
+    );
+    let writer_task = tokio::spawn(async move { writer.write_file().await });
+
+    for piece_idx in 0..2 {
+        let data = vec![piece_idx as u8 + 1; 16];
+        piece_sender
+            .send(PieceData { piece_idx, data })
+            .await
+            .unwrap();
+    }
+    // Writer keeps the piece and pauses torrent instead of failing
+    pause_receiver
+        .wait_for(|reason| reason.as_deref() == Some(DISK_FULL_REASON))
+        .await
+        .unwrap();
+    assert!(!writer_task.is_finished());
+
+    // Space is freed and torrent resumed by user
+    storage.set_full(false);
+    pause.send_replace(None);
+    writer_task.await.unwrap().unwrap();
+    assert_eq!(*pause.borrow(), None);
+    assert_eq!(storage.read_block(1, 0, 16).await.unwrap(), vec![2u8; 16]);
 }
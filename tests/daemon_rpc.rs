@@ -0,0 +1,169 @@
+use std::net::SocketAddr;
+use std::time::Duration;
+
+use base64::Engine;
+use serde_json::{json, Value};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpListener;
+use torrent_client::daemon::{Daemon, DaemonOptions, RPC_PATH};
+use torrent_client::metainfo::Metainfo;
+use torrent_client::session::SessionOptions;
+use torrent_client::test_utils::{data_torrent, spawn_seeder};
+
+const TOKEN: &str = "secret";
+
+/// HTTP tracker, which returns the seed as the only peer.
+async fn serve_tracker(listener: TcpListener, seed: SocketAddr) {
+    let SocketAddr::V4(seed) = seed else {
+        unreachable!()
+    };
+    loop {
+        let (mut stream, _) = listener.accept().await.unwrap();
+        tokio::spawn(async move {
+            let mut request = Vec::new();
+            let mut buffer = [0u8; 1024];
+            while !request.windows(4).any(|window| window == b"\r\n\r\n") {
+                let read = stream.read(&mut buffer).await?;
+                if read == 0 {
+                    return Ok(());
+                }
+                request.extend_from_slice(&buffer[..read]);
+            }
+            let mut body = b"d8:intervali60e5:peers6:".to_vec();
+            body.extend_from_slice(&seed.ip().octets());
+            body.extend_from_slice(&seed.port().to_be_bytes());
+            body.push(b'e');
+            let head = format!(
+                "HTTP/1.1 200 OK\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+                body.len()
+            );
+            stream.write_all(head.as_bytes()).await?;
+            stream.write_all(&body).await?;
+            std::io::Result::Ok(())
+        });
+    }
+}
+
+/// Call method of API over HTTP and return its result.
+async fn call(client: &reqwest::Client, url: &str, method: &str, params: Value) -> Value {
+    let response: Value = client
+        .post(url)
+        .bearer_auth(TOKEN)
+        .json(&json!({ "jsonrpc": "2.0", "id": 1, "method": method, "params": params }))
+        .send()
+        .await
+        .unwrap()
+        .json()
+        .await
+        .unwrap();
+    assert_eq!(
+        response["error"],
+        Value::Null,
+        "{method} failed: {response}"
+    );
+    response["result"].clone()
+}
+
+#[tokio::test]
+async fn download_through_rpc() {
+    let data: Vec<u8> = (0..32).collect();
+    let tracker = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let tracker_addr = tracker.local_addr().unwrap();
+    let mut torrent = data_torrent(&data, 16);
+    torrent.announce = Some(format!("http://{tracker_addr}/announce"));
+    let torrent_bytes = torrent.clone().encode().unwrap();
+    let info_hash = Metainfo::from_torrent(torrent).unwrap().info_hash();
+    let seed_addr = spawn_seeder(info_hash, data.clone(), 16).await;
+    tokio::spawn(serve_tracker(tracker, seed_addr));
+
+    let folder = tempfile::tempdir().unwrap();
+    let mut options = DaemonOptions::new(TOKEN.to_string(), folder.path().to_path_buf());
+    options.rpc_addr = SocketAddr::from(([127, 0, 0, 1], 0));
+    options.rpc_socket = Some(folder.path().join("rpc.sock"));
+    options.session = SessionOptions {
+        listen_addr: SocketAddr::from(([127, 0, 0, 1], 0)),
+        ..SessionOptions::default()
+    };
+    let daemon = Daemon::start(options).await.unwrap();
+    let url = format!("http://{}{RPC_PATH}", daemon.rpc_addr());
+    let client = reqwest::Client::builder().no_proxy().build().unwrap();
+
+    // Requests without token are refused
+    let response = client
+        .post(&url)
+        .bearer_auth("wrong")
+        .body("{}")
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status(), reqwest::StatusCode::UNAUTHORIZED);
+
+    let metainfo = base64::engine::general_purpose::STANDARD.encode(torrent_bytes);
+    let added = call(
+        &client,
+        &url,
+        "add_torrent",
+        json!({ "metainfo": metainfo }),
+    )
+    .await;
+    assert_eq!(added["info_hash"], info_hash.to_string());
+    assert_eq!(added["name"], "data");
+    let torrent = json!({ "info_hash": info_hash.to_string() });
+
+    let priority =
+        json!({ "info_hash": info_hash.to_string(), "file_index": 0, "priority": "high" });
+    call(&client, &url, "set_file_priority", priority).await;
+    let limits =
+        json!({ "info_hash": info_hash.to_string(), "download_limit": 1 << 20, "upload_limit": 0 });
+    call(&client, &url, "set_limits", limits).await;
+    let session_limits = json!({ "download_limit": 0, "upload_limit": 0 });
+    call(&client, &url, "set_limits", session_limits).await;
+
+    // Torrent is downloaded from the seed returned by tracker
+    let stats = tokio::time::timeout(Duration::from_secs(10), async {
+        loop {
+            let stats = call(&client, &url, "torrent_stats", torrent.clone()).await;
+            let written = std::fs::read(folder.path().join("data")).unwrap_or_default();
+            if stats["remaining_pieces"] == 0 && written == data {
+                return stats;
+            }
+            tokio::time::sleep(Duration::from_millis(20)).await;
+        }
+    })
+    .await
+    .unwrap();
+    assert_eq!(stats["files"][0]["priority"], "high");
+    assert_eq!(stats["download_limit"], 1 << 20);
+
+    call(&client, &url, "pause_torrent", torrent.clone()).await;
+    let stats = call(&client, &url, "torrent_stats", torrent.clone()).await;
+    assert_eq!(stats["state"], "paused");
+    call(&client, &url, "resume_torrent", torrent.clone()).await;
+    let stats = call(&client, &url, "torrent_stats", torrent.clone()).await;
+    assert_ne!(stats["state"], "paused");
+
+    // The same API is available over Unix socket
+    #[cfg(unix)]
+    {
+        let mut stream = tokio::net::UnixStream::connect(folder.path().join("rpc.sock"))
+            .await
+            .unwrap();
+        let body = r#"{"jsonrpc":"2.0","id":2,"method":"list_torrents"}"#;
+        let request = format!(
+            "POST {RPC_PATH} HTTP/1.1\r\nAuthorization: Bearer {TOKEN}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{body}",
+            body.len()
+        );
+        stream.write_all(request.as_bytes()).await.unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).await.unwrap();
+        let (head, body) = response.split_once("\r\n\r\n").unwrap();
+        assert!(head.starts_with("HTTP/1.1 200 OK"));
+        let response: Value = serde_json::from_str(body).unwrap();
+        assert_eq!(response["result"][0]["info_hash"], info_hash.to_string());
+    }
+
+    call(&client, &url, "remove_torrent", torrent).await;
+    let torrents = call(&client, &url, "list_torrents", Value::Null).await;
+    assert_eq!(torrents, json!([]));
+    daemon.shutdown().await;
+}